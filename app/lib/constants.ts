@@ -188,6 +188,10 @@ module.exports = {
       httpCode: 400,
       uerr: { ucode: 2030, message: "Transaction already processed" },
     },
+    TX_REJECTED: {
+      httpCode: 400,
+      uerr: { ucode: 2036, message: "Transaction rejected" },
+    },
     A_MORE_RECENT_MEMBERSHIP_EXISTS: {
       httpCode: 400,
       uerr: { ucode: 2031, message: "A more recent membership already exists" },
@@ -43,7 +43,14 @@ import { MetaDAL } from "./sqliteDAL/MetaDAL";
 import { DataErrors } from "../common-libs/errors";
 import { BasicRevocableIdentity, IdentityDTO } from "../dto/IdentityDTO";
 import { FileSystem } from "../system/directory";
-import { RustDbTx, RustServer, RustServerConf, Wot } from "../../../neon/lib";
+import {
+  RustDbTx,
+  RustServer,
+  RustServerConf,
+  TxsHistoryFilter,
+  UdHistoryRange,
+  Wot,
+} from "../../../neon/lib";
 import { IIndexDAO } from "./indexDAL/abstract/IIndexDAO";
 import { BIndexDAO } from "./indexDAL/abstract/BIndexDAO";
 import { MIndexDAO } from "./indexDAL/abstract/MIndexDAO";
@@ -245,13 +252,26 @@ export class FileDAL implements ServerDAO {
     commandName: string | null = null
   ) {
     let selfKeypair = conf.pair ? conf.pair.sec : null;
-    let rustServerConf = {
+    let rustServerConf: RustServerConf = {
       command: commandName,
       currency: currency || "",
       selfKeypair,
       txsMempoolSize:
         conf.txsMempoolSize || constants.SANDBOX_SIZE_TRANSACTIONS,
     };
+    if (conf.mempoolMaintenanceInterval) {
+      rustServerConf.mempoolMaintenanceInterval =
+        conf.mempoolMaintenanceInterval;
+    }
+    if (conf.txsMempoolMaxAge) {
+      rustServerConf.txsMempoolMaxAge = conf.txsMempoolMaxAge;
+    }
+    if (conf.wotMempoolMaxAge) {
+      rustServerConf.wotMempoolMaxAge = conf.wotMempoolMaxAge;
+    }
+    if (process.env.DUNITER_METRICS_ADDRESS) {
+      rustServerConf.metricsAddress = process.env.DUNITER_METRICS_ADDRESS;
+    }
     if (conf.memory) {
       this.rustServer = new RustServer(rustServerConf, null);
     } else {
@@ -259,8 +279,20 @@ export class FileDAL implements ServerDAO {
     }
   }
 
+  /**
+   * Duniter modules publish their endpoints before the rust server is started.
+   * Return an empty array if they have not published them.
+   */
   getRustEndpoints(): string[] {
-    return this.rustServer.getSelfEndpoints();
+    try {
+      return this.rustServer.getSelfEndpoints(0);
+    } catch (e) {
+      if (e && e.code === "SELF_ENDPOINTS_NOT_READY") {
+        logger.debug("Rust endpoints not ready yet");
+        return [];
+      }
+      throw e;
+    }
   }
 
   getDBVersion() {
@@ -497,6 +529,19 @@ export class FileDAL implements ServerDAO {
       );
   }
 
+  getSourcesByScript(script: string, withPending = false): HttpSource[] {
+    return this.rustServer.getSources(script, withPending).map((s) => {
+      return {
+        type: s.type,
+        noffset: s.noffset,
+        identifier: s.identifier,
+        amount: s.amount,
+        base: s.base,
+        conditions: s.conditions,
+      };
+    });
+  }
+
   async findByIdentifierPosAmountBase(
     identifier: string,
     pos: number,
@@ -872,6 +917,32 @@ export class FileDAL implements ServerDAO {
     return this.rustServer.getTransactionsPending(versionMin, medianTime);
   }
 
+  selectTxsForNextBlock(
+    maxSize: number,
+    medianTime: number,
+    versionMin: number
+  ) {
+    return this.rustServer.selectTxsForNextBlock(
+      maxSize,
+      medianTime,
+      versionMin
+    );
+  }
+
+  getTransactionsPendingPage(
+    pageSize: number,
+    cursor: string | null = null,
+    versionMin = 0,
+    medianTime: number | null = null
+  ) {
+    return this.rustServer.getTransactionsPendingPage(
+      versionMin,
+      medianTime,
+      pageSize,
+      cursor
+    );
+  }
+
   getNewPendingTxs() {
     return this.rustServer.getNewPendingTxs();
   }
@@ -1405,6 +1476,18 @@ export class FileDAL implements ServerDAO {
     );
   }
 
+  /**
+   * Add a new transaction to the mempool if it is accepted, evicting pending transactions if
+   * needed. Return the rejection reason otherwise.
+   */
+  saveNewTransaction(tx: TransactionDTO, serverPubkey: string) {
+    let currentTimestamp = Math.floor(Date.now() / 1000);
+    return this.rustServer.addNewTx(
+      tx.toTransactionDTOV10(currentTimestamp),
+      serverPubkey
+    );
+  }
+
   async computeTxBlockstampTime(tx: TransactionDTO): Promise<TransactionDTO> {
     let blockNumber = parseInt(tx.blockstamp.split("-")[0]);
     let basedBlock = await this.getBlock(blockNumber);
@@ -1462,7 +1545,74 @@ export class FileDAL implements ServerDAO {
     return history;
   }
 
-  async getUDHistory(pubkey: string): Promise<{ history: HttpUD[] }> {
+  async getFilteredTransactionsHistory(
+    pubkey: string,
+    filter: TxsHistoryFilter
+  ) {
+    const res = this.rustServer.getFilteredTransactionsHistory(pubkey, filter);
+    return {
+      sent: await Promise.all(
+        res.sent.map(async (tx) => this.RustDbTxToDbTx(tx))
+      ),
+      received: await Promise.all(
+        res.received.map(async (tx) => this.RustDbTxToDbTx(tx))
+      ),
+      sending: await Promise.all(
+        res.sending.map(async (tx) => this.RustPendingTxToDbTx(tx))
+      ),
+      pending: await Promise.all(
+        res.pending.map(async (tx) => this.RustPendingTxToDbTx(tx))
+      ),
+    };
+  }
+
+  async getTransactionsHistoryPage(
+    pubkey: string,
+    filter: TxsHistoryFilter,
+    pageSize: number,
+    cursor: string | null = null
+  ) {
+    const res = this.rustServer.getTransactionsHistoryPage(
+      pubkey,
+      filter,
+      pageSize,
+      cursor
+    );
+    return {
+      sent: await Promise.all(
+        res.sent.map(async (tx) => this.RustDbTxToDbTx(tx))
+      ),
+      received: await Promise.all(
+        res.received.map(async (tx) => this.RustDbTxToDbTx(tx))
+      ),
+      sending: await Promise.all(
+        res.sending.map(async (tx) => this.RustPendingTxToDbTx(tx))
+      ),
+      pending: await Promise.all(
+        res.pending.map(async (tx) => this.RustPendingTxToDbTx(tx))
+      ),
+      nextCursor: res.nextCursor,
+    };
+  }
+
+  async getUDHistory(
+    pubkey: string,
+    range: UdHistoryRange = {}
+  ): Promise<{ history: HttpUD[] }> {
+    try {
+      return {
+        history: this.rustServer.getUdHistory(pubkey, range).map((ud) => ({
+          block_number: ud.blockNumber,
+          time: ud.time,
+          consumed: ud.consumed,
+          amount: ud.amount,
+          base: ud.base,
+        })),
+      };
+    } catch (e) {
+      // Memberships are not indexed in Rust on nodes synchronized by an older version
+      logger.warn("Fail to get UD history from Rust: %s", e);
+    }
     const sources: UDSource[] = await this.dividendDAL.getUDSources(pubkey);
     return {
       history: await Promise.all<HttpUD>(
@@ -203,7 +203,11 @@ export class ConfDTO
       transactions: false,
       wotwizard: false,
     },
-    public txsMempoolSize?: number
+    public txsMempoolSize?: number,
+    // Mempools maintenance, in seconds
+    public mempoolMaintenanceInterval?: number,
+    public txsMempoolMaxAge?: number,
+    public wotMempoolMaxAge?: number
   ) {}
 
   static mock() {
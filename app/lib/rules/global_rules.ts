@@ -158,6 +158,9 @@ export const GLOBAL_RULES_FUNCTIONS = {
                 hypotheticSrc = TransactionDTO.outputStr2Obj(outputStr);
                 hypotheticSrc.consumed = false;
                 hypotheticSrc.time = 0;
+                // The output of a pending transaction can not be written before the
+                // checked block, so time locks count from its median time
+                hypotheticSrc.written_time = block.medianTime;
               }
             }
             return hypotheticSrc;
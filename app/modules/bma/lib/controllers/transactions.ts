@@ -24,7 +24,7 @@ import {
   HttpTxPending,
 } from "../dtos";
 import { DBTx } from "../../../../lib/db/DBTx";
-import { Underscore } from "../../../../lib/common-libs/underscore";
+import { TxsHistoryFilter } from "../../../../../neon/lib";
 
 const http2raw = require("../http2raw");
 
@@ -51,7 +51,7 @@ export class TransactionBinding extends AbstractController {
 
   async getSources(req: any): Promise<HttpSources> {
     const pubkey = await ParametersService.getPubkeyP(req);
-    const sources = await this.server.dal.getAvailableSourcesByPubkey(pubkey);
+    const sources = this.server.dal.getSourcesByScript("SIG(" + pubkey + ")");
     return {
       currency: this.conf.currency,
       pubkey,
@@ -93,16 +93,9 @@ export class TransactionBinding extends AbstractController {
     const pubkey = await ParametersService.getPubkeyP(req);
     const from = await ParametersService.getFromP(req);
     const to = await ParametersService.getToP(req);
-    return this.getFilteredHistory(pubkey, (res: any) => {
-      const histo = res.history;
-      histo.sent = Underscore.filter(histo.sent, function (tx: any) {
-        return tx && tx.block_number >= from && tx.block_number <= to;
-      });
-      histo.received = Underscore.filter(histo.received, function (tx: any) {
-        return tx && tx.block_number >= from && tx.block_number <= to;
-      });
-      Underscore.extend(histo, { sending: [], receiving: [] });
-      return res;
+    return this.getWrittenHistory(pubkey, {
+      fromBlock: from,
+      toBlock: to,
     });
   }
 
@@ -110,25 +103,16 @@ export class TransactionBinding extends AbstractController {
     const pubkey = await ParametersService.getPubkeyP(req);
     const from = await ParametersService.getFromP(req);
     const to = await ParametersService.getToP(req);
-    return this.getFilteredHistory(pubkey, (res: any) => {
-      const histo = res.history;
-      histo.sent = Underscore.filter(histo.sent, function (tx: any) {
-        return tx && tx.time >= from && tx.time <= to;
-      });
-      histo.received = Underscore.filter(histo.received, function (tx: any) {
-        return tx && tx.time >= from && tx.time <= to;
-      });
-      Underscore.extend(histo, { sending: [], receiving: [] });
-      return res;
+    return this.getWrittenHistory(pubkey, {
+      fromTime: from,
+      toTime: to,
     });
   }
 
   async getPendingForPubkey(req: any): Promise<HttpTxHistory> {
     const pubkey = await ParametersService.getPubkeyP(req);
-    return this.getFilteredHistory(pubkey, function (res: any) {
-      const histo = res.history;
-      Underscore.extend(histo, { sent: [], received: [] });
-      return res;
+    return this.getHistoryMatching(pubkey, {
+      directions: ["sending", "pending"],
     });
   }
 
@@ -156,6 +140,44 @@ export class TransactionBinding extends AbstractController {
     };
   }
 
+  /**
+   * Written transactions matching the range of `filter`, and all pending transactions.
+   */
+  private async getWrittenHistory(
+    pubkey: string,
+    filter: TxsHistoryFilter
+  ): Promise<HttpTxHistory> {
+    const res = await this.getHistoryMatching(pubkey, {
+      ...filter,
+      directions: ["sent", "received"],
+    });
+    const pending = await this.getHistoryMatching(pubkey, {
+      directions: ["pending"],
+    });
+    res.history.pending = pending.history.pending;
+    return res;
+  }
+
+  private async getHistoryMatching(
+    pubkey: string,
+    filter: TxsHistoryFilter
+  ): Promise<HttpTxHistory> {
+    const history = await this.server.dal.getFilteredTransactionsHistory(
+      pubkey,
+      filter
+    );
+    return {
+      currency: this.conf.currency,
+      pubkey: pubkey,
+      history: {
+        sending: history.sending.map(dbtx2HttpTxOfHistory),
+        received: history.received.map(dbtx2HttpTxOfHistory),
+        sent: history.sent.map(dbtx2HttpTxOfHistory),
+        pending: history.pending.map(dbtx2HttpTxOfHistory),
+      },
+    };
+  }
+
   private async getFilteredHistory(
     pubkey: string,
     filter: any
@@ -16,18 +16,20 @@ import { ParametersService } from "../parameters";
 import { Source } from "../entity/source";
 import { HttpUDHistory } from "../dtos";
 import { Underscore } from "../../../../lib/common-libs/underscore";
+import { UdHistoryRange } from "../../../../../neon/lib";
 
 export class UDBinding extends AbstractController {
   async getHistory(req: any): Promise<HttpUDHistory> {
     const pubkey = await ParametersService.getPubkeyP(req);
-    return this.getUDSources(pubkey, (results: any) => results);
+    return this.getUDSources(pubkey, {}, (results: any) => results);
   }
 
   async getHistoryBetweenBlocks(req: any) {
     const pubkey = await ParametersService.getPubkeyP(req);
     const from = await ParametersService.getFromP(req);
     const to = await ParametersService.getToP(req);
-    return this.getUDSources(pubkey, (results: any) => {
+    const range = { fromBlock: from, toBlock: to };
+    return this.getUDSources(pubkey, range, (results: any) => {
       results.history.history = Underscore.filter(
         results.history.history,
         function (ud: any) {
@@ -42,7 +44,8 @@ export class UDBinding extends AbstractController {
     const pubkey = await ParametersService.getPubkeyP(req);
     const from = await ParametersService.getFromP(req);
     const to = await ParametersService.getToP(req);
-    return this.getUDSources(pubkey, (results: any) => {
+    const range = { fromTime: from, toTime: to };
+    return this.getUDSources(pubkey, range, (results: any) => {
       results.history.history = Underscore.filter(
         results.history.history,
         function (ud: any) {
@@ -53,8 +56,12 @@ export class UDBinding extends AbstractController {
     });
   }
 
-  private async getUDSources(pubkey: string, filter: any) {
-    const history: any = await this.server.dal.getUDHistory(pubkey);
+  private async getUDSources(
+    pubkey: string,
+    range: UdHistoryRange,
+    filter: any
+  ) {
+    const history: any = await this.server.dal.getUDHistory(pubkey, range);
     const result = {
       currency: this.conf.currency,
       pubkey: pubkey,
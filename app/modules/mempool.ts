@@ -0,0 +1,80 @@
+// Source file from duniter: Crypto-currency software to manage libre currency such as Ğ1
+// Copyright (C) 2018  Cedric Moreau <cem.moreau@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+"use strict";
+import { ConfDTO } from "../lib/dto/ConfDTO";
+import { Server } from "../../server";
+
+module.exports = {
+  duniter: {
+    cli: [
+      {
+        name: "export-mempool <file>",
+        desc: "Write pending transactions in <file>.",
+        preventIfRunning: true,
+        onDatabaseExecute: async (
+          server: Server,
+          conf: ConfDTO,
+          program: any,
+          params: any
+        ) => {
+          const file = params[0];
+          const logger = server.logger;
+          try {
+            const count = server.dal.rustServer.exportMempool(file);
+            logger.info("%s pending transactions exported to %s", count, file);
+          } catch (err) {
+            logger.error("Error during mempool export:", err);
+            // Fail the command, so that scripts can tell it did not complete
+            throw err;
+          }
+          await server.disconnect();
+        },
+      },
+      {
+        name: "import-mempool <file>",
+        desc:
+          "Add the pending transactions of <file> (written by export-mempool) to the mempool.",
+        preventIfRunning: true,
+        onDatabaseExecute: async (
+          server: Server,
+          conf: ConfDTO,
+          program: any,
+          params: any
+        ) => {
+          const file = params[0];
+          const logger = server.logger;
+          try {
+            const res = server.dal.rustServer.importMempool(file);
+            for (const rejected of res.rejected) {
+              logger.warn(
+                "Transaction %s rejected: %s",
+                rejected.hash,
+                rejected.message
+              );
+            }
+            logger.info(
+              "%s pending transactions imported, %s rejected",
+              res.accepted,
+              res.rejected.length
+            );
+          } catch (err) {
+            logger.error("Error during mempool import:", err);
+            throw err;
+          }
+          await server.disconnect();
+        },
+      },
+    ],
+  },
+};
@@ -125,7 +125,6 @@ export class BlockGenerator {
       generator.filterJoiners(joinersData)
     );
     const leavers = await this.findLeavers(current);
-    const transactions = await this.findTransactions(current, manualValues);
     const certifiersOfNewcomers = Underscore.uniq(
       Underscore.keys(newcomers).reduce((theCertifiers, newcomer: string) => {
         return theCertifiers.concat(
@@ -156,13 +155,15 @@ export class BlockGenerator {
       revocations,
       exclusions,
       wereExcludeds,
-      transactions,
+      (maxSize: number) =>
+        this.findTransactions(current, maxSize, manualValues),
       manualValues
     );
   }
 
   private async findTransactions(
     current: DBBlock | null,
+    maxSize: number,
     options: { dontCareAboutChaining?: boolean }
   ) {
     if (!current) {
@@ -172,7 +173,12 @@ export class BlockGenerator {
     const versionMin = current
       ? Math.min(CommonConstants.LAST_VERSION_FOR_TX, current.version)
       : CommonConstants.DOCUMENTS_VERSION;
-    const txs = await this.dal.getTransactionsPending(versionMin, medianTime);
+    // Available, conflict-free and sorted by dependencies
+    const txs = await this.dal.selectTxsForNextBlock(
+      maxSize,
+      medianTime,
+      versionMin
+    );
     const transactions = [];
     const passingTxs: any[] = [];
     for (const obj of txs) {
@@ -632,7 +638,7 @@ export class BlockGenerator {
     revocations: any,
     exclusions: any,
     wereExcluded: any,
-    transactions: any,
+    findTransactions: (maxSize: number) => Promise<TransactionDTO[]>,
     manualValues: ForcedBlockValues
   ) {
     if (manualValues && manualValues.excluded) {
@@ -849,6 +855,10 @@ export class BlockGenerator {
     block.transactions = [];
     blockLen = BlockDTO.getLen(block);
     if (blockLen < maxLenOfBlock) {
+      // The block length must stay lower than its maximum
+      const transactions = await findTransactions(
+        maxLenOfBlock - blockLen - 1
+      );
       transactions.forEach((tx: any) => {
         const txDTO = TransactionDTO.fromJSONObject(tx);
         const txLen = txDTO.getLen();
@@ -20,6 +20,7 @@ import { FIFOService } from "./FIFOService";
 import { GlobalFifoPromise } from "./GlobalFifoPromise";
 import { DataErrors } from "../lib/common-libs/errors";
 import { DBTx } from "../lib/db/DBTx";
+import { TxRejection } from "../../neon/lib";
 
 const constants = require("../lib/constants");
 
@@ -72,10 +73,10 @@ export class TransactionService extends FIFOService {
           await this.dal.getTxByHash.bind(this.dal)
         );
         const server_pubkey = this.conf.pair && this.conf.pair.pub;
-        if (!(await this.dal.rustServer.acceptNewTx(tx, server_pubkey))) {
-          throw constants.ERRORS.SANDBOX_FOR_TRANSACTION_IS_FULL;
+        const rejection = this.dal.saveNewTransaction(tx, server_pubkey);
+        if (rejection) {
+          throw txRejectionToError(rejection);
         }
-        await this.dal.saveTransaction(tx);
         this.logger.info(
           "✔ TX %s:%s from %s",
           tx.output_amount,
@@ -95,3 +96,29 @@ export class TransactionService extends FIFOService {
     });
   }
 }
+
+function txRejectionToError(rejection: TxRejection) {
+  switch (rejection.code) {
+    case "MEMPOOL_FULL":
+      return constants.ERRORS.SANDBOX_FOR_TRANSACTION_IS_FULL;
+    case "DUPLICATE":
+      return constants.ERRORS.TX_ALREADY_PROCESSED;
+    case "SOURCE_NOT_AVAILABLE":
+    case "DOUBLE_SPEND":
+      return constants.ERRORS.SOURCE_ALREADY_CONSUMED;
+    case "INVALID_UNLOCK":
+      return constants.ERRORS.WRONG_UNLOCKER;
+    case "LOCKED":
+      return constants.ERRORS.LOCKTIME_PREVENT;
+    case "UNBALANCED_AMOUNTS":
+      return constants.ERRORS.WRONG_AMOUNTS;
+    default:
+      return {
+        httpCode: constants.ERRORS.TX_REJECTED.httpCode,
+        uerr: {
+          ucode: constants.ERRORS.TX_REJECTED.uerr.ucode,
+          message: rejection.message,
+        },
+      };
+  }
+}
@@ -46,6 +46,7 @@ const daemonDependency    = require('./app/modules/daemon');
 const pSignalDependency   = require('./app/modules/peersignal');
 const pluginDependency    = require('./app/modules/plugin');
 const dumpDependency      = require('./app/modules/dump');
+const mempoolDependency   = require('./app/modules/mempool');
 
 let sigintListening = false
 
@@ -137,6 +138,7 @@ const DEFAULT_DEPENDENCIES = MINIMAL_DEPENDENCIES.concat([
   { name: 'duniter-bma',       required: BmaDependency },
   { name: 'duniter-ws2p',      required: WS2PDependency },
   { name: 'duniter-dump',      required: dumpDependency },
+  { name: 'duniter-mempool',   required: mempoolDependency },
 ]);
 
 const PRODUCTION_DEPENDENCIES = DEFAULT_DEPENDENCIES.concat([
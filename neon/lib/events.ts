@@ -0,0 +1,34 @@
+import { RustEventsStream, RustServer, ServerEvent } from "../native";
+
+/**
+ * Call `listener` for each server event, until the returned function is called.
+ * Each subscription receives all events independently of other subscriptions.
+ */
+export function subscribeServerEvents(
+    server: RustServer,
+    listener: (event: ServerEvent) => void,
+    onError?: (err: any) => void
+): () => void {
+    let subscribed = true;
+    const stream = new RustEventsStream(server, (err, events) => {
+        if (!subscribed) {
+            return;
+        }
+        if (err) {
+            subscribed = false;
+            if (onError) {
+                onError(err);
+            }
+            return;
+        }
+        for (const event of events) {
+            listener(event);
+        }
+    });
+    return () => {
+        if (subscribed) {
+            subscribed = false;
+            stream.close();
+        }
+    };
+}
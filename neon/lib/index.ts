@@ -1,18 +1,35 @@
 export {
+    BlockMeta,
     Ed25519Signator,
     generateRandomSeed,
+    MempoolConflict,
+    MempoolImport,
+    PendingTxsPage,
     rawTxParseAndVerify,
     RustDbTx,
+    RustEventsStream,
     RustServer,
     RustServerConf,
     sha256,
     seedToSecretKey,
+    ServerEvent,
     sourceIsUnlockable,
+    TxRejection,
+    TxStatus,
     TxsHistory,
+    TxsHistoryFilter,
+    TxsHistoryPage,
     txVerify,
     txsInputsAreUnlockable,
+    UdHistoryEntry,
+    UdHistoryRange,
     verify,
-    Wot
+    WalletGroupTx,
+    WalletSource,
+    Wot,
+    WotDocKind,
+    WotDocRejection,
 } from "../native";
 export { KeyPairBuilder } from "./crypto";
+export { subscribeServerEvents } from "./events";
 export { WotBuilder } from "./wot";
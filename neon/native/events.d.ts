@@ -0,0 +1,20 @@
+/* tslint:disable */
+
+import { RustServer } from './server';
+
+export class ServerEvent {
+    seq: number;
+    type: 'blockApplied' | 'blockReverted' | 'txAdded' | 'txRemoved' | 'txEvicted' | 'peerSaved' | 'peerRemoved' | 'headReceived' | 'selfEndpointsChanged';
+    blockstamp?: string;
+    hash?: string;
+    pubkey?: string;
+}
+
+export class RustEventsStream {
+    // The callback receives each batch of events, or an error once the stream is closed by
+    // the server (on shutdown, or if the callback does not keep up).
+    constructor(server: RustServer, callback: (err: any, events: ServerEvent[]) => void);
+
+    // Stop receiving events.
+    close(): void;
+}
@@ -1,6 +1,7 @@
 /* tslint:disable */
 
 import * as _crypto from './crypto';
+import * as _events from './events';
 import * as _logger from './logger';
 import * as _server from './server';
 import * as _transactions from './transaction';
@@ -12,12 +13,29 @@ export import seedToSecretKey = _crypto.seedToSecretKey;
 export import sha256 = _crypto.sha256;
 export import verify = _crypto.verify;
 
+export import RustEventsStream = _events.RustEventsStream;
+export import ServerEvent = _events.ServerEvent;
+
 export import RustLogger = _logger.RustLogger;
 
+export import BlockMeta = _server.BlockMeta;
+export import MempoolConflict = _server.MempoolConflict;
+export import MempoolImport = _server.MempoolImport;
+export import PendingTxsPage = _server.PendingTxsPage;
 export import RustDbTx = _server.RustDbTx;
 export import RustServer = _server.RustServer;
 export import RustServerConf = _server.RustServerConf;
+export import TxRejection = _server.TxRejection;
+export import TxStatus = _server.TxStatus;
 export import TxsHistory = _server.TxsHistory;
+export import TxsHistoryFilter = _server.TxsHistoryFilter;
+export import TxsHistoryPage = _server.TxsHistoryPage;
+export import UdHistoryEntry = _server.UdHistoryEntry;
+export import UdHistoryRange = _server.UdHistoryRange;
+export import WalletGroupTx = _server.WalletGroupTx;
+export import WalletSource = _server.WalletSource;
+export import WotDocKind = _server.WotDocKind;
+export import WotDocRejection = _server.WotDocRejection;
 
 export import TransactionDTOV10 = _transactions.TransactionDTOV10;
 export import rawTxParseAndVerify = _transactions.rawTxParseAndVerify;
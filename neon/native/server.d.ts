@@ -34,6 +34,29 @@ export class BlockDTOV10 {
     monetaryMass: number;
 }
 
+// Metadata of a block of the current chain
+export class BlockMeta {
+    version: number;
+    number: number;
+    hash: string;
+    signature: string;
+    innerHash: string;
+    previousHash: string;
+    issuer: string;
+    previousIssuer: string;
+    time: number;
+    powMin: number;
+    membersCount: number;
+    issuersCount: number;
+    issuersFrame: number;
+    issuersFrameVar: number;
+    medianTime: number;
+    nonce: number;
+    monetaryMass: number;
+    unitBase: number;
+    dividend: number | null;
+}
+
 export class GvaConf {
     enabled: boolean;
     ip4?: string
@@ -55,6 +78,12 @@ export class HeadWS2Pv1 {
     step?: number;
 }
 
+export class ModuleStatus {
+    name: string;
+    status: 'starting' | 'running' | 'stopped' | 'failed';
+    error: string | null;
+}
+
 export class PeerCard {
     version: number
     currency: string
@@ -101,10 +130,60 @@ export class RustPendingTx {
 }
 
 export class RustServerConf {
+    blockVerification?: 'none' | 'structural' | 'full'
     command: string | null
     currency: string
+    // Mempools maintenance, in seconds
+    mempoolMaintenanceInterval?: number
+    metricsAddress?: string
     selfKeypair: string | null
+    txsMempoolMaxAge?: number
+    txsMempoolConflictPolicy?: 'reject' | 'keep-first'
+    // 'none' | 'oldest' | 'closest-to-expiry' | 'max-per-issuer:<N>'. Only 'max-per-issuer'
+    // bounds the room taken by each public key, the others evict any issuer's txs.
+    txsMempoolEvictionPolicy?: string
     txsMempoolSize: number
+    wotMempoolMaxAge?: number
+    wotMempoolSize?: number
+}
+
+export class MempoolImport {
+    accepted: number
+    rejected: { hash: string, code: string, message: string }[]
+}
+
+export class MempoolConflict {
+    // D:<issuer>:<block_number> or T:<tx_hash>:<output_index>
+    source: string
+    txs: string[]
+    issuers: string[]
+}
+
+export class PendingTxsPage {
+    txs: TransactionDTOV10[]
+    nextCursor: string | null
+}
+
+export class TxRejection {
+    code: 'MEMPOOL_FULL' | 'SOURCE_NOT_AVAILABLE' | 'INVALID_UNLOCK' | 'INPUT_AMOUNT_MISMATCH' | 'LOCKED' | 'BAD_SIGNATURE' | 'EXPIRED_BLOCKSTAMP' | 'DUPLICATE' | 'DOUBLE_SPEND' | 'UNBALANCED_AMOUNTS' | 'WRONG_CURRENCY'
+    message: string
+}
+
+export class WotDocRejection {
+    code: 'MEMPOOL_FULL' | 'BAD_SIGNATURE' | 'UNKNOWN_BLOCKSTAMP' | 'DUPLICATE' | 'IDENTITY_ALREADY_USED' | 'UNKNOWN_IDENTITY' | 'ISSUER_NOT_MEMBER' | 'WRONG_CURRENCY'
+    message: string
+}
+
+export type WotDocKind = 'identity' | 'certification' | 'membership' | 'revocation'
+
+export class TxStatus {
+    status: 'pending' | 'written' | 'expired' | 'evicted' | 'unknown'
+    // Pending only
+    receivedTime?: number
+    // Written only, confirmations is 1 when written in the current block
+    blockNumber?: number
+    medianTime?: number
+    confirmations?: number
 }
 
 export class TxsHistory {
@@ -114,30 +193,122 @@ export class TxsHistory {
     pending: RustPendingTx[];
 }
 
+export class TxsHistoryFilter {
+    fromBlock?: number
+    toBlock?: number
+    fromTime?: number
+    toTime?: number
+    directions?: ('sent' | 'received' | 'sending' | 'pending')[]
+    comment?: string
+}
+
+export class TxsHistoryPage extends TxsHistory {
+    nextCursor: string | null
+}
+
+export class UdHistoryEntry {
+    blockNumber: number
+    // Median time of the block
+    time: number
+    consumed: boolean
+    amount: number
+    base: number
+}
+
+// All dividends if neither a blocks range nor a times range is given
+export class UdHistoryRange {
+    fromBlock?: number
+    toBlock?: number
+    fromTime?: number
+    toTime?: number
+}
+
+export class WalletGroupTx {
+    tx: TransactionDTOV10
+    hash: string
+    // null for pending transactions
+    writtenBlockNumber: number | null
+    // Written time, or received time for pending transactions
+    time: number
+    // Transfer between keys of the group
+    internal: boolean
+    // Amount received by the group minus amount spent by the group, null if it overflows
+    netAmount: number | null
+}
+
+export class WalletSource {
+    type: 'D' | 'T'
+    identifier: string
+    noffset: number
+    amount: number
+    base: number
+    conditions: string
+    // Output of a pending transaction
+    pending: boolean
+}
+
 export class RustServer {
     constructor(conf: RustServerConf, home: string | null);
 
+    shutdown(): void;
+    getModulesStatus(): ModuleStatus[];
+
     // Indexing blockchain
     revertBlock(block: BlockDTOV10): void;
     applyBlock(block: BlockDTOV10): void;
     applyChunkOfBlocks(blocks: BlockDTOV10[]): void;
+    switchBranch(reverted: BlockDTOV10[], applied: BlockDTOV10[]): void;
+    checkBlockRules(block: BlockDTOV10): string[];
+
+    // Blocks of the current chain (metadata only)
+    blockstampExists(blockstamp: string): boolean;
+    getBlock(number: number): BlockMeta | null;
+    getBlockByHash(hash: string): BlockMeta | null;
+    getBlocks(from: number, to: number): BlockMeta[];
+    getCurrent(): BlockMeta | null;
     
     // Rust Endpoints (GVA, etc)
-    getSelfEndpoints(): string[];
+    // Throw an error with code "SELF_ENDPOINTS_NOT_READY" if endpoints are not published before
+    // timeout (10 s by default). Blocks the JS thread. Endpoints are published once the server
+    // is started.
+    getSelfEndpoints(timeoutMs?: number): string[];
 
     // Txs mempool
-    acceptNewTx(tx: TransactionDTOV10, serverPubkey: string): boolean;
+    acceptNewTx(tx: TransactionDTOV10, serverPubkey: string): TxRejection | null;
+    addNewTx(tx: TransactionDTOV10, serverPubkey: string): TxRejection | null;
     addPendingTx(tx: TransactionDTOV10): void;
+    exportMempool(path: string): number;
+    getMempoolConflicts(): MempoolConflict[];
     getMempoolTxsFreeRooms(): number;
     getNewPendingTxs(): TransactionDTOV10[];
     getTransactionsPending(versionMin: number, medianTime: number): TransactionDTOV10[];
+    importMempool(path: string): MempoolImport;
+    getTransactionsPendingPage(versionMin: number, medianTime: number | null, pageSize: number, cursor?: string | null): PendingTxsPage;
     removeAllPendingTxs(): void;
+    selectTxsForNextBlock(maxSize: number, medianTime: number, versionMin: number): TransactionDTOV10[];
     removePendingTxByHash(hash: string): void;
     trimExpiredNonWrittenTxs(limitTime: number): void;
 
+    // Wot mempools (documents in raw format)
+    acceptNewWotDoc(rawDoc: string): WotDocRejection | null;
+    getPendingWotDocs(kind: WotDocKind): string[];
+    getWotMempoolFreeRooms(kind: WotDocKind): number;
+    removeAllPendingWotDocs(): void;
+    removePendingWotDocByHash(hash: string): void;
+    trimExpiredWotDocs(limitTime: number): void;
+
+    // Wallets (script is an output conditions script, for example SIG(<pubkey>))
+    getBalance(script: string, withPending: boolean): { amount: number, base: number };
+    getSources(script: string, withPending: boolean): WalletSource[];
+
     // Transactions history (for BMA only)
     getTransactionsHistory(pubkey: string): TxsHistory;
+    getFilteredTransactionsHistory(pubkey: string, filter: TxsHistoryFilter): TxsHistory;
+    getTransactionsHistoryPage(pubkey: string, filter: TxsHistoryFilter, pageSize: number, cursor?: string | null): TxsHistoryPage;
     getTxByHash(hash: string): TransactionDTOV10 | null;
+    getTxStatus(hash: string): TxStatus;
+    getUdHistory(pubkey: string, range: UdHistoryRange): UdHistoryEntry[];
+    getWalletGroupHistory(pubkeys: string[]): WalletGroupTx[];
     
     // WS2Pv1: HEADs and peers
     receiveNewHeads(heads: HeadWS2Pv1[]): void;
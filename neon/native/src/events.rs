@@ -0,0 +1,163 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::server::JsServer;
+use duniter_core::common::crypto::keys::PublicKey as _;
+use duniter_server::{SequencedEvent, ServerEvent};
+use neon::declare_types;
+use neon::event::EventHandler;
+use neon::prelude::*;
+use serde::Serialize;
+
+/// Maximum number of events passed to one call of the listener
+const EVENTS_BATCH_MAX: usize = 1_000;
+
+/// Dropping the sender (on `close`) stops the thread forwarding events to the listener
+pub struct RustEventsStream(Option<flume::Sender<()>>);
+
+/// Call the listener with each batch of received events, until the stream is closed or the
+/// server is stopped. The events receiver is dropped when the thread stops.
+fn forward_events(
+    events_recv: flume::Receiver<SequencedEvent>,
+    close_recv: flume::Receiver<()>,
+    listener: EventHandler,
+) {
+    loop {
+        let res = flume::Selector::new()
+            .recv(&close_recv, |_| None)
+            .recv(&events_recv, Some)
+            .wait();
+        match res {
+            None => break,
+            Some(Ok(event)) => {
+                let events: Vec<_> = std::iter::once(event)
+                    .chain(events_recv.try_iter().take(EVENTS_BATCH_MAX - 1))
+                    .map(ServerEventStringified::from)
+                    .collect();
+                listener.schedule(move |cx| {
+                    let events = neon_serde::to_value(cx, &events)
+                        .unwrap_or_else(|_| cx.undefined().upcast());
+                    vec![cx.null().upcast(), events]
+                });
+            }
+            Some(Err(_)) => {
+                listener.schedule(|cx| {
+                    vec![cx
+                        .string("events stream closed: server stopped or listener too slow")
+                        .upcast::<JsValue>()]
+                });
+                break;
+            }
+        }
+    }
+}
+
+declare_types! {
+    pub class JsEventsStream for RustEventsStream {
+        init(mut cx) {
+            let server = cx.argument::<JsServer>(0)?;
+            let callback = cx.argument::<JsFunction>(1)?;
+            let events_recv = {
+                let guard = cx.lock();
+                let server = server.borrow(&guard);
+                server.server.subscribe_events()
+            };
+            let this = cx.this();
+            let listener = EventHandler::new(&cx, this, callback);
+            let (close_sender, close_recv) = flume::bounded(1);
+            if let Err(e) = std::thread::Builder::new()
+                .name("duniter-events-stream".to_owned())
+                .spawn(move || forward_events(events_recv, close_recv, listener))
+            {
+                return cx.throw_error(format!("{}", e));
+            }
+            Ok(RustEventsStream(Some(close_sender)))
+        }
+
+        method close(mut cx) {
+            let mut this = cx.this();
+            {
+                let guard = cx.lock();
+                let mut stream = this.borrow_mut(&guard);
+                stream.0.take();
+            }
+            Ok(cx.undefined().upcast())
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerEventStringified {
+    seq: u64,
+    r#type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blockstamp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pubkey: Option<String>,
+}
+
+impl From<SequencedEvent> for ServerEventStringified {
+    fn from(SequencedEvent { seq, event }: SequencedEvent) -> Self {
+        let mut event_stringified = ServerEventStringified {
+            seq,
+            r#type: "",
+            blockstamp: None,
+            hash: None,
+            pubkey: None,
+        };
+        match event {
+            ServerEvent::BlockApplied(blockstamp) => {
+                event_stringified.r#type = "blockApplied";
+                event_stringified.blockstamp = Some(blockstamp.to_string());
+            }
+            ServerEvent::BlockReverted(blockstamp) => {
+                event_stringified.r#type = "blockReverted";
+                event_stringified.blockstamp = Some(blockstamp.to_string());
+            }
+            ServerEvent::TxAdded(hash) => {
+                event_stringified.r#type = "txAdded";
+                event_stringified.hash = Some(hash.to_hex());
+            }
+            ServerEvent::TxRemoved(hash) => {
+                event_stringified.r#type = "txRemoved";
+                event_stringified.hash = Some(hash.to_hex());
+            }
+            ServerEvent::TxEvicted(hash) => {
+                event_stringified.r#type = "txEvicted";
+                event_stringified.hash = Some(hash.to_hex());
+            }
+            ServerEvent::PeerSaved(pubkey) => {
+                event_stringified.r#type = "peerSaved";
+                event_stringified.pubkey = Some(pubkey.to_base58());
+            }
+            ServerEvent::PeerRemoved(pubkey) => {
+                event_stringified.r#type = "peerRemoved";
+                event_stringified.pubkey = Some(pubkey.to_base58());
+            }
+            ServerEvent::HeadReceived { pubkey, blockstamp } => {
+                event_stringified.r#type = "headReceived";
+                event_stringified.pubkey = Some(pubkey.to_base58());
+                event_stringified.blockstamp = Some(blockstamp.to_string());
+            }
+            ServerEvent::SelfEndpointsChanged => {
+                event_stringified.r#type = "selfEndpointsChanged";
+            }
+        }
+        event_stringified
+    }
+}
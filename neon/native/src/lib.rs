@@ -26,6 +26,7 @@
 )]
 
 mod crypto;
+mod events;
 mod logger;
 mod server;
 mod transaction;
@@ -52,6 +53,7 @@ register_module!(mut cx, {
     cx.export_function("sha256", crate::crypto::sha256)?;
     cx.export_function("verify", crate::crypto::verify)?;
     cx.export_class::<crate::crypto::JsKeyPair>("Ed25519Signator")?;
+    cx.export_class::<crate::events::JsEventsStream>("RustEventsStream")?;
     cx.export_class::<crate::logger::JsLogger>("RustLogger")?;
     cx.export_class::<crate::server::JsServer>("RustServer")?;
     cx.export_function(
@@ -27,19 +27,43 @@ use duniter_core::{
     },
     documents::{
         prelude::*,
-        transaction::{TransactionDocumentV10, TransactionDocumentV10Stringified},
+        transaction::{
+            SourceIdV10, TransactionDocumentV10, TransactionDocumentV10Stringified, UdSourceIdV10,
+            UtxoIdV10,
+        },
     },
     documents_parser::prelude::*,
     peer::PeerV10,
 };
-use duniter_server::{DuniterCoreConf, DuniterMode, DuniterServer};
+use duniter_server::{
+    BlockMetaV2, BlockVerificationLevel, DuniterCoreConf, DuniterMode, DuniterServer,
+    DuniterServerBuilder, MempoolConflictPolicy, MempoolEvictionPolicy, MempoolMaintenanceConf,
+    ModuleStatus, PendingTxsCursor, SelfEndpointsNotReady, TxDirection, TxHistoryEntry, TxStatus,
+    TxsHistoryCursor, TxsHistoryFilter, UdHistoryRange, WotDocKind, WotDocument,
+};
 use neon::declare_types;
 use neon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::{path::PathBuf, str::FromStr};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
 pub struct RustServer {
-    server: DuniterServer,
+    pub(crate) server: DuniterServer,
+}
+
+/// Blockchain time argument of pending transactions queries, no filtering if not a number
+fn blockchain_time_arg(cx: &mut MethodContext<JsServer>, i: i32) -> NeonResult<Option<i64>> {
+    if let Some(arg) = cx.argument_opt(i) {
+        if arg.is_a::<JsNumber>() {
+            let blockchain_time = arg.downcast::<JsNumber>().or_throw(cx)?.value();
+            return Ok(Some(blockchain_time as i64));
+        }
+    }
+    Ok(None)
 }
 
 declare_types! {
@@ -57,6 +81,38 @@ declare_types! {
                 Ed25519KeyPair::generate_random().expect("fail to gen random keyypair")
             };
             let txs_mempool_size = rust_server_conf_stringified.txs_mempool_size as usize;
+            let block_verification = if let Some(ref level) = rust_server_conf_stringified.block_verification {
+                into_neon_res(&mut cx, BlockVerificationLevel::from_str(level))?
+            } else {
+                BlockVerificationLevel::default()
+            };
+            let mempool_conflict_policy = if let Some(ref policy) = rust_server_conf_stringified.txs_mempool_conflict_policy {
+                into_neon_res(&mut cx, MempoolConflictPolicy::from_str(policy))?
+            } else {
+                MempoolConflictPolicy::default()
+            };
+            let mempool_eviction_policy = if let Some(ref policy) = rust_server_conf_stringified.txs_mempool_eviction_policy {
+                into_neon_res(&mut cx, MempoolEvictionPolicy::from_str(policy))?
+            } else {
+                MempoolEvictionPolicy::default()
+            };
+            let metrics_address_opt = if let Some(ref address) = rust_server_conf_stringified.metrics_address {
+                Some(into_neon_res(&mut cx, SocketAddr::from_str(address))?)
+            } else {
+                None
+            };
+            let default_maintenance_conf = MempoolMaintenanceConf::default();
+            let mempool_maintenance_conf = MempoolMaintenanceConf {
+                interval: rust_server_conf_stringified
+                    .mempool_maintenance_interval
+                    .map_or(default_maintenance_conf.interval, Duration::from_secs),
+                tx_max_age: rust_server_conf_stringified
+                    .txs_mempool_max_age
+                    .map_or(default_maintenance_conf.tx_max_age, Duration::from_secs),
+                wot_doc_max_age: rust_server_conf_stringified
+                    .wot_mempool_max_age
+                    .map_or(default_maintenance_conf.wot_doc_max_age, Duration::from_secs),
+            };
             let conf = DuniterCoreConf {
                 self_key_pair,
                 txs_mempool_size
@@ -91,16 +147,58 @@ declare_types! {
             } else {
                 return cx.throw_error("Env var DUNITER_MODE not exist or contain invalid utf8");
             };
+            let builder = DuniterServerBuilder::new(currency)
+                .conf(conf)
+                .duniter_mode(duniter_mode)
+                .block_verification(block_verification)
+                .mempool_conflict_policy(mempool_conflict_policy)
+                .mempool_eviction_policy(mempool_eviction_policy)
+                .mempool_maintenance(mempool_maintenance_conf)
+                .software_version(std::env!("CARGO_PKG_VERSION"));
+            let builder = if let Some(wot_mempool_size) = rust_server_conf_stringified.wot_mempool_size {
+                builder.wot_mempool_size(wot_mempool_size as usize)
+            } else {
+                builder
+            };
+            let builder = if let Some(metrics_address) = metrics_address_opt {
+                builder.metrics_address(metrics_address)
+            } else {
+                builder
+            };
             into_neon_res(
                 &mut cx,
                 if let Some(home_path) = home_path_opt {
-                    DuniterServer::start(conf, currency, duniter_mode, Some(home_path.as_path()), std::env!("CARGO_PKG_VERSION"))
+                    builder.profile_path(home_path)
                 } else {
-                    DuniterServer::start(conf, currency, duniter_mode, None, std::env!("CARGO_PKG_VERSION"))
-                }.map(|server| RustServer { server })
+                    builder
+                }.start().map(|server| RustServer { server })
             )
         }
 
+        method shutdown(mut cx) {
+            let mut this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let mut server = this.borrow_mut(&guard);
+                server.server.shutdown()
+            }.map(|()| cx.undefined().upcast());
+            into_neon_res(&mut cx, res)
+        }
+
+        method getModulesStatus(mut cx) {
+            let this = cx.this();
+            let modules_status = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.modules_status()
+            };
+            let modules_status: Vec<_> = modules_status
+                .into_iter()
+                .map(|(name, status)| ModuleStatusStringified::new(name, status))
+                .collect();
+            Ok(neon_serde::to_value(&mut cx, &modules_status)?)
+        }
+
         // Indexing blockchain
         method revertBlock(mut cx) {
             let block_js = cx.argument::<JsValue>(0)?;
@@ -142,24 +240,148 @@ declare_types! {
             into_neon_res(&mut cx, res)
         }
 
+        method switchBranch(mut cx) {
+            let reverted_js = cx.argument::<JsValue>(0)?;
+            let applied_js = cx.argument::<JsValue>(1)?;
+
+            let reverted_stringified: Vec<duniter_core::block::DubpBlockV10Stringified> = neon_serde::from_value(&mut cx, reverted_js)?;
+            let applied_stringified: Vec<duniter_core::block::DubpBlockV10Stringified> = neon_serde::from_value(&mut cx, applied_js)?;
+
+            let mut this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let mut server = this.borrow_mut(&guard);
+                server.server.switch_branch(reverted_stringified, applied_stringified)
+            }.map(|()| cx.undefined().upcast());
+            into_neon_res(&mut cx, res)
+        }
+        method checkBlockRules(mut cx) {
+            let block_js = cx.argument::<JsValue>(0)?;
+
+            let block_stringified: duniter_core::block::DubpBlockV10Stringified = neon_serde::from_value(&mut cx, block_js)?;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.check_block_rules(block_stringified)
+            }.map(|violated_rules| violated_rules.into_iter().map(|rule| rule.id()).collect::<Vec<_>>());
+            let violated_rules = into_neon_res(&mut cx, res)?;
+            Ok(neon_serde::to_value(&mut cx, &violated_rules)?)
+        }
+
+        // Blocks of the current chain (metadata only)
+        method blockstampExists(mut cx) {
+            let blockstamp_str = cx.argument::<JsString>(0)?.value();
+            let blockstamp = into_neon_res(&mut cx, Blockstamp::from_str(&blockstamp_str))?;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.blockstamp_exists(blockstamp)
+            };
+            let exists = into_neon_res(&mut cx, res)?;
+            Ok(cx.boolean(exists).upcast())
+        }
+        method getBlock(mut cx) {
+            let number = cx.argument::<JsNumber>(0)?.value() as u32;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.get_block(BlockNumber(number))
+            };
+            let block_meta_opt = into_neon_res(&mut cx, res)?;
+            Ok(neon_serde::to_value(&mut cx, &block_meta_opt.map(BlockMetaStringified::from))?)
+        }
+        method getBlockByHash(mut cx) {
+            let hash_str = cx.argument::<JsString>(0)?.value();
+            let hash = into_neon_res(&mut cx, Hash::from_hex(&hash_str))?;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.get_block_by_hash(BlockHash(hash))
+            };
+            let block_meta_opt = into_neon_res(&mut cx, res)?;
+            Ok(neon_serde::to_value(&mut cx, &block_meta_opt.map(BlockMetaStringified::from))?)
+        }
+        method getBlocks(mut cx) {
+            let from = cx.argument::<JsNumber>(0)?.value() as u32;
+            let to = cx.argument::<JsNumber>(1)?.value() as u32;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.get_blocks(from..=to)
+            };
+            let blocks_meta: Vec<_> = into_neon_res(&mut cx, res)?
+                .into_iter()
+                .map(BlockMetaStringified::from)
+                .collect();
+            Ok(neon_serde::to_value(&mut cx, &blocks_meta)?)
+        }
+        method getCurrent(mut cx) {
+            let this = cx.this();
+            let current_opt = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.get_current()
+            };
+            Ok(neon_serde::to_value(&mut cx, &current_opt.map(BlockMetaStringified::from))?)
+        }
+
 
         // Rust Endpoints (GVA, etc)
         method getSelfEndpoints(mut cx) {
+            let timeout_opt = if let Some(arg0) = cx.argument_opt(0) {
+                if arg0.is_a::<JsNumber>() {
+                    let timeout_ms = arg0.downcast::<JsNumber>().or_throw(&mut cx)?.value();
+                    Some(Duration::from_millis(timeout_ms as u64))
+                } else if arg0.is_a::<JsUndefined>() {
+                    None
+                } else {
+                    return cx.throw_type_error("arg0 must be a number");
+                }
+            } else {
+                None
+            };
+
             let this = cx.this();
             let res = {
                 let guard = cx.lock();
                 let server = this.borrow(&guard);
-                server.server.get_self_endpoints()
-            }.map(|endpoints| {
-                log::debug!("rust-server: get_self_endpoints: {:?}", endpoints);
-                let js_array = JsArray::new(&mut cx, endpoints.len() as u32);
-                for (i, ep) in endpoints.iter().enumerate() {
-                    let js_string = cx.string(ep);
-                    js_array.set(&mut cx, i as u32, js_string).expect("fail to convert Vec<String> to JsArray");
+                if let Some(timeout) = timeout_opt {
+                    server.server.get_self_endpoints_timeout(timeout)
+                } else {
+                    server.server.get_self_endpoints()
                 }
-                js_array.upcast()
-            });
-            into_neon_res(&mut cx, res)
+            };
+            match res {
+                Ok(endpoints) => {
+                    log::debug!("rust-server: get_self_endpoints: {:?}", endpoints);
+                    let js_array = JsArray::new(&mut cx, endpoints.len() as u32);
+                    for (i, ep) in endpoints.iter().enumerate() {
+                        let js_string = cx.string(ep);
+                        js_array.set(&mut cx, i as u32, js_string)?;
+                    }
+                    Ok(js_array.upcast())
+                }
+                Err(e) => {
+                    if let Some(not_ready) = e.downcast_ref::<SelfEndpointsNotReady>() {
+                        let js_error = cx.error(not_ready.to_string())?;
+                        let code = cx.string(not_ready.code());
+                        js_error.set(&mut cx, "code", code)?;
+                        cx.throw(js_error)
+                    } else {
+                        cx.throw_error(format!("{}", e))
+                    }
+                }
+            }
         }
 
 
@@ -177,8 +399,42 @@ declare_types! {
                 let guard = cx.lock();
                 let server = this.borrow(&guard);
                 server.server.accept_new_tx(tx, server_pubkey)
-            }.map(|accepted| cx.boolean(accepted).upcast());
-            into_neon_res(&mut cx, res)
+            };
+            match into_neon_res(&mut cx, res)? {
+                Ok(()) => Ok(cx.null().upcast()),
+                Err(rejection) => {
+                    let rejection = RejectionStringified {
+                        code: rejection.code(),
+                        message: rejection.to_string(),
+                    };
+                    Ok(neon_serde::to_value(&mut cx, &rejection)?)
+                }
+            }
+        }
+        method addNewTx(mut cx) {
+            let tx_js = cx.argument::<JsValue>(0)?;
+            let server_pubkey_str = cx.argument::<JsString>(1)?.value();
+
+            let tx_str: TransactionDocumentV10Stringified = neon_serde::from_value(&mut cx, tx_js)?;
+            let tx = into_neon_res(&mut cx, TransactionDocumentV10::from_string_object(&tx_str))?;
+            let server_pubkey = into_neon_res(&mut cx, PublicKey::from_base58(&server_pubkey_str))?;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.add_pending_tx(tx, server_pubkey)
+            };
+            match into_neon_res(&mut cx, res)? {
+                Ok(()) => Ok(cx.null().upcast()),
+                Err(rejection) => {
+                    let rejection = RejectionStringified {
+                        code: rejection.code(),
+                        message: rejection.to_string(),
+                    };
+                    Ok(neon_serde::to_value(&mut cx, &rejection)?)
+                }
+            }
         }
         method addPendingTx(mut cx) {
             let tx_js = cx.argument::<JsValue>(0)?;
@@ -194,6 +450,59 @@ declare_types! {
             }.map(|_| cx.undefined().upcast());
             into_neon_res(&mut cx, res)
         }
+        method exportMempool(mut cx) {
+            let path = cx.argument::<JsString>(0)?.value();
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.export_mempool(Path::new(&path))
+            }.map(|count| cx.number(count as f64).upcast());
+            into_neon_res(&mut cx, res)
+        }
+        method importMempool(mut cx) {
+            let path = cx.argument::<JsString>(0)?.value();
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.import_mempool(Path::new(&path))
+            };
+            let import = into_neon_res(&mut cx, res)?;
+            let import = MempoolImportStringified {
+                accepted: import.accepted,
+                rejected: import.rejected.into_iter().map(|(hash, rejection)| RejectedTxStringified {
+                    hash: hash.to_hex(),
+                    code: rejection.code(),
+                    message: rejection.to_string(),
+                }).collect(),
+            };
+            Ok(neon_serde::to_value(&mut cx, &import)?)
+        }
+        method getMempoolConflicts(mut cx) {
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.get_mempool_conflicts()
+            };
+            match res {
+                Ok(conflicts) => {
+                    let conflicts: Vec<_> = conflicts.into_iter().map(|conflict| MempoolConflictStringified {
+                        source: match conflict.source {
+                            SourceIdV10::Ud(UdSourceIdV10 { issuer, block_number }) => format!("D:{}:{}", issuer, block_number),
+                            SourceIdV10::Utxo(UtxoIdV10 { tx_hash, output_index }) => format!("T:{}:{}", tx_hash, output_index),
+                        },
+                        txs: conflict.txs.iter().map(ToString::to_string).collect(),
+                        issuers: conflict.issuers.iter().map(ToString::to_string).collect(),
+                    }).collect();
+                    Ok(neon_serde::to_value(&mut cx, &conflicts)?)
+                },
+                Err(e) => cx.throw_error(format!("{}", e)),
+            }
+        }
         method getMempoolTxsFreeRooms(mut cx) {
             let this = cx.this();
             let res = {
@@ -236,6 +545,38 @@ declare_types! {
                 Err(e) => cx.throw_error(format!("{}", e)),
             }
         }
+        method getTransactionsPendingPage(mut cx) {
+            let min_version = cx.argument::<JsNumber>(0)?.value() as usize;
+            let blockchain_time_opt = blockchain_time_arg(&mut cx, 1)?;
+            let page_size = cx.argument::<JsNumber>(2)?.value() as usize;
+            let cursor_opt = if let Some(arg3) = cx.argument_opt(3) {
+                if arg3.is_a::<JsString>() {
+                    let cursor_str = arg3.downcast::<JsString>().or_throw(&mut cx)?.value();
+                    Some(into_neon_res(&mut cx, PendingTxsCursor::from_str(&cursor_str))?)
+                } else {
+                    None
+                }
+            } else {
+                None
+            };
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.get_pending_txs_page(blockchain_time_opt, min_version, cursor_opt, page_size)
+            };
+            match res {
+                Ok(page) => {
+                    let page = PendingTxsPageStringified {
+                        txs: page.txs.into_iter().map(|tx| tx.doc.to_string_object()).collect(),
+                        next_cursor: page.next_cursor.map(|cursor| cursor.to_string()),
+                    };
+                    Ok(neon_serde::to_value(&mut cx, &page)?)
+                },
+                Err(e) => cx.throw_error(format!("{}", e)),
+            }
+        }
         method removeAllPendingTxs(mut cx) {
             let this = cx.this();
             let res = {
@@ -257,6 +598,25 @@ declare_types! {
             }.map(|()| cx.undefined().upcast());
             into_neon_res(&mut cx, res)
         }
+        method selectTxsForNextBlock(mut cx) {
+            let max_size = cx.argument::<JsNumber>(0)?.value() as usize;
+            let median_time = cx.argument::<JsNumber>(1)?.value() as u64;
+            let min_version = cx.argument::<JsNumber>(2)?.value() as usize;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.select_txs_for_next_block(max_size, median_time, min_version)
+            };
+            match res {
+                Ok(txs) => {
+                    let txs: Vec<_> = txs.into_iter().map(|tx| tx.to_string_object()).collect();
+                    Ok(neon_serde::to_value(&mut cx, &txs)?)
+                },
+                Err(e) => cx.throw_error(format!("{}", e)),
+            }
+        }
         method trimExpiredNonWrittenTxs(mut cx) {
             let limit_time = cx.argument::<JsNumber>(0)?.value() as i64;
 
@@ -269,6 +629,141 @@ declare_types! {
             into_neon_res(&mut cx, res)
         }
 
+        // Wot mempools
+        method acceptNewWotDoc(mut cx) {
+            let raw_doc = cx.argument::<JsString>(0)?.value();
+            let doc = into_neon_res(&mut cx, WotDocument::parse_from_raw_text(&raw_doc))?;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.accept_new_wot_doc(doc)
+            };
+            match into_neon_res(&mut cx, res)? {
+                Ok(()) => Ok(cx.null().upcast()),
+                Err(rejection) => {
+                    let rejection = RejectionStringified {
+                        code: rejection.code(),
+                        message: rejection.to_string(),
+                    };
+                    Ok(neon_serde::to_value(&mut cx, &rejection)?)
+                }
+            }
+        }
+        method getPendingWotDocs(mut cx) {
+            let kind_str = cx.argument::<JsString>(0)?.value();
+            let kind = into_neon_res(&mut cx, WotDocKind::from_str(&kind_str))?;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.get_pending_wot_docs(kind)
+            };
+            match res {
+                Ok(docs) => {
+                    let docs: Vec<_> = docs.iter().map(WotDocument::as_signed_text).collect();
+                    Ok(neon_serde::to_value(&mut cx, &docs)?)
+                },
+                Err(e) => cx.throw_error(format!("{}", e)),
+            }
+        }
+        method getWotMempoolFreeRooms(mut cx) {
+            let kind_str = cx.argument::<JsString>(0)?.value();
+            let kind = into_neon_res(&mut cx, WotDocKind::from_str(&kind_str))?;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.get_wot_mempool_free_rooms(kind)
+            }.map(|free_rooms| cx.number(free_rooms as f64).upcast());
+            into_neon_res(&mut cx, res)
+        }
+        method removeAllPendingWotDocs(mut cx) {
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.remove_all_pending_wot_docs()
+            }.map(|()| cx.undefined().upcast());
+            into_neon_res(&mut cx, res)
+        }
+        method removePendingWotDocByHash(mut cx) {
+            let hash_str = cx.argument::<JsString>(0)?.value();
+            let hash = into_neon_res(&mut cx, Hash::from_hex(&hash_str))?;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.remove_pending_wot_doc_by_hash(hash)
+            }.map(|()| cx.undefined().upcast());
+            into_neon_res(&mut cx, res)
+        }
+        method trimExpiredWotDocs(mut cx) {
+            let limit_time = cx.argument::<JsNumber>(0)?.value() as i64;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.trim_expired_wot_docs(limit_time)
+            }.map(|()| cx.undefined().upcast());
+            into_neon_res(&mut cx, res)
+        }
+
+        // Wallets
+        method getBalance(mut cx) {
+            let script_str = cx.argument::<JsString>(0)?.value();
+            let with_pending = cx.argument::<JsBoolean>(1)?.value();
+            let script = into_neon_res(&mut cx, duniter_core::documents_parser::wallet_script_from_str(&script_str))?;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.get_balance(script, with_pending)
+            };
+            let balance = into_neon_res(&mut cx, res)?;
+            Ok(neon_serde::to_value(&mut cx, &AmountStringified {
+                amount: balance.amount(),
+                base: balance.base(),
+            })?)
+        }
+        method getSources(mut cx) {
+            let script_str = cx.argument::<JsString>(0)?.value();
+            let with_pending = cx.argument::<JsBoolean>(1)?.value();
+            let script = into_neon_res(&mut cx, duniter_core::documents_parser::wallet_script_from_str(&script_str))?;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.get_sources(script, with_pending)
+            };
+            let sources: Vec<_> = into_neon_res(&mut cx, res)?
+                .into_iter()
+                .map(|source| {
+                    let (source_type, identifier, noffset) = match source.id {
+                        SourceIdV10::Ud(UdSourceIdV10 { issuer, block_number }) => ("D", issuer.to_string(), block_number.0 as usize),
+                        SourceIdV10::Utxo(UtxoIdV10 { tx_hash, output_index }) => ("T", tx_hash.to_hex(), output_index),
+                    };
+                    SourceStringified {
+                        source_type,
+                        identifier,
+                        noffset,
+                        amount: source.amount.amount(),
+                        base: source.amount.base(),
+                        conditions: source.conditions.to_string(),
+                        pending: source.pending,
+                    }
+                })
+                .collect();
+            Ok(neon_serde::to_value(&mut cx, &sources)?)
+        }
+
         // Transactions history (for BMA only)
         method getTransactionsHistory(mut cx) {
             let pubkey_str = cx.argument::<JsString>(0)?.value();
@@ -311,6 +806,115 @@ declare_types! {
                 Err(e) => cx.throw_error(format!("{}", e)),
             }
         }
+        method getFilteredTransactionsHistory(mut cx) {
+            let pubkey_str = cx.argument::<JsString>(0)?.value();
+            let pubkey = into_neon_res(&mut cx, PublicKey::from_base58(&pubkey_str))?;
+            let filter_js = cx.argument::<JsValue>(1)?;
+            let filter_stringified: TxsHistoryFilterStringified = neon_serde::from_value(&mut cx, filter_js)?;
+            let filter = into_neon_res(&mut cx, filter_stringified.into_filter())?;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.get_filtered_transactions_history(pubkey, filter)
+            };
+            match res {
+                Ok(txs) => Ok(neon_serde::to_value(&mut cx, &TxsHistoryStringified::from_entries(txs))?),
+                Err(e) => cx.throw_error(format!("{}", e)),
+            }
+        }
+        method getTransactionsHistoryPage(mut cx) {
+            let pubkey_str = cx.argument::<JsString>(0)?.value();
+            let pubkey = into_neon_res(&mut cx, PublicKey::from_base58(&pubkey_str))?;
+            let filter_js = cx.argument::<JsValue>(1)?;
+            let filter_stringified: TxsHistoryFilterStringified = neon_serde::from_value(&mut cx, filter_js)?;
+            let page_size = cx.argument::<JsNumber>(2)?.value() as usize;
+            let cursor_opt = if let Some(arg3) = cx.argument_opt(3) {
+                if arg3.is_a::<JsString>() {
+                    let cursor_str = arg3.downcast::<JsString>().or_throw(&mut cx)?.value();
+                    Some(into_neon_res(&mut cx, TxsHistoryCursor::from_str(&cursor_str))?)
+                } else {
+                    None
+                }
+            } else {
+                None
+            };
+            let filter = into_neon_res(&mut cx, filter_stringified.into_filter())?;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.get_transactions_history_page(pubkey, filter, cursor_opt, page_size)
+            };
+            match res {
+                Ok(page) => {
+                    let history = TxsHistoryStringified::from_entries(page.txs);
+                    Ok(neon_serde::to_value(&mut cx, &TxsHistoryPageStringified {
+                        sent: history.sent,
+                        received: history.received,
+                        sending: history.sending,
+                        pending: history.pending,
+                        next_cursor: page.next_cursor.map(|cursor| cursor.to_string()),
+                    })?)
+                },
+                Err(e) => cx.throw_error(format!("{}", e)),
+            }
+        }
+        method getUdHistory(mut cx) {
+            let pubkey_str = cx.argument::<JsString>(0)?.value();
+            let pubkey = into_neon_res(&mut cx, PublicKey::from_base58(&pubkey_str))?;
+            let range_js = cx.argument::<JsValue>(1)?;
+            let range_stringified: UdHistoryRangeStringified = neon_serde::from_value(&mut cx, range_js)?;
+            let range = match range_stringified {
+                UdHistoryRangeStringified { from_block: Some(from), to_block: Some(to), .. } => UdHistoryRange::Blocks(from..=to),
+                UdHistoryRangeStringified { from_time: Some(from), to_time: Some(to), .. } => UdHistoryRange::Times(from..=to),
+                _ => UdHistoryRange::All,
+            };
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.get_ud_history(pubkey, range)
+            };
+            let history: Vec<_> = into_neon_res(&mut cx, res)?
+                .into_iter()
+                .map(|ud| UdStringified {
+                    block_number: ud.block_number.0,
+                    time: ud.time,
+                    consumed: ud.consumed,
+                    amount: ud.amount.amount(),
+                    base: ud.amount.base(),
+                })
+                .collect();
+            Ok(neon_serde::to_value(&mut cx, &history)?)
+        }
+        method getWalletGroupHistory(mut cx) {
+            let pubkeys_js = cx.argument::<JsValue>(0)?;
+            let pubkeys_str: Vec<String> = neon_serde::from_value(&mut cx, pubkeys_js)?;
+            let pubkeys = into_neon_res(&mut cx, pubkeys_str.iter().map(|pubkey_str| PublicKey::from_base58(pubkey_str)).collect::<Result<Vec<_>, _>>())?;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.get_wallet_group_history(&pubkeys)
+            };
+            let txs: Vec<_> = into_neon_res(&mut cx, res)?
+                .into_iter()
+                .map(|group_tx| WalletGroupTxStringified {
+                    hash: group_tx.tx.get_hash().to_hex(),
+                    tx: group_tx.tx.to_string_object(),
+                    written_block_number: group_tx.written_block.map(|wb| wb.number.0),
+                    time: group_tx.time,
+                    internal: group_tx.internal,
+                    net_amount: group_tx.net_amount,
+                })
+                .collect();
+            Ok(neon_serde::to_value(&mut cx, &txs)?)
+        }
         method getTxByHash(mut cx) {
             let hash_str = cx.argument::<JsString>(0)?.value();
             let hash = into_neon_res(&mut cx, Hash::from_hex(&hash_str))?;
@@ -336,6 +940,19 @@ declare_types! {
                 Err(e) => cx.throw_error(format!("{}", e)),
             }
         }
+        method getTxStatus(mut cx) {
+            let hash_str = cx.argument::<JsString>(0)?.value();
+            let hash = into_neon_res(&mut cx, Hash::from_hex(&hash_str))?;
+
+            let this = cx.this();
+            let res = {
+                let guard = cx.lock();
+                let server = this.borrow(&guard);
+                server.server.get_tx_status(hash)
+            };
+            let status = into_neon_res(&mut cx, res)?;
+            Ok(neon_serde::to_value(&mut cx, &TxStatusStringified::from(status))?)
+        }
 
         // WS2Pv1: HEADs and peers
         method receiveNewHeads(mut cx) {
@@ -491,6 +1108,30 @@ impl PendingTx {
     }
 }
 
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ModuleStatusStringified {
+    name: String,
+    status: String,
+    error: Option<String>,
+}
+
+impl ModuleStatusStringified {
+    fn new(name: &str, status: ModuleStatus) -> Self {
+        let (status, error) = match status {
+            ModuleStatus::Starting => ("starting", None),
+            ModuleStatus::Running => ("running", None),
+            ModuleStatus::Stopped => ("stopped", None),
+            ModuleStatus::Failed(error) => ("failed", Some(error)),
+        };
+        Self {
+            name: name.to_owned(),
+            status: status.to_owned(),
+            error,
+        }
+    }
+}
+
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -535,15 +1176,291 @@ impl PeerCardStringified {
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct RustServerConfStringified {
+    #[serde(default)]
+    block_verification: Option<String>,
     currency: String,
+    #[serde(default)]
+    mempool_maintenance_interval: Option<u64>,
+    #[serde(default)]
+    metrics_address: Option<String>,
     self_keypair: Option<String>,
+    #[serde(default)]
+    txs_mempool_conflict_policy: Option<String>,
+    #[serde(default)]
+    txs_mempool_eviction_policy: Option<String>,
+    #[serde(default)]
+    txs_mempool_max_age: Option<u64>,
     txs_mempool_size: u32,
+    #[serde(default)]
+    wot_mempool_max_age: Option<u64>,
+    #[serde(default)]
+    wot_mempool_size: Option<u32>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BlockMetaStringified {
+    version: u64,
+    number: u32,
+    hash: String,
+    signature: String,
+    inner_hash: String,
+    previous_hash: String,
+    issuer: String,
+    previous_issuer: String,
+    time: u64,
+    pow_min: u32,
+    members_count: u64,
+    issuers_count: u32,
+    issuers_frame: u64,
+    issuers_frame_var: i64,
+    median_time: u64,
+    nonce: u64,
+    monetary_mass: u64,
+    unit_base: u32,
+    dividend: Option<i64>,
+}
+
+impl From<BlockMetaV2> for BlockMetaStringified {
+    fn from(block_meta: BlockMetaV2) -> Self {
+        BlockMetaStringified {
+            version: block_meta.version,
+            number: block_meta.number,
+            hash: block_meta.hash.to_hex(),
+            signature: block_meta.signature.to_base64(),
+            inner_hash: block_meta.inner_hash.to_hex(),
+            previous_hash: block_meta.previous_hash.to_hex(),
+            issuer: block_meta.issuer.to_base58(),
+            previous_issuer: block_meta.previous_issuer.to_base58(),
+            time: block_meta.time,
+            pow_min: block_meta.pow_min,
+            members_count: block_meta.members_count,
+            issuers_count: block_meta.issuers_count,
+            issuers_frame: block_meta.issuers_frame,
+            issuers_frame_var: block_meta.issuers_frame_var,
+            median_time: block_meta.median_time,
+            nonce: block_meta.nonce,
+            monetary_mass: block_meta.monetary_mass,
+            unit_base: block_meta.unit_base,
+            dividend: block_meta.dividend.map(|dividend| dividend.amount()),
+        }
+    }
+}
+
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TxStatusStringified {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    received_time: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_number: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    median_time: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confirmations: Option<u32>,
+}
+
+impl From<TxStatus> for TxStatusStringified {
+    fn from(status: TxStatus) -> Self {
+        match status {
+            TxStatus::Pending { received_time } => TxStatusStringified {
+                status: "pending",
+                received_time: Some(received_time),
+                ..Default::default()
+            },
+            TxStatus::Written {
+                block_number,
+                median_time,
+                confirmations,
+            } => TxStatusStringified {
+                status: "written",
+                block_number: Some(block_number.0),
+                median_time: Some(median_time),
+                confirmations: Some(confirmations),
+                ..Default::default()
+            },
+            TxStatus::Expired => TxStatusStringified {
+                status: "expired",
+                ..Default::default()
+            },
+            TxStatus::Evicted => TxStatusStringified {
+                status: "evicted",
+                ..Default::default()
+            },
+            TxStatus::Unknown => TxStatusStringified {
+                status: "unknown",
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UdHistoryRangeStringified {
+    from_block: Option<u32>,
+    to_block: Option<u32>,
+    from_time: Option<u64>,
+    to_time: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UdStringified {
+    block_number: u32,
+    time: u64,
+    consumed: bool,
+    amount: i64,
+    base: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WalletGroupTxStringified {
+    tx: TransactionDocumentV10Stringified,
+    hash: String,
+    written_block_number: Option<u32>,
+    time: i64,
+    internal: bool,
+    net_amount: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct AmountStringified {
+    amount: i64,
+    base: i64,
+}
+
+#[derive(Serialize)]
+struct SourceStringified {
+    #[serde(rename = "type")]
+    source_type: &'static str,
+    identifier: String,
+    noffset: usize,
+    amount: i64,
+    base: i64,
+    conditions: String,
+    pending: bool,
+}
+
+#[derive(Serialize)]
+struct MempoolImportStringified {
+    accepted: usize,
+    rejected: Vec<RejectedTxStringified>,
+}
+
+#[derive(Serialize)]
+struct RejectedTxStringified {
+    hash: String,
+    code: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct MempoolConflictStringified {
+    source: String,
+    txs: Vec<String>,
+    issuers: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PendingTxsPageStringified {
+    txs: Vec<TransactionDocumentV10Stringified>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RejectionStringified {
+    code: &'static str,
+    message: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TxsHistoryFilterStringified {
+    #[serde(default)]
+    from_block: Option<u32>,
+    #[serde(default)]
+    to_block: Option<u32>,
+    #[serde(default)]
+    from_time: Option<i64>,
+    #[serde(default)]
+    to_time: Option<i64>,
+    #[serde(default)]
+    directions: Option<Vec<String>>,
+    #[serde(default)]
+    comment: Option<String>,
+}
+
+impl TxsHistoryFilterStringified {
+    fn into_filter(self) -> Result<TxsHistoryFilter, <TxDirection as FromStr>::Err> {
+        let directions = if let Some(directions) = self.directions {
+            Some(
+                directions
+                    .iter()
+                    .map(|direction| TxDirection::from_str(direction))
+                    .collect::<Result<Vec<_>, _>>()?,
+            )
+        } else {
+            None
+        };
+        Ok(TxsHistoryFilter {
+            from_block: self.from_block,
+            to_block: self.to_block,
+            from_time: self.from_time,
+            to_time: self.to_time,
+            directions,
+            comment: self.comment,
+        })
+    }
+}
+
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TxsHistoryPageStringified {
+    sent: Vec<DbTx>,
+    received: Vec<DbTx>,
+    sending: Vec<PendingTx>,
+    pending: Vec<PendingTx>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
 struct TxsHistoryStringified {
     sent: Vec<DbTx>,
     received: Vec<DbTx>,
     sending: Vec<PendingTx>,
     pending: Vec<PendingTx>,
 }
+
+impl TxsHistoryStringified {
+    fn from_entries(entries: Vec<TxHistoryEntry>) -> Self {
+        let mut history = TxsHistoryStringified::default();
+        for entry in entries {
+            let tx_str = entry.tx.to_string_object();
+            let hash = entry.tx.get_hash();
+            match (entry.direction, entry.written_block) {
+                (TxDirection::Sent, Some(wb)) => {
+                    history
+                        .sent
+                        .push(DbTx::v10(tx_str, hash, wb.number.0, entry.time))
+                }
+                (TxDirection::Received, Some(wb)) => {
+                    history
+                        .received
+                        .push(DbTx::v10(tx_str, hash, wb.number.0, entry.time))
+                }
+                (TxDirection::Sending, _) => history
+                    .sending
+                    .push(PendingTx::v10(tx_str, hash, entry.time)),
+                (TxDirection::Pending, _) => history
+                    .pending
+                    .push(PendingTx::v10(tx_str, hash, entry.time)),
+                _ => (),
+            }
+        }
+        history
+    }
+}
@@ -0,0 +1,463 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::*;
+use duniter_core::dbs::U32BE;
+use std::collections::{HashMap, VecDeque};
+
+// Difficulty ratio between 2 hexadecimal characters of the hash (16^(1/16))
+const POW_DIFFICULTY_RANGE_RATIO: f64 = 1.189;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockVerificationLevel {
+    /// Blocks are applied without any verification
+    None,
+    /// Check that the block follows the current block
+    Structural,
+    /// Structural verification, plus hashs, signature and proof of work
+    Full,
+}
+
+impl Default for BlockVerificationLevel {
+    fn default() -> Self {
+        BlockVerificationLevel::None
+    }
+}
+
+impl std::str::FromStr for BlockVerificationLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(BlockVerificationLevel::None),
+            "structural" => Ok(BlockVerificationLevel::Structural),
+            "full" => Ok(BlockVerificationLevel::Full),
+            _ => Err(anyhow::anyhow!("Invalid block verification level: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BlockVerificationError {
+    WrongNumber {
+        expected: BlockNumber,
+        found: BlockNumber,
+    },
+    WrongPreviousHash {
+        expected: Hash,
+        found: Hash,
+    },
+    WrongInnerHash,
+    WrongHash,
+    WrongSignature,
+    InsufficientProofOfWork {
+        /// Personalized difficulty of the block issuer
+        difficulty: usize,
+        hash: Hash,
+    },
+}
+
+impl std::fmt::Display for BlockVerificationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::WrongNumber { expected, found } => write!(
+                f,
+                "number check failed: expected #{}, found #{}",
+                expected, found
+            ),
+            Self::WrongPreviousHash { expected, found } => write!(
+                f,
+                "previous_hash check failed: expected {}, found {}",
+                expected, found
+            ),
+            Self::WrongInnerHash => write!(f, "inner_hash check failed"),
+            Self::WrongHash => write!(f, "hash check failed"),
+            Self::WrongSignature => write!(f, "signature check failed"),
+            Self::InsufficientProofOfWork { difficulty, hash } => write!(
+                f,
+                "proof of work check failed: hash {} does not match the issuer difficulty {}",
+                hash, difficulty
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BlockVerificationError {}
+
+/// What the verification of a block needs to know about the previous blocks
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PreviousBlock {
+    hash: Hash,
+    issuer: PublicKey,
+    issuers_count: usize,
+    issuers_frame: usize,
+    number: u32,
+}
+
+impl From<&BlockMetaV2> for PreviousBlock {
+    fn from(block_meta: &BlockMetaV2) -> Self {
+        PreviousBlock {
+            hash: block_meta.hash,
+            issuer: block_meta.issuer,
+            issuers_count: block_meta.issuers_count as usize,
+            issuers_frame: block_meta.issuers_frame as usize,
+            number: block_meta.number,
+        }
+    }
+}
+
+impl From<&DubpBlockV10> for PreviousBlock {
+    fn from(block: &DubpBlockV10) -> Self {
+        PreviousBlock {
+            hash: block.hash().0,
+            issuer: block.issuer(),
+            issuers_count: block.issuers_count(),
+            issuers_frame: block.issuers_frame(),
+            number: block.number().0,
+        }
+    }
+}
+
+impl PreviousBlock {
+    fn blockstamp(&self) -> Blockstamp {
+        Blockstamp {
+            number: BlockNumber(self.number),
+            hash: BlockHash(self.hash),
+        }
+    }
+}
+
+/// Verify blocks that follow the current block, in ascending order, according to the given
+/// level. The genesis block parameters must be given in `currency_params` when it is verified.
+pub(crate) fn verify_blocks<BcDb: BcV2DbReadable>(
+    bc_db: &BcDb,
+    blocks: &[DubpBlockV10],
+    current: Option<BlockMetaV2>,
+    currency_params: CurrencyParameters,
+    level: BlockVerificationLevel,
+) -> anyhow::Result<()> {
+    if level == BlockVerificationLevel::None {
+        return Ok(());
+    }
+
+    // The proof of work depends on the issuers frame of the previous block
+    let previous_blocks_count = if level == BlockVerificationLevel::Full {
+        current
+            .map(|current| PreviousBlock::from(&current).issuers_frame)
+            .into_iter()
+            .chain(blocks.iter().map(DubpBlockV10::issuers_frame))
+            .max()
+            .unwrap_or(0)
+            .max(1)
+    } else {
+        1
+    };
+    let mut previous_blocks = if let Some(current) = current {
+        bc_db
+            .blocks_meta()
+            .iter_rev(..=U32BE(current.number), |it| {
+                it.values()
+                    .take(previous_blocks_count)
+                    .map_ok(|block_meta| PreviousBlock::from(&block_meta))
+                    .collect::<KvResult<VecDeque<_>>>()
+            })?
+    } else {
+        VecDeque::new()
+    };
+
+    for block in blocks {
+        verify_block(
+            block,
+            &previous_blocks,
+            f64::from(currency_params.percent_rot),
+            level,
+        )?;
+        previous_blocks.push_front(PreviousBlock::from(block));
+        previous_blocks.truncate(previous_blocks_count);
+    }
+    Ok(())
+}
+
+/// Verify a block according to the given level, `previous_blocks` are the blocks that the
+/// verified block must follow, most recent first (empty for the genesis block).
+fn verify_block(
+    block: &DubpBlockV10,
+    previous_blocks: &VecDeque<PreviousBlock>,
+    percent_rot: f64,
+    level: BlockVerificationLevel,
+) -> Result<(), BlockVerificationError> {
+    if level == BlockVerificationLevel::None {
+        return Ok(());
+    }
+
+    // Structural verification
+    let previous = previous_blocks.front().map(PreviousBlock::blockstamp);
+    let expected_number = previous.map_or(BlockNumber(0), |previous| {
+        BlockNumber(previous.number.0 + 1)
+    });
+    if block.number() != expected_number {
+        return Err(BlockVerificationError::WrongNumber {
+            expected: expected_number,
+            found: block.number(),
+        });
+    }
+    if let Some(previous) = previous {
+        if block.previous_hash() != previous.hash.0 {
+            return Err(BlockVerificationError::WrongPreviousHash {
+                expected: previous.hash.0,
+                found: block.previous_hash(),
+            });
+        }
+    }
+
+    if level == BlockVerificationLevel::Full {
+        block
+            .verify_inner_hash()
+            .map_err(|_| BlockVerificationError::WrongInnerHash)?;
+        block
+            .verify_hash()
+            .map_err(|_| BlockVerificationError::WrongHash)?;
+        block
+            .verify_signature()
+            .map_err(|_| BlockVerificationError::WrongSignature)?;
+        let frame_len = previous_blocks
+            .front()
+            .map_or(0, |previous| previous.issuers_frame);
+        let difficulty = personalized_difficulty(
+            block.issuer(),
+            block.pow_min(),
+            previous_blocks.iter().take(frame_len),
+            percent_rot,
+        );
+        verify_proof_of_work(block.hash().0, difficulty)?;
+    }
+
+    Ok(())
+}
+
+/// Personalized difficulty of `issuer` (BR_G18). `frame` contains the blocks of the issuers
+/// frame of the previous block, most recent first.
+fn personalized_difficulty<'a>(
+    issuer: PublicKey,
+    pow_min: usize,
+    frame: impl Iterator<Item = &'a PreviousBlock>,
+    percent_rot: f64,
+) -> usize {
+    let mut previous_number = None;
+    let mut last_block_of_issuer = None;
+    let mut blocks_per_issuer = HashMap::new();
+    for block in frame {
+        previous_number.get_or_insert(block.number);
+        if block.issuer == issuer && last_block_of_issuer.is_none() {
+            last_block_of_issuer = Some(*block);
+        }
+        *blocks_per_issuer.entry(block.issuer).or_insert(0usize) += 1;
+    }
+
+    let personal_blocks_in_frame = blocks_per_issuer.get(&issuer).copied().unwrap_or(0);
+    let mut blocks_per_issuer: Vec<usize> = blocks_per_issuer.values().copied().collect();
+    blocks_per_issuer.sort_unstable();
+    let median_of_blocks_in_frame = median(&blocks_per_issuer).max(1.0);
+    let (previous_issuers, blocks_since) = match (last_block_of_issuer, previous_number) {
+        (Some(last_block), Some(previous_number)) => (
+            last_block.issuers_count,
+            (previous_number - last_block.number) as usize,
+        ),
+        _ => (0, 0),
+    };
+
+    let personal_excess =
+        ((personal_blocks_in_frame + 1) as f64 / median_of_blocks_in_frame - 1.0).max(0.0);
+    let personal_handicap =
+        ((1.0 + personal_excess).ln() / POW_DIFFICULTY_RANGE_RATIO.ln()).floor() as usize;
+    let rotation_factor =
+        (percent_rot * previous_issuers as f64 / (1 + blocks_since) as f64).floor() as usize;
+
+    let difficulty = pow_min.max(pow_min * rotation_factor) + personal_handicap;
+    // A difficulty of 15 modulo 16 would require a zero and an hexadecimal character <= 0
+    if (difficulty + 1) % 16 == 0 {
+        difficulty + 1
+    } else {
+        difficulty
+    }
+}
+
+// Average of the 2 central values if there is an even number of values.
+fn median(sorted_values: &[usize]) -> f64 {
+    let len = sorted_values.len();
+    if len == 0 {
+        0.0
+    } else if len % 2 == 0 {
+        (sorted_values[len / 2 - 1] + sorted_values[len / 2]) as f64 / 2.0
+    } else {
+        sorted_values[len / 2] as f64
+    }
+}
+
+// The hash must start with `difficulty / 16` zeros, followed by an hexadecimal
+// character lower or equal to `15 - difficulty % 16`.
+fn verify_proof_of_work(hash: Hash, difficulty: usize) -> Result<(), BlockVerificationError> {
+    let zeros = difficulty / 16;
+    let high_mark = 15 - (difficulty % 16) as u32;
+
+    let hash_hex = hash.to_hex();
+    let mut hash_chars = hash_hex.chars();
+    let enough_zeros = hash_chars.by_ref().take(zeros).all(|c| c == '0');
+    let next_char_ok = hash_chars
+        .next()
+        .and_then(|c| c.to_digit(16))
+        .map_or(false, |digit| digit <= high_mark);
+
+    if enough_zeros && next_char_ok {
+        Ok(())
+    } else {
+        Err(BlockVerificationError::InsufficientProofOfWork { difficulty, hash })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{block, block_hash, branch};
+
+    fn parse(block: DubpBlockV10Stringified) -> anyhow::Result<DubpBlockV10> {
+        Ok(DubpBlockV10::from_string_object(&block)?)
+    }
+
+    fn previous_block(number: u32, issuer: PublicKey, issuers_count: usize) -> PreviousBlock {
+        PreviousBlock {
+            hash: Hash::default(),
+            issuer,
+            issuers_count,
+            issuers_frame: 5,
+            number,
+        }
+    }
+
+    #[test]
+    fn test_verify_proof_of_work() -> anyhow::Result<()> {
+        let hash =
+            Hash::from_hex("0000051A5A8FB7E6A8FD77FB2ED6C09C1D5ED0AA5C4B1E14D2D3A6F4A8D8F123")?;
+
+        assert!(verify_proof_of_work(hash, 80).is_ok());
+        assert!(verify_proof_of_work(hash, 90).is_ok());
+        assert!(verify_proof_of_work(hash, 92).is_err());
+        assert!(verify_proof_of_work(hash, 96).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_personalized_difficulty() -> anyhow::Result<()> {
+        let a = PublicKey::from_base58("D9D2zaJoWYWveii1JRYLVK3J4Z7ZH3QczoKrnQeiM6mx")?;
+        let b = PublicKey::from_base58("82NdD9eEbXSjRJXeJdqf56xkpu6taTfTeEqtAtmtbyXY")?;
+        let c = PublicKey::from_base58("DA4PYtXdvQqk1nCaprXH52iMsK5Ahxs1nRWbWKLhpVkQ")?;
+        let d = PublicKey::default();
+        // Frame of the previous block #10, most recent first
+        let frame = vec![
+            previous_block(10, a, 3),
+            previous_block(9, b, 3),
+            previous_block(8, a, 3),
+            previous_block(7, c, 3),
+            previous_block(6, b, 3),
+        ];
+
+        // The genesis block has no frame
+        assert_eq!(personalized_difficulty(a, 70, [].iter(), 0.67), 70);
+        // 2 blocks in frame, median 2: handicap 2. Last block just before, 3 issuers:
+        // rotation factor 2.
+        assert_eq!(personalized_difficulty(a, 70, frame.iter(), 0.67), 142);
+        // 1 block in frame: no handicap. Last block 3 blocks before: no rotation factor.
+        assert_eq!(personalized_difficulty(c, 70, frame.iter(), 0.67), 70);
+        // Issuers outside the frame have the minimal difficulty
+        assert_eq!(personalized_difficulty(d, 70, frame.iter(), 0.67), 70);
+        // A difficulty of 15 modulo 16 is increased
+        assert_eq!(personalized_difficulty(d, 79, frame.iter(), 0.67), 80);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_block_levels() -> anyhow::Result<()> {
+        let genesis = parse(block(0, 0, None))?;
+        let previous_blocks: VecDeque<_> = vec![PreviousBlock::from(&genesis)].into();
+
+        let next = parse(block(0, 1, Some(block_hash(0, 0))))?;
+        let wrong_number = parse(block(0, 2, Some(block_hash(0, 0))))?;
+        let wrong_previous = parse(block(0, 1, Some(block_hash(1, 0))))?;
+
+        for block in &[&next, &wrong_number, &wrong_previous] {
+            assert!(
+                verify_block(block, &previous_blocks, 0.67, BlockVerificationLevel::None).is_ok()
+            );
+        }
+
+        let structural = BlockVerificationLevel::Structural;
+        assert!(verify_block(&next, &previous_blocks, 0.67, structural).is_ok());
+        assert!(verify_block(&genesis, &VecDeque::new(), 0.67, structural).is_ok());
+        assert!(matches!(
+            verify_block(&wrong_number, &previous_blocks, 0.67, structural),
+            Err(BlockVerificationError::WrongNumber { expected, found })
+                if expected == BlockNumber(1) && found == BlockNumber(2)
+        ));
+        assert!(matches!(
+            verify_block(&wrong_previous, &previous_blocks, 0.67, structural),
+            Err(BlockVerificationError::WrongPreviousHash { .. })
+        ));
+        assert!(matches!(
+            verify_block(&next, &VecDeque::new(), 0.67, structural),
+            Err(BlockVerificationError::WrongNumber { .. })
+        ));
+
+        // Test blocks hashs are not valid
+        assert!(matches!(
+            verify_block(&next, &previous_blocks, 0.67, BlockVerificationLevel::Full),
+            Err(BlockVerificationError::WrongInnerHash)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_blocks() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        server.apply_chunk_of_blocks(branch(0, 0..3, None))?;
+
+        let next_blocks: Vec<_> = branch(0, 3..6, Some(block_hash(0, 2)))
+            .into_iter()
+            .map(parse)
+            .collect::<anyhow::Result<_>>()?;
+        let structural = BlockVerificationLevel::Structural;
+        assert!(verify_blocks(
+            &server.bc_db,
+            &next_blocks,
+            server.current,
+            server.currency_params,
+            structural
+        )
+        .is_ok());
+        // Blocks must follow each other
+        assert!(verify_blocks(
+            &server.bc_db,
+            &next_blocks[1..],
+            server.current,
+            server.currency_params,
+            structural
+        )
+        .is_err());
+
+        Ok(())
+    }
+}
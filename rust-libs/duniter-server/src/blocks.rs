@@ -0,0 +1,156 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Read access to the blocks of the current chain.
+//!
+//! The blockchain database only stores the metadata of blocks (their header and the
+//! indicators computed when they are applied), not their documents: only metadata are
+//! returned.
+
+use crate::*;
+use duniter_core::dbs::U32BE;
+use std::ops::RangeInclusive;
+
+fn get_block_by_hash(
+    dbs: &dbs_pool::PoolDbs<'_>,
+    hash: BlockHash,
+) -> KvResult<Option<BlockMetaV2>> {
+    match dbs.server.blocks_index.number(hash) {
+        Some(number) => dbs.bc_db_ro.blocks_meta().get(&U32BE(number)),
+        None => Ok(None),
+    }
+}
+
+fn blockstamp_exists<BcDb: BcV2DbReadable>(bc_db: &BcDb, blockstamp: Blockstamp) -> KvResult<bool> {
+    Ok(bc_db
+        .blocks_meta()
+        .get(&U32BE(blockstamp.number.0))?
+        .map_or(false, |block_meta| block_meta.hash == blockstamp.hash.0))
+}
+
+impl DuniterServer {
+    pub fn get_current(&self) -> Option<BlockMetaV2> {
+        self.current
+    }
+    /// Metadata of the block `number` of the current chain
+    pub fn get_block(&self, number: BlockNumber) -> KvResult<Option<BlockMetaV2>> {
+        self.dbs_pool
+            .execute(move |dbs| dbs.bc_db_ro.blocks_meta().get(&U32BE(number.0)))
+            .expect("dbs pool disconnected")
+    }
+    pub async fn get_block_async(&self, number: BlockNumber) -> KvResult<Option<BlockMetaV2>> {
+        self.dbs_pool_async
+            .execute(move |dbs| dbs.bc_db_ro.blocks_meta().get(&U32BE(number.0)))
+            .await
+            .expect("dbs pool disconnected")
+    }
+    /// Metadata of the block `hash` of the current chain
+    pub fn get_block_by_hash(&self, hash: BlockHash) -> KvResult<Option<BlockMetaV2>> {
+        self.dbs_pool
+            .execute(move |dbs| get_block_by_hash(dbs, hash))
+            .expect("dbs pool disconnected")
+    }
+    pub async fn get_block_by_hash_async(&self, hash: BlockHash) -> KvResult<Option<BlockMetaV2>> {
+        self.dbs_pool_async
+            .execute(move |dbs| get_block_by_hash(dbs, hash))
+            .await
+            .expect("dbs pool disconnected")
+    }
+    /// Metadata of the blocks of the current chain in `range`, sorted by number.
+    pub fn get_blocks(&self, range: RangeInclusive<u32>) -> KvResult<Vec<BlockMetaV2>> {
+        self.dbs_pool
+            .execute(move |dbs| {
+                dbs.bc_db_ro
+                    .blocks_meta()
+                    .iter(U32BE(*range.start())..=U32BE(*range.end()), |it| {
+                        it.values().collect::<KvResult<Vec<_>>>()
+                    })
+            })
+            .expect("dbs pool disconnected")
+    }
+    pub async fn get_blocks_async(&self, range: RangeInclusive<u32>) -> KvResult<Vec<BlockMetaV2>> {
+        self.dbs_pool_async
+            .execute(move |dbs| {
+                dbs.bc_db_ro
+                    .blocks_meta()
+                    .iter(U32BE(*range.start())..=U32BE(*range.end()), |it| {
+                        it.values().collect::<KvResult<Vec<_>>>()
+                    })
+            })
+            .await
+            .expect("dbs pool disconnected")
+    }
+    /// Whether the block `blockstamp` is in the current chain.
+    pub fn blockstamp_exists(&self, blockstamp: Blockstamp) -> KvResult<bool> {
+        self.dbs_pool
+            .execute(move |dbs| blockstamp_exists(&dbs.bc_db_ro, blockstamp))
+            .expect("dbs pool disconnected")
+    }
+    pub async fn blockstamp_exists_async(&self, blockstamp: Blockstamp) -> KvResult<bool> {
+        self.dbs_pool_async
+            .execute(move |dbs| blockstamp_exists(&dbs.bc_db_ro, blockstamp))
+            .await
+            .expect("dbs pool disconnected")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{block, block_hash, blockstamp, branch};
+
+    fn numbers(blocks: &[BlockMetaV2]) -> Vec<u32> {
+        blocks.iter().map(|block_meta| block_meta.number).collect()
+    }
+
+    #[test]
+    fn test_get_blocks() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        assert_eq!(server.get_block_by_hash(BlockHash(block_hash(0, 0)))?, None);
+        server.apply_chunk_of_blocks(branch(0, 0..3, None))?;
+
+        assert_eq!(server.get_current().map(|current| current.number), Some(2));
+        assert_eq!(
+            server
+                .get_block(BlockNumber(1))?
+                .map(|block_meta| block_meta.hash),
+            Some(block_hash(0, 1))
+        );
+        assert_eq!(
+            server
+                .get_block_by_hash(BlockHash(block_hash(0, 1)))?
+                .map(|block_meta| block_meta.number),
+            Some(1)
+        );
+        assert_eq!(numbers(&server.get_blocks(1..=10)?), vec![1, 2]);
+        assert!(server.blockstamp_exists(blockstamp(0, 1))?);
+        assert!(!server.blockstamp_exists(blockstamp(1, 1))?);
+
+        // Blocks of another branch replace the reverted ones
+        server.revert_block(block(0, 2, Some(block_hash(0, 1))))?;
+        server.apply_block(block(1, 2, Some(block_hash(0, 1))))?;
+        assert_eq!(server.get_block_by_hash(BlockHash(block_hash(0, 2)))?, None);
+        assert_eq!(
+            server
+                .get_block_by_hash(BlockHash(block_hash(1, 2)))?
+                .map(|block_meta| block_meta.number),
+            Some(2)
+        );
+        assert!(!server.blockstamp_exists(blockstamp(0, 2))?);
+        assert!(server.blockstamp_exists(blockstamp(1, 2))?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! In memory index of the blocks of the current chain by hash.
+//!
+//! Blocks metadata are keyed by number in the blockchain database, this index finds the
+//! number of a block from its hash without scanning them. Written and reverted blocks are
+//! received as database events, applied before each lookup and by a background thread.
+
+use crate::events::DbEventsIndex;
+use crate::*;
+use duniter_core::dbs::databases::bc_v2::{BcV2Db, BlocksMetaEvent};
+use duniter_core::dbs::U32BE;
+use std::collections::HashMap;
+use std::sync::PoisonError;
+
+#[derive(Clone)]
+pub(crate) struct BlocksIndex(Arc<Mutex<BlocksIndexInner>>);
+
+struct BlocksIndexInner {
+    events_recv: flume::Receiver<Arc<Events<BlocksMetaEvent>>>,
+    /// Number of each block
+    numbers: HashMap<Hash, u32>,
+    /// Hash of each block
+    hashs: HashMap<u32, Hash>,
+}
+
+impl BlocksIndex {
+    /// Index the blocks, then follow the changes of the blockchain database
+    pub(crate) fn new(bc_db: &BcV2Db<FileBackend>) -> anyhow::Result<Self> {
+        // Subscribe before reading the blocks so that no change is missed
+        let (events_sender, events_recv) = flume::unbounded();
+        bc_db.blocks_meta().subscribe(events_sender)?;
+        let mut inner = BlocksIndexInner {
+            events_recv,
+            numbers: HashMap::new(),
+            hashs: HashMap::new(),
+        };
+        bc_db.blocks_meta().iter(.., |it| {
+            for block_meta_res in it.values() {
+                let block_meta = block_meta_res?;
+                inner.insert(block_meta.number, block_meta.hash);
+            }
+            Ok::<_, KvError>(())
+        })?;
+        let inner = Arc::new(Mutex::new(inner));
+        crate::events::follow_db_events("duniter-blocks-index", &inner)?;
+        Ok(BlocksIndex(inner))
+    }
+    /// Number of the block `hash` of the current chain
+    pub(crate) fn number(&self, hash: BlockHash) -> Option<u32> {
+        let mut inner = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.apply_events();
+        inner.numbers.get(&hash.0).copied()
+    }
+}
+
+impl DbEventsIndex for BlocksIndexInner {
+    fn apply_events(&mut self) {
+        let events: Vec<_> = self.events_recv.try_iter().collect();
+        for event in events.iter().flat_map(|events| events.iter()) {
+            match event {
+                BlocksMetaEvent::Upsert {
+                    key: U32BE(number),
+                    value,
+                } => self.insert(*number, value.hash),
+                BlocksMetaEvent::Remove { key: U32BE(number) } => {
+                    if let Some(hash) = self.hashs.remove(number) {
+                        self.numbers.remove(&hash);
+                    }
+                }
+                BlocksMetaEvent::RemoveAll => {
+                    self.numbers.clear();
+                    self.hashs.clear();
+                }
+            }
+        }
+    }
+}
+
+impl BlocksIndexInner {
+    fn insert(&mut self, number: u32, hash: Hash) {
+        if let Some(replaced) = self.hashs.insert(number, hash) {
+            self.numbers.remove(&replaced);
+        }
+        self.numbers.insert(hash, number);
+    }
+}
@@ -0,0 +1,371 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::*;
+
+/// Configure and start a `DuniterServer`.
+///
+/// Without profile path, databases are not persisted: they use the memory backend
+/// when the `mem` feature is enabled, and temporary databases otherwise.
+pub struct DuniterServerBuilder {
+    block_verification: BlockVerificationLevel,
+    conf: DuniterCoreConf,
+    currency: String,
+    dbs_threadpool_conf: ThreadPoolConfig,
+    duniter_mode: DuniterMode,
+    enabled_modules: Option<Vec<String>>,
+    mempool_conflict_policy: MempoolConflictPolicy,
+    mempool_eviction_policy: MempoolEvictionPolicy,
+    mempool_maintenance_conf: Option<MempoolMaintenanceConf>,
+    metrics_address: Option<std::net::SocketAddr>,
+    profile_path_opt: Option<PathBuf>,
+    software_version: &'static str,
+    wot_mempool_size: usize,
+}
+
+impl DuniterServerBuilder {
+    pub fn new(currency: String) -> Self {
+        DuniterServerBuilder {
+            block_verification: BlockVerificationLevel::default(),
+            conf: DuniterCoreConf::default(),
+            currency,
+            dbs_threadpool_conf: ThreadPoolConfig::default(),
+            duniter_mode: DuniterMode::Start,
+            enabled_modules: None,
+            mempool_conflict_policy: MempoolConflictPolicy::default(),
+            mempool_eviction_policy: MempoolEvictionPolicy::default(),
+            mempool_maintenance_conf: Some(MempoolMaintenanceConf::default()),
+            metrics_address: None,
+            profile_path_opt: None,
+            software_version: env!("CARGO_PKG_VERSION"),
+            wot_mempool_size: wot_mempools::DEFAULT_WOT_MEMPOOL_SIZE,
+        }
+    }
+    /// Verification applied to blocks before indexing them (none by default).
+    pub fn block_verification(mut self, level: BlockVerificationLevel) -> Self {
+        self.block_verification = level;
+        self
+    }
+    pub fn conf(mut self, conf: DuniterCoreConf) -> Self {
+        self.conf = conf;
+        self
+    }
+    /// Maximum number of threads used to access databases.
+    pub fn dbs_threadpool_size(mut self, size: usize) -> Self {
+        self.dbs_threadpool_conf = self.dbs_threadpool_conf.max_workers(size);
+        self
+    }
+    pub fn duniter_mode(mut self, duniter_mode: DuniterMode) -> Self {
+        self.duniter_mode = duniter_mode;
+        self
+    }
+    /// Start only the given modules (by type name, for example `GvaModule`).
+    /// All plugged modules are started by default.
+    pub fn modules<S: ToString>(mut self, modules: &[S]) -> Self {
+        self.enabled_modules = Some(modules.iter().map(ToString::to_string).collect());
+        self
+    }
+    /// Policy applied when a new transaction spends a source already spent by a pending
+    /// transaction (kept but never proposed for a block by default).
+    pub fn mempool_conflict_policy(mut self, policy: MempoolConflictPolicy) -> Self {
+        self.mempool_conflict_policy = policy;
+        self
+    }
+    /// Policy applied when a new transaction does not fit in the mempool (nothing is
+    /// evicted by default).
+    pub fn mempool_eviction_policy(mut self, policy: MempoolEvictionPolicy) -> Self {
+        self.mempool_eviction_policy = policy;
+        self
+    }
+    /// Configure the background task trimming expired documents and dropping pending
+    /// transactions whose sources are no longer available.
+    pub fn mempool_maintenance(mut self, conf: MempoolMaintenanceConf) -> Self {
+        self.mempool_maintenance_conf = Some(conf);
+        self
+    }
+    /// Do not run the mempool maintenance task, expired documents must be trimmed manually.
+    pub fn without_mempool_maintenance(mut self) -> Self {
+        self.mempool_maintenance_conf = None;
+        self
+    }
+    /// Serve metrics in Prometheus text format on the given address (not served by default).
+    pub fn metrics_address(mut self, address: std::net::SocketAddr) -> Self {
+        self.metrics_address = Some(address);
+        self
+    }
+    /// Store databases in the given profile directory.
+    pub fn profile_path(mut self, profile_path: PathBuf) -> Self {
+        self.profile_path_opt = Some(profile_path);
+        self
+    }
+    /// Keep databases in memory (the `mem` feature switches the databases backend).
+    #[cfg(feature = "mem")]
+    pub fn in_memory(mut self) -> Self {
+        self.profile_path_opt = None;
+        self
+    }
+    pub fn software_version(mut self, software_version: &'static str) -> Self {
+        self.software_version = software_version;
+        self
+    }
+    /// Maximum number of pending identities, certifications, memberships and revocations
+    /// (each kind has its own mempool).
+    pub fn wot_mempool_size(mut self, size: usize) -> Self {
+        self.wot_mempool_size = size;
+        self
+    }
+    /// Fail with a `ModulesStartError` if a duniter module fails to initialize or to start.
+    pub fn start(self) -> anyhow::Result<DuniterServer> {
+        let DuniterServerBuilder {
+            block_verification,
+            conf,
+            currency,
+            dbs_threadpool_conf,
+            duniter_mode,
+            enabled_modules,
+            mempool_conflict_policy,
+            mempool_eviction_policy,
+            mempool_maintenance_conf,
+            metrics_address,
+            profile_path_opt,
+            software_version,
+            wot_mempool_size,
+        } = self;
+        log::info!("mode={:?}", duniter_mode);
+
+        let txs_mempool = TxsMempool::new(conf.txs_mempool_size);
+
+        log::info!("open duniter databases...");
+        let (bc_db, shared_dbs) = duniter_core::dbs::open_dbs(profile_path_opt.as_deref())?;
+        shared_dbs.dunp_db.heads_old_write().clear()?; // Clear WS2Pv1 HEADs
+        let server_dbs = dbs_pool::ServerDbs {
+            blocks_index: blocks_index::BlocksIndex::new(&bc_db)?,
+            memberships_db: ud_history::open_memberships_db(profile_path_opt.as_deref())?,
+            rules_index_db: rules_index::open_rules_index_db(profile_path_opt.as_deref())?,
+            txs_mp_index: txs_mp_index::TxsMpIndex::new(&shared_dbs.txs_mp_db)?,
+            txs_dropped_db: tx_status::open_txs_dropped_db(profile_path_opt.as_deref())?,
+            utxos_index: utxos_index::UtxosIndex::new(&bc_db)?,
+            wot_mp_db: wot_mempools::open_wot_mp_db(profile_path_opt.as_deref())?,
+        };
+
+        // Create channel with global async task
+        let (global_sender, global_recv) = flume::unbounded();
+
+        // Fill and get current meta
+        let current = fill_cm::fill_and_get_current_meta(&bc_db, &global_sender)?;
+        let uds_count = fill_cm::count_uds(&bc_db)?;
+        log::info!("Databases successfully opened.");
+
+        // Get currency parameters
+        let currency_params = bc_db.currency_params().get(&())?.unwrap_or_default().params;
+
+        if let Some(current) = current {
+            log::info!("Current block: #{}-{}", current.number, current.hash);
+        } else {
+            log::info!("Current block: no blockchain");
+        }
+
+        let (s, pending_txs_subscriber) = flume::unbounded();
+        shared_dbs
+            .txs_mp_db
+            .txs()
+            .subscribe(s)
+            .context("Fail to subscribe to txs col")?;
+
+        let (shutdown_sender, shutdown_recv) = flume::bounded::<()>(1);
+        let events_bus = events::EventsBus::default();
+        let mut background_threads = vec![events::forward_dbs_events(
+            &shared_dbs,
+            events_bus.clone(),
+            shutdown_recv.clone(),
+        )?];
+
+        log::info!("start dbs threadpool...");
+
+        let threadpool =
+            fast_threadpool::ThreadPool::start(dbs_threadpool_conf, shared_dbs.clone());
+        // Block writes launch jobs in the main pool and wait for them, so they have their
+        // own pool (with a single worker, as blocks are written one after the other).
+        let block_writer_threadpool = fast_threadpool::ThreadPool::start(
+            ThreadPoolConfig::default().max_workers(1),
+            shared_dbs.clone(),
+        );
+        let block_writer_queued_jobs = dbs_pool::QueuedJobs::default();
+        let block_writer_pool_async = dbs_pool::DbsPoolAsync::new(
+            block_writer_threadpool.async_handler(),
+            block_writer_queued_jobs.clone(),
+            server_dbs.clone(),
+        );
+        let block_writer_pool = dbs_pool::DbsPool::new(
+            block_writer_threadpool.into_sync_handler(),
+            block_writer_queued_jobs,
+            server_dbs.clone(),
+        );
+
+        // Start async runtime
+        let modules_ctx = modules::ModulesCtx {
+            conf: conf.clone(),
+            currency: currency.clone(),
+            dbs_pool: threadpool.async_handler(),
+            enabled_modules,
+            events_bus: events_bus.clone(),
+            mempools: Mempools { txs: txs_mempool },
+            mode: duniter_mode,
+            profile_path_opt: profile_path_opt.clone(),
+            shutdown_recv: shutdown_recv.clone(),
+            software_version,
+        };
+        let modules_status = modules::ModulesStatus::default();
+        let modules_status_clone = modules_status.clone();
+        let (modules_init_sender, modules_init_recv) = flume::bounded(1);
+        let runtime_handle = std::thread::spawn(move || {
+            duniter_core::global::get_async_runtime().block_on(async {
+                // Start global background task
+                duniter_core::global::start_global_background_task(global_recv).await;
+
+                // Start duniter modules
+                log::info!("start duniter modules...");
+                let (started_modules, res) =
+                    modules::start_modules(modules_ctx, modules_status_clone).await;
+                let _ = modules_init_sender.send(res);
+                // Duniter modules stop on shutdown request (or when the server is dropped)
+                started_modules.join().await;
+                log::info!("Duniter modules stopped.");
+            });
+        });
+
+        // Wait for the start of duniter modules
+        let modules_start_res = modules_init_recv
+            .recv()
+            .map_err(|_| anyhow::Error::msg("duniter modules thread panicked"))?;
+        if let Err(e) = modules_start_res {
+            // Stop the modules already started
+            drop(shutdown_sender);
+            let _ = runtime_handle.join();
+            return Err(e.into());
+        }
+
+        log::info!("Duniter sever started.");
+
+        let metrics = metrics::Metrics::default();
+        let dbs_pool_async = dbs_pool::DbsPoolAsync::new(
+            threadpool.async_handler(),
+            metrics.dbs_pool_queued_jobs(),
+            server_dbs.clone(),
+        );
+        let dbs_pool = dbs_pool::DbsPool::new(
+            threadpool.into_sync_handler(),
+            metrics.dbs_pool_queued_jobs(),
+            server_dbs,
+        );
+
+        let mempool_maintenance_done = mempool_maintenance_conf.map(|conf| {
+            let mempool_maintenance = mempool_maintenance::MempoolMaintenance {
+                conf,
+                dbs_pool: dbs_pool_async.clone(),
+                events_recv: events_bus.subscribe_internal(),
+                shutdown_recv: shutdown_recv.clone(),
+            };
+            let (done_sender, done_recv) = flume::bounded::<()>(1);
+            duniter_core::global::get_async_runtime().spawn(async move {
+                mempool_maintenance.run().await;
+                drop(done_sender);
+            });
+            done_recv
+        });
+
+        if let Some(metrics_address) = metrics_address {
+            background_threads.push(metrics::serve_metrics(
+                metrics_address,
+                metrics::MetricsRenderer {
+                    metrics: metrics.clone(),
+                    profile_path_opt: profile_path_opt.clone(),
+                    shared_dbs: shared_dbs.clone(),
+                    txs_mempool,
+                },
+                shutdown_recv,
+            )?);
+        }
+
+        Ok(DuniterServer {
+            background_threads,
+            bc_db,
+            block_verification,
+            block_writer_pool,
+            block_writer_pool_async,
+            conf,
+            currency,
+            current,
+            currency_params,
+            dbs_pool,
+            dbs_pool_async,
+            events_bus,
+            #[cfg(test)]
+            fail_block_writes: false,
+            global_sender,
+            inconsistent_chain: None,
+            mempool_conflict_policy,
+            mempool_eviction_policy,
+            mempool_maintenance_done,
+            metrics,
+            modules_status,
+            pending_txs_subscriber,
+            profile_path_opt,
+            runtime_handle: Some(runtime_handle),
+            shared_dbs,
+            shutdown_sender: Some(shutdown_sender),
+            txs_history_cache: Mutex::new(txs_history::WrittenHistoryCache::default()),
+            txs_mempool,
+            txs_mp_insert_lock: Arc::new(Mutex::new(())),
+            uds_count,
+            wot_mempool_size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_start_without_modules() -> anyhow::Result<()> {
+        let server = DuniterServerBuilder::new("test".to_owned())
+            .modules::<&str>(&[])
+            .dbs_threadpool_size(2)
+            .start()?;
+
+        assert!(server.modules_status().is_empty());
+        assert_eq!(
+            server.get_mempool_txs_free_rooms()?,
+            server.conf.txs_mempool_size
+        );
+
+        Ok(())
+    }
+
+    #[cfg(feature = "mem")]
+    #[test]
+    fn test_in_memory() -> anyhow::Result<()> {
+        assert!(std::any::type_name::<FileBackend>().contains("Mem"));
+
+        let server = DuniterServerBuilder::new("test".to_owned())
+            .in_memory()
+            .modules::<&str>(&[])
+            .start()?;
+        assert!(server.get_current().is_none());
+
+        Ok(())
+    }
+}
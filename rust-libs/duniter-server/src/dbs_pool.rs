@@ -0,0 +1,157 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Databases thread pool handlers that count the jobs waiting for a worker.
+//!
+//! Jobs receive the duniter-core shared databases with the databases of the server.
+
+use crate::*;
+use fast_threadpool::{ThreadPoolAsyncHandler, ThreadPoolDisconnected, ThreadPoolSyncHandler};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Databases and indexes of the server, in addition to the duniter-core shared databases
+#[derive(Clone)]
+pub(crate) struct ServerDbs {
+    pub(crate) blocks_index: crate::blocks_index::BlocksIndex,
+    pub(crate) memberships_db: crate::ud_history::MembershipsV1Db<FileBackend>,
+    pub(crate) rules_index_db: crate::rules_index::RulesIndexV1Db<FileBackend>,
+    pub(crate) txs_dropped_db: crate::tx_status::TxsDroppedV1Db<FileBackend>,
+    pub(crate) txs_mp_index: crate::txs_mp_index::TxsMpIndex,
+    pub(crate) utxos_index: crate::utxos_index::UtxosIndex,
+    pub(crate) wot_mp_db: crate::wot_mempools::WotMpV1Db<FileBackend>,
+}
+
+/// Databases given to the jobs of the pool. Derefs to the duniter-core shared databases.
+pub(crate) struct PoolDbs<'a> {
+    shared: &'a SharedDbs<FileBackend>,
+    pub(crate) server: &'a ServerDbs,
+}
+
+impl Deref for PoolDbs<'_> {
+    type Target = SharedDbs<FileBackend>;
+
+    fn deref(&self) -> &Self::Target {
+        self.shared
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct QueuedJobs(Arc<AtomicU64>);
+
+impl QueuedJobs {
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+    fn push(&self) -> QueuedJobs {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        self.clone()
+    }
+    fn pop(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct DbsPool {
+    handler: ThreadPoolSyncHandler<SharedDbs<FileBackend>>,
+    queued_jobs: QueuedJobs,
+    server_dbs: ServerDbs,
+}
+
+impl DbsPool {
+    pub(crate) fn new(
+        handler: ThreadPoolSyncHandler<SharedDbs<FileBackend>>,
+        queued_jobs: QueuedJobs,
+        server_dbs: ServerDbs,
+    ) -> Self {
+        DbsPool {
+            handler,
+            queued_jobs,
+            server_dbs,
+        }
+    }
+    pub(crate) fn server_dbs(&self) -> &ServerDbs {
+        &self.server_dbs
+    }
+    /// Raw handler, needed by duniter-core functions
+    pub(crate) fn handler(&self) -> &ThreadPoolSyncHandler<SharedDbs<FileBackend>> {
+        &self.handler
+    }
+    pub(crate) fn execute<F, R>(&self, f: F) -> Result<R, ThreadPoolDisconnected>
+    where
+        F: 'static + Send + FnOnce(&PoolDbs<'_>) -> R,
+        R: 'static + Send,
+    {
+        let queued_jobs = self.queued_jobs.push();
+        let queued_jobs_clone = queued_jobs.clone();
+        let server_dbs = self.server_dbs.clone();
+        let res = self.handler.execute(move |dbs| {
+            queued_jobs_clone.pop();
+            f(&PoolDbs {
+                shared: dbs,
+                server: &server_dbs,
+            })
+        });
+        if res.is_err() {
+            queued_jobs.pop();
+        }
+        res
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct DbsPoolAsync {
+    handler: ThreadPoolAsyncHandler<SharedDbs<FileBackend>>,
+    queued_jobs: QueuedJobs,
+    server_dbs: ServerDbs,
+}
+
+impl DbsPoolAsync {
+    pub(crate) fn new(
+        handler: ThreadPoolAsyncHandler<SharedDbs<FileBackend>>,
+        queued_jobs: QueuedJobs,
+        server_dbs: ServerDbs,
+    ) -> Self {
+        DbsPoolAsync {
+            handler,
+            queued_jobs,
+            server_dbs,
+        }
+    }
+    pub(crate) async fn execute<F, R>(&self, f: F) -> Result<R, ThreadPoolDisconnected>
+    where
+        F: 'static + Send + FnOnce(&PoolDbs<'_>) -> R,
+        R: 'static + Send,
+    {
+        let queued_jobs = self.queued_jobs.push();
+        let queued_jobs_clone = queued_jobs.clone();
+        let server_dbs = self.server_dbs.clone();
+        let res = self
+            .handler
+            .execute(move |dbs| {
+                queued_jobs_clone.pop();
+                f(&PoolDbs {
+                    shared: dbs,
+                    server: &server_dbs,
+                })
+            })
+            .await;
+        if res.is_err() {
+            queued_jobs.pop();
+        }
+        res
+    }
+}
@@ -0,0 +1,288 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::*;
+use duniter_core::dbs::databases::{
+    network_v1::{HeadsOldEvent, NetworkV1DbReadable, PeersOldEvent},
+    txs_mp_v2::TxsEvent,
+};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// Maximum number of events waiting to be received by a subscriber
+const SUBSCRIBER_QUEUE_SIZE: usize = 10_000;
+/// Interval between two applications of the events received by an in memory index, if it is
+/// not queried meanwhile
+const INDEX_EVENTS_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServerEvent {
+    BlockApplied(Blockstamp),
+    BlockReverted(Blockstamp),
+    TxAdded(Hash),
+    TxRemoved(Hash),
+    /// Pending transaction evicted to make room for a new one, or depending on an evicted
+    /// transaction (it is also removed)
+    TxEvicted(Hash),
+    PeerSaved(PublicKey),
+    PeerRemoved(PublicKey),
+    HeadReceived {
+        pubkey: PublicKey,
+        blockstamp: Blockstamp,
+    },
+    /// Self endpoints published by duniter modules have changed
+    SelfEndpointsChanged,
+}
+
+/// Server event with its sequence number. Sequence numbers are strictly increasing,
+/// and all subscribers receive the events in the same order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SequencedEvent {
+    pub seq: u64,
+    pub event: ServerEvent,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct EventsBus(Arc<Mutex<EventsBusInner>>);
+
+#[derive(Debug, Default)]
+struct EventsBusInner {
+    next_seq: u64,
+    subscribers: Vec<flume::Sender<SequencedEvent>>,
+}
+
+impl EventsBus {
+    pub(crate) fn publish(&self, event: ServerEvent) {
+        let mut inner = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        // Forget subscribers whose receiver has been dropped, and subscribers that do not
+        // keep up (their receiver is disconnected once the queued events are received).
+        inner.subscribers.retain(|subscriber| {
+            match subscriber.try_send(SequencedEvent { seq, event }) {
+                Ok(()) => true,
+                Err(flume::TrySendError::Full(_)) => {
+                    log::warn!("events subscriber does not keep up, unsubscribe it");
+                    false
+                }
+                Err(flume::TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+    pub(crate) fn subscribe(&self) -> flume::Receiver<SequencedEvent> {
+        self.add_subscriber(flume::bounded(SUBSCRIBER_QUEUE_SIZE))
+    }
+    /// Subscribe a task of the server. Its queue is not bounded, so that it receives all
+    /// events even when many blocks are applied at once.
+    pub(crate) fn subscribe_internal(&self) -> flume::Receiver<SequencedEvent> {
+        self.add_subscriber(flume::unbounded())
+    }
+    fn add_subscriber(
+        &self,
+        (sender, receiver): (
+            flume::Sender<SequencedEvent>,
+            flume::Receiver<SequencedEvent>,
+        ),
+    ) -> flume::Receiver<SequencedEvent> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .subscribers
+            .push(sender);
+        receiver
+    }
+    /// Disconnect all subscribers, once they have received the queued events
+    pub(crate) fn close(&self) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .subscribers
+            .clear();
+    }
+}
+
+/// Forward mempool and network databases changes to the events bus.
+/// The forwarding thread stops on server shutdown.
+pub(crate) fn forward_dbs_events(
+    shared_dbs: &SharedDbs<FileBackend>,
+    events_bus: EventsBus,
+    shutdown_recv: flume::Receiver<()>,
+) -> anyhow::Result<std::thread::JoinHandle<()>> {
+    let (txs_sender, txs_recv) = flume::unbounded();
+    shared_dbs.txs_mp_db.txs().subscribe(txs_sender)?;
+    let (peers_sender, peers_recv) = flume::unbounded();
+    shared_dbs.dunp_db.peers_old().subscribe(peers_sender)?;
+    let (heads_sender, heads_recv) = flume::unbounded();
+    shared_dbs.dunp_db.heads_old().subscribe(heads_sender)?;
+
+    std::thread::Builder::new()
+        .name("duniter-events".to_owned())
+        .spawn(move || loop {
+            let res = flume::Selector::new()
+                .recv(&shutdown_recv, |_| Err(flume::RecvError::Disconnected))
+                .recv(&txs_recv, |events_res| {
+                    events_res.map(|events| {
+                        for event in events.iter() {
+                            match event {
+                                TxsEvent::Upsert { key, .. } => {
+                                    events_bus.publish(ServerEvent::TxAdded(key.0))
+                                }
+                                TxsEvent::Remove { key } => {
+                                    events_bus.publish(ServerEvent::TxRemoved(key.0))
+                                }
+                                _ => (),
+                            }
+                        }
+                    })
+                })
+                .recv(&peers_recv, |events_res| {
+                    events_res.map(|events| {
+                        for event in events.iter() {
+                            match event {
+                                PeersOldEvent::Upsert { key, .. } => {
+                                    events_bus.publish(ServerEvent::PeerSaved(key.0))
+                                }
+                                PeersOldEvent::Remove { key } => {
+                                    events_bus.publish(ServerEvent::PeerRemoved(key.0))
+                                }
+                                _ => (),
+                            }
+                        }
+                    })
+                })
+                .recv(&heads_recv, |events_res| {
+                    events_res.map(|events| {
+                        for event in events.iter() {
+                            if let HeadsOldEvent::Upsert { value, .. } = event {
+                                events_bus.publish(ServerEvent::HeadReceived {
+                                    pubkey: value.pubkey,
+                                    blockstamp: value.blockstamp,
+                                })
+                            }
+                        }
+                    })
+                })
+                .wait();
+            if res.is_err() {
+                break;
+            }
+        })
+        .context("Fail to spawn events thread")
+}
+
+/// In memory index following the events of a database
+pub(crate) trait DbEventsIndex: 'static + Send {
+    /// Apply the events received since the last call
+    fn apply_events(&mut self);
+}
+
+/// Apply the events received by `index` at regular intervals, so that they do not pile up
+/// when the index is not queried. Events are only received under the lock of the index, so
+/// they are applied in order whoever receives them. The thread stops once the index is
+/// dropped.
+pub(crate) fn follow_db_events<I: DbEventsIndex>(
+    name: &str,
+    index: &Arc<Mutex<I>>,
+) -> anyhow::Result<()> {
+    let index = Arc::downgrade(index);
+    std::thread::Builder::new()
+        .name(name.to_owned())
+        .spawn(move || loop {
+            std::thread::sleep(INDEX_EVENTS_INTERVAL);
+            if let Some(index) = index.upgrade() {
+                index
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .apply_events();
+            } else {
+                break;
+            }
+        })
+        .with_context(|| format!("Fail to spawn {} thread", name))?;
+    Ok(())
+}
+
+impl DuniterServer {
+    /// Subscribe to server events. Each subscriber receives all events published after
+    /// its subscription, independently of other subscribers.
+    ///
+    /// The receiver is disconnected on server shutdown, or if more than
+    /// `SUBSCRIBER_QUEUE_SIZE` events are waiting to be received.
+    pub fn subscribe_events(&self) -> flume::Receiver<SequencedEvent> {
+        self.events_bus.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_bus() {
+        let events_bus = EventsBus::default();
+        let recv1 = events_bus.subscribe();
+        events_bus.publish(ServerEvent::TxAdded(Hash::default()));
+        let recv2 = events_bus.subscribe();
+        events_bus.publish(ServerEvent::TxRemoved(Hash::default()));
+
+        assert_eq!(
+            recv1.try_iter().collect::<Vec<_>>(),
+            vec![
+                SequencedEvent {
+                    seq: 0,
+                    event: ServerEvent::TxAdded(Hash::default())
+                },
+                SequencedEvent {
+                    seq: 1,
+                    event: ServerEvent::TxRemoved(Hash::default())
+                },
+            ]
+        );
+        assert_eq!(
+            recv2.try_iter().collect::<Vec<_>>(),
+            vec![SequencedEvent {
+                seq: 1,
+                event: ServerEvent::TxRemoved(Hash::default())
+            }]
+        );
+
+        // Dropped subscribers are forgotten
+        drop(recv1);
+        events_bus.publish(ServerEvent::TxAdded(Hash::default()));
+        assert_eq!(recv2.try_iter().count(), 1);
+
+        // Closed subscribers receive the queued events
+        events_bus.publish(ServerEvent::TxAdded(Hash::default()));
+        events_bus.close();
+        assert_eq!(recv2.try_iter().count(), 1);
+        assert_eq!(recv2.recv(), Err(flume::RecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_events_bus_slow_subscriber() {
+        let events_bus = EventsBus::default();
+        let slow_recv = events_bus.subscribe();
+        let internal_recv = events_bus.subscribe_internal();
+        for _ in 0..=SUBSCRIBER_QUEUE_SIZE {
+            events_bus.publish(ServerEvent::SelfEndpointsChanged);
+        }
+
+        assert_eq!(slow_recv.try_iter().count(), SUBSCRIBER_QUEUE_SIZE);
+        assert_eq!(slow_recv.recv(), Err(flume::RecvError::Disconnected));
+        // Internal subscribers receive all events
+        assert_eq!(internal_recv.try_iter().count(), SUBSCRIBER_QUEUE_SIZE + 1);
+        assert_eq!(events_bus.0.lock().expect("poisoned").subscribers.len(), 1);
+    }
+}
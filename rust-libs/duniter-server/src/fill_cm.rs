@@ -45,3 +45,19 @@ pub(super) fn fill_and_get_current_meta<BcDb: BcV2DbReadable>(
         Ok(None)
     }
 }
+
+/// Blocks metadata do not count dividends, so they are counted once at startup, then the
+/// count is updated each time a block is applied or reverted.
+pub(super) fn count_uds<BcDb: BcV2DbReadable>(bc_db_ro: &BcDb) -> KvResult<u64> {
+    bc_db_ro.blocks_meta().iter(.., |it| {
+        it.values().try_fold(0u64, |count, block_meta_res| {
+            block_meta_res.map(|block_meta| {
+                if block_meta.dividend.is_some() {
+                    count + 1
+                } else {
+                    count
+                }
+            })
+        })
+    })
+}
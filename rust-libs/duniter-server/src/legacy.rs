@@ -19,3 +19,6 @@ mod block_indexer;
 mod dunp;
 mod tx_history;
 mod txs_mempool;
+mod wot_mempools;
+
+pub use dunp::SelfEndpointsNotReady;
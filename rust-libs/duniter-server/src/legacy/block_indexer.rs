@@ -14,69 +14,444 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::*;
+use std::time::Instant;
 
 impl DuniterServer {
     pub fn apply_block(&mut self, block: DubpBlockV10Stringified) -> KvResult<()> {
+        let start = Instant::now();
         let block = Arc::new(
             DubpBlockV10::from_string_object(&block).map_err(|e| KvError::DeserError(e.into()))?,
         );
-
-        // Get currency parameters from genesis block
-        if let Some(currency_params) = block.currency_parameters() {
-            self.currency_params = currency_params;
-        }
-
-        self.current = Some(duniter_core::dbs_write_ops::apply_block::apply_block(
-            &self.bc_db,
-            block.clone(),
-            self.current,
-            &self.dbs_pool,
-            &self.global_sender,
-            false,
-        )?);
-        apply_block_modules(
-            block,
-            Arc::new(self.conf.clone()),
-            self.currency_params,
-            &self.dbs_pool,
-            self.profile_path_opt.clone(),
-        )
+        let mut indexer = self.block_indexer();
+        let res = self
+            .block_writer_pool
+            .execute(move |_| indexer.run(|indexer| indexer.apply_block(block)))
+            .expect("dbs pool disconnected");
+        self.end_indexing(res)?;
+        self.metrics.observe_apply_block(start);
+        Ok(())
     }
     pub fn apply_chunk_of_blocks(&mut self, blocks: Vec<DubpBlockV10Stringified>) -> KvResult<()> {
         log::debug!("apply_chunk(#{})", blocks[0].number);
 
-        let blocks = Arc::from(
-            blocks
-                .into_iter()
-                .map(|block| DubpBlockV10::from_string_object(&block))
-                .collect::<Result<Vec<_>, _>>()
-                .map_err(|e| KvError::DeserError(e.into()))?,
+        let start = Instant::now();
+        let blocks: Arc<[DubpBlockV10]> = Arc::from(parse_blocks(blocks)?);
+        let mut indexer = self.block_indexer();
+        let res = self
+            .block_writer_pool
+            .execute(move |_| indexer.run(|indexer| indexer.apply_chunk(blocks)))
+            .expect("dbs pool disconnected");
+        self.end_indexing(res)?;
+        self.metrics.observe_apply_chunk(start);
+        Ok(())
+    }
+    pub fn revert_block(&mut self, block: DubpBlockV10Stringified) -> KvResult<()> {
+        let block = Arc::new(
+            DubpBlockV10::from_string_object(&block).map_err(|e| KvError::DeserError(e.into()))?,
+        );
+        let mut indexer = self.block_indexer();
+        let res = self
+            .block_writer_pool
+            .execute(move |_| indexer.run(|indexer| indexer.revert_block(block)))
+            .expect("dbs pool disconnected");
+        self.end_indexing(res)
+    }
+    /// Revert `reverted` blocks (from the current block) then apply `applied` blocks (in
+    /// ascending order). If any step fails, the original chain is restored.
+    pub fn switch_branch(
+        &mut self,
+        reverted: Vec<DubpBlockV10Stringified>,
+        applied: Vec<DubpBlockV10Stringified>,
+    ) -> KvResult<()> {
+        let reverted: Vec<_> = parse_blocks(reverted)?.into_iter().map(Arc::new).collect();
+        let applied: Vec<_> = parse_blocks(applied)?.into_iter().map(Arc::new).collect();
+        let mut indexer = self.block_indexer();
+        let res = self
+            .block_writer_pool
+            .execute(move |_| indexer.run(|indexer| indexer.switch_branch(reverted, applied)))
+            .expect("dbs pool disconnected");
+        self.end_indexing(res)
+    }
+    pub async fn apply_block_async(&mut self, block: DubpBlockV10Stringified) -> KvResult<()> {
+        let start = Instant::now();
+        let block = Arc::new(
+            DubpBlockV10::from_string_object(&block).map_err(|e| KvError::DeserError(e.into()))?,
+        );
+        let mut indexer = self.block_indexer();
+        let res = self
+            .block_writer_pool_async
+            .execute(move |_| indexer.run(|indexer| indexer.apply_block(block)))
+            .await
+            .expect("dbs pool disconnected");
+        self.end_indexing(res)?;
+        self.metrics.observe_apply_block(start);
+        Ok(())
+    }
+    pub async fn apply_chunk_of_blocks_async(
+        &mut self,
+        blocks: Vec<DubpBlockV10Stringified>,
+    ) -> KvResult<()> {
+        log::debug!("apply_chunk(#{})", blocks[0].number);
+
+        let start = Instant::now();
+        let blocks: Arc<[DubpBlockV10]> = Arc::from(parse_blocks(blocks)?);
+        let mut indexer = self.block_indexer();
+        let res = self
+            .block_writer_pool_async
+            .execute(move |_| indexer.run(|indexer| indexer.apply_chunk(blocks)))
+            .await
+            .expect("dbs pool disconnected");
+        self.end_indexing(res)?;
+        self.metrics.observe_apply_chunk(start);
+        Ok(())
+    }
+    pub async fn revert_block_async(&mut self, block: DubpBlockV10Stringified) -> KvResult<()> {
+        let block = Arc::new(
+            DubpBlockV10::from_string_object(&block).map_err(|e| KvError::DeserError(e.into()))?,
         );
+        let mut indexer = self.block_indexer();
+        let res = self
+            .block_writer_pool_async
+            .execute(move |_| indexer.run(|indexer| indexer.revert_block(block)))
+            .await
+            .expect("dbs pool disconnected");
+        self.end_indexing(res)
+    }
+    /// Revert `reverted` blocks (from the current block) then apply `applied` blocks (in
+    /// ascending order). If any step fails, the original chain is restored.
+    pub async fn switch_branch_async(
+        &mut self,
+        reverted: Vec<DubpBlockV10Stringified>,
+        applied: Vec<DubpBlockV10Stringified>,
+    ) -> KvResult<()> {
+        let reverted: Vec<_> = parse_blocks(reverted)?.into_iter().map(Arc::new).collect();
+        let applied: Vec<_> = parse_blocks(applied)?.into_iter().map(Arc::new).collect();
+        let mut indexer = self.block_indexer();
+        let res = self
+            .block_writer_pool_async
+            .execute(move |_| indexer.run(|indexer| indexer.switch_branch(reverted, applied)))
+            .await
+            .expect("dbs pool disconnected");
+        self.end_indexing(res)
+    }
+    fn block_indexer(&self) -> BlockIndexer {
+        BlockIndexer {
+            block_verification: self.block_verification,
+            chain: IndexedChain {
+                current: self.current,
+                currency_params: self.currency_params,
+                inconsistent_chain: self.inconsistent_chain.clone(),
+                uds_count: self.uds_count,
+            },
+            events_bus: self.events_bus.clone(),
+            metrics: self.metrics.clone(),
+            writer: self.block_writer(),
+        }
+    }
+    /// Get back the chain state from the block indexer, it is updated even if indexing failed
+    fn end_indexing(&mut self, (chain, res): (IndexedChain, KvResult<()>)) -> KvResult<()> {
+        self.current = chain.current;
+        self.currency_params = chain.currency_params;
+        self.inconsistent_chain = chain.inconsistent_chain;
+        self.uds_count = chain.uds_count;
+        res
+    }
+    fn block_writer(&self) -> BlockWriter {
+        BlockWriter {
+            bc_db: self.bc_db.clone(),
+            conf: Arc::new(self.conf.clone()),
+            dbs_pool: self.dbs_pool.handler().clone(),
+            global_sender: self.global_sender.clone(),
+            profile_path_opt: self.profile_path_opt.clone(),
+            server_dbs: self.dbs_pool.server_dbs().clone(),
+            #[cfg(test)]
+            fail_writes: self.fail_block_writes,
+        }
+    }
+}
 
-        // Get currency parameters from genesis block
-        if let Some(currency_params) = blocks[0].currency_parameters() {
-            self.currency_params = currency_params;
+/// Chain state updated by block indexing
+struct IndexedChain {
+    current: Option<BlockMetaV2>,
+    currency_params: CurrencyParameters,
+    /// Set when a branch switch could not be rolled back
+    inconsistent_chain: Option<String>,
+    /// Number of dividends created in the current chain
+    uds_count: u64,
+}
+
+/// Index blocks in the block writer pool. Indexing steps launch jobs in the main databases
+/// pool and wait for them, so they must not run in the main pool.
+struct BlockIndexer {
+    block_verification: BlockVerificationLevel,
+    chain: IndexedChain,
+    events_bus: events::EventsBus,
+    metrics: metrics::Metrics,
+    writer: BlockWriter,
+}
+
+impl BlockIndexer {
+    /// Run an indexing operation, unless a previous branch switch could not be rolled back
+    fn run<F>(mut self, f: F) -> (IndexedChain, KvResult<()>)
+    where
+        F: FnOnce(&mut BlockIndexer) -> anyhow::Result<()>,
+    {
+        let res = if let Some(ref reason) = self.chain.inconsistent_chain {
+            Err(KvError::DbCorrupted(reason.clone()))
+        } else {
+            f(&mut self).map_err(|e| match e.downcast::<KvError>() {
+                Ok(kv_error) => kv_error,
+                Err(e) => KvError::Custom(e.into()),
+            })
+        };
+        (self.chain, res)
+    }
+    fn apply_block(&mut self, block: Arc<DubpBlockV10>) -> anyhow::Result<()> {
+        self.prepare_apply_chunk(std::slice::from_ref(&block))?;
+        self.write_block(Arc::clone(&block))?;
+        self.writer
+            .apply_block_modules(block, self.chain.currency_params)?;
+        Ok(())
+    }
+    fn apply_chunk(&mut self, blocks: Arc<[DubpBlockV10]>) -> anyhow::Result<()> {
+        self.prepare_apply_chunk(&blocks)?;
+        self.chain.current = Some(
+            self.writer
+                .write_chunk(Arc::clone(&blocks), self.chain.current)?,
+        );
+        for block in blocks.iter() {
+            self.notify_block_applied(block);
         }
+        self.writer
+            .apply_chunk_modules(blocks, self.chain.currency_params)?;
+        Ok(())
+    }
+    fn revert_block(&mut self, block: Arc<DubpBlockV10>) -> anyhow::Result<()> {
+        self.unwrite_block(Arc::clone(&block))?;
+        self.writer
+            .revert_block_modules(block, self.chain.currency_params)?;
+        Ok(())
+    }
+    fn switch_branch(
+        &mut self,
+        reverted: Vec<Arc<DubpBlockV10>>,
+        applied: Vec<Arc<DubpBlockV10>>,
+    ) -> anyhow::Result<()> {
+        self.check_branch(&reverted, &applied)?;
+
+        let currency_params = self.chain.currency_params;
+        // Blocks are counted as soon as the blockchain database is written, so that a block
+        // whose modules failed is also rolled back.
+        let mut reverted_count = 0;
+        let mut applied_count = 0;
+        let mut switch = || {
+            for block in &reverted {
+                self.unwrite_block(Arc::clone(block))?;
+                reverted_count += 1;
+                self.writer
+                    .revert_block_modules(Arc::clone(block), self.chain.currency_params)?;
+            }
+            for block in &applied {
+                self.prepare_apply_chunk(std::slice::from_ref(block))?;
+                self.write_block(Arc::clone(block))?;
+                applied_count += 1;
+                self.writer
+                    .apply_block_modules(Arc::clone(block), self.chain.currency_params)?;
+            }
+            Ok::<(), anyhow::Error>(())
+        };
 
-        self.current = Some(duniter_core::dbs_write_ops::apply_block::apply_chunk(
+        if let Err(e) = switch() {
+            log::error!(
+                "Fail to switch branch: {}. Rollback to the original chain...",
+                e
+            );
+            if let Err(rollback_err) = self.rollback_branch(
+                &reverted[..reverted_count],
+                &applied[..applied_count],
+                currency_params,
+            ) {
+                // The chain is neither the original one nor the new one
+                let reason = format!("Fail to rollback branch switch ({:#}): {}", e, rollback_err);
+                log::error!("{}", reason);
+                self.chain.inconsistent_chain = Some(reason.clone());
+                return Err(KvError::DbCorrupted(reason).into());
+            }
+            Err(e.context("Fail to switch branch"))
+        } else {
+            Ok(())
+        }
+    }
+    /// Restore the original chain after a failed branch switch. Rolled back blocks are not
+    /// verified again, they were part of the original chain.
+    fn rollback_branch(
+        &mut self,
+        reverted: &[Arc<DubpBlockV10>],
+        applied: &[Arc<DubpBlockV10>],
+        currency_params: CurrencyParameters,
+    ) -> KvResult<()> {
+        for block in applied.iter().rev() {
+            self.unwrite_block(Arc::clone(block))?;
+            self.writer
+                .revert_block_modules(Arc::clone(block), self.chain.currency_params)?;
+        }
+        self.chain.currency_params = currency_params;
+        for block in reverted.iter().rev() {
+            self.write_block(Arc::clone(block))?;
+            self.writer
+                .apply_block_modules(Arc::clone(block), currency_params)?;
+        }
+        Ok(())
+    }
+    /// Write the block in the blockchain database, without applying it to modules
+    fn write_block(&mut self, block: Arc<DubpBlockV10>) -> KvResult<()> {
+        self.chain.current = Some(
+            self.writer
+                .write_block(Arc::clone(&block), self.chain.current)?,
+        );
+        self.notify_block_applied(&block);
+        Ok(())
+    }
+    /// Remove the block from the blockchain database, without reverting it in modules
+    fn unwrite_block(&mut self, block: Arc<DubpBlockV10>) -> KvResult<()> {
+        self.chain.current = self.writer.unwrite_block(Arc::clone(&block))?;
+        self.notify_block_reverted(&block);
+        Ok(())
+    }
+    /// Verify the blocks and get currency parameters from the genesis block
+    fn prepare_apply_chunk(&mut self, blocks: &[DubpBlockV10]) -> anyhow::Result<()> {
+        let currency_params = blocks[0]
+            .currency_parameters()
+            .unwrap_or(self.chain.currency_params);
+        crate::block_verification::verify_blocks(
+            &self.writer.bc_db,
+            blocks,
+            self.chain.current,
+            currency_params,
+            self.block_verification,
+        )?;
+        self.chain.currency_params = currency_params;
+        Ok(())
+    }
+    /// Must be called once the block is written in the blockchain database
+    fn notify_block_applied(&mut self, block: &DubpBlockV10) {
+        if block.dividend().is_some() {
+            self.chain.uds_count += 1;
+        }
+        self.metrics.block_applied();
+        self.events_bus
+            .publish(ServerEvent::BlockApplied(block.blockstamp()));
+    }
+    /// Must be called once the block is removed from the blockchain database
+    fn notify_block_reverted(&mut self, block: &DubpBlockV10) {
+        if block.dividend().is_some() {
+            self.chain.uds_count = self.chain.uds_count.saturating_sub(1);
+        }
+        self.metrics.block_reverted();
+        self.events_bus
+            .publish(ServerEvent::BlockReverted(block.blockstamp()));
+    }
+    fn current_blockstamp(&self) -> Option<Blockstamp> {
+        self.chain.current.map(|current| Blockstamp {
+            number: BlockNumber(current.number),
+            hash: BlockHash(current.hash),
+        })
+    }
+    fn check_branch(
+        &self,
+        reverted: &[Arc<DubpBlockV10>],
+        applied: &[Arc<DubpBlockV10>],
+    ) -> anyhow::Result<()> {
+        // Reverted blocks must be the top of the current chain, from the current block
+        let mut tip = self.current_blockstamp();
+        for block in reverted {
+            if Some(block.blockstamp()) != tip {
+                return Err(anyhow::anyhow!(
+                    "Reverted block {} is not the top of the current chain",
+                    block.blockstamp()
+                ));
+            }
+            tip = if block.number().0 == 0 {
+                None
+            } else {
+                Some(block.previous_blockstamp())
+            };
+        }
+        // Applied blocks must follow each other from the fork point
+        for block in applied {
+            let joins = match tip {
+                Some(tip) => block.number().0 > 0 && block.previous_blockstamp() == tip,
+                None => block.number().0 == 0,
+            };
+            if !joins {
+                return Err(anyhow::anyhow!(
+                    "Applied block {} does not join the current chain",
+                    block.blockstamp()
+                ));
+            }
+            tip = Some(block.blockstamp());
+        }
+        Ok(())
+    }
+}
+
+/// Owns everything needed to write blocks, so that writes can be moved to another thread.
+#[derive(Clone)]
+struct BlockWriter {
+    bc_db: BcV2Db<FileBackend>,
+    conf: Arc<DuniterCoreConf>,
+    dbs_pool: fast_threadpool::ThreadPoolSyncHandler<SharedDbs<FileBackend>>,
+    global_sender: flume::Sender<GlobalBackGroundTaskMsg>,
+    profile_path_opt: Option<PathBuf>,
+    server_dbs: dbs_pool::ServerDbs,
+    #[cfg(test)]
+    fail_writes: bool,
+}
+
+impl BlockWriter {
+    fn write_block(
+        &self,
+        block: Arc<DubpBlockV10>,
+        current: Option<BlockMetaV2>,
+    ) -> KvResult<BlockMetaV2> {
+        #[cfg(test)]
+        if self.fail_writes {
+            return Err(KvError::Custom("injected write failure".into()));
+        }
+        let new_current = duniter_core::dbs_write_ops::apply_block::apply_block(
             &self.bc_db,
-            self.current,
+            Arc::clone(&block),
+            current,
             &self.dbs_pool,
-            blocks.clone(),
-            Some(&self.global_sender),
-        )?);
-        apply_chunk_of_blocks_modules(
-            blocks,
-            Arc::new(self.conf.clone()),
-            self.currency_params,
+            &self.global_sender,
+            false,
+        )?;
+        crate::ud_history::apply_block(&self.server_dbs.memberships_db, &block)?;
+        crate::rules_index::apply_block(&self.server_dbs.rules_index_db, &block)?;
+        crate::wot_mempools::remove_written_docs(&self.server_dbs.wot_mp_db, &block)?;
+        Ok(new_current)
+    }
+    fn write_chunk(
+        &self,
+        blocks: Arc<[DubpBlockV10]>,
+        current: Option<BlockMetaV2>,
+    ) -> KvResult<BlockMetaV2> {
+        let new_current = duniter_core::dbs_write_ops::apply_block::apply_chunk(
+            &self.bc_db,
+            current,
             &self.dbs_pool,
-            self.profile_path_opt.clone(),
-        )
+            Arc::clone(&blocks),
+            Some(&self.global_sender),
+        )?;
+        for block in blocks.iter() {
+            crate::ud_history::apply_block(&self.server_dbs.memberships_db, block)?;
+            crate::rules_index::apply_block(&self.server_dbs.rules_index_db, block)?;
+            crate::wot_mempools::remove_written_docs(&self.server_dbs.wot_mp_db, block)?;
+        }
+        Ok(new_current)
     }
-    pub fn revert_block(&mut self, block: DubpBlockV10Stringified) -> KvResult<()> {
-        let block = Arc::new(
-            DubpBlockV10::from_string_object(&block).map_err(|e| KvError::DeserError(e.into()))?,
-        );
+    /// Remove the block from the blockchain and put back its transactions in the mempool
+    fn unwrite_block(&self, block: Arc<DubpBlockV10>) -> KvResult<Option<BlockMetaV2>> {
         let block_arc_clone = Arc::clone(&block);
         let txs_mp_job_handle = self
             .dbs_pool
@@ -87,14 +462,200 @@ impl DuniterServer {
                 )
             })
             .expect("dbs pool disconnected");
-        self.current = duniter_core::dbs_write_ops::bc::revert_block(&self.bc_db, &block)?;
+        let new_current = duniter_core::dbs_write_ops::bc::revert_block(&self.bc_db, &block)?;
+        crate::ud_history::revert_block(&self.server_dbs.memberships_db, &block)?;
+        crate::rules_index::revert_block(&self.server_dbs.rules_index_db, &block)?;
         txs_mp_job_handle.join().expect("dbs pool disconnected")?;
-        revert_block_modules(
+        Ok(new_current)
+    }
+    fn apply_block_modules(
+        &self,
+        block: Arc<DubpBlockV10>,
+        currency_params: CurrencyParameters,
+    ) -> KvResult<()> {
+        crate::plugged_modules::apply_block(
+            block,
+            Arc::clone(&self.conf),
+            currency_params,
+            &self.dbs_pool,
+            self.profile_path_opt.clone(),
+        )
+    }
+    fn apply_chunk_modules(
+        &self,
+        blocks: Arc<[DubpBlockV10]>,
+        currency_params: CurrencyParameters,
+    ) -> KvResult<()> {
+        crate::plugged_modules::apply_chunk_of_blocks(
+            blocks,
+            Arc::clone(&self.conf),
+            currency_params,
+            &self.dbs_pool,
+            self.profile_path_opt.clone(),
+        )
+    }
+    fn revert_block_modules(
+        &self,
+        block: Arc<DubpBlockV10>,
+        currency_params: CurrencyParameters,
+    ) -> KvResult<()> {
+        crate::plugged_modules::revert_block(
             block,
-            Arc::new(self.conf.clone()),
-            self.currency_params,
+            Arc::clone(&self.conf),
+            currency_params,
             &self.dbs_pool,
             None,
         )
     }
 }
+
+fn parse_blocks(blocks: Vec<DubpBlockV10Stringified>) -> KvResult<Vec<DubpBlockV10>> {
+    blocks
+        .into_iter()
+        .map(|block| DubpBlockV10::from_string_object(&block))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| KvError::DeserError(e.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{block_hash, branch};
+
+    fn current_hash(server: &DuniterServer) -> Option<Hash> {
+        server.current.map(|current| current.hash)
+    }
+
+    #[test]
+    fn test_switch_branch() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let main_branch = branch(0, 0..3, None);
+        server.apply_chunk_of_blocks(main_branch.clone())?;
+
+        // Replace blocks #1 and #2 by a longer fork
+        let fork = branch(1, 1..4, Some(block_hash(0, 0)));
+        server.switch_branch(vec![main_branch[2].clone(), main_branch[1].clone()], fork)?;
+        assert_eq!(current_hash(&server), Some(block_hash(1, 3)));
+        assert_eq!(
+            server.get_block(BlockNumber(1))?.map(|block| block.hash),
+            Some(block_hash(1, 1))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_switch_branch_async() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let main_branch = branch(0, 0..3, None);
+
+        duniter_core::global::get_async_runtime().block_on(async {
+            server
+                .apply_chunk_of_blocks_async(main_branch.clone())
+                .await?;
+            let fork = branch(1, 1..4, Some(block_hash(0, 0)));
+            server
+                .switch_branch_async(vec![main_branch[2].clone(), main_branch[1].clone()], fork)
+                .await?;
+            assert_eq!(current_hash(&server), Some(block_hash(1, 3)));
+
+            server
+                .revert_block_async(branch(1, 3..4, Some(block_hash(1, 2))).remove(0))
+                .await?;
+            assert_eq!(current_hash(&server), Some(block_hash(1, 2)));
+
+            Ok::<(), anyhow::Error>(())
+        })
+    }
+
+    #[test]
+    fn test_switch_branch_rollback() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let main_branch = branch(0, 0..3, None);
+        server.apply_chunk_of_blocks(main_branch.clone())?;
+
+        // Hashs of test blocks are not valid, so the fork is rejected once the main branch
+        // blocks have been reverted.
+        server.block_verification = BlockVerificationLevel::Full;
+        let fork = branch(1, 1..4, Some(block_hash(0, 0)));
+        assert!(server
+            .switch_branch(vec![main_branch[2].clone(), main_branch[1].clone()], fork)
+            .is_err());
+
+        assert_eq!(current_hash(&server), Some(block_hash(0, 2)));
+        for number in 0..3 {
+            assert_eq!(
+                server
+                    .get_block(BlockNumber(number as u32))?
+                    .map(|block| block.hash),
+                Some(block_hash(0, number))
+            );
+        }
+        assert_eq!(server.get_block(BlockNumber(3))?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_switch_branch_failed_rollback() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let main_branch = branch(0, 0..3, None);
+        server.apply_chunk_of_blocks(main_branch.clone())?;
+
+        // Writes fail once the main branch blocks have been reverted, so they can not be
+        // written back.
+        server.fail_block_writes = true;
+        let fork = branch(1, 1..4, Some(block_hash(0, 0)));
+        assert!(matches!(
+            server.switch_branch(vec![main_branch[2].clone(), main_branch[1].clone()], fork),
+            Err(KvError::DbCorrupted(_))
+        ));
+        assert_eq!(current_hash(&server), Some(block_hash(0, 0)));
+
+        // Blocks are no longer indexed
+        server.fail_block_writes = false;
+        assert!(matches!(
+            server.apply_block(main_branch[1].clone()),
+            Err(KvError::DbCorrupted(_))
+        ));
+        assert_eq!(current_hash(&server), Some(block_hash(0, 0)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_branch() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let main_branch = branch(0, 0..3, None);
+        server.apply_chunk_of_blocks(main_branch.clone())?;
+        let parse = |blocks: Vec<DubpBlockV10Stringified>| -> KvResult<Vec<Arc<DubpBlockV10>>> {
+            Ok(parse_blocks(blocks)?.into_iter().map(Arc::new).collect())
+        };
+
+        let reverted = parse(vec![main_branch[2].clone(), main_branch[1].clone()])?;
+        let fork = parse(branch(1, 1..3, Some(block_hash(0, 0))))?;
+        let indexer = server.block_indexer();
+        assert!(indexer.check_branch(&reverted, &fork).is_ok());
+
+        // Reverted blocks must start from the current block
+        assert!(indexer.check_branch(&reverted[1..], &fork).is_err());
+        // The fork must follow the last reverted block
+        let orphan_fork = parse(branch(1, 2..3, Some(block_hash(1, 1))))?;
+        assert!(indexer.check_branch(&reverted, &orphan_fork).is_err());
+        // Applied blocks must follow each other
+        assert!(indexer
+            .check_branch(&reverted, &[Arc::clone(&fork[1]), Arc::clone(&fork[0])])
+            .is_err());
+
+        // Rejected branches are not applied
+        assert!(server
+            .switch_branch(
+                vec![main_branch[1].clone()],
+                branch(1, 1..3, Some(block_hash(0, 0)))
+            )
+            .is_err());
+        assert_eq!(current_hash(&server), Some(block_hash(0, 2)));
+
+        Ok(())
+    }
+}
@@ -14,20 +14,58 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::*;
+use std::time::{Duration, Instant};
+
+const DEFAULT_SELF_ENDPOINTS_TIMEOUT: Duration = Duration::from_secs(10);
+const GLOBAL_TASK_REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Duniter modules did not publish their endpoints before the deadline.
+#[derive(Clone, Copy, Debug)]
+pub struct SelfEndpointsNotReady;
+
+impl std::fmt::Display for SelfEndpointsNotReady {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "self endpoints not ready")
+    }
+}
+
+impl std::error::Error for SelfEndpointsNotReady {}
+
+impl SelfEndpointsNotReady {
+    /// Stable error code, intended to be used by clients
+    pub fn code(self) -> &'static str {
+        "SELF_ENDPOINTS_NOT_READY"
+    }
+}
 
 impl DuniterServer {
     pub fn get_self_endpoints(&self) -> anyhow::Result<Vec<Endpoint>> {
+        self.get_self_endpoints_timeout(DEFAULT_SELF_ENDPOINTS_TIMEOUT)
+    }
+    /// Get self endpoints, fail with `SelfEndpointsNotReady` if they are not published
+    /// before `timeout`. A zero timeout only checks if they are already published.
+    pub fn get_self_endpoints_timeout(&self, timeout: Duration) -> anyhow::Result<Vec<Endpoint>> {
         // Do not get rust endpoints on js tests or when gva is disabled
         if std::env::var_os("DUNITER_JS_TESTS") != Some("yes".into()) {
+            let deadline = Instant::now() + timeout;
             let (sender, recv) = flume::bounded(1);
             loop {
                 self.global_sender
                     .send(GlobalBackGroundTaskMsg::GetSelfEndpoints(sender.clone()))?;
-                if let Some(self_endpoints) = recv.recv()? {
+                // The global task answers immediately, even if the deadline is already reached
+                let reply_deadline =
+                    std::cmp::max(deadline, Instant::now() + GLOBAL_TASK_REPLY_TIMEOUT);
+                if let Some(self_endpoints) = recv
+                    .recv_deadline(reply_deadline)
+                    .context("global task does not answer")?
+                {
                     break Ok(self_endpoints);
-                } else {
-                    std::thread::sleep(std::time::Duration::from_millis(100));
                 }
+                let now = Instant::now();
+                if now >= deadline {
+                    break Err(SelfEndpointsNotReady.into());
+                }
+                std::thread::sleep(std::cmp::min(deadline - now, Duration::from_millis(100)));
             }
         } else {
             Ok(vec![])
@@ -51,18 +89,51 @@ impl DuniterServer {
             })
             .expect("dbs pool disconnected")
     }
+    pub async fn receive_new_heads_async(
+        &self,
+        heads: Vec<(
+            duniter_core::dbs::DunpNodeIdV1Db,
+            duniter_core::dbs::DunpHeadDbV1,
+        )>,
+    ) -> KvResult<()> {
+        self.dbs_pool_async
+            .execute(move |dbs| {
+                for (dunp_node_id, dunp_head) in heads {
+                    dbs.dunp_db
+                        .heads_old_write()
+                        .upsert(dunp_node_id, dunp_head)?
+                }
+                Ok::<(), KvError>(())
+            })
+            .await
+            .expect("dbs pool disconnected")
+    }
     pub fn remove_all_peers(&self) -> KvResult<()> {
         use duniter_core::dbs::databases::network_v1::NetworkV1DbWritable as _;
         self.dbs_pool
             .execute(move |dbs| dbs.dunp_db.peers_old_write().clear())
             .expect("dbs pool disconnected")
     }
+    pub async fn remove_all_peers_async(&self) -> KvResult<()> {
+        use duniter_core::dbs::databases::network_v1::NetworkV1DbWritable as _;
+        self.dbs_pool_async
+            .execute(move |dbs| dbs.dunp_db.peers_old_write().clear())
+            .await
+            .expect("dbs pool disconnected")
+    }
     pub fn remove_peer_by_pubkey(&self, pubkey: PublicKey) -> KvResult<()> {
         use duniter_core::dbs::databases::network_v1::NetworkV1DbWritable as _;
         self.dbs_pool
             .execute(move |dbs| dbs.dunp_db.peers_old_write().remove(PubKeyKeyV2(pubkey)))
             .expect("dbs pool disconnected")
     }
+    pub async fn remove_peer_by_pubkey_async(&self, pubkey: PublicKey) -> KvResult<()> {
+        use duniter_core::dbs::databases::network_v1::NetworkV1DbWritable as _;
+        self.dbs_pool_async
+            .execute(move |dbs| dbs.dunp_db.peers_old_write().remove(PubKeyKeyV2(pubkey)))
+            .await
+            .expect("dbs pool disconnected")
+    }
     pub fn save_peer(&self, new_peer_card: PeerCardDbV1) -> anyhow::Result<()> {
         let pubkey = new_peer_card.peer.pubkey;
         use duniter_core::dbs::databases::network_v1::NetworkV1DbWritable as _;
@@ -75,6 +146,19 @@ impl DuniterServer {
             .expect("dbs pool disconnected")
             .map_err(|e| e.into())
     }
+    pub async fn save_peer_async(&self, new_peer_card: PeerCardDbV1) -> anyhow::Result<()> {
+        let pubkey = new_peer_card.peer.pubkey;
+        use duniter_core::dbs::databases::network_v1::NetworkV1DbWritable as _;
+        self.dbs_pool_async
+            .execute(move |dbs| {
+                dbs.dunp_db
+                    .peers_old_write()
+                    .upsert(PubKeyKeyV2(pubkey), new_peer_card)
+            })
+            .await
+            .expect("dbs pool disconnected")
+            .map_err(|e| e.into())
+    }
     pub fn update_self_peer(&self, new_peer_card: PeerCardDbV1) {
         self.global_sender
             .send(GlobalBackGroundTaskMsg::SetSelfPeerOld(new_peer_card))
@@ -153,4 +237,34 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_save_peer_async() -> anyhow::Result<()> {
+        use duniter_core::dbs::databases::network_v1::NetworkV1DbReadable as _;
+        let server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let dbs = server.get_shared_dbs();
+
+        let peer_db = PeerCardDbV1 {
+            peer: PeerV10 {
+                currency: "test".to_owned(),
+                pubkey: PublicKey::from_base58("82NdD9eEbXSjRJXeJdqf56xkpu6taTfTeEqtAtmtbyXY")?,
+                blockstamp: Blockstamp::from_str("379922-0000001D97770A8203062F9E618F29FFAA2EF4218649FCE6DD13E01C3932E943")?,
+                endpoints: duniter_core::dbs::smallvec::SmallVec::new(),
+                signature: Signature::from_base64("KBaoJuKIfkWJO015BTegUN8l81VYPfleVUfQUwPRPAAF1oB398hDb1bX/QUFe+3CKFz57aGT8bB745mz90x5Ag==")?,
+            },
+            status: true,
+            member: false,
+        };
+        let pubkey = peer_db.peer.pubkey;
+
+        duniter_core::global::get_async_runtime().block_on(async {
+            server.save_peer_async(peer_db.clone()).await?;
+            assert_eq!(dbs.dunp_db.peers_old().count()?, 1);
+
+            server.remove_peer_by_pubkey_async(pubkey).await?;
+            assert_eq!(dbs.dunp_db.peers_old().get(&PubKeyKeyV2(pubkey))?, None);
+
+            Ok::<(), anyhow::Error>(())
+        })
+    }
 }
@@ -17,13 +17,49 @@ use crate::*;
 
 impl DuniterServer {
     pub fn get_transactions_history(&self, pubkey: PublicKey) -> KvResult<TxsHistoryForBma> {
-        get_transactions_history_for_bma(&self.dbs_pool, self.profile_path_opt.as_deref(), pubkey)
+        crate::plugged_modules::transactions_history_for_bma(
+            self.dbs_pool.handler(),
+            self.profile_path_opt.as_deref(),
+            pubkey,
+        )
+    }
+
+    pub async fn get_transactions_history_async(
+        &self,
+        pubkey: PublicKey,
+    ) -> anyhow::Result<TxsHistoryForBma> {
+        let dbs_pool = self.dbs_pool.handler().clone();
+        let profile_path_opt = self.profile_path_opt.clone();
+        Ok(tokio::task::spawn_blocking(move || {
+            crate::plugged_modules::transactions_history_for_bma(
+                &dbs_pool,
+                profile_path_opt.as_deref(),
+                pubkey,
+            )
+        })
+        .await??)
     }
 
     pub fn get_tx_by_hash(
         &self,
         hash: Hash,
     ) -> KvResult<Option<(TransactionDocumentV10, Option<BlockNumber>)>> {
-        get_tx_by_hash(&self.dbs_pool, hash, self.profile_path_opt.as_deref())
+        crate::plugged_modules::tx_by_hash(
+            self.dbs_pool.handler(),
+            hash,
+            self.profile_path_opt.as_deref(),
+        )
+    }
+
+    pub async fn get_tx_by_hash_async(
+        &self,
+        hash: Hash,
+    ) -> anyhow::Result<Option<(TransactionDocumentV10, Option<BlockNumber>)>> {
+        let dbs_pool = self.dbs_pool.handler().clone();
+        let profile_path_opt = self.profile_path_opt.clone();
+        Ok(tokio::task::spawn_blocking(move || {
+            crate::plugged_modules::tx_by_hash(&dbs_pool, hash, profile_path_opt.as_deref())
+        })
+        .await??)
     }
 }
@@ -13,26 +13,96 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::tx_status::{record_dropped_txs, TxDropReason};
 use crate::*;
+use duniter_core::dbs::databases::txs_mp_v2::TxsMpV2Db;
+use duniter_core::dbs::{HashKeyV2, TimestampKeyV1};
+use duniter_core::documents::transaction::TransactionDocumentTrait;
+use std::sync::PoisonError;
 
 impl DuniterServer {
+    /// Check if a new transaction can enter the mempool, the transaction is not added and no
+    /// pending transaction is evicted. A full mempool does not reject the transaction if the
+    /// mempool eviction policy can make room for it.
     pub fn accept_new_tx(
         &self,
         tx: TransactionDocumentV10,
         server_pubkey: PublicKey,
-    ) -> KvResult<bool> {
-        let txs_mempool = self.txs_mempool;
-        match self
+    ) -> KvResult<Result<(), TxRejection>> {
+        let new_tx_ctx = self.new_tx_ctx();
+        self.dbs_pool
+            .execute(move |dbs| {
+                check_new_tx(dbs, &new_tx_ctx, server_pubkey, &tx).map(|res| res.map(|_| ()))
+            })
+            .expect("dbs pool disconnected")
+    }
+    pub async fn accept_new_tx_async(
+        &self,
+        tx: TransactionDocumentV10,
+        server_pubkey: PublicKey,
+    ) -> KvResult<Result<(), TxRejection>> {
+        let new_tx_ctx = self.new_tx_ctx();
+        self.dbs_pool_async
+            .execute(move |dbs| {
+                check_new_tx(dbs, &new_tx_ctx, server_pubkey, &tx).map(|res| res.map(|_| ()))
+            })
+            .await
+            .expect("dbs pool disconnected")
+    }
+    /// Add a new transaction to the mempool if it is accepted (see `accept_new_tx`).
+    ///
+    /// If needed, a pending transaction is evicted according to the mempool eviction policy,
+    /// with the pending transactions depending on it.
+    pub fn add_pending_tx(
+        &self,
+        tx: TransactionDocumentV10,
+        server_pubkey: PublicKey,
+    ) -> KvResult<Result<(), TxRejection>> {
+        let new_tx_ctx = self.new_tx_ctx();
+        let (res, evicted) = self
+            .dbs_pool
+            .execute(move |dbs| add_pending_tx(dbs, &new_tx_ctx, server_pubkey, tx, None))
+            .expect("dbs pool disconnected")?;
+        for hash in evicted {
+            self.notify_tx_evicted(hash);
+        }
+        Ok(res)
+    }
+    pub async fn add_pending_tx_async(
+        &self,
+        tx: TransactionDocumentV10,
+        server_pubkey: PublicKey,
+    ) -> KvResult<Result<(), TxRejection>> {
+        let new_tx_ctx = self.new_tx_ctx();
+        let (res, evicted) = self
+            .dbs_pool_async
+            .execute(move |dbs| add_pending_tx(dbs, &new_tx_ctx, server_pubkey, tx, None))
+            .await
+            .expect("dbs pool disconnected")?;
+        for hash in evicted {
+            self.notify_tx_evicted(hash);
+        }
+        Ok(res)
+    }
+    /// Add a new transaction to the mempool like `add_pending_tx`, but the transaction keeps
+    /// the given received time, so that it expires as if it had stayed in this mempool.
+    pub(crate) fn add_pending_tx_received_at(
+        &self,
+        tx: TransactionDocumentV10,
+        server_pubkey: PublicKey,
+        received_time: i64,
+    ) -> KvResult<Result<(), TxRejection>> {
+        let new_tx_ctx = self.new_tx_ctx();
+        let (res, evicted) = self
             .dbs_pool
             .execute(move |dbs| {
-                txs_mempool.accept_new_tx(&dbs.bc_db_ro, server_pubkey, tx, &dbs.txs_mp_db)
+                add_pending_tx(dbs, &new_tx_ctx, server_pubkey, tx, Some(received_time))
             })
-            .expect("dbs pool discorrected")
-        {
-            Ok(()) => Ok(true),
-            Err(TxMpError::Db(e)) => Err(e),
-            Err(_) => Ok(false),
+            .expect("dbs pool disconnected")?;
+        for hash in evicted {
+            self.notify_tx_evicted(hash);
         }
+        Ok(res)
     }
     pub fn add_pending_tx_force(&self, tx: TransactionDocumentV10) -> KvResult<()> {
         let txs_mempool = self.txs_mempool;
@@ -40,11 +110,25 @@ impl DuniterServer {
             .execute(move |dbs| txs_mempool.add_pending_tx_force(&dbs.txs_mp_db, &tx))
             .expect("dbs pool disconnected")
     }
+    pub async fn add_pending_tx_force_async(&self, tx: TransactionDocumentV10) -> KvResult<()> {
+        let txs_mempool = self.txs_mempool;
+        self.dbs_pool_async
+            .execute(move |dbs| txs_mempool.add_pending_tx_force(&dbs.txs_mp_db, &tx))
+            .await
+            .expect("dbs pool disconnected")
+    }
     pub fn get_mempool_txs_free_rooms(&self) -> KvResult<usize> {
         let txs_mempool = self.txs_mempool;
         self.dbs_pool
             .execute(move |dbs| txs_mempool.get_free_rooms(&dbs.txs_mp_db))
-            .expect("dbs pool discorrected")
+            .expect("dbs pool disconnected")
+    }
+    pub async fn get_mempool_txs_free_rooms_async(&self) -> KvResult<usize> {
+        let txs_mempool = self.txs_mempool;
+        self.dbs_pool_async
+            .execute(move |dbs| txs_mempool.get_free_rooms(&dbs.txs_mp_db))
+            .await
+            .expect("dbs pool disconnected")
     }
     pub fn get_new_pending_txs(&self) -> KvResult<Vec<TransactionDocumentV10>> {
         let mut new_pending_txs = BTreeMap::new();
@@ -64,21 +148,127 @@ impl DuniterServer {
         }
         Ok(new_pending_txs.into_iter().map(|(_k, v)| v).collect())
     }
+    /// Get pending transactions usable at `blockchain_time`, sorted by received time
     pub fn get_pending_txs(
         &self,
-        _blockchain_time: i64,
+        blockchain_time: i64,
+        min_version: usize,
+    ) -> KvResult<Vec<PendingTxDbV2>> {
+        self.dbs_pool
+            .execute(move |dbs| {
+                crate::pending_txs::get_pending_txs(dbs, blockchain_time, min_version)
+            })
+            .expect("dbs pool disconnected")
+    }
+    pub async fn get_pending_txs_async(
+        &self,
+        blockchain_time: i64,
         min_version: usize,
     ) -> KvResult<Vec<PendingTxDbV2>> {
+        self.dbs_pool_async
+            .execute(move |dbs| {
+                crate::pending_txs::get_pending_txs(dbs, blockchain_time, min_version)
+            })
+            .await
+            .expect("dbs pool disconnected")
+    }
+    /// Get at most `page_size` pending transactions usable at `blockchain_time_opt`, received
+    /// after `cursor_opt`, sorted by received time.
+    pub fn get_pending_txs_page(
+        &self,
+        blockchain_time_opt: Option<i64>,
+        min_version: usize,
+        cursor_opt: Option<PendingTxsCursor>,
+        page_size: usize,
+    ) -> KvResult<PendingTxsPage> {
+        self.dbs_pool
+            .execute(move |dbs| {
+                crate::pending_txs::get_pending_txs_page(
+                    &dbs.bc_db_ro,
+                    &dbs.txs_mp_db,
+                    blockchain_time_opt,
+                    min_version,
+                    cursor_opt,
+                    page_size,
+                )
+            })
+            .expect("dbs pool disconnected")
+    }
+    pub async fn get_pending_txs_page_async(
+        &self,
+        blockchain_time_opt: Option<i64>,
+        min_version: usize,
+        cursor_opt: Option<PendingTxsCursor>,
+        page_size: usize,
+    ) -> KvResult<PendingTxsPage> {
+        self.dbs_pool_async
+            .execute(move |dbs| {
+                crate::pending_txs::get_pending_txs_page(
+                    &dbs.bc_db_ro,
+                    &dbs.txs_mp_db,
+                    blockchain_time_opt,
+                    min_version,
+                    cursor_opt,
+                    page_size,
+                )
+            })
+            .await
+            .expect("dbs pool disconnected")
+    }
+    /// Get the sources spent by several pending transactions.
+    pub fn get_mempool_conflicts(&self) -> KvResult<Vec<MempoolConflict>> {
+        self.dbs_pool
+            .execute(move |dbs| {
+                crate::mempool_conflicts::get_conflicts(&dbs.txs_mp_db, &dbs.server.txs_mp_index)
+            })
+            .expect("dbs pool disconnected")
+    }
+    pub async fn get_mempool_conflicts_async(&self) -> KvResult<Vec<MempoolConflict>> {
+        self.dbs_pool_async
+            .execute(move |dbs| {
+                crate::mempool_conflicts::get_conflicts(&dbs.txs_mp_db, &dbs.server.txs_mp_index)
+            })
+            .await
+            .expect("dbs pool disconnected")
+    }
+    /// Select the pending transactions to include in the next block: their sources are
+    /// available, they do not conflict, and their total size does not exceed `max_size`
+    /// (in lines of their compact format). Each transaction comes after its parents.
+    pub fn select_txs_for_next_block(
+        &self,
+        max_size: usize,
+        median_time: u64,
+        min_version: usize,
+    ) -> KvResult<Vec<TransactionDocumentV10>> {
         self.dbs_pool
             .execute(move |dbs| {
-                dbs.txs_mp_db.txs().iter(.., |it| {
-                    it.values()
-                        .filter_ok(|tx| tx.doc.version() >= min_version)
-                        .collect()
-                })
+                crate::tx_selection::select_txs_for_next_block(
+                    dbs,
+                    max_size,
+                    median_time,
+                    min_version,
+                )
             })
             .expect("dbs pool disconnected")
     }
+    pub async fn select_txs_for_next_block_async(
+        &self,
+        max_size: usize,
+        median_time: u64,
+        min_version: usize,
+    ) -> KvResult<Vec<TransactionDocumentV10>> {
+        self.dbs_pool_async
+            .execute(move |dbs| {
+                crate::tx_selection::select_txs_for_next_block(
+                    dbs,
+                    max_size,
+                    median_time,
+                    min_version,
+                )
+            })
+            .await
+            .expect("dbs pool disconnected")
+    }
     pub fn remove_all_pending_txs(&self) -> KvResult<()> {
         self.dbs_pool
             .execute(move |dbs| {
@@ -86,6 +276,17 @@ impl DuniterServer {
             })
             .expect("dbs pool disconnected")
     }
+    pub async fn remove_all_pending_txs_async(&self) -> KvResult<()> {
+        self.dbs_pool_async
+            .execute(move |dbs| {
+                duniter_core::dbs_write_ops::txs_mp::remove_all_pending_txs(&dbs.txs_mp_db)
+            })
+            .await
+            .expect("dbs pool disconnected")
+    }
+    /// Remove the pending transaction, which is not recorded as evicted: this is done when a
+    /// transaction is written. Pending transactions depending on it are kept, they can be
+    /// written now.
     pub fn remove_pending_tx_by_hash(&self, hash: Hash) -> KvResult<()> {
         self.dbs_pool
             .execute(move |dbs| {
@@ -93,14 +294,177 @@ impl DuniterServer {
             })
             .expect("dbs pool disconnected")
     }
+    pub async fn remove_pending_tx_by_hash_async(&self, hash: Hash) -> KvResult<()> {
+        self.dbs_pool_async
+            .execute(move |dbs| {
+                duniter_core::dbs_write_ops::txs_mp::remove_pending_tx_by_hash(&dbs.txs_mp_db, hash)
+            })
+            .await
+            .expect("dbs pool disconnected")
+    }
+    /// Remove expired transactions and the pending transactions depending on them, which are
+    /// recorded as evicted.
     pub fn trim_expired_non_written_txs(&self, limit_time: i64) -> KvResult<()> {
         self.dbs_pool
             .execute(move |dbs| {
-                duniter_core::dbs_write_ops::txs_mp::trim_expired_non_written_txs(
-                    &dbs.txs_mp_db,
-                    limit_time,
-                )
+                crate::mempool_maintenance::drop_expired_txs(dbs, limit_time)?;
+                crate::tx_status::trim_dropped_txs(&dbs.server.txs_dropped_db, limit_time)
+            })
+            .expect("dbs pool disconnected")
+    }
+    pub async fn trim_expired_non_written_txs_async(&self, limit_time: i64) -> KvResult<()> {
+        self.dbs_pool_async
+            .execute(move |dbs| {
+                crate::mempool_maintenance::drop_expired_txs(dbs, limit_time)?;
+                crate::tx_status::trim_dropped_txs(&dbs.server.txs_dropped_db, limit_time)
             })
+            .await
             .expect("dbs pool disconnected")
     }
 }
+
+impl DuniterServer {
+    fn new_tx_ctx(&self) -> NewTxCtx {
+        NewTxCtx {
+            currency: self.currency.clone(),
+            conflict_policy: self.mempool_conflict_policy,
+            current: self.current,
+            eviction_policy: self.mempool_eviction_policy,
+            insert_lock: self.txs_mp_insert_lock.clone(),
+            max_size: self.conf.txs_mempool_size,
+            txs_mempool: self.txs_mempool,
+        }
+    }
+    fn notify_tx_evicted(&self, hash: Hash) {
+        log::info!("evict pending tx {}", hash);
+        self.metrics.tx_evicted();
+        self.events_bus.publish(ServerEvent::TxEvicted(hash));
+    }
+}
+
+/// Everything needed to accept a new transaction in the dbs pool
+struct NewTxCtx {
+    conflict_policy: MempoolConflictPolicy,
+    currency: String,
+    current: Option<BlockMetaV2>,
+    eviction_policy: MempoolEvictionPolicy,
+    insert_lock: Arc<Mutex<()>>,
+    max_size: usize,
+    txs_mempool: TxsMempool,
+}
+
+/// Check a new transaction, return the pending transaction to evict to make room for it,
+/// if any
+fn check_new_tx(
+    dbs: &dbs_pool::PoolDbs<'_>,
+    ctx: &NewTxCtx,
+    server_pubkey: PublicKey,
+    tx: &TransactionDocumentV10,
+) -> KvResult<Result<Option<Hash>, TxRejection>> {
+    if let Err(rejection) = crate::tx_rejection::check_new_tx(
+        &dbs.bc_db_ro,
+        &dbs.txs_mp_db,
+        &ctx.currency,
+        ctx.current,
+        tx,
+    )? {
+        return Ok(Err(rejection));
+    }
+    if ctx.conflict_policy == MempoolConflictPolicy::Reject {
+        if let Some((source, pending_tx)) =
+            crate::mempool_conflicts::find_conflicting_tx(&dbs.server.txs_mp_index, tx)
+        {
+            return Ok(Err(TxRejection::DoubleSpend { source, pending_tx }));
+        }
+    }
+    let evicted_opt = crate::mempool_eviction::choose_tx_to_evict(
+        &dbs.bc_db_ro,
+        &dbs.txs_mp_db,
+        ctx.eviction_policy,
+        ctx.max_size,
+        server_pubkey,
+        tx,
+    )?;
+    match ctx
+        .txs_mempool
+        .accept_new_tx(&dbs.bc_db_ro, server_pubkey, tx.clone(), &dbs.txs_mp_db)
+    {
+        Ok(()) => Ok(Ok(evicted_opt)),
+        Err(TxMpError::Db(e)) => Err(e),
+        Err(TxMpError::Full) if evicted_opt.is_some() => Ok(Ok(evicted_opt)),
+        Err(TxMpError::Full) => Ok(Err(TxRejection::MempoolFull)),
+        Err(TxMpError::TxAlreadyWritten) => Ok(Err(TxRejection::Duplicate)),
+    }
+}
+
+/// Return the acceptance result and the evicted transactions: the one chosen by the eviction
+/// policy and its pending descendants. The transaction is received now, unless
+/// `received_time_opt` is given.
+fn add_pending_tx(
+    dbs: &dbs_pool::PoolDbs<'_>,
+    ctx: &NewTxCtx,
+    server_pubkey: PublicKey,
+    tx: TransactionDocumentV10,
+    received_time_opt: Option<i64>,
+) -> KvResult<(Result<(), TxRejection>, Vec<Hash>)> {
+    // Another new transaction must not be checked nor inserted between the eviction and the
+    // insertion, it could take the room made for this one.
+    let _insert_guard = ctx
+        .insert_lock
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let evicted_opt = match check_new_tx(dbs, ctx, server_pubkey, &tx)? {
+        Ok(evicted_opt) => evicted_opt,
+        Err(rejection) => return Ok((Err(rejection), Vec::new())),
+    };
+    let evicted = if let Some(evicted) = evicted_opt {
+        let evicted = crate::pending_txs_graph::remove_with_descendants(
+            &dbs.txs_mp_db,
+            &dbs.server.txs_mp_index,
+            vec![evicted],
+        )?;
+        record_dropped_txs(&dbs.server.txs_dropped_db, TxDropReason::Evicted, &evicted)?;
+        evicted
+    } else {
+        Vec::new()
+    };
+    ctx.txs_mempool.add_pending_tx_force(&dbs.txs_mp_db, &tx)?;
+    if let Some(received_time) = received_time_opt {
+        set_received_time(&dbs.txs_mp_db, tx.get_hash(), received_time)?;
+    }
+    Ok((Ok(()), evicted))
+}
+
+/// Move the pending transaction `hash` to `received_time`, in its record and in the received
+/// time index
+fn set_received_time(
+    txs_mp_db: &TxsMpV2Db<FileBackend>,
+    hash: Hash,
+    received_time: i64,
+) -> KvResult<()> {
+    if let Some(mut pending_tx) = txs_mp_db.txs().get(&HashKeyV2(hash))? {
+        let old_time = TimestampKeyV1(pending_tx.received_time);
+        if let Some(mut hashs) = txs_mp_db.txs_by_received_time().get(&old_time)? {
+            hashs.0.remove(&HashKeyV2(hash));
+            if hashs.0.is_empty() {
+                txs_mp_db.txs_by_received_time_write().remove(old_time)?;
+            } else {
+                txs_mp_db
+                    .txs_by_received_time_write()
+                    .upsert(old_time, hashs)?;
+            }
+        }
+        let new_time = TimestampKeyV1(received_time);
+        let mut hashs = txs_mp_db
+            .txs_by_received_time()
+            .get(&new_time)?
+            .unwrap_or_default();
+        hashs.0.insert(HashKeyV2(hash));
+        txs_mp_db
+            .txs_by_received_time_write()
+            .upsert(new_time, hashs)?;
+        pending_tx.received_time = received_time;
+        txs_mp_db.txs_write().upsert(HashKeyV2(hash), pending_tx)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,299 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::*;
+
+impl DuniterServer {
+    /// Check a new identity, certification, membership or revocation and add it to its
+    /// mempool if it is accepted.
+    pub fn accept_new_wot_doc(&self, doc: WotDocument) -> KvResult<Result<(), WotDocRejection>> {
+        let currency = self.currency.clone();
+        let max_size = self.wot_mempool_size;
+        self.dbs_pool
+            .execute(move |dbs| {
+                crate::wot_mempools::accept_new_doc(
+                    &dbs.bc_db_ro,
+                    &dbs.server.wot_mp_db,
+                    &currency,
+                    max_size,
+                    doc,
+                    crate::wot_mempools::now(),
+                )
+            })
+            .expect("dbs pool disconnected")
+    }
+    pub async fn accept_new_wot_doc_async(
+        &self,
+        doc: WotDocument,
+    ) -> KvResult<Result<(), WotDocRejection>> {
+        let currency = self.currency.clone();
+        let max_size = self.wot_mempool_size;
+        self.dbs_pool_async
+            .execute(move |dbs| {
+                crate::wot_mempools::accept_new_doc(
+                    &dbs.bc_db_ro,
+                    &dbs.server.wot_mp_db,
+                    &currency,
+                    max_size,
+                    doc,
+                    crate::wot_mempools::now(),
+                )
+            })
+            .await
+            .expect("dbs pool disconnected")
+    }
+    pub fn get_wot_mempool_free_rooms(&self, kind: WotDocKind) -> KvResult<usize> {
+        let docs_count = self
+            .dbs_pool
+            .execute(move |dbs| crate::wot_mempools::count_docs(&dbs.server.wot_mp_db, kind))
+            .expect("dbs pool disconnected")?;
+        Ok(self.wot_mempool_size.saturating_sub(docs_count))
+    }
+    /// Get pending documents of the given kind, sorted by received time.
+    pub fn get_pending_wot_docs(&self, kind: WotDocKind) -> KvResult<Vec<WotDocument>> {
+        self.dbs_pool
+            .execute(move |dbs| crate::wot_mempools::get_docs(&dbs.server.wot_mp_db, kind))
+            .expect("dbs pool disconnected")
+    }
+    pub async fn get_pending_wot_docs_async(&self, kind: WotDocKind) -> KvResult<Vec<WotDocument>> {
+        self.dbs_pool_async
+            .execute(move |dbs| crate::wot_mempools::get_docs(&dbs.server.wot_mp_db, kind))
+            .await
+            .expect("dbs pool disconnected")
+    }
+    pub fn remove_all_pending_wot_docs(&self) -> KvResult<()> {
+        self.dbs_pool
+            .execute(move |dbs| crate::wot_mempools::remove_all_docs(&dbs.server.wot_mp_db))
+            .expect("dbs pool disconnected")
+    }
+    pub fn remove_pending_wot_doc_by_hash(&self, hash: Hash) -> KvResult<()> {
+        self.dbs_pool
+            .execute(move |dbs| {
+                crate::wot_mempools::remove_doc_by_hash(&dbs.server.wot_mp_db, hash)
+            })
+            .expect("dbs pool disconnected")
+    }
+    pub async fn remove_pending_wot_doc_by_hash_async(&self, hash: Hash) -> KvResult<()> {
+        self.dbs_pool_async
+            .execute(move |dbs| {
+                crate::wot_mempools::remove_doc_by_hash(&dbs.server.wot_mp_db, hash)
+            })
+            .await
+            .expect("dbs pool disconnected")
+    }
+    /// Remove the documents received before `limit_time`.
+    pub fn trim_expired_wot_docs(&self, limit_time: i64) -> KvResult<()> {
+        self.dbs_pool
+            .execute(move |dbs| {
+                crate::wot_mempools::trim_expired_docs(&dbs.server.wot_mp_db, limit_time)
+            })
+            .expect("dbs pool disconnected")
+    }
+    pub async fn trim_expired_wot_docs_async(&self, limit_time: i64) -> KvResult<()> {
+        self.dbs_pool_async
+            .execute(move |dbs| {
+                crate::wot_mempools::trim_expired_docs(&dbs.server.wot_mp_db, limit_time)
+            })
+            .await
+            .expect("dbs pool disconnected")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{block, keypair};
+    use crate::wot_mempools::WotMpV1DbReadable as _;
+    use duniter_core::common::crypto::keys::{
+        ed25519::Ed25519KeyPair, KeyPair as _, Signature as _,
+    };
+    use duniter_core::dbs::HashKeyV2;
+    use duniter_core::documents::identity::{IdentityDocumentV10, IdentityDocumentV10Builder};
+    use duniter_core::documents::membership::{
+        MembershipDocumentV10, MembershipDocumentV10Builder, MembershipType,
+    };
+
+    fn identity(keypair: &Ed25519KeyPair, username: &str) -> IdentityDocumentV10 {
+        IdentityDocumentV10Builder {
+            currency: "test",
+            username,
+            blockstamp: &Blockstamp::default(),
+            issuer: keypair.public_key(),
+        }
+        .build_and_sign(vec![keypair.generate_signator()])
+    }
+
+    fn join(keypair: &Ed25519KeyPair, username: &str) -> MembershipDocumentV10 {
+        MembershipDocumentV10Builder {
+            currency: "test",
+            issuer: keypair.public_key(),
+            blockstamp: &Blockstamp::default(),
+            membership: MembershipType::In(),
+            identity_username: username,
+            identity_blockstamp: &Blockstamp::default(),
+        }
+        .build_and_sign(vec![keypair.generate_signator()])
+    }
+
+    fn pending_hashs(server: &DuniterServer, kind: WotDocKind) -> KvResult<Vec<Hash>> {
+        Ok(server
+            .get_pending_wot_docs(kind)?
+            .iter()
+            .map(WotDocument::hash)
+            .collect())
+    }
+
+    // Hashs in the received time index
+    fn received_hashs(server: &DuniterServer) -> KvResult<Vec<Hash>> {
+        server
+            .dbs_pool
+            .execute(|dbs| {
+                dbs.server.wot_mp_db.docs_by_received_time().iter(.., |it| {
+                    let mut hashs = Vec::new();
+                    for hashs_res in it.values() {
+                        hashs.extend(hashs_res?.0.into_iter().map(|HashKeyV2(hash)| hash));
+                    }
+                    Ok::<_, KvError>(hashs)
+                })
+            })
+            .expect("dbs pool disconnected")
+    }
+
+    #[test]
+    fn test_accept_new_wot_doc() -> anyhow::Result<()> {
+        let server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let alice = keypair();
+        let idty = WotDocument::Identity(identity(&alice, "alice"));
+
+        assert_eq!(server.accept_new_wot_doc(idty.clone())?, Ok(()));
+        assert_eq!(
+            pending_hashs(&server, WotDocKind::Identity)?,
+            vec![idty.hash()]
+        );
+        assert_eq!(
+            server.get_wot_mempool_free_rooms(WotDocKind::Identity)?,
+            crate::wot_mempools::DEFAULT_WOT_MEMPOOL_SIZE - 1
+        );
+        assert_eq!(
+            server.accept_new_wot_doc(idty)?,
+            Err(WotDocRejection::Duplicate)
+        );
+
+        // A membership needs a written or pending identity
+        assert_eq!(
+            server.accept_new_wot_doc(WotDocument::Membership(join(&alice, "alice")))?,
+            Ok(())
+        );
+        assert_eq!(
+            server.accept_new_wot_doc(WotDocument::Membership(join(&keypair(), "bob")))?,
+            Err(WotDocRejection::UnknownIdentity)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wot_mempool_full() -> anyhow::Result<()> {
+        let server = DuniterServerBuilder::new("test".to_owned())
+            .duniter_mode(DuniterMode::Start)
+            .software_version(duniter_core::module::SOFTWARE_NAME)
+            .wot_mempool_size(1)
+            .start()?;
+        let alice = keypair();
+
+        assert_eq!(
+            server.accept_new_wot_doc(WotDocument::Identity(identity(&alice, "alice")))?,
+            Ok(())
+        );
+        assert_eq!(
+            server.accept_new_wot_doc(WotDocument::Identity(identity(&keypair(), "bob")))?,
+            Err(WotDocRejection::MempoolFull)
+        );
+        // Each kind of document has its own mempool
+        assert_eq!(
+            server.accept_new_wot_doc(WotDocument::Membership(join(&alice, "alice")))?,
+            Ok(())
+        );
+        assert_eq!(server.get_wot_mempool_free_rooms(WotDocKind::Identity)?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_pending_wot_doc_by_hash() -> anyhow::Result<()> {
+        let server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let alice = WotDocument::Identity(identity(&keypair(), "alice"));
+        let bob = WotDocument::Identity(identity(&keypair(), "bob"));
+        assert_eq!(server.accept_new_wot_doc(alice.clone())?, Ok(()));
+        assert_eq!(server.accept_new_wot_doc(bob.clone())?, Ok(()));
+
+        server.remove_pending_wot_doc_by_hash(alice.hash())?;
+        assert_eq!(
+            pending_hashs(&server, WotDocKind::Identity)?,
+            vec![bob.hash()]
+        );
+        assert_eq!(received_hashs(&server)?, vec![bob.hash()]);
+
+        server.remove_pending_wot_doc_by_hash(bob.hash())?;
+        assert!(pending_hashs(&server, WotDocKind::Identity)?.is_empty());
+        assert!(received_hashs(&server)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_written_wot_docs() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let alice = keypair();
+        let alice_idty = identity(&alice, "alice");
+        let alice_join = join(&alice, "alice");
+        let bob_idty = WotDocument::Identity(identity(&keypair(), "bob"));
+        // Uses the username of a written identity
+        let other_alice_idty = WotDocument::Identity(identity(&keypair(), "alice"));
+        for doc in vec![
+            WotDocument::Identity(alice_idty.clone()),
+            WotDocument::Membership(alice_join.clone()),
+            bob_idty.clone(),
+            other_alice_idty,
+        ] {
+            assert_eq!(server.accept_new_wot_doc(doc)?, Ok(()));
+        }
+
+        let mut genesis = block(0, 0, None);
+        genesis.identities = vec![format!(
+            "{}:{}:{}:alice",
+            alice.public_key(),
+            alice_idty.signatures()[0].to_base64(),
+            Blockstamp::default(),
+        )];
+        genesis.joiners = vec![format!(
+            "{}:{}:{}:{}:alice",
+            alice.public_key(),
+            alice_join.signatures()[0].to_base64(),
+            Blockstamp::default(),
+            Blockstamp::default(),
+        )];
+        server.apply_block(genesis)?;
+
+        assert_eq!(
+            pending_hashs(&server, WotDocKind::Identity)?,
+            vec![bob_idty.hash()]
+        );
+        assert!(pending_hashs(&server, WotDocKind::Membership)?.is_empty());
+        assert_eq!(received_hashs(&server)?, vec![bob_idty.hash()]);
+
+        Ok(())
+    }
+}
@@ -22,16 +22,66 @@
     unused_import_braces
 )]
 
+mod block_verification;
+mod blocks;
+mod blocks_index;
+mod builder;
+mod dbs_pool;
+mod events;
 mod fill_cm;
 mod legacy;
+mod mempool_conflicts;
+mod mempool_dump;
+mod mempool_eviction;
+mod mempool_maintenance;
+mod metrics;
+mod modules;
+mod pending_txs;
+mod pending_txs_graph;
+mod rules;
+mod rules_index;
+#[cfg(test)]
+mod test_utils;
+mod tx_rejection;
+mod tx_selection;
+mod tx_status;
+mod txs_history;
+mod txs_mp_index;
+mod ud_history;
+mod utxos_index;
+mod wallet_group_history;
+mod wallet_sources;
+mod wot_mempools;
+
+pub use crate::block_verification::{BlockVerificationError, BlockVerificationLevel};
+pub use crate::builder::DuniterServerBuilder;
+pub use crate::events::{SequencedEvent, ServerEvent};
+pub use crate::legacy::SelfEndpointsNotReady;
+pub use crate::mempool_conflicts::{MempoolConflict, MempoolConflictPolicy};
+pub use crate::mempool_dump::MempoolImport;
+pub use crate::mempool_eviction::MempoolEvictionPolicy;
+pub use crate::mempool_maintenance::MempoolMaintenanceConf;
+pub use crate::modules::{ModuleStatus, ModulesStartError};
+pub use crate::pending_txs::{PendingTxsCursor, PendingTxsPage};
+pub use crate::rules::BlockRule;
+pub use crate::tx_rejection::TxRejection;
+pub use crate::tx_status::TxStatus;
+pub use crate::txs_history::{
+    TxDirection, TxHistoryEntry, TxsHistoryCursor, TxsHistoryFilter, TxsHistoryPage,
+};
+pub use crate::ud_history::{UdHistoryEntry, UdHistoryRange};
+pub use crate::wallet_group_history::WalletGroupTx;
+pub use crate::wallet_sources::WalletSource;
+pub use crate::wot_mempools::{WotDocKind, WotDocRejection, WotDocument};
 
 pub use duniter_core::conf::{DuniterCoreConf, DuniterMode};
 use duniter_core::dbs::databases::{bc_v2::BcV2DbReadable, network_v1::NetworkV1DbWritable};
 pub use duniter_core::dbs::{
-    kv_typed::prelude::KvResult, smallvec, DunpHeadDbV1, DunpNodeIdV1Db, PeerCardDbV1,
+    kv_typed::prelude::KvResult, smallvec, BlockMetaV2, DunpHeadDbV1, DunpNodeIdV1Db, PeerCardDbV1,
 };
 #[cfg(target_arch = "x86_64")]
 pub use duniter_gva::GvaModule;
+pub use fast_threadpool::ThreadPoolConfig;
 
 use anyhow::Context;
 use duniter_core::common::prelude::*;
@@ -41,46 +91,122 @@ use duniter_core::dbs::{
     kv_typed::prelude::*,
     PendingTxDbV2, PubKeyKeyV2,
 };
-use duniter_core::dbs::{prelude::*, BlockMetaV2, FileBackend};
+use duniter_core::dbs::{prelude::*, FileBackend};
 use duniter_core::documents::{prelude::*, transaction::TransactionDocumentV10};
 use duniter_core::global::{tokio, GlobalBackGroundTaskMsg};
 use duniter_core::mempools::{Mempools, TxMpError, TxsMempool};
-use duniter_core::module::{plug_duniter_modules, Endpoint, TxsHistoryForBma};
+use duniter_core::module::{Endpoint, TxsHistoryForBma};
 use duniter_core::{
     block::prelude::*, common::crypto::hashs::Hash, documents_parser::prelude::FromStringObject,
 };
-use fast_threadpool::ThreadPoolConfig;
-use resiter::{filter::Filter, map::Map};
+use resiter::map::Map;
 use std::{
     collections::BTreeMap,
     path::{Path, PathBuf},
+    sync::Mutex,
 };
 
-// Plug duniter modules
-use duniter_core::conf as duniter_conf;
-use duniter_core::global as duniter_global;
-use duniter_core::mempools as duniter_mempools;
-cfg_if::cfg_if! {
-    if #[cfg(target_arch = "x86_64")] {
-        use duniter_core::module::DuniterModule;
-        plug_duniter_modules!([GvaModule], TxsHistoryForBma);
-    } else {
-        plug_duniter_modules!([], TxsHistoryForBma);
+// Plug duniter modules. Each module is started in its own task by `modules::start_modules`,
+// to report its status and stop it on shutdown, so the generated `start_duniter_modules` is
+// not used.
+#[allow(dead_code)]
+mod plugged_modules {
+    use super::*;
+    use duniter_core::conf as duniter_conf;
+    use duniter_core::global as duniter_global;
+    use duniter_core::mempools as duniter_mempools;
+    use duniter_core::module::plug_duniter_modules;
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "x86_64")] {
+            use duniter_core::module::DuniterModule;
+            plug_duniter_modules!([GvaModule], TxsHistoryForBma);
+        } else {
+            plug_duniter_modules!([], TxsHistoryForBma);
+        }
+    }
+
+    type DbsPoolHandler = fast_threadpool::ThreadPoolSyncHandler<SharedDbs<FileBackend>>;
+
+    pub(crate) fn apply_block(
+        block: Arc<DubpBlockV10>,
+        conf: Arc<DuniterCoreConf>,
+        currency_params: CurrencyParameters,
+        dbs_pool: &DbsPoolHandler,
+        profile_path_opt: Option<PathBuf>,
+    ) -> KvResult<()> {
+        apply_block_modules(block, conf, currency_params, dbs_pool, profile_path_opt)
+    }
+    pub(crate) fn apply_chunk_of_blocks(
+        blocks: Arc<[DubpBlockV10]>,
+        conf: Arc<DuniterCoreConf>,
+        currency_params: CurrencyParameters,
+        dbs_pool: &DbsPoolHandler,
+        profile_path_opt: Option<PathBuf>,
+    ) -> KvResult<()> {
+        apply_chunk_of_blocks_modules(blocks, conf, currency_params, dbs_pool, profile_path_opt)
+    }
+    pub(crate) fn revert_block(
+        block: Arc<DubpBlockV10>,
+        conf: Arc<DuniterCoreConf>,
+        currency_params: CurrencyParameters,
+        dbs_pool: &DbsPoolHandler,
+        profile_path_opt: Option<PathBuf>,
+    ) -> KvResult<()> {
+        revert_block_modules(block, conf, currency_params, dbs_pool, profile_path_opt)
+    }
+    pub(crate) fn transactions_history_for_bma(
+        dbs_pool: &DbsPoolHandler,
+        profile_path_opt: Option<&Path>,
+        pubkey: PublicKey,
+    ) -> KvResult<TxsHistoryForBma> {
+        get_transactions_history_for_bma(dbs_pool, profile_path_opt, pubkey)
+    }
+    pub(crate) fn tx_by_hash(
+        dbs_pool: &DbsPoolHandler,
+        hash: Hash,
+        profile_path_opt: Option<&Path>,
+    ) -> KvResult<Option<(TransactionDocumentV10, Option<BlockNumber>)>> {
+        get_tx_by_hash(dbs_pool, hash, profile_path_opt)
     }
 }
 
 pub struct DuniterServer {
+    background_threads: Vec<std::thread::JoinHandle<()>>,
     bc_db: BcV2Db<FileBackend>,
+    block_verification: BlockVerificationLevel,
+    /// Single worker databases pool where blocks are written
+    block_writer_pool: dbs_pool::DbsPool,
+    block_writer_pool_async: dbs_pool::DbsPoolAsync,
     conf: DuniterCoreConf,
+    currency: String,
     currency_params: CurrencyParameters,
     current: Option<BlockMetaV2>,
-    dbs_pool: fast_threadpool::ThreadPoolSyncHandler<SharedDbs<FileBackend>>,
+    dbs_pool: dbs_pool::DbsPool,
+    dbs_pool_async: dbs_pool::DbsPoolAsync,
+    events_bus: events::EventsBus,
+    #[cfg(test)]
+    fail_block_writes: bool,
     global_sender: flume::Sender<GlobalBackGroundTaskMsg>,
+    /// Set when a branch switch could not be rolled back, blocks are no longer indexed then
+    inconsistent_chain: Option<String>,
+    mempool_conflict_policy: MempoolConflictPolicy,
+    mempool_eviction_policy: MempoolEvictionPolicy,
+    mempool_maintenance_done: Option<flume::Receiver<()>>,
+    metrics: metrics::Metrics,
+    modules_status: modules::ModulesStatus,
     pending_txs_subscriber:
         flume::Receiver<Arc<Events<duniter_core::dbs::databases::txs_mp_v2::TxsEvent>>>,
     profile_path_opt: Option<PathBuf>,
+    runtime_handle: Option<std::thread::JoinHandle<()>>,
     shared_dbs: SharedDbs<FileBackend>,
+    shutdown_sender: Option<flume::Sender<()>>,
+    txs_history_cache: Mutex<txs_history::WrittenHistoryCache>,
     txs_mempool: TxsMempool,
+    /// Held while a new transaction is checked and inserted in the mempool
+    txs_mp_insert_lock: Arc<Mutex<()>>,
+    /// Number of dividends created in the current chain
+    uds_count: u64,
+    wot_mempool_size: usize,
 }
 
 impl DuniterServer {
@@ -94,93 +220,140 @@ impl DuniterServer {
         profile_path_opt: Option<&Path>,
         software_version: &'static str,
     ) -> anyhow::Result<DuniterServer> {
-        log::info!("mode={:?}", duniter_mode);
-
-        let txs_mempool = TxsMempool::new(conf.txs_mempool_size);
-
-        log::info!("open duniter databases...");
-        let (bc_db, shared_dbs) = duniter_core::dbs::open_dbs(profile_path_opt)?;
-        shared_dbs.dunp_db.heads_old_write().clear()?; // Clear WS2Pv1 HEADs
+        let builder = DuniterServerBuilder::new(currency)
+            .conf(conf)
+            .duniter_mode(duniter_mode)
+            .software_version(software_version);
+        if let Some(profile_path) = profile_path_opt {
+            builder.profile_path(profile_path.to_owned())
+        } else {
+            builder
+        }
+        .start()
+    }
+    pub fn modules_status(&self) -> BTreeMap<&'static str, ModuleStatus> {
+        self.modules_status.get()
+    }
+    /// Stop duniter modules and background tasks, wait for the global background task to
+    /// process all pending messages, then flush databases on disk. Calling it several times
+    /// is harmless, and it is called when the server is dropped.
+    pub fn shutdown(&mut self) -> anyhow::Result<()> {
+        if let Some(runtime_handle) = self.runtime_handle.take() {
+            log::info!("stop duniter modules...");
+            // Dropping the sender notifies all modules and tasks waiting for shutdown
+            self.shutdown_sender.take();
+            runtime_handle
+                .join()
+                .map_err(|_| anyhow::Error::msg("duniter modules thread panicked"))?;
+            // Events subscribers stop waiting for new events
+            self.events_bus.close();
 
-        // Create channel with global async task
-        let (global_sender, global_recv) = flume::unbounded();
+            log::info!("stop background tasks...");
+            if let Some(mempool_maintenance_done) = self.mempool_maintenance_done.take() {
+                // The sender is dropped when the task ends
+                let _ = mempool_maintenance_done.recv();
+            }
+            for thread in self.background_threads.drain(..) {
+                thread
+                    .join()
+                    .map_err(|_| anyhow::Error::msg("background thread panicked"))?;
+            }
 
-        // Fill and get current meta
-        let current = fill_cm::fill_and_get_current_meta(&bc_db, &global_sender)?;
-        log::info!("Databases successfully opened.");
+            // Messages are processed in order, so once this one is answered,
+            // all previous messages have been processed.
+            let (sender, recv) = flume::bounded(1);
+            self.global_sender
+                .send(GlobalBackGroundTaskMsg::GetSelfEndpoints(sender))?;
+            recv.recv()?;
 
-        // Get currency parameters
-        let currency_params = bc_db.currency_params().get(&())?.unwrap_or_default().params;
+            log::info!("flush duniter databases...");
+            self.bc_db.save()?;
+            self.dbs_pool.server_dbs().memberships_db.save()?;
+            self.shared_dbs.dunp_db.save()?;
+            self.shared_dbs.txs_mp_db.save()?;
+            self.dbs_pool.server_dbs().txs_dropped_db.save()?;
+            self.dbs_pool.server_dbs().wot_mp_db.save()?;
 
-        if let Some(current) = current {
-            log::info!("Current block: #{}-{}", current.number, current.hash);
-        } else {
-            log::info!("Current block: no blockchain");
+            log::info!("Duniter server stopped.");
         }
-
-        let (s, pending_txs_subscriber) = flume::unbounded();
-        shared_dbs
-            .txs_mp_db
-            .txs()
-            .subscribe(s)
-            .context("Fail to subscribe to txs col")?;
-
-        log::info!("start dbs threadpool...");
-
-        let threadpool =
-            fast_threadpool::ThreadPool::start(ThreadPoolConfig::default(), shared_dbs.clone());
-
-        // Start async runtime
-        let conf_clone = conf.clone();
-        let profile_path_opt_clone = profile_path_opt.map(ToOwned::to_owned);
-        let threadpool_async_handler = threadpool.async_handler();
-        std::thread::spawn(move || {
-            duniter_core::global::get_async_runtime().block_on(async {
-                // Start global background task
-                duniter_core::global::start_global_background_task(global_recv).await;
-
-                // Start duniter modules
-                log::info!("start duniter modules...");
-                start_duniter_modules(
-                    &conf_clone,
-                    currency,
-                    threadpool_async_handler,
-                    Mempools { txs: txs_mempool },
-                    duniter_mode,
-                    profile_path_opt_clone,
-                    software_version,
-                )
-                .await
-                .expect("Fail to start duniter modules");
-            });
-        });
-
-        log::info!("Duniter sever started.");
-
-        Ok(DuniterServer {
-            bc_db,
-            conf,
-            current,
-            currency_params,
-            dbs_pool: threadpool.into_sync_handler(),
-            global_sender,
-            pending_txs_subscriber,
-            profile_path_opt: profile_path_opt.map(ToOwned::to_owned),
-            shared_dbs,
-            txs_mempool,
-        })
+        Ok(())
     }
     #[cfg(test)]
     pub(crate) fn test(
         conf: DuniterCoreConf,
         duniter_mode: DuniterMode,
     ) -> anyhow::Result<DuniterServer> {
-        DuniterServer::start(
-            conf,
-            "test".to_owned(),
-            duniter_mode,
-            None,
-            duniter_core::module::SOFTWARE_NAME,
-        )
+        DuniterServerBuilder::new("test".to_owned())
+            .conf(conf)
+            .duniter_mode(duniter_mode)
+            .software_version(duniter_core::module::SOFTWARE_NAME)
+            .start()
+    }
+}
+
+impl Drop for DuniterServer {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown() {
+            log::error!("Fail to shutdown duniter server: {:#}", e);
+        }
+    }
+}
+
+/// Run `fut` until it completes, or until the server shutdown (`None` is returned in this case).
+pub(crate) async fn until_shutdown<F: std::future::Future>(
+    fut: F,
+    shutdown_recv: &flume::Receiver<()>,
+) -> Option<F::Output> {
+    use std::future::Future as _;
+    use std::task::Poll;
+
+    let mut fut = Box::pin(fut);
+    let mut shutdown = Box::pin(shutdown_recv.recv_async());
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(output) = fut.as_mut().poll(cx) {
+            Poll::Ready(Some(output))
+        } else if shutdown.as_mut().poll(cx).is_ready() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shutdown() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+
+        server.shutdown()?;
+        assert!(server
+            .modules_status()
+            .values()
+            .all(|status| *status != ModuleStatus::Running));
+        // A second call must be a no-op
+        server.shutdown()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_until_shutdown() {
+        let (shutdown_sender, shutdown_recv) = flume::bounded::<()>(1);
+        let runtime = duniter_core::global::get_async_runtime();
+
+        assert_eq!(
+            runtime.block_on(until_shutdown(async { 42 }, &shutdown_recv)),
+            Some(42)
+        );
+
+        drop(shutdown_sender);
+        assert_eq!(
+            runtime.block_on(until_shutdown(std::future::pending::<()>(), &shutdown_recv)),
+            None
+        );
     }
 }
@@ -0,0 +1,256 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Pending transactions spending the same source (double spends).
+//!
+//! Only one of them can ever be written, so depending on the conflict policy a new
+//! transaction is refused, or kept in the mempool but never proposed for a block.
+
+use crate::txs_mp_index::TxsMpIndex;
+use crate::*;
+use duniter_core::dbs::HashKeyV2;
+use duniter_core::documents::transaction::{SourceIdV10, TransactionDocumentTrait};
+use std::collections::HashSet;
+
+/// What to do with a new transaction spending a source already spent by a pending one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MempoolConflictPolicy {
+    /// Refuse the new transaction
+    Reject,
+    /// Accept the new transaction, but only the first received one can be proposed for a block
+    KeepFirst,
+}
+
+impl Default for MempoolConflictPolicy {
+    fn default() -> Self {
+        MempoolConflictPolicy::KeepFirst
+    }
+}
+
+impl std::str::FromStr for MempoolConflictPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(MempoolConflictPolicy::Reject),
+            "keep-first" => Ok(MempoolConflictPolicy::KeepFirst),
+            _ => Err(anyhow::anyhow!("Invalid mempool conflict policy: {}", s)),
+        }
+    }
+}
+
+/// A source spent by several pending transactions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MempoolConflict {
+    pub source: SourceIdV10,
+    /// Transactions spending the source, sorted by received time
+    pub txs: Vec<Hash>,
+    /// Issuers of these transactions
+    pub issuers: Vec<PublicKey>,
+}
+
+/// Pending transaction already spending one of the sources of `tx`, if any
+pub(crate) fn find_conflicting_tx(
+    txs_mp_index: &TxsMpIndex,
+    tx: &TransactionDocumentV10,
+) -> Option<(SourceIdV10, Hash)> {
+    let tx_hash = tx.get_hash();
+    tx.get_inputs().iter().find_map(|input| {
+        txs_mp_index
+            .spenders(input.id)
+            .into_iter()
+            .find(|spender| *spender != tx_hash)
+            .map(|spender| (input.id, spender))
+    })
+}
+
+/// All sources spent by several pending transactions
+pub(crate) fn get_conflicts<TxsMpDb: TxsMpV2DbReadable>(
+    txs_mp_db: &TxsMpDb,
+    txs_mp_index: &TxsMpIndex,
+) -> KvResult<Vec<MempoolConflict>> {
+    let mut conflicts = Vec::new();
+    for (source, txs) in txs_mp_index.conflicts() {
+        let mut issuers = Vec::new();
+        for hash in &txs {
+            if let Some(pending_tx) = txs_mp_db.txs().get(&HashKeyV2(*hash))? {
+                for issuer in pending_tx.doc.issuers() {
+                    if !issuers.contains(issuer) {
+                        issuers.push(*issuer);
+                    }
+                }
+            }
+        }
+        conflicts.push(MempoolConflict {
+            source,
+            txs,
+            issuers,
+        });
+    }
+    Ok(conflicts)
+}
+
+/// Drop the transactions spending a source already spent by a previous transaction of `txs`
+pub(crate) fn keep_first_spenders(txs: Vec<PendingTxDbV2>) -> Vec<PendingTxDbV2> {
+    let mut spent = HashSet::new();
+    txs.into_iter()
+        .filter(|pending_tx| {
+            let inputs = pending_tx.doc.get_inputs();
+            if inputs.iter().any(|input| spent.contains(&input.id)) {
+                false
+            } else {
+                spent.extend(inputs.iter().map(|input| input.id));
+                true
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{sig_unlock, tx, utxo_input, ISSUER};
+    use duniter_core::wallet::prelude::*;
+
+    fn issuer() -> PublicKey {
+        PublicKey::from_base58(ISSUER).expect("invalid test issuer")
+    }
+
+    // Transaction consuming the output `output_index` of `parent`. Outputs locked until
+    // distinct times give distinct hashs to transactions spending the same source.
+    fn spend(parent: Hash, output_index: usize, lock: u64) -> TransactionDocumentV10 {
+        tx(
+            &[utxo_input(parent, output_index)],
+            &[sig_unlock(0)],
+            vec![WalletScriptV10::single(WalletConditionV10::Cltv(lock))],
+        )
+    }
+
+    fn execute<R, F>(server: &DuniterServer, f: F) -> R
+    where
+        F: 'static + Send + FnOnce(&dbs_pool::PoolDbs<'_>) -> R,
+        R: 'static + Send,
+    {
+        server.dbs_pool.execute(f).expect("dbs pool disconnected")
+    }
+
+    #[test]
+    fn test_find_conflicting_tx() -> anyhow::Result<()> {
+        let server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let parent = Hash([1; 32]);
+        let first = spend(parent, 0, 1);
+        server.add_pending_tx_force(first.clone())?;
+
+        let double_spend = spend(parent, 0, 2);
+        let other_output = spend(parent, 1, 3);
+        let first_clone = first.clone();
+        let (conflict_opt, no_conflict_opt, itself_opt) = execute(&server, move |dbs| {
+            (
+                find_conflicting_tx(&dbs.server.txs_mp_index, &double_spend),
+                find_conflicting_tx(&dbs.server.txs_mp_index, &other_output),
+                find_conflicting_tx(&dbs.server.txs_mp_index, &first_clone),
+            )
+        });
+
+        assert_eq!(
+            conflict_opt,
+            Some((utxo_input(parent, 0).id, first.get_hash()))
+        );
+        assert_eq!(no_conflict_opt, None);
+        assert_eq!(itself_opt, None);
+
+        // The source is free again once its spender is removed
+        server.remove_pending_tx_by_hash(first.get_hash())?;
+        let double_spend = spend(parent, 0, 2);
+        assert_eq!(
+            execute(&server, move |dbs| find_conflicting_tx(
+                &dbs.server.txs_mp_index,
+                &double_spend
+            )),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_conflicts() -> anyhow::Result<()> {
+        let server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let parent = Hash([1; 32]);
+        let first = spend(parent, 0, 1);
+        let second = spend(parent, 0, 2);
+        let third = spend(parent, 0, 3);
+        let other_output = spend(parent, 1, 4);
+        for tx in vec![&first, &second, &third, &other_output] {
+            server.add_pending_tx_force(tx.clone())?;
+        }
+
+        assert_eq!(
+            server.get_mempool_conflicts()?,
+            vec![MempoolConflict {
+                source: utxo_input(parent, 0).id,
+                txs: vec![first.get_hash(), second.get_hash(), third.get_hash()],
+                issuers: vec![issuer()],
+            }]
+        );
+
+        server.remove_pending_tx_by_hash(first.get_hash())?;
+        assert_eq!(
+            server.get_mempool_conflicts()?,
+            vec![MempoolConflict {
+                source: utxo_input(parent, 0).id,
+                txs: vec![second.get_hash(), third.get_hash()],
+                issuers: vec![issuer()],
+            }]
+        );
+
+        server.remove_pending_tx_by_hash(third.get_hash())?;
+        assert!(server.get_mempool_conflicts()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_first_spenders() -> anyhow::Result<()> {
+        let server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let parent = Hash([1; 32]);
+        let first = spend(parent, 0, 1);
+        let second = spend(parent, 0, 2);
+        let other_output = spend(parent, 1, 3);
+        for tx in vec![&first, &second, &other_output] {
+            server.add_pending_tx_force(tx.clone())?;
+        }
+        let hashs = vec![first.get_hash(), second.get_hash(), other_output.get_hash()];
+        let txs = execute(&server, move |dbs| {
+            let mut txs = Vec::new();
+            for hash in hashs {
+                if let Some(pending_tx) = dbs.txs_mp_db.txs().get(&HashKeyV2(hash))? {
+                    txs.push(pending_tx);
+                }
+            }
+            Ok::<_, KvError>(txs)
+        })?;
+
+        assert_eq!(
+            keep_first_spenders(txs)
+                .iter()
+                .map(|pending_tx| pending_tx.doc.get_hash())
+                .collect::<Vec<_>>(),
+            vec![first.get_hash(), other_output.get_hash()]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Export and import of the pending transactions, to move them to another node or profile.
+//!
+//! The dump is a text file: a header line, then one entry per transaction, separated by
+//! empty lines. Each entry is a `ReceivedTime: <unix timestamp>` line followed by the signed
+//! transaction in raw text format.
+
+use crate::*;
+use duniter_core::common::crypto::keys::KeyPair as _;
+use duniter_core::documents::transaction::TransactionDocumentTrait;
+use duniter_core::documents_parser::prelude::*;
+use std::io::Write as _;
+
+const DUMP_HEADER: &str = "DuniterMempoolDump: 1";
+const RECEIVED_TIME_PREFIX: &str = "ReceivedTime: ";
+
+/// Result of a mempool import.
+#[derive(Clone, Debug, Default)]
+pub struct MempoolImport {
+    pub accepted: usize,
+    /// Transactions refused by the mempool
+    pub rejected: Vec<(Hash, TxRejection)>,
+}
+
+fn write_dump<W: std::io::Write>(mut w: W, txs: &[(i64, String)]) -> std::io::Result<()> {
+    writeln!(w, "{}", DUMP_HEADER)?;
+    for (received_time, raw_tx) in txs {
+        writeln!(w)?;
+        writeln!(w, "{}{}", RECEIVED_TIME_PREFIX, received_time)?;
+        write!(w, "{}", raw_tx)?;
+        if !raw_tx.ends_with('\n') {
+            writeln!(w)?;
+        }
+    }
+    w.flush()
+}
+
+fn parse_dump(dump: &str) -> anyhow::Result<Vec<(i64, String)>> {
+    let mut entries = dump.split("\n\n");
+    if entries.next().map(str::trim_end) != Some(DUMP_HEADER) {
+        return Err(anyhow::anyhow!("not a mempool dump"));
+    }
+    entries
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(2, '\n');
+            let received_time = parts
+                .next()
+                .and_then(|line| line.strip_prefix(RECEIVED_TIME_PREFIX))
+                .ok_or_else(|| anyhow::anyhow!("missing received time"))?
+                .parse()
+                .context("invalid received time")?;
+            let raw_tx = parts
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("missing transaction"))?;
+            Ok((received_time, format!("{}\n", raw_tx.trim_end())))
+        })
+        .collect()
+}
+
+/// Pending transactions in signed raw format, sorted by received time
+fn get_raw_pending_txs<TxsMpDb: TxsMpV2DbReadable>(
+    txs_mp_db: &TxsMpDb,
+) -> KvResult<Vec<(i64, String)>> {
+    txs_mp_db.txs_by_received_time().iter(.., |it| {
+        let mut txs = Vec::new();
+        for entry_res in it {
+            let (received_time, hashs) = entry_res?;
+            for hash in hashs.0 {
+                if let Some(pending_tx) = txs_mp_db.txs().get(&hash)? {
+                    txs.push((received_time.0, pending_tx.doc.as_signed_text()));
+                }
+            }
+        }
+        Ok::<_, KvError>(txs)
+    })
+}
+
+impl DuniterServer {
+    /// Write the pending transactions in the file at `path`, return the number of exported
+    /// transactions.
+    pub fn export_mempool(&self, path: &Path) -> anyhow::Result<usize> {
+        let txs = self
+            .dbs_pool
+            .execute(|dbs| get_raw_pending_txs(&dbs.txs_mp_db))
+            .expect("dbs pool disconnected")?;
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Fail to create {}", path.display()))?;
+        write_dump(std::io::BufWriter::new(file), &txs)?;
+        Ok(txs.len())
+    }
+    /// Add the transactions of a dump written by `export_mempool` to the mempool. Each
+    /// transaction is checked like a new one, in received time order, and keeps its received
+    /// time.
+    pub fn import_mempool(&self, path: &Path) -> anyhow::Result<MempoolImport> {
+        let dump = std::fs::read_to_string(path)
+            .with_context(|| format!("Fail to read {}", path.display()))?;
+        let server_pubkey = self.conf.self_key_pair.public_key();
+        let mut import = MempoolImport::default();
+        for (received_time, raw_tx) in parse_dump(&dump)? {
+            let tx = TransactionDocumentV10::parse_from_raw_text(&raw_tx)
+                .with_context(|| format!("Invalid transaction in dump:\n{}", raw_tx))?;
+            let hash = tx.get_hash();
+            match self.add_pending_tx_received_at(tx, server_pubkey, received_time)? {
+                Ok(()) => import.accepted += 1,
+                Err(rejection) => import.rejected.push((hash, rejection)),
+            }
+        }
+        Ok(import)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{empty_signed_tx, keypair};
+
+    fn create_tmp_dir(name: &str) -> std::io::Result<PathBuf> {
+        let tmp_dir = std::env::temp_dir().join(format!(
+            "duniter-mempool-dump-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&tmp_dir)?;
+        Ok(tmp_dir)
+    }
+
+    #[test]
+    fn test_import_keeps_received_time() -> anyhow::Result<()> {
+        let server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let tmp_dir = create_tmp_dir("import")?;
+        let tx = empty_signed_tx(&keypair());
+        let received_time = crate::wot_mempools::now() - 3_600;
+        let dump_path = tmp_dir.join("mempool.dump");
+        let mut dump = Vec::new();
+        write_dump(&mut dump, &[(received_time, tx.as_signed_text())])?;
+        std::fs::write(&dump_path, dump)?;
+
+        let import = server.import_mempool(&dump_path)?;
+        assert_eq!(import.accepted, 1);
+        assert!(import.rejected.is_empty());
+
+        let export_path = tmp_dir.join("export.dump");
+        assert_eq!(server.export_mempool(&export_path)?, 1);
+        assert_eq!(
+            parse_dump(&std::fs::read_to_string(&export_path)?)?,
+            vec![(received_time, tx.as_signed_text())]
+        );
+
+        // Already pending
+        let import = server.import_mempool(&dump_path)?;
+        assert_eq!(import.accepted, 0);
+        assert_eq!(
+            import.rejected,
+            vec![(tx.get_hash(), TxRejection::Duplicate)]
+        );
+
+        std::fs::write(&dump_path, "Version: 10\n")?;
+        assert!(server.import_mempool(&dump_path).is_err());
+
+        std::fs::remove_dir_all(tmp_dir)?;
+        Ok(())
+    }
+}
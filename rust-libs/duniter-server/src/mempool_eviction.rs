@@ -0,0 +1,397 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::*;
+use duniter_core::dbs::{HashKeyV2, U32BE};
+use duniter_core::documents::transaction::TransactionDocumentTrait;
+use std::collections::{HashMap, HashSet};
+
+/// Which pending transaction to evict to make room for a new one.
+///
+/// `Oldest` and `ClosestToExpiry` only evict when the mempool is full, and do not care about
+/// issuers: an issuer filling the mempool evicts the transactions of everyone else.
+/// `MaxTxsPerIssuer` bounds the room taken by each public key, but an issuer spreading its
+/// transactions over fresh keys gets around it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MempoolEvictionPolicy {
+    /// Nothing is evicted, new transactions are refused when the mempool is full
+    None,
+    /// Evict the oldest received transaction
+    Oldest,
+    /// Evict the transaction whose blockstamp is the closest to expiry
+    ClosestToExpiry,
+    /// An issuer has at most this number of pending transactions, beyond it its oldest
+    /// transaction is evicted. When the mempool is full, the oldest transaction of the
+    /// issuer with the most pending transactions is evicted.
+    MaxTxsPerIssuer(usize),
+}
+
+impl Default for MempoolEvictionPolicy {
+    fn default() -> Self {
+        MempoolEvictionPolicy::None
+    }
+}
+
+impl std::str::FromStr for MempoolEvictionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(MempoolEvictionPolicy::None),
+            "oldest" => Ok(MempoolEvictionPolicy::Oldest),
+            "closest-to-expiry" => Ok(MempoolEvictionPolicy::ClosestToExpiry),
+            _ => {
+                if let Some(max) = s.strip_prefix("max-per-issuer:") {
+                    Ok(MempoolEvictionPolicy::MaxTxsPerIssuer(
+                        max.parse()
+                            .with_context(|| format!("Invalid mempool eviction policy: {}", s))?,
+                    ))
+                } else {
+                    Err(anyhow::anyhow!("Invalid mempool eviction policy: {}", s))
+                }
+            }
+        }
+    }
+}
+
+/// Choose the pending transaction to evict before accepting `tx`, if any.
+/// Transactions issued by the server never cause an eviction, because they are always accepted.
+///
+/// Evicting a transaction also drops its descendants, so the ancestors of `tx` are never
+/// chosen.
+pub(crate) fn choose_tx_to_evict<BcDb: BcV2DbReadable, TxsMpDb: TxsMpV2DbReadable>(
+    bc_db: &BcDb,
+    txs_mp_db: &TxsMpDb,
+    policy: MempoolEvictionPolicy,
+    max_size: usize,
+    server_pubkey: PublicKey,
+    tx: &TransactionDocumentV10,
+) -> KvResult<Option<Hash>> {
+    let issuers = tx.issuers();
+    if issuers.contains(&server_pubkey) {
+        return Ok(None);
+    }
+    let is_full = txs_mp_db.txs().count()? >= max_size;
+    if policy == MempoolEvictionPolicy::None {
+        return Ok(None);
+    }
+    let ancestors = crate::pending_txs_graph::pending_ancestors(txs_mp_db, tx)?;
+    match policy {
+        MempoolEvictionPolicy::None => Ok(None),
+        MempoolEvictionPolicy::Oldest if is_full => {
+            oldest_tx(txs_mp_db, |hash| !ancestors.contains(hash))
+        }
+        MempoolEvictionPolicy::ClosestToExpiry if is_full => {
+            closest_to_expiry_tx(bc_db, txs_mp_db, &ancestors)
+        }
+        MempoolEvictionPolicy::MaxTxsPerIssuer(max_txs_per_issuer) => {
+            for issuer in issuers.iter() {
+                if let Some(issuer_txs) = txs_mp_db.txs_by_issuer().get(&PubKeyKeyV2(*issuer))? {
+                    if issuer_txs.0.len() >= max_txs_per_issuer {
+                        return oldest_tx(txs_mp_db, |hash| {
+                            issuer_txs.0.contains(&HashKeyV2(*hash)) && !ancestors.contains(hash)
+                        });
+                    }
+                }
+            }
+            if is_full {
+                let biggest_issuer_txs_opt = txs_mp_db.txs_by_issuer().iter(.., |it| {
+                    it.values().try_fold(None, |biggest_opt, issuer_txs_res| {
+                        let issuer_txs = issuer_txs_res?;
+                        Ok::<_, KvError>(match biggest_opt {
+                            Some(biggest) if issuer_txs.0.len() <= biggest.0.len() => Some(biggest),
+                            _ => Some(issuer_txs),
+                        })
+                    })
+                })?;
+                if let Some(biggest_issuer_txs) = biggest_issuer_txs_opt {
+                    return oldest_tx(txs_mp_db, |hash| {
+                        biggest_issuer_txs.0.contains(&HashKeyV2(*hash))
+                            && !ancestors.contains(hash)
+                    });
+                }
+            }
+            Ok(None)
+        }
+        MempoolEvictionPolicy::Oldest | MempoolEvictionPolicy::ClosestToExpiry => Ok(None),
+    }
+}
+
+/// Oldest received transaction among those matching `filter`
+fn oldest_tx<TxsMpDb: TxsMpV2DbReadable, F: Fn(&Hash) -> bool>(
+    txs_mp_db: &TxsMpDb,
+    filter: F,
+) -> KvResult<Option<Hash>> {
+    txs_mp_db.txs_by_received_time().iter(.., |it| {
+        for hashs_res in it.values() {
+            for HashKeyV2(hash) in hashs_res?.0 {
+                if filter(&hash) {
+                    return Ok(Some(hash));
+                }
+            }
+        }
+        Ok(None)
+    })
+}
+
+/// Transaction whose blockstamp is the oldest, except `excluded` ones. Transactions whose
+/// blockstamp is no longer in the chain are already expired, so they come first.
+fn closest_to_expiry_tx<BcDb: BcV2DbReadable, TxsMpDb: TxsMpV2DbReadable>(
+    bc_db: &BcDb,
+    txs_mp_db: &TxsMpDb,
+    excluded: &HashSet<Hash>,
+) -> KvResult<Option<Hash>> {
+    let mut blocks_times = HashMap::new();
+    txs_mp_db.txs().iter(.., |it| {
+        let mut closest_opt: Option<(u64, Hash)> = None;
+        for entry_res in it {
+            let (HashKeyV2(hash), pending_tx) = entry_res?;
+            if excluded.contains(&hash) {
+                continue;
+            }
+            let blockstamp = pending_tx.doc.blockstamp();
+            let block_time = if let Some(block_time) = blocks_times.get(&blockstamp) {
+                *block_time
+            } else {
+                let block_time = bc_db
+                    .blocks_meta()
+                    .get(&U32BE(blockstamp.number.0))?
+                    .filter(|block_meta| block_meta.hash == blockstamp.hash.0)
+                    .map(|block_meta| block_meta.median_time)
+                    .unwrap_or(0);
+                blocks_times.insert(blockstamp, block_time);
+                block_time
+            };
+            if closest_opt.map_or(true, |closest| (block_time, hash) < closest) {
+                closest_opt = Some((block_time, hash));
+            }
+        }
+        Ok(closest_opt.map(|(_, hash)| hash))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        blockstamp, branch, empty_signed_tx, keypair, sig_unlock, signed_tx_at, utxo_input,
+    };
+    use duniter_core::common::crypto::keys::{ed25519::Ed25519KeyPair, KeyPair as _};
+    use duniter_core::wallet::prelude::*;
+
+    fn server(policy: MempoolEvictionPolicy, max_size: usize) -> anyhow::Result<DuniterServer> {
+        DuniterServerBuilder::new("test".to_owned())
+            .conf(DuniterCoreConf {
+                txs_mempool_size: max_size,
+                ..Default::default()
+            })
+            .duniter_mode(DuniterMode::Start)
+            .software_version(duniter_core::module::SOFTWARE_NAME)
+            .mempool_eviction_policy(policy)
+            .start()
+    }
+
+    // Transaction without inputs nor outputs, issued by `issuer`
+    fn new_tx(issuer: &Ed25519KeyPair) -> TransactionDocumentV10 {
+        empty_signed_tx(issuer)
+    }
+
+    // Pending transactions hashs of `issuer`, by received time
+    fn issuer_pending_hashs(
+        server: &DuniterServer,
+        issuer: &Ed25519KeyPair,
+    ) -> KvResult<Vec<Hash>> {
+        Ok(server
+            .get_pending_txs_page(None, 0, None, usize::MAX)?
+            .txs
+            .iter()
+            .filter(|pending_tx| pending_tx.doc.issuers().contains(&issuer.public_key()))
+            .map(|pending_tx| pending_tx.doc.get_hash())
+            .collect())
+    }
+
+    fn server_pubkey() -> PublicKey {
+        keypair().public_key()
+    }
+
+    // Pending transactions hashs, by received time
+    fn pending_hashs(server: &DuniterServer) -> KvResult<Vec<Hash>> {
+        Ok(server
+            .get_pending_txs_page(None, 0, None, usize::MAX)?
+            .txs
+            .iter()
+            .map(|pending_tx| pending_tx.doc.get_hash())
+            .collect())
+    }
+
+    fn evicted_events(events: &flume::Receiver<SequencedEvent>) -> Vec<Hash> {
+        events
+            .try_iter()
+            .filter_map(|event| match event.event {
+                ServerEvent::TxEvicted(hash) => Some(hash),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_no_eviction() -> anyhow::Result<()> {
+        let server = server(MempoolEvictionPolicy::None, 2)?;
+        let issuer = keypair();
+        for _ in 0..2 {
+            assert_eq!(
+                server.add_pending_tx(new_tx(&issuer), server_pubkey())?,
+                Ok(())
+            );
+        }
+
+        let tx = new_tx(&issuer);
+        assert_eq!(
+            server.accept_new_tx(tx.clone(), server_pubkey())?,
+            Err(TxRejection::MempoolFull)
+        );
+        assert_eq!(
+            server.add_pending_tx(tx, server_pubkey())?,
+            Err(TxRejection::MempoolFull)
+        );
+        assert_eq!(pending_hashs(&server)?.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_evict_oldest() -> anyhow::Result<()> {
+        let server = server(MempoolEvictionPolicy::Oldest, 2)?;
+        let events = server.subscribe_events();
+        for _ in 0..2 {
+            assert_eq!(
+                server.add_pending_tx(new_tx(&keypair()), server_pubkey())?,
+                Ok(())
+            );
+        }
+        let before = pending_hashs(&server)?;
+
+        // Checking a transaction does not evict anything
+        let tx = new_tx(&keypair());
+        assert_eq!(server.accept_new_tx(tx.clone(), server_pubkey())?, Ok(()));
+        assert_eq!(pending_hashs(&server)?, before);
+        assert!(evicted_events(&events).is_empty());
+
+        assert_eq!(server.add_pending_tx(tx.clone(), server_pubkey())?, Ok(()));
+        let mut pending = pending_hashs(&server)?;
+        pending.sort();
+        let mut expected = vec![before[1], tx.get_hash()];
+        expected.sort();
+        assert_eq!(pending, expected);
+        assert_eq!(evicted_events(&events), vec![before[0]]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_evict_closest_to_expiry() -> anyhow::Result<()> {
+        let mut server = server(MempoolEvictionPolicy::ClosestToExpiry, 3)?;
+        server.apply_chunk_of_blocks(branch(0, 0..3, None))?;
+        let events = server.subscribe_events();
+
+        // The closest to expiry transaction has a pending child, evicted with it
+        let recipient = keypair();
+        let parent = signed_tx_at(
+            &keypair(),
+            blockstamp(0, 0),
+            &[],
+            &[],
+            vec![WalletScriptV10::single(WalletConditionV10::Sig(
+                recipient.public_key(),
+            ))],
+        );
+        let child = signed_tx_at(
+            &recipient,
+            blockstamp(0, 2),
+            &[utxo_input(parent.get_hash(), 0)],
+            &[sig_unlock(0)],
+            vec![],
+        );
+        let other = signed_tx_at(&keypair(), blockstamp(0, 1), &[], &[], vec![]);
+        for tx in vec![parent.clone(), child.clone(), other.clone()] {
+            server.add_pending_tx_force(tx)?;
+        }
+
+        let tx = signed_tx_at(&keypair(), blockstamp(0, 2), &[], &[], vec![]);
+        assert_eq!(server.add_pending_tx(tx.clone(), server_pubkey())?, Ok(()));
+
+        let mut pending = pending_hashs(&server)?;
+        pending.sort();
+        let mut expected = vec![other.get_hash(), tx.get_hash()];
+        expected.sort();
+        assert_eq!(pending, expected);
+        let mut evicted = evicted_events(&events);
+        evicted.sort();
+        let mut expected = vec![parent.get_hash(), child.get_hash()];
+        expected.sort();
+        assert_eq!(evicted, expected);
+        assert_eq!(server.get_tx_status(parent.get_hash())?, TxStatus::Evicted);
+        assert_eq!(server.get_tx_status(child.get_hash())?, TxStatus::Evicted);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_evict_max_txs_per_issuer() -> anyhow::Result<()> {
+        let server = server(MempoolEvictionPolicy::MaxTxsPerIssuer(2), 4)?;
+        let events = server.subscribe_events();
+        let (issuer1, issuer2) = (keypair(), keypair());
+        for issuer in &[&issuer1, &issuer1, &issuer2] {
+            assert_eq!(
+                server.add_pending_tx(new_tx(issuer), server_pubkey())?,
+                Ok(())
+            );
+        }
+        let issuer1_txs = issuer_pending_hashs(&server, &issuer1)?;
+        let issuer2_txs = issuer_pending_hashs(&server, &issuer2)?;
+
+        // The issuer already has 2 pending transactions, its oldest one is evicted although
+        // the mempool is not full
+        let tx = new_tx(&issuer1);
+        assert_eq!(server.add_pending_tx(tx.clone(), server_pubkey())?, Ok(()));
+        assert_eq!(evicted_events(&events), vec![issuer1_txs[0]]);
+        let mut pending = issuer_pending_hashs(&server, &issuer1)?;
+        pending.sort();
+        let mut expected = vec![issuer1_txs[1], tx.get_hash()];
+        expected.sort();
+        assert_eq!(pending, expected);
+
+        // When the mempool is full, the oldest transaction of the biggest issuer is evicted
+        assert_eq!(
+            server.add_pending_tx(new_tx(&keypair()), server_pubkey())?,
+            Ok(())
+        );
+        assert!(evicted_events(&events).is_empty());
+        let issuer1_txs = issuer_pending_hashs(&server, &issuer1)?;
+        assert_eq!(
+            server.add_pending_tx(new_tx(&keypair()), server_pubkey())?,
+            Ok(())
+        );
+        assert_eq!(evicted_events(&events), vec![issuer1_txs[0]]);
+        assert_eq!(pending_hashs(&server)?.len(), 4);
+        assert_eq!(
+            issuer_pending_hashs(&server, &issuer1)?,
+            vec![issuer1_txs[1]]
+        );
+        assert_eq!(issuer_pending_hashs(&server, &issuer2)?, issuer2_txs);
+
+        Ok(())
+    }
+}
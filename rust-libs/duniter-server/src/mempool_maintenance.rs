@@ -0,0 +1,293 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Background task keeping the mempools clean: expired documents are trimmed, and pending
+//! transactions whose sources are no longer available are dropped.
+//!
+//! The task runs periodically, and also after each applied or reverted block, because
+//! applied blocks consume sources and reverted blocks remove the sources they created.
+//! Documents referencing a reverted block are dropped too, they can no longer be written.
+
+use crate::tx_status::{record_dropped_txs, TxDropReason};
+use crate::*;
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// Configuration of the mempools maintenance task.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MempoolMaintenanceConf {
+    /// Maximum time between two maintenances
+    pub interval: Duration,
+    /// Pending transactions received for longer than this are trimmed
+    pub tx_max_age: Duration,
+    /// Pending wot documents received for longer than this are trimmed
+    pub wot_doc_max_age: Duration,
+}
+
+impl Default for MempoolMaintenanceConf {
+    fn default() -> Self {
+        MempoolMaintenanceConf {
+            interval: Duration::from_secs(60),
+            tx_max_age: Duration::from_secs(604_800),
+            wot_doc_max_age: Duration::from_secs(5_259_600),
+        }
+    }
+}
+
+pub(crate) struct MempoolMaintenance {
+    pub(crate) conf: MempoolMaintenanceConf,
+    pub(crate) dbs_pool: dbs_pool::DbsPoolAsync,
+    pub(crate) events_recv: flume::Receiver<SequencedEvent>,
+    pub(crate) shutdown_recv: flume::Receiver<()>,
+}
+
+impl MempoolMaintenance {
+    /// Run until the server shutdown
+    pub(crate) async fn run(self) {
+        loop {
+            let wake_up = tokio::time::timeout(self.conf.interval, self.wait_block_event());
+            let reverted = match crate::until_shutdown(wake_up, &self.shutdown_recv).await {
+                None | Some(Ok(Err(_))) => break,
+                Some(Ok(Ok(reverted))) => reverted,
+                Some(Err(_elapsed)) => HashSet::new(),
+            };
+            match self.maintain(reverted).await {
+                Ok(removed_txs) if removed_txs > 0 => {
+                    log::info!("mempool maintenance: {} pending txs removed", removed_txs)
+                }
+                Ok(_) => (),
+                Err(e) => log::error!("mempool maintenance: {}", e),
+            }
+        }
+        log::info!("Mempool maintenance task stopped.");
+    }
+    /// Wait for an applied or reverted block, return the blockstamps of the blocks reverted
+    /// since the last maintenance.
+    async fn wait_block_event(&self) -> Result<HashSet<Blockstamp>, flume::RecvError> {
+        let mut reverted = HashSet::new();
+        loop {
+            match self.events_recv.recv_async().await?.event {
+                ServerEvent::BlockApplied(_) => break,
+                ServerEvent::BlockReverted(blockstamp) => {
+                    reverted.insert(blockstamp);
+                    break;
+                }
+                _ => (),
+            }
+        }
+        // Blocks reverted during the previous maintenance
+        for event in self.events_recv.try_iter() {
+            if let ServerEvent::BlockReverted(blockstamp) = event.event {
+                reverted.insert(blockstamp);
+            }
+        }
+        Ok(reverted)
+    }
+    /// Return the number of removed pending transactions
+    async fn maintain(&self, reverted: HashSet<Blockstamp>) -> KvResult<usize> {
+        let now = crate::wot_mempools::now();
+        let txs_limit_time = now - self.conf.tx_max_age.as_secs() as i64;
+        let wot_limit_time = now - self.conf.wot_doc_max_age.as_secs() as i64;
+        Ok(self
+            .dbs_pool
+            .execute(move |dbs| maintain_mempools(dbs, &reverted, txs_limit_time, wot_limit_time))
+            .await
+            .expect("dbs pool disconnected")?)
+    }
+}
+
+/// Remove from the mempools the documents that can no longer be written, and the expired
+/// ones. `reverted` are the blockstamps of the blocks reverted since the last maintenance.
+/// Return the number of removed pending transactions.
+fn maintain_mempools(
+    dbs: &dbs_pool::PoolDbs<'_>,
+    reverted: &HashSet<Blockstamp>,
+    txs_limit_time: i64,
+    wot_limit_time: i64,
+) -> KvResult<usize> {
+    let txs_dropped_db = &dbs.server.txs_dropped_db;
+    let txs_count = dbs.txs_mp_db.txs().count()?;
+    if !reverted.is_empty() {
+        // Documents referencing a reverted block
+        let invalid = crate::pending_txs_graph::remove_txs_by_blockstamps(
+            &dbs.txs_mp_db,
+            &dbs.server.txs_mp_index,
+            reverted,
+        )?;
+        record_dropped_txs(txs_dropped_db, TxDropReason::Evicted, &invalid)?;
+        crate::wot_mempools::remove_docs_by_blockstamps(&dbs.server.wot_mp_db, reverted)?;
+    }
+    // Sources consumed or removed by a block, then expired transactions
+    let orphans = crate::pending_txs_graph::remove_orphan_txs(
+        &dbs.bc_db_ro,
+        &dbs.txs_mp_db,
+        &dbs.server.txs_mp_index,
+    )?;
+    record_dropped_txs(txs_dropped_db, TxDropReason::Evicted, &orphans)?;
+    drop_expired_txs(dbs, txs_limit_time)?;
+    crate::tx_status::trim_dropped_txs(txs_dropped_db, txs_limit_time)?;
+    crate::wot_mempools::trim_expired_docs(&dbs.server.wot_mp_db, wot_limit_time)?;
+    Ok(txs_count.saturating_sub(dbs.txs_mp_db.txs().count()?))
+}
+
+/// Remove the pending transactions received before `limit_time`, recorded as expired, then
+/// the pending transactions depending on them, recorded as evicted because their sources are
+/// no longer available.
+pub(crate) fn drop_expired_txs(dbs: &dbs_pool::PoolDbs<'_>, limit_time: i64) -> KvResult<()> {
+    let expired = crate::pending_txs_graph::remove_expired_txs(&dbs.txs_mp_db, limit_time)?;
+    record_dropped_txs(&dbs.server.txs_dropped_db, TxDropReason::Expired, &expired)?;
+    let descendants = crate::pending_txs_graph::remove_with_descendants(
+        &dbs.txs_mp_db,
+        &dbs.server.txs_mp_index,
+        expired,
+    )?;
+    record_dropped_txs(
+        &dbs.server.txs_dropped_db,
+        TxDropReason::Evicted,
+        &descendants,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        block, block_hash, blockstamp, branch, keypair, sig_unlock, spending_tx_at, tx_at,
+        utxo_input,
+    };
+    use crate::tx_status::TxStatus;
+    use duniter_core::common::crypto::keys::KeyPair as _;
+    use duniter_core::documents::identity::IdentityDocumentV10Builder;
+    use duniter_core::wallet::prelude::*;
+
+    fn server() -> anyhow::Result<DuniterServer> {
+        DuniterServerBuilder::new("test".to_owned())
+            .duniter_mode(DuniterMode::Start)
+            .software_version(duniter_core::module::SOFTWARE_NAME)
+            .without_mempool_maintenance()
+            .start()
+    }
+
+    fn script() -> WalletScriptV10 {
+        WalletScriptV10::single(WalletConditionV10::Sig(keypair().public_key()))
+    }
+
+    fn maintain(
+        server: &DuniterServer,
+        reverted: HashSet<Blockstamp>,
+        txs_limit_time: i64,
+    ) -> KvResult<usize> {
+        server
+            .dbs_pool
+            .execute(move |dbs| maintain_mempools(dbs, &reverted, txs_limit_time, 0))
+            .expect("dbs pool disconnected")
+    }
+
+    fn is_pending(status: TxStatus) -> bool {
+        matches!(status, TxStatus::Pending { .. })
+    }
+
+    #[test]
+    fn test_expired_txs_descendants_are_evicted() -> anyhow::Result<()> {
+        let server = server()?;
+        let expired = tx_at(Blockstamp::default(), 0, vec![script()]);
+        server.add_pending_tx_force(expired.clone())?;
+        // Received times are in seconds
+        std::thread::sleep(Duration::from_secs(1));
+        let limit_time = crate::wot_mempools::now();
+        let child = spending_tx_at(
+            Blockstamp::default(),
+            &[utxo_input(expired.get_hash(), 0)],
+            &[sig_unlock(0)],
+            vec![script()],
+        );
+        let other = tx_at(Blockstamp::default(), 0, vec![script()]);
+        server.add_pending_tx_force(child.clone())?;
+        server.add_pending_tx_force(other.clone())?;
+
+        assert_eq!(maintain(&server, HashSet::new(), limit_time)?, 2);
+        assert_eq!(server.get_tx_status(expired.get_hash())?, TxStatus::Expired);
+        assert_eq!(server.get_tx_status(child.get_hash())?, TxStatus::Evicted);
+        assert!(is_pending(server.get_tx_status(other.get_hash())?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_orphan_txs_are_evicted() -> anyhow::Result<()> {
+        let server = server()?;
+        // Consumes the output of a transaction that is neither pending nor written
+        let orphan = spending_tx_at(
+            Blockstamp::default(),
+            &[utxo_input(Hash::default(), 0)],
+            &[sig_unlock(0)],
+            vec![script()],
+        );
+        let other = tx_at(Blockstamp::default(), 0, vec![script()]);
+        server.add_pending_tx_force(orphan.clone())?;
+        server.add_pending_tx_force(other.clone())?;
+
+        assert_eq!(maintain(&server, HashSet::new(), 0)?, 1);
+        assert_eq!(server.get_tx_status(orphan.get_hash())?, TxStatus::Evicted);
+        assert!(is_pending(server.get_tx_status(other.get_hash())?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_docs_of_reverted_blocks_are_dropped() -> anyhow::Result<()> {
+        let mut server = server()?;
+        server.apply_chunk_of_blocks(branch(0, 0..3, None))?;
+        let reverted_tx = tx_at(blockstamp(0, 2), 0, vec![script()]);
+        // Its own blockstamp is still in the chain, but it depends on a dropped transaction
+        let child = spending_tx_at(
+            blockstamp(0, 1),
+            &[utxo_input(reverted_tx.get_hash(), 0)],
+            &[sig_unlock(0)],
+            vec![script()],
+        );
+        let other = tx_at(blockstamp(0, 1), 0, vec![script()]);
+        for tx in vec![reverted_tx.clone(), child.clone(), other.clone()] {
+            server.add_pending_tx_force(tx)?;
+        }
+        let issuer = keypair();
+        let idty = WotDocument::Identity(
+            IdentityDocumentV10Builder {
+                currency: "test",
+                username: "alice",
+                blockstamp: &blockstamp(0, 2),
+                issuer: issuer.public_key(),
+            }
+            .build_and_sign(vec![issuer.generate_signator()]),
+        );
+        assert_eq!(server.accept_new_wot_doc(idty)?, Ok(()));
+
+        server.revert_block(block(0, 2, Some(block_hash(0, 1))))?;
+        let reverted: HashSet<_> = std::iter::once(blockstamp(0, 2)).collect();
+
+        assert_eq!(maintain(&server, reverted, 0)?, 2);
+        assert_eq!(
+            server.get_tx_status(reverted_tx.get_hash())?,
+            TxStatus::Evicted
+        );
+        assert_eq!(server.get_tx_status(child.get_hash())?, TxStatus::Evicted);
+        assert!(is_pending(server.get_tx_status(other.get_hash())?));
+        assert!(server
+            .get_pending_wot_docs(WotDocKind::Identity)?
+            .is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,392 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Server metrics, served in Prometheus text format.
+
+use crate::dbs_pool::QueuedJobs;
+use crate::*;
+use duniter_core::dbs::databases::network_v1::NetworkV1DbReadable;
+use std::fmt::Write as _;
+use std::io::{ErrorKind, Read as _, Write as _};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const SCRAPE_IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Metrics(Arc<MetricsInner>);
+
+#[derive(Debug, Default)]
+struct MetricsInner {
+    apply_block_duration: Histogram,
+    apply_chunk_duration: Histogram,
+    blocks_applied: AtomicU64,
+    blocks_reverted: AtomicU64,
+    dbs_pool_queued_jobs: QueuedJobs,
+    txs_evicted: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn block_applied(&self) {
+        self.0.blocks_applied.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn block_reverted(&self) {
+        self.0.blocks_reverted.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn tx_evicted(&self) {
+        self.0.txs_evicted.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn observe_apply_block(&self, start: Instant) {
+        self.0.apply_block_duration.observe(start.elapsed());
+    }
+    pub(crate) fn observe_apply_chunk(&self, start: Instant) {
+        self.0.apply_chunk_duration.observe(start.elapsed());
+    }
+    pub(crate) fn dbs_pool_queued_jobs(&self) -> QueuedJobs {
+        self.0.dbs_pool_queued_jobs.clone()
+    }
+}
+
+/// Latency histogram, with one (non cumulative) counter per bucket plus the `+Inf` bucket.
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let index = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            if let Some(bound) = LATENCY_BUCKETS.get(i) {
+                let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+            } else {
+                let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+            }
+        }
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+    }
+}
+
+/// Everything needed to render metrics, so that they can be served from another thread.
+#[derive(Clone)]
+pub(crate) struct MetricsRenderer {
+    pub(crate) metrics: Metrics,
+    pub(crate) profile_path_opt: Option<PathBuf>,
+    pub(crate) shared_dbs: SharedDbs<FileBackend>,
+    pub(crate) txs_mempool: TxsMempool,
+}
+
+impl MetricsRenderer {
+    /// Databases are read directly rather than through the dbs pool, so that metrics can
+    /// still be scraped when the pool is saturated.
+    pub(crate) fn render(&self) -> KvResult<String> {
+        let metrics = &self.metrics.0;
+        let mut out = String::new();
+
+        render_counter(
+            &mut out,
+            "duniter_blocks_applied_total",
+            "Number of blocks applied.",
+            metrics.blocks_applied.load(Ordering::Relaxed),
+        );
+        render_counter(
+            &mut out,
+            "duniter_blocks_reverted_total",
+            "Number of blocks reverted.",
+            metrics.blocks_reverted.load(Ordering::Relaxed),
+        );
+        metrics.apply_block_duration.render(
+            &mut out,
+            "duniter_apply_block_duration_seconds",
+            "Duration of apply_block calls.",
+        );
+        metrics.apply_chunk_duration.render(
+            &mut out,
+            "duniter_apply_chunk_of_blocks_duration_seconds",
+            "Duration of apply_chunk_of_blocks calls.",
+        );
+        render_gauge(
+            &mut out,
+            "duniter_mempool_txs",
+            "Number of pending transactions in the mempool.",
+            self.shared_dbs.txs_mp_db.txs().count()? as u64,
+        );
+        render_gauge(
+            &mut out,
+            "duniter_mempool_txs_free_rooms",
+            "Number of transactions the mempool can still accept.",
+            self.txs_mempool
+                .get_free_rooms(&self.shared_dbs.txs_mp_db)? as u64,
+        );
+        render_counter(
+            &mut out,
+            "duniter_mempool_txs_evicted_total",
+            "Number of pending transactions evicted from the mempool.",
+            metrics.txs_evicted.load(Ordering::Relaxed),
+        );
+        render_gauge(
+            &mut out,
+            "duniter_peers",
+            "Number of known peers.",
+            self.shared_dbs.dunp_db.peers_old().count()? as u64,
+        );
+        render_gauge(
+            &mut out,
+            "duniter_heads",
+            "Number of known HEADs.",
+            self.shared_dbs.dunp_db.heads_old().count()? as u64,
+        );
+        render_gauge(
+            &mut out,
+            "duniter_dbs_pool_queued_jobs",
+            "Number of jobs waiting for a dbs pool worker.",
+            metrics.dbs_pool_queued_jobs.get(),
+        );
+
+        if let Some(ref profile_path) = self.profile_path_opt {
+            let _ = writeln!(
+                out,
+                "# HELP duniter_db_size_bytes Size of sled databases on disk."
+            );
+            let _ = writeln!(out, "# TYPE duniter_db_size_bytes gauge");
+            for (db_name, size) in sled_dbs_sizes(&profile_path.join("data")) {
+                let _ = writeln!(out, "duniter_db_size_bytes{{db=\"{}\"}} {}", db_name, size);
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn render_gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Size of each `<name>_sled` directory found in `data_path`, by name
+fn sled_dbs_sizes(data_path: &Path) -> BTreeMap<String, u64> {
+    let mut sizes = BTreeMap::new();
+    if let Ok(entries) = std::fs::read_dir(data_path) {
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(db_name) = file_name.strip_suffix("_sled") {
+                sizes.insert(db_name.to_owned(), dir_size(&entry.path()));
+            }
+        }
+    }
+    sizes
+}
+
+fn dir_size(path: &Path) -> u64 {
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| match entry.metadata() {
+                    Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+                    Ok(metadata) => metadata.len(),
+                    Err(_) => 0,
+                })
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Serve metrics on `address` until server shutdown.
+pub(crate) fn serve_metrics(
+    address: SocketAddr,
+    renderer: MetricsRenderer,
+    shutdown_recv: flume::Receiver<()>,
+) -> anyhow::Result<std::thread::JoinHandle<()>> {
+    let listener = TcpListener::bind(address)
+        .with_context(|| format!("Fail to bind metrics address {}", address))?;
+    listener.set_nonblocking(true)?;
+    log::info!("serve metrics on http://{}/metrics", address);
+
+    std::thread::Builder::new()
+        .name("duniter-metrics".to_owned())
+        .spawn(move || loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = answer_scrape(stream, &renderer) {
+                        log::warn!("Fail to serve metrics: {}", e);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    if let Err(flume::RecvTimeoutError::Disconnected) =
+                        shutdown_recv.recv_timeout(SHUTDOWN_POLL_INTERVAL)
+                    {
+                        break;
+                    }
+                }
+                Err(e) => log::warn!("Fail to accept metrics connection: {}", e),
+            }
+        })
+        .context("Fail to spawn metrics thread")
+}
+
+fn answer_scrape(mut stream: TcpStream, renderer: &MetricsRenderer) -> anyhow::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(SCRAPE_IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_IO_TIMEOUT))?;
+
+    // Any request path is answered with metrics, only the request line is needed
+    let mut request = [0u8; 1024];
+    let read = stream.read(&mut request)?;
+    let response = if request[..read].starts_with(b"GET ") {
+        match renderer.render() {
+            Ok(body) => format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                CONTENT_TYPE,
+                body.len(),
+                body
+            ),
+            Err(e) => {
+                log::error!("Fail to render metrics: {}", e);
+                "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_owned()
+            }
+        }
+    } else {
+        "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_owned()
+    };
+    stream.write_all(response.as_bytes())?;
+    Ok(())
+}
+
+impl DuniterServer {
+    /// Render server metrics in Prometheus text format.
+    pub fn render_metrics(&self) -> KvResult<String> {
+        self.metrics_renderer().render()
+    }
+    pub(crate) fn metrics_renderer(&self) -> MetricsRenderer {
+        MetricsRenderer {
+            metrics: self.metrics.clone(),
+            profile_path_opt: self.profile_path_opt.clone(),
+            shared_dbs: self.shared_dbs.clone(),
+            txs_mempool: self.txs_mempool,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_render() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(30));
+        histogram.observe(Duration::from_secs(20));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "test_duration_seconds", "Test.");
+
+        assert!(out.contains("test_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(out.contains("test_duration_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(out.contains("test_duration_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(out.contains("test_duration_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(out.contains("test_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("test_duration_seconds_sum 20.033\n"));
+        assert!(out.contains("test_duration_seconds_count 3\n"));
+    }
+
+    #[test]
+    fn test_render_metrics() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let blocks = crate::test_utils::branch(0, 0..3, None);
+        server.apply_chunk_of_blocks(blocks.clone())?;
+        server.revert_block(blocks[2].clone())?;
+
+        let out = server.render_metrics()?;
+
+        assert!(out.contains("duniter_blocks_applied_total 3\n"));
+        assert!(out.contains("duniter_blocks_reverted_total 1\n"));
+        assert!(out.contains("duniter_apply_chunk_of_blocks_duration_seconds_count 1\n"));
+        assert!(out.contains("duniter_apply_block_duration_seconds_count 0\n"));
+        assert!(out.contains("duniter_mempool_txs 0\n"));
+        assert!(out.contains(&format!(
+            "duniter_mempool_txs_free_rooms {}\n",
+            server.conf.txs_mempool_size
+        )));
+        assert!(out.contains("duniter_peers 0\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve_metrics() -> anyhow::Result<()> {
+        let server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let address = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let (shutdown_sender, shutdown_recv) = flume::bounded::<()>(1);
+        let handle = serve_metrics(address, server.metrics_renderer(), shutdown_recv)?;
+
+        let scrape = |request: &[u8]| -> anyhow::Result<String> {
+            let mut stream = TcpStream::connect(address)?;
+            stream.write_all(request)?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok(response)
+        };
+        let response = scrape(b"GET /metrics HTTP/1.1\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("duniter_blocks_applied_total 0\n"));
+        let response = scrape(b"POST /metrics HTTP/1.1\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+        // The metrics thread stops on shutdown
+        drop(shutdown_sender);
+        handle
+            .join()
+            .map_err(|_| anyhow::Error::msg("metrics thread panicked"))?;
+
+        Ok(())
+    }
+}
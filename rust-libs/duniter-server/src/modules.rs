@@ -0,0 +1,380 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::*;
+use duniter_core::global::tokio::task::JoinHandle;
+use std::future::Future;
+use std::sync::{PoisonError, RwLock};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ModuleStatus {
+    Starting,
+    Running,
+    Stopped,
+    Failed(String),
+}
+
+/// Duniter modules that failed to initialize
+#[derive(Debug)]
+pub struct ModulesStartError {
+    pub failed_modules: Vec<(&'static str, anyhow::Error)>,
+}
+
+impl std::fmt::Display for ModulesStartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Fail to start duniter modules:")?;
+        for (module_name, error) in &self.failed_modules {
+            write!(f, " module '{}': {:#};", module_name, error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ModulesStartError {}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ModulesStatus(Arc<RwLock<BTreeMap<&'static str, ModuleStatus>>>);
+
+impl ModulesStatus {
+    pub(crate) fn get(&self) -> BTreeMap<&'static str, ModuleStatus> {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+    fn set(&self, module_name: &'static str, status: ModuleStatus) {
+        self.0
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(module_name, status);
+    }
+}
+
+pub(crate) struct ModulesCtx {
+    pub(crate) conf: DuniterCoreConf,
+    pub(crate) currency: String,
+    pub(crate) dbs_pool: fast_threadpool::ThreadPoolAsyncHandler<SharedDbs<FileBackend>>,
+    pub(crate) enabled_modules: Option<Vec<String>>,
+    pub(crate) events_bus: events::EventsBus,
+    pub(crate) mempools: Mempools,
+    pub(crate) mode: DuniterMode,
+    pub(crate) profile_path_opt: Option<PathBuf>,
+    pub(crate) shutdown_recv: flume::Receiver<()>,
+    pub(crate) software_version: &'static str,
+}
+
+impl ModulesCtx {
+    fn is_enabled(&self, module_name: &str) -> bool {
+        if let Some(ref enabled_modules) = self.enabled_modules {
+            enabled_modules.iter().any(|name| name == module_name)
+        } else {
+            true
+        }
+    }
+}
+
+/// Result sent by each module task once its module is initialized
+type ModuleInit = (&'static str, anyhow::Result<Vec<Endpoint>>);
+
+/// Shared by the tasks of plugged modules
+#[derive(Clone)]
+struct ModuleTaskCtx {
+    init_sender: flume::Sender<ModuleInit>,
+    shutdown_recv: flume::Receiver<()>,
+    start_recv: flume::Receiver<()>,
+    status: ModulesStatus,
+}
+
+pub(crate) struct StartedModules {
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl StartedModules {
+    /// Wait for all modules to stop. Modules stop on server shutdown: a module stopped this
+    /// way finishes its pending databases jobs, but does not start new ones.
+    pub(crate) async fn join(self) {
+        for handle in self.handles {
+            let _ = handle.await;
+        }
+    }
+}
+
+fn module_name<M>() -> &'static str {
+    let type_name = std::any::type_name::<M>();
+    type_name.rsplit("::").next().unwrap_or(type_name)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn spawn_module<M: 'static + duniter_core::module::DuniterModule>(
+    ctx: &Arc<ModulesCtx>,
+    task_ctx: &ModuleTaskCtx,
+) -> Option<JoinHandle<()>> {
+    if ctx.is_enabled(module_name::<M>()) {
+        let ctx = Arc::clone(ctx);
+        let init = async move {
+            M::init(
+                &ctx.conf,
+                &ctx.currency,
+                &ctx.dbs_pool,
+                ctx.mempools,
+                ctx.mode,
+                ctx.profile_path_opt.as_deref(),
+                ctx.software_version,
+            )
+            .await
+        };
+        Some(spawn_module_task(
+            module_name::<M>(),
+            init,
+            M::start,
+            task_ctx.clone(),
+        ))
+    } else {
+        log::info!("Module '{}' disabled.", module_name::<M>());
+        None
+    }
+}
+
+/// Initialize a module, send the init result, then start the module once all plugged modules
+/// have been successfully initialized.
+fn spawn_module_task<M, I, S, F>(
+    module_name: &'static str,
+    init: I,
+    start: S,
+    task_ctx: ModuleTaskCtx,
+) -> JoinHandle<()>
+where
+    M: 'static + Send,
+    I: 'static + Send + Future<Output = anyhow::Result<(M, Vec<Endpoint>)>>,
+    S: 'static + Send + FnOnce(M) -> F,
+    F: 'static + Send + Future<Output = anyhow::Result<()>>,
+{
+    let ModuleTaskCtx {
+        init_sender,
+        shutdown_recv,
+        start_recv,
+        status,
+    } = task_ctx;
+    status.set(module_name, ModuleStatus::Starting);
+    tokio::spawn(async move {
+        let module = match init.await {
+            Ok((module, endpoints)) => {
+                let _ = init_sender.send((module_name, Ok(endpoints)));
+                module
+            }
+            Err(e) => {
+                status.set(module_name, ModuleStatus::Failed(format!("{:#}", e)));
+                let _ = init_sender.send((module_name, Err(e)));
+                return;
+            }
+        };
+        drop(init_sender);
+        // The start signal is never sent if another module failed to initialize
+        if start_recv.recv_async().await.is_err() {
+            status.set(module_name, ModuleStatus::Stopped);
+            return;
+        }
+        status.set(module_name, ModuleStatus::Running);
+        match crate::until_shutdown(start(module), &shutdown_recv).await {
+            None => {
+                log::info!("Module '{}' stopped.", module_name);
+                status.set(module_name, ModuleStatus::Stopped);
+            }
+            Some(Ok(())) => status.set(module_name, ModuleStatus::Stopped),
+            Some(Err(e)) => {
+                log::error!("Module '{}' failed: {:#}", module_name, e);
+                status.set(module_name, ModuleStatus::Failed(format!("{:#}", e)));
+            }
+        }
+    })
+}
+
+/// Wait for the init result of each module task, until all tasks have sent it
+async fn modules_init(
+    init_recv: flume::Receiver<ModuleInit>,
+) -> Result<Vec<Endpoint>, ModulesStartError> {
+    let mut all_endpoints = Vec::new();
+    let mut failed_modules = Vec::new();
+    while let Ok((module_name, init_res)) = init_recv.recv_async().await {
+        match init_res {
+            Ok(mut endpoints) => all_endpoints.append(&mut endpoints),
+            Err(e) => failed_modules.push((module_name, e)),
+        }
+    }
+    if failed_modules.is_empty() {
+        Ok(all_endpoints)
+    } else {
+        Err(ModulesStartError { failed_modules })
+    }
+}
+
+/// Initialize all plugged modules, then start them only if each one has been successfully
+/// initialized. A module failing after its start is reported in the modules status.
+/// Started modules must be joined in all cases: they stop on server shutdown.
+pub(crate) async fn start_modules(
+    ctx: ModulesCtx,
+    status: ModulesStatus,
+) -> (StartedModules, Result<(), ModulesStartError>) {
+    let ctx = Arc::new(ctx);
+    let (init_sender, init_recv) = flume::unbounded();
+    let (start_sender, start_recv) = flume::unbounded();
+    let task_ctx = ModuleTaskCtx {
+        init_sender,
+        shutdown_recv: ctx.shutdown_recv.clone(),
+        start_recv,
+        status,
+    };
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "x86_64")] {
+            let handles: Vec<JoinHandle<()>> = vec![spawn_module::<GvaModule>(&ctx, &task_ctx)]
+                .into_iter()
+                .flatten()
+                .collect();
+        } else {
+            let handles: Vec<JoinHandle<()>> = Vec::new();
+        }
+    }
+    drop(task_ctx);
+
+    let res = match modules_init(init_recv).await {
+        Ok(all_endpoints) => {
+            duniter_core::global::SELF_ENDPOINTS
+                .write()
+                .await
+                .replace(all_endpoints);
+            ctx.events_bus.publish(ServerEvent::SelfEndpointsChanged);
+            for _ in 0..handles.len() {
+                let _ = start_sender.send(());
+            }
+            Ok(())
+        }
+        Err(e) => Err(e),
+    };
+    (StartedModules { handles }, res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task_ctx(
+        status: &ModulesStatus,
+    ) -> (
+        ModuleTaskCtx,
+        flume::Receiver<ModuleInit>,
+        flume::Sender<()>,
+        flume::Sender<()>,
+    ) {
+        let (init_sender, init_recv) = flume::unbounded();
+        let (shutdown_sender, shutdown_recv) = flume::bounded(1);
+        let (start_sender, start_recv) = flume::unbounded();
+        let task_ctx = ModuleTaskCtx {
+            init_sender,
+            shutdown_recv,
+            start_recv,
+            status: status.clone(),
+        };
+        (task_ctx, init_recv, shutdown_sender, start_sender)
+    }
+
+    #[test]
+    fn test_module_init_failure() {
+        duniter_core::global::get_async_runtime().block_on(async {
+            let status = ModulesStatus::default();
+            let (task_ctx, init_recv, _shutdown_sender, start_sender) = task_ctx(&status);
+
+            let started_modules = StartedModules {
+                handles: vec![
+                    spawn_module_task(
+                        "BindFailure",
+                        async {
+                            Err::<((), Vec<Endpoint>), _>(anyhow::Error::msg(
+                                "address already in use",
+                            ))
+                        },
+                        |()| std::future::pending::<anyhow::Result<()>>(),
+                        task_ctx.clone(),
+                    ),
+                    spawn_module_task(
+                        "Server",
+                        async { Ok(((), Vec::new())) },
+                        |()| std::future::pending::<anyhow::Result<()>>(),
+                        task_ctx,
+                    ),
+                ],
+            };
+            let failed_modules = modules_init(init_recv)
+                .await
+                .expect_err("must fail")
+                .failed_modules;
+
+            assert_eq!(failed_modules.len(), 1);
+            assert_eq!(failed_modules[0].0, "BindFailure");
+            assert_eq!(
+                status.get().get("BindFailure"),
+                Some(&ModuleStatus::Failed("address already in use".to_owned()))
+            );
+
+            // Initialized modules are not started
+            drop(start_sender);
+            started_modules.join().await;
+            assert_eq!(status.get().get("Server"), Some(&ModuleStatus::Stopped));
+        });
+    }
+
+    #[test]
+    fn test_module_failure_after_start() {
+        duniter_core::global::get_async_runtime().block_on(async {
+            let status = ModulesStatus::default();
+            let (task_ctx, init_recv, shutdown_sender, start_sender) = task_ctx(&status);
+            let (fail_sender, fail_recv) = flume::bounded::<()>(1);
+
+            let started_modules = StartedModules {
+                handles: vec![
+                    spawn_module_task(
+                        "Failing",
+                        async { Ok(((), Vec::new())) },
+                        move |()| async move {
+                            let _ = fail_recv.recv_async().await;
+                            Err(anyhow::Error::msg("connection lost"))
+                        },
+                        task_ctx.clone(),
+                    ),
+                    spawn_module_task(
+                        "Server",
+                        async { Ok(((), Vec::new())) },
+                        |()| std::future::pending::<anyhow::Result<()>>(),
+                        task_ctx,
+                    ),
+                ],
+            };
+            assert!(modules_init(init_recv).await.is_ok());
+            start_sender.send(()).expect("start signal");
+            start_sender.send(()).expect("start signal");
+
+            // A failure occurring long after the start is still reported
+            tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+            assert_eq!(status.get().get("Failing"), Some(&ModuleStatus::Running));
+            fail_sender.send(()).expect("fail signal");
+            drop(shutdown_sender);
+            started_modules.join().await;
+            assert_eq!(
+                status.get().get("Failing"),
+                Some(&ModuleStatus::Failed("connection lost".to_owned()))
+            );
+            assert_eq!(status.get().get("Server"), Some(&ModuleStatus::Stopped));
+        });
+    }
+}
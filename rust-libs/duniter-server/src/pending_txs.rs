@@ -0,0 +1,320 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Pending transactions queries, sorted by received time.
+
+use crate::*;
+use duniter_core::dbs::{BTreeSetV2, HashKeyV2, TimestampKeyV1, U32BE};
+use duniter_core::documents::transaction::TransactionDocumentTrait;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Position in the pending transactions, sorted by received time then by hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PendingTxsCursor {
+    pub received_time: i64,
+    pub hash: Hash,
+}
+
+impl std::fmt::Display for PendingTxsCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.received_time, self.hash)
+    }
+}
+
+impl FromStr for PendingTxsCursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(received_time), Some(hash)) => Ok(PendingTxsCursor {
+                received_time: received_time.parse().context("invalid cursor time")?,
+                hash: Hash::from_hex(hash).context("invalid cursor hash")?,
+            }),
+            _ => Err(anyhow::anyhow!("invalid cursor: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PendingTxsPage {
+    pub txs: Vec<PendingTxDbV2>,
+    /// Cursor of the last transaction of the page, `None` if there is no next page
+    pub next_cursor: Option<PendingTxsCursor>,
+}
+
+/// Get at most `page_size` pending transactions received after `cursor_opt`.
+///
+/// If `blockchain_time_opt` is given, transactions whose blockstamp is unknown or outside the
+/// transaction window, or whose locktime is not reached at this time, are skipped.
+pub(crate) fn get_pending_txs_page<BcDb: BcV2DbReadable, TxsMpDb: TxsMpV2DbReadable>(
+    bc_db: &BcDb,
+    txs_mp_db: &TxsMpDb,
+    blockchain_time_opt: Option<i64>,
+    min_version: usize,
+    cursor_opt: Option<PendingTxsCursor>,
+    page_size: usize,
+) -> KvResult<PendingTxsPage> {
+    let mut page_filler = PageFiller {
+        bc_db,
+        txs_mp_db,
+        blockchain_time_opt,
+        min_version,
+        cursor_opt,
+        page_size,
+        blocks_times: HashMap::new(),
+        txs: Vec::new(),
+    };
+    if let Some(cursor) = cursor_opt {
+        txs_mp_db
+            .txs_by_received_time()
+            .iter(TimestampKeyV1(cursor.received_time).., |it| {
+                page_filler.fill(it)
+            })?;
+    } else {
+        txs_mp_db
+            .txs_by_received_time()
+            .iter(.., |it| page_filler.fill(it))?;
+    }
+    Ok(page_filler.into_page())
+}
+
+/// Get all pending transactions usable at `blockchain_time`, sorted by received time.
+pub(crate) fn get_pending_txs(
+    dbs: &SharedDbs<FileBackend>,
+    blockchain_time: i64,
+    min_version: usize,
+) -> KvResult<Vec<PendingTxDbV2>> {
+    Ok(get_pending_txs_page(
+        &dbs.bc_db_ro,
+        &dbs.txs_mp_db,
+        Some(blockchain_time),
+        min_version,
+        None,
+        usize::MAX,
+    )?
+    .txs)
+}
+
+/// Get all pending transactions usable at `blockchain_time`, each one after the pending
+/// transactions it depends on.
+pub(crate) fn get_pending_txs_by_dependencies(
+    dbs: &SharedDbs<FileBackend>,
+    blockchain_time: i64,
+    min_version: usize,
+) -> KvResult<Vec<PendingTxDbV2>> {
+    crate::pending_txs_graph::sort_by_dependencies(
+        &dbs.txs_mp_db,
+        get_pending_txs(dbs, blockchain_time, min_version)?,
+    )
+}
+
+struct PageFiller<'a, BcDb, TxsMpDb> {
+    bc_db: &'a BcDb,
+    txs_mp_db: &'a TxsMpDb,
+    blockchain_time_opt: Option<i64>,
+    min_version: usize,
+    cursor_opt: Option<PendingTxsCursor>,
+    page_size: usize,
+    // Median time of blocks referenced by transactions blockstamps (None if not in the chain)
+    blocks_times: HashMap<Blockstamp, Option<u64>>,
+    txs: Vec<(PendingTxsCursor, PendingTxDbV2)>,
+}
+
+impl<'a, BcDb: BcV2DbReadable, TxsMpDb: TxsMpV2DbReadable> PageFiller<'a, BcDb, TxsMpDb> {
+    fn fill<I>(&mut self, it: I) -> KvResult<()>
+    where
+        I: Iterator<Item = KvResult<(TimestampKeyV1, BTreeSetV2<HashKeyV2>)>>,
+    {
+        // One more transaction than the page size is needed to know if there is a next page
+        let max_txs = self.page_size.saturating_add(1);
+        for entry_res in it {
+            let (received_time, hashs) = entry_res?;
+            for HashKeyV2(hash) in hashs.0 {
+                let cursor = PendingTxsCursor {
+                    received_time: received_time.0,
+                    hash,
+                };
+                if let Some(start) = self.cursor_opt {
+                    if cursor.received_time == start.received_time && cursor.hash <= start.hash {
+                        continue;
+                    }
+                }
+                if let Some(pending_tx) = self.txs_mp_db.txs().get(&HashKeyV2(hash))? {
+                    if self.is_usable(&pending_tx.doc)? {
+                        self.txs.push((cursor, pending_tx));
+                        if self.txs.len() >= max_txs {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    fn is_usable(&mut self, tx: &TransactionDocumentV10) -> KvResult<bool> {
+        if tx.version() < self.min_version {
+            return Ok(false);
+        }
+        let blockchain_time = if let Some(blockchain_time) = self.blockchain_time_opt {
+            blockchain_time
+        } else {
+            return Ok(true);
+        };
+        if tx.get_locktime() as i64 > blockchain_time {
+            return Ok(false);
+        }
+        let blockstamp = tx.blockstamp();
+        let block_time_opt = if let Some(block_time_opt) = self.blocks_times.get(&blockstamp) {
+            *block_time_opt
+        } else {
+            let block_time_opt = self
+                .bc_db
+                .blocks_meta()
+                .get(&U32BE(blockstamp.number.0))?
+                .filter(|block_meta| block_meta.hash == blockstamp.hash.0)
+                .map(|block_meta| block_meta.median_time);
+            self.blocks_times.insert(blockstamp, block_time_opt);
+            block_time_opt
+        };
+        Ok(block_time_opt
+            .map(|block_time| {
+                blockchain_time - (block_time as i64) <= crate::tx_rejection::TX_WINDOW as i64
+            })
+            .unwrap_or(false))
+    }
+    fn into_page(mut self) -> PendingTxsPage {
+        let next_cursor = if self.txs.len() > self.page_size {
+            self.txs.truncate(self.page_size);
+            self.txs.last().map(|(cursor, _)| *cursor)
+        } else {
+            None
+        };
+        PendingTxsPage {
+            txs: self
+                .txs
+                .into_iter()
+                .map(|(_, pending_tx)| pending_tx)
+                .collect(),
+            next_cursor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{blockstamp, branch, keypair, tx_at};
+    use duniter_core::common::crypto::keys::KeyPair as _;
+    use duniter_core::wallet::prelude::*;
+
+    // Script of a new random key, to get distinct transactions
+    fn script() -> WalletScriptV10 {
+        WalletScriptV10::single(WalletConditionV10::Sig(keypair().public_key()))
+    }
+
+    fn hashs(txs: &[PendingTxDbV2]) -> Vec<Hash> {
+        txs.iter()
+            .map(|pending_tx| pending_tx.doc.get_hash())
+            .collect()
+    }
+
+    #[test]
+    fn test_get_pending_txs_filters() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        // Blocks #0 to #2, median times 1_000 to 1_020
+        server.apply_chunk_of_blocks(branch(0, 0..3, None))?;
+        let b1 = blockstamp(0, 1);
+        let unknown = blockstamp(1, 1);
+
+        let usable = tx_at(b1, 0, vec![script()]);
+        let locked = tx_at(b1, 1_015, vec![script()]);
+        let unknown_blockstamp = tx_at(unknown, 0, vec![script()]);
+        for tx in vec![usable.clone(), locked.clone(), unknown_blockstamp.clone()] {
+            server.add_pending_tx_force(tx)?;
+        }
+        let mut all = vec![
+            usable.get_hash(),
+            locked.get_hash(),
+            unknown_blockstamp.get_hash(),
+        ];
+        all.sort();
+
+        // Without blockchain time, only the version is filtered
+        let page = server.get_pending_txs_page(None, 0, None, usize::MAX)?;
+        let mut txs = hashs(&page.txs);
+        txs.sort();
+        assert_eq!(txs, all);
+        assert!(server.get_pending_txs(1_015, 11)?.is_empty());
+
+        // The locktime is reached at 1_015
+        let mut txs = hashs(&server.get_pending_txs(1_010, 10)?);
+        txs.sort();
+        assert_eq!(txs, vec![usable.get_hash()]);
+        let mut txs = hashs(&server.get_pending_txs(1_015, 10)?);
+        txs.sort();
+        let mut expected = vec![usable.get_hash(), locked.get_hash()];
+        expected.sort();
+        assert_eq!(txs, expected);
+
+        // Blockstamps older than the transaction window are skipped
+        let after_window = 1_010 + crate::tx_rejection::TX_WINDOW as i64 + 1;
+        assert!(server.get_pending_txs(after_window, 10)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_pending_txs_pages() -> anyhow::Result<()> {
+        let server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let txs: Vec<_> = (0..5)
+            .map(|_| tx_at(Blockstamp::default(), 0, vec![script()]))
+            .collect();
+        for tx in &txs {
+            server.add_pending_tx_force(tx.clone())?;
+        }
+
+        // Pages of 2 transactions, that may share the same received time
+        let mut pages = Vec::new();
+        let mut cursor_opt = None;
+        loop {
+            let page = server.get_pending_txs_page(None, 0, cursor_opt, 2)?;
+            assert!(page.txs.len() <= 2);
+            pages.push(hashs(&page.txs));
+            if let Some(cursor) = page.next_cursor {
+                assert_eq!(PendingTxsCursor::from_str(&cursor.to_string())?, cursor);
+                cursor_opt = Some(cursor);
+            } else {
+                break;
+            }
+        }
+        assert_eq!(
+            pages.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+        // Pages follow each other without gap nor duplicate
+        let mut paged: Vec<_> = pages.into_iter().flatten().collect();
+        let page = server.get_pending_txs_page(None, 0, None, 5)?;
+        assert_eq!(page.next_cursor, None);
+        assert_eq!(paged, hashs(&page.txs));
+        paged.sort();
+        let mut expected: Vec<_> = txs.iter().map(|tx| tx.get_hash()).collect();
+        expected.sort();
+        assert_eq!(paged, expected);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,473 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Dependencies between pending transactions: a transaction depends on the pending
+//! transactions whose outputs it consumes.
+//!
+//! Ancestors are read from the mempool database, descendants are found with the
+//! transactions mempool index, which follows the database.
+
+use crate::txs_mp_index::TxsMpIndex;
+use crate::*;
+use duniter_core::dbs::databases::txs_mp_v2::TxsMpV2Db;
+use duniter_core::dbs::{HashKeyV2, TimestampKeyV1, UdIdV2, UtxoIdDbV2};
+use duniter_core::documents::transaction::{
+    SourceIdV10, TransactionDocumentTrait, UdSourceIdV10, UtxoIdV10,
+};
+use std::collections::HashSet;
+
+/// Hashs of the transactions whose outputs are consumed by `tx`, for which `is_parent` is true
+fn parents<F>(tx: &TransactionDocumentV10, mut is_parent: F) -> KvResult<Vec<Hash>>
+where
+    F: FnMut(Hash) -> KvResult<bool>,
+{
+    let mut parents = Vec::new();
+    for input in tx.get_inputs() {
+        if let SourceIdV10::Utxo(UtxoIdV10 { tx_hash, .. }) = input.id {
+            if !parents.contains(&tx_hash) && is_parent(tx_hash)? {
+                parents.push(tx_hash);
+            }
+        }
+    }
+    Ok(parents)
+}
+
+/// Pending transactions on which `tx` depends, directly or not
+pub(crate) fn pending_ancestors<TxsMpDb: TxsMpV2DbReadable>(
+    txs_mp_db: &TxsMpDb,
+    tx: &TransactionDocumentV10,
+) -> KvResult<HashSet<Hash>> {
+    let mut ancestors = HashSet::new();
+    let mut to_visit = vec![tx.clone()];
+    while let Some(tx) = to_visit.pop() {
+        for input in tx.get_inputs() {
+            if let SourceIdV10::Utxo(UtxoIdV10 { tx_hash, .. }) = input.id {
+                if !ancestors.contains(&tx_hash) {
+                    if let Some(parent) = txs_mp_db.txs().get(&HashKeyV2(tx_hash))? {
+                        ancestors.insert(tx_hash);
+                        to_visit.push(parent.doc);
+                    }
+                }
+            }
+        }
+    }
+    Ok(ancestors)
+}
+
+/// Sort transactions so that each one comes after its parents, keeping the original order
+/// otherwise. Transactions depending on a pending transaction missing from `txs` are dropped,
+/// because they cannot be written before it.
+pub(crate) fn sort_by_dependencies<TxsMpDb: TxsMpV2DbReadable>(
+    txs_mp_db: &TxsMpDb,
+    txs: Vec<PendingTxDbV2>,
+) -> KvResult<Vec<PendingTxDbV2>> {
+    let in_list: HashSet<Hash> = txs.iter().map(|tx| tx.doc.get_hash()).collect();
+
+    let mut remaining = Vec::with_capacity(txs.len());
+    for tx in txs {
+        let parents = parents(&tx.doc, |hash| {
+            Ok(in_list.contains(&hash) || txs_mp_db.txs().get(&HashKeyV2(hash))?.is_some())
+        })?;
+        if parents.iter().all(|parent| in_list.contains(parent)) {
+            remaining.push((parents, tx));
+        }
+    }
+
+    let mut sorted = Vec::with_capacity(remaining.len());
+    let mut emitted = HashSet::with_capacity(remaining.len());
+    loop {
+        let remaining_count = remaining.len();
+        let mut next_remaining = Vec::new();
+        for (parents, tx) in remaining {
+            if parents.iter().all(|parent| emitted.contains(parent)) {
+                emitted.insert(tx.doc.get_hash());
+                sorted.push(tx);
+            } else {
+                next_remaining.push((parents, tx));
+            }
+        }
+        remaining = next_remaining;
+        // Stop when no more transaction can be emitted, the remaining ones have a dropped parent
+        if remaining.is_empty() || remaining.len() == remaining_count {
+            break;
+        }
+    }
+    Ok(sorted)
+}
+
+/// Remove the pending transactions `roots` and the pending transactions depending on them,
+/// directly or not. Roots may be already removed, their descendants are removed anyway.
+/// Return the hashs of removed transactions.
+pub(crate) fn remove_with_descendants(
+    txs_mp_db: &TxsMpV2Db<FileBackend>,
+    txs_mp_index: &TxsMpIndex,
+    roots: Vec<Hash>,
+) -> KvResult<Vec<Hash>> {
+    let mut visited: HashSet<Hash> = roots.iter().copied().collect();
+    let mut to_visit = roots;
+    let mut removed = Vec::new();
+    while let Some(hash) = to_visit.pop() {
+        for child in txs_mp_index.children(hash) {
+            if visited.insert(child) {
+                to_visit.push(child);
+            }
+        }
+        if txs_mp_db.txs().get(&HashKeyV2(hash))?.is_some() {
+            duniter_core::dbs_write_ops::txs_mp::remove_pending_tx_by_hash(txs_mp_db, hash)?;
+            removed.push(hash);
+        }
+    }
+    Ok(removed)
+}
+
+/// Remove the pending transactions received before `limit_time`. The pending transactions
+/// depending on them are kept, they must be removed with `remove_with_descendants`. Return
+/// the hashs of removed transactions.
+pub(crate) fn remove_expired_txs(
+    txs_mp_db: &TxsMpV2Db<FileBackend>,
+    limit_time: i64,
+) -> KvResult<Vec<Hash>> {
+    let expired = txs_mp_db
+        .txs_by_received_time()
+        .iter(..TimestampKeyV1(limit_time), |it| {
+            let mut expired = Vec::new();
+            for hashs_res in it.values() {
+                expired.extend(hashs_res?.0.into_iter().map(|HashKeyV2(hash)| hash));
+            }
+            Ok::<_, KvError>(expired)
+        })?;
+    for hash in &expired {
+        duniter_core::dbs_write_ops::txs_mp::remove_pending_tx_by_hash(txs_mp_db, *hash)?;
+    }
+    Ok(expired)
+}
+
+/// Remove the pending transactions whose blockstamp is one of the `reverted` blocks, they can
+/// no longer be written in the current chain. Removals are cascaded to the descendants.
+/// Return the hashs of removed transactions.
+pub(crate) fn remove_txs_by_blockstamps(
+    txs_mp_db: &TxsMpV2Db<FileBackend>,
+    txs_mp_index: &TxsMpIndex,
+    reverted: &HashSet<Blockstamp>,
+) -> KvResult<Vec<Hash>> {
+    let invalid = txs_mp_db.txs().iter(.., |it| {
+        let mut invalid = Vec::new();
+        for entry_res in it {
+            let (HashKeyV2(hash), pending_tx) = entry_res?;
+            if reverted.contains(&pending_tx.doc.blockstamp()) {
+                invalid.push(hash);
+            }
+        }
+        Ok::<_, KvError>(invalid)
+    })?;
+    remove_with_descendants(txs_mp_db, txs_mp_index, invalid)
+}
+
+/// Remove pending transactions consuming a source that no longer exists, neither in the
+/// blockchain nor in the outputs of another pending transaction. Removals are cascaded to
+/// the descendants. Return the hashs of removed transactions.
+pub(crate) fn remove_orphan_txs<BcDb: BcV2DbReadable>(
+    bc_db: &BcDb,
+    txs_mp_db: &TxsMpV2Db<FileBackend>,
+    txs_mp_index: &TxsMpIndex,
+) -> KvResult<Vec<Hash>> {
+    let orphans = txs_mp_db.txs().iter(.., |it| {
+        let mut orphans = Vec::new();
+        for entry_res in it {
+            let (HashKeyV2(hash), pending_tx) = entry_res?;
+            if !inputs_are_available(bc_db, txs_mp_db, &pending_tx.doc)? {
+                orphans.push(hash);
+            }
+        }
+        Ok::<_, KvError>(orphans)
+    })?;
+    remove_with_descendants(txs_mp_db, txs_mp_index, orphans)
+}
+
+fn inputs_are_available<BcDb: BcV2DbReadable, TxsMpDb: TxsMpV2DbReadable>(
+    bc_db: &BcDb,
+    txs_mp_db: &TxsMpDb,
+    tx: &TransactionDocumentV10,
+) -> KvResult<bool> {
+    for input in tx.get_inputs() {
+        let available = match input.id {
+            SourceIdV10::Ud(UdSourceIdV10 {
+                issuer,
+                block_number,
+            }) => bc_db.uds().get(&UdIdV2(issuer, block_number))?.is_some(),
+            SourceIdV10::Utxo(UtxoIdV10 {
+                tx_hash,
+                output_index,
+            }) => {
+                bc_db
+                    .utxos()
+                    .get(&UtxoIdDbV2(tx_hash, output_index as u32))?
+                    .is_some()
+                    || txs_mp_db
+                        .txs()
+                        .get(&HashKeyV2(tx_hash))?
+                        .map_or(false, |parent| {
+                            output_index < parent.doc.get_outputs().len()
+                        })
+            }
+        };
+        if !available {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{block, keypair, sig_unlock, tx, utxo_input};
+    use duniter_core::common::crypto::keys::KeyPair as _;
+    use duniter_core::wallet::prelude::*;
+
+    fn script() -> WalletScriptV10 {
+        WalletScriptV10::single(WalletConditionV10::Sig(keypair().public_key()))
+    }
+
+    fn root() -> TransactionDocumentV10 {
+        tx(&[], &[], vec![script()])
+    }
+
+    // Transaction consuming the first output of each of `parents`
+    fn child(parents: &[&TransactionDocumentV10]) -> TransactionDocumentV10 {
+        let inputs: Vec<_> = parents
+            .iter()
+            .map(|parent| utxo_input(parent.get_hash(), 0))
+            .collect();
+        let unlocks: Vec<_> = (0..parents.len()).map(sig_unlock).collect();
+        tx(&inputs, &unlocks, vec![script()])
+    }
+
+    fn add_all(server: &DuniterServer, txs: &[&TransactionDocumentV10]) -> KvResult<()> {
+        for tx in txs {
+            server.add_pending_tx_force((*tx).clone())?;
+        }
+        Ok(())
+    }
+
+    fn pending_hashs(server: &DuniterServer) -> KvResult<HashSet<Hash>> {
+        Ok(server
+            .get_pending_txs_page(None, 0, None, usize::MAX)?
+            .txs
+            .iter()
+            .map(|pending_tx| pending_tx.doc.get_hash())
+            .collect())
+    }
+
+    fn hashs(txs: &[&TransactionDocumentV10]) -> HashSet<Hash> {
+        txs.iter().map(|tx| tx.get_hash()).collect()
+    }
+
+    #[test]
+    fn test_remove_with_descendants() -> anyhow::Result<()> {
+        let server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let removed_root = root();
+        let child1 = child(&[&removed_root]);
+        let grandchild = child(&[&child1]);
+        let other = root();
+        let other_child = child(&[&other]);
+        // Depends on the removed root through one of its parents only
+        let joined = child(&[&child1, &other]);
+        add_all(
+            &server,
+            &[
+                &removed_root,
+                &child1,
+                &grandchild,
+                &other,
+                &other_child,
+                &joined,
+            ],
+        )?;
+
+        let root_hash = removed_root.get_hash();
+        let removed = server
+            .dbs_pool
+            .execute(move |dbs| {
+                remove_with_descendants(&dbs.txs_mp_db, &dbs.server.txs_mp_index, vec![root_hash])
+            })
+            .expect("dbs pool disconnected")?;
+
+        assert_eq!(removed.len(), 4);
+        assert_eq!(
+            removed.into_iter().collect::<HashSet<_>>(),
+            hashs(&[&removed_root, &child1, &grandchild, &joined])
+        );
+        assert_eq!(pending_hashs(&server)?, hashs(&[&other, &other_child]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_orphan_txs() -> anyhow::Result<()> {
+        let server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        // Consumes the output of a transaction that is neither pending nor written
+        let orphan = tx(
+            &[utxo_input(Hash::default(), 0)],
+            &[sig_unlock(0)],
+            vec![script()],
+        );
+        let orphan_child = child(&[&orphan]);
+        let parent = root();
+        let valid = child(&[&parent]);
+        // Consumes an output that the pending parent does not have
+        let missing_output = tx(
+            &[utxo_input(parent.get_hash(), 1)],
+            &[sig_unlock(0)],
+            vec![],
+        );
+        add_all(
+            &server,
+            &[&orphan, &orphan_child, &parent, &valid, &missing_output],
+        )?;
+
+        let removed = server
+            .dbs_pool
+            .execute(move |dbs| {
+                remove_orphan_txs(&dbs.bc_db_ro, &dbs.txs_mp_db, &dbs.server.txs_mp_index)
+            })
+            .expect("dbs pool disconnected")?;
+
+        assert_eq!(
+            removed.into_iter().collect::<HashSet<_>>(),
+            hashs(&[&orphan, &orphan_child, &missing_output])
+        );
+        assert_eq!(pending_hashs(&server)?, hashs(&[&parent, &valid]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_written_parent_keeps_children() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let parent = root();
+        let spending = child(&[&parent]);
+        add_all(&server, &[&parent, &spending])?;
+
+        // The JS node removes the transactions of each applied block from the mempool
+        let mut genesis = block(0, 0, None);
+        genesis.transactions = vec![parent.to_string_object()];
+        server.apply_block(genesis)?;
+        server.remove_pending_tx_by_hash(parent.get_hash())?;
+
+        assert_eq!(pending_hashs(&server)?, hashs(&[&spending]));
+        assert!(matches!(
+            server.get_tx_status(spending.get_hash())?,
+            TxStatus::Pending { .. }
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort_by_dependencies() -> anyhow::Result<()> {
+        let server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let parent = root();
+        let child1 = child(&[&parent]);
+        let grandchild = child(&[&child1]);
+        let other = root();
+        // Its pending parent is not in the sorted transactions
+        let excluded_parent = root();
+        let excluded_child = child(&[&excluded_parent]);
+        add_all(
+            &server,
+            &[
+                &parent,
+                &child1,
+                &grandchild,
+                &other,
+                &excluded_parent,
+                &excluded_child,
+            ],
+        )?;
+
+        let unsorted: Vec<Hash> = vec![&grandchild, &other, &child1, &parent, &excluded_child]
+            .into_iter()
+            .map(|tx| tx.get_hash())
+            .collect();
+        let sorted = server
+            .dbs_pool
+            .execute(move |dbs| {
+                let mut txs = Vec::new();
+                for hash in unsorted {
+                    if let Some(pending_tx) = dbs.txs_mp_db.txs().get(&HashKeyV2(hash))? {
+                        txs.push(pending_tx);
+                    }
+                }
+                sort_by_dependencies(&dbs.txs_mp_db, txs)
+            })
+            .expect("dbs pool disconnected")?;
+
+        assert_eq!(
+            sorted
+                .iter()
+                .map(|pending_tx| pending_tx.doc.get_hash())
+                .collect::<Vec<_>>(),
+            vec![
+                other.get_hash(),
+                parent.get_hash(),
+                child1.get_hash(),
+                grandchild.get_hash()
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_follows_mempool() -> anyhow::Result<()> {
+        let server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let parent = root();
+        let child1 = child(&[&parent]);
+        let child2 = child(&[&parent]);
+        add_all(&server, &[&parent, &child1, &child2])?;
+        let children = |server: &DuniterServer, parent: Hash| {
+            server
+                .dbs_pool
+                .execute(move |dbs| dbs.server.txs_mp_index.children(parent))
+                .expect("dbs pool disconnected")
+                .into_iter()
+                .collect::<HashSet<_>>()
+        };
+
+        assert_eq!(
+            children(&server, parent.get_hash()),
+            hashs(&[&child1, &child2])
+        );
+
+        // Removals made by duniter-core are followed too
+        let child1_hash = child1.get_hash();
+        server
+            .dbs_pool
+            .execute(move |dbs| {
+                duniter_core::dbs_write_ops::txs_mp::remove_pending_tx_by_hash(
+                    &dbs.txs_mp_db,
+                    child1_hash,
+                )
+            })
+            .expect("dbs pool disconnected")?;
+        assert_eq!(children(&server, parent.get_hash()), hashs(&[&child2]));
+
+        server.remove_all_pending_txs()?;
+        assert!(children(&server, parent.get_hash()).is_empty());
+
+        Ok(())
+    }
+}
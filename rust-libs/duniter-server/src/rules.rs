@@ -0,0 +1,738 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! DUBP global rules that can be checked against the bc_v2 database state.
+//!
+//! The bc_v2 database does not index memberships and certifications expiry, so the rules
+//! depending on them (BR_G70, BR_G79, and the expiry rules) are only checked by the JS
+//! validator. Certification stock (BR_G66), replay (BR_G71) and distance (BR_G76) rules are
+//! checked with the main WoT, which must be loaded.
+//!
+//! The written time of UTXOs, needed by relative time locks (BR_G89), and the WoT nodes of
+//! members come from the rules index, see `rules_index`.
+
+use crate::rules_index::RulesIndexV1Db;
+use crate::*;
+use duniter_core::dbs::{IdtyDbV2, UdIdV2, UtxoIdDbV2, U32BE};
+use duniter_core::documents::transaction::{
+    SourceIdV10, TransactionDocumentTrait, UdSourceIdV10, UtxoIdV10,
+};
+use duniter_core::wallet::prelude::*;
+use duniter_core::wot::{
+    data::{rusty::RustyWebOfTrust, HasLinkResult, NewLinkResult, WebOfTrust, WotId},
+    operations::distance::{DistanceCalculator, RustyDistanceCalculator, WotDistanceParameters},
+    MAIN_WOT,
+};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::convert::TryFrom;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BlockRule {
+    /// BR_G51
+    Number,
+    /// BR_G52
+    PreviousHash,
+    /// BR_G58
+    Dividend,
+    /// BR_G66
+    CertificationStock,
+    /// BR_G68
+    CertificationFromMember,
+    /// BR_G69
+    CertificationToMemberOrNewcomer,
+    /// BR_G71
+    CertificationReplay,
+    /// BR_G73
+    IdentityUidUnicity,
+    /// BR_G74
+    IdentityPubkeyUnicity,
+    /// BR_G76
+    MembershipDistance,
+    /// BR_G78
+    MembershipJoinsTwice,
+    /// BR_G80
+    MembershipLeaverIsMember,
+    /// BR_G81
+    MembershipActiveIsMember,
+    /// BR_G82
+    MembershipRevokedIsMember,
+    /// BR_G85
+    MembershipExcludedIsMember,
+    /// BR_G87
+    InputIsAvailable,
+    /// BR_G88
+    InputIsUnlocked,
+    /// BR_G89
+    InputIsTimeUnlocked,
+    /// BR_G101
+    IssuerIsMember,
+}
+
+impl BlockRule {
+    /// Protocol identifier of the rule
+    pub fn id(self) -> &'static str {
+        match self {
+            Self::Number => "BR_G51",
+            Self::PreviousHash => "BR_G52",
+            Self::Dividend => "BR_G58",
+            Self::CertificationStock => "BR_G66",
+            Self::CertificationFromMember => "BR_G68",
+            Self::CertificationToMemberOrNewcomer => "BR_G69",
+            Self::CertificationReplay => "BR_G71",
+            Self::IdentityUidUnicity => "BR_G73",
+            Self::IdentityPubkeyUnicity => "BR_G74",
+            Self::MembershipDistance => "BR_G76",
+            Self::MembershipJoinsTwice => "BR_G78",
+            Self::MembershipLeaverIsMember => "BR_G80",
+            Self::MembershipActiveIsMember => "BR_G81",
+            Self::MembershipRevokedIsMember => "BR_G82",
+            Self::MembershipExcludedIsMember => "BR_G85",
+            Self::InputIsAvailable => "BR_G87",
+            Self::InputIsUnlocked => "BR_G88",
+            Self::InputIsTimeUnlocked => "BR_G89",
+            Self::IssuerIsMember => "BR_G101",
+        }
+    }
+}
+
+impl std::fmt::Display for BlockRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+impl DuniterServer {
+    /// Check a block against the current blockchain state, and return all violated rules.
+    ///
+    /// Fail if the main WoT is not loaded, or if WoT nodes are not indexed from the genesis
+    /// block.
+    pub fn check_block_rules(
+        &self,
+        block: DubpBlockV10Stringified,
+    ) -> anyhow::Result<Vec<BlockRule>> {
+        let block =
+            DubpBlockV10::from_string_object(&block).map_err(|e| KvError::DeserError(e.into()))?;
+        let wot = MAIN_WOT
+            .get()
+            .ok_or_else(|| {
+                anyhow::Error::msg(
+                    "main WoT not loaded: rules BR_G66, BR_G71 and BR_G76 cannot be checked",
+                )
+            })?
+            .read()
+            .clone();
+        let head = HeadState {
+            current: self.current,
+            currency_params: self.currency_params,
+            uds_count: self.uds_count,
+        };
+        self.dbs_pool
+            .execute(move |dbs| {
+                let rules_index_db = &dbs.server.rules_index_db;
+                let wot_ids = block_wot_ids(rules_index_db, &block)?;
+                Ok(check_block_rules(
+                    &dbs.bc_db_ro,
+                    rules_index_db,
+                    &block,
+                    head,
+                    wot,
+                    &wot_ids,
+                )?)
+            })
+            .expect("dbs pool disconnected")
+    }
+}
+
+/// Blockchain state needed to check the head of a new block
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct HeadState {
+    pub(crate) current: Option<BlockMetaV2>,
+    pub(crate) currency_params: CurrencyParameters,
+    /// Number of dividends created in the current chain
+    pub(crate) uds_count: u64,
+}
+
+pub(crate) fn check_block_rules<BcDb: BcV2DbReadable>(
+    bc_db: &BcDb,
+    rules_index_db: &RulesIndexV1Db<FileBackend>,
+    block: &DubpBlockV10,
+    head: HeadState,
+    wot: RustyWebOfTrust,
+    wot_ids: &HashMap<PublicKey, WotId>,
+) -> KvResult<Vec<BlockRule>> {
+    let mut violations = BTreeSet::new();
+
+    check_head(bc_db, block, head, &mut violations)?;
+    let newcomers = check_identities(bc_db, block, &mut violations)?;
+    check_certifications(bc_db, block, &newcomers, &mut violations)?;
+    check_sources(bc_db, rules_index_db, block, &mut violations)?;
+    check_wot(wot, block, head, &newcomers, wot_ids, &mut violations);
+
+    Ok(violations.into_iter().collect())
+}
+
+/// Main WoT nodes of the members involved in the block certifications and memberships.
+/// Newcomers do not have a node yet.
+fn block_wot_ids(
+    rules_index_db: &RulesIndexV1Db<FileBackend>,
+    block: &DubpBlockV10,
+) -> anyhow::Result<HashMap<PublicKey, WotId>> {
+    let certs_pubkeys = block.certifications().iter().flat_map(|cert| {
+        let cert = cert.to_compact_document();
+        vec![cert.issuer, cert.target]
+    });
+    let memberships_pubkeys = block
+        .joiners()
+        .iter()
+        .chain(block.actives())
+        .map(|membership| membership.issuers()[0]);
+    let mut wot_ids = HashMap::new();
+    for pubkey in certs_pubkeys.chain(memberships_pubkeys) {
+        if let Some(wot_id) = crate::rules_index::wot_id(rules_index_db, pubkey)? {
+            wot_ids.insert(pubkey, wot_id);
+        }
+    }
+    Ok(wot_ids)
+}
+
+/// Unlocks of the input `input_index` of the transaction. Unlocks are not necessarily in
+/// the same order as inputs, they are found by their index.
+pub(crate) fn input_unlocks(
+    tx: &TransactionDocumentV10,
+    input_index: usize,
+) -> Option<&[WalletUnlockProofV10]> {
+    tx.get_inputs_unlocks()
+        .iter()
+        .find(|input_unlocks| input_unlocks.index == input_index)
+        .map(|input_unlocks| &input_unlocks.unlocks[..])
+}
+
+pub(crate) fn is_member<BcDb: BcV2DbReadable>(bc_db: &BcDb, pubkey: PublicKey) -> KvResult<bool> {
+    Ok(bc_db
+        .identities()
+        .get(&PubKeyKeyV2(pubkey))?
+        .map_or(false, |IdtyDbV2 { is_member, .. }| is_member))
+}
+
+// BR_G51, BR_G52, BR_G58, BR_G101
+fn check_head<BcDb: BcV2DbReadable>(
+    bc_db: &BcDb,
+    block: &DubpBlockV10,
+    head: HeadState,
+    violations: &mut BTreeSet<BlockRule>,
+) -> KvResult<()> {
+    let current = if let Some(current) = head.current {
+        current
+    } else {
+        if block.number() != BlockNumber(0) {
+            violations.insert(BlockRule::Number);
+        }
+        if block.dividend().is_some() {
+            violations.insert(BlockRule::Dividend);
+        }
+        return Ok(());
+    };
+
+    if block.number() != BlockNumber(current.number + 1) {
+        violations.insert(BlockRule::Number);
+    }
+    if block.previous_hash() != current.hash {
+        violations.insert(BlockRule::PreviousHash);
+    }
+    if !is_member(bc_db, block.issuer())? {
+        violations.insert(BlockRule::IssuerIsMember);
+    }
+
+    // A dividend is created when the median time reaches the next UD time,
+    // which is incremented by `dt` at each dividend.
+    let ud_time = head.currency_params.ud_time0 + head.uds_count * head.currency_params.dt;
+    if block.dividend().is_some() != (ud_time <= block.common_time()) {
+        violations.insert(BlockRule::Dividend);
+    }
+
+    Ok(())
+}
+
+// BR_G73, BR_G74, BR_G78, BR_G80, BR_G81, BR_G82, BR_G85
+// Return newcomers public keys.
+fn check_identities<BcDb: BcV2DbReadable>(
+    bc_db: &BcDb,
+    block: &DubpBlockV10,
+    violations: &mut BTreeSet<BlockRule>,
+) -> KvResult<HashSet<PublicKey>> {
+    let mut newcomers = HashSet::new();
+    let mut usernames = HashSet::new();
+    for idty in block.identities() {
+        let pubkey = idty.issuers()[0];
+        let username = idty.username().to_owned();
+        if bc_db.uids_index().get(&username)?.is_some() || !usernames.insert(username) {
+            violations.insert(BlockRule::IdentityUidUnicity);
+        }
+        if bc_db.identities().get(&PubKeyKeyV2(pubkey))?.is_some() || !newcomers.insert(pubkey) {
+            violations.insert(BlockRule::IdentityPubkeyUnicity);
+        }
+    }
+
+    for joiner in block.joiners() {
+        if is_member(bc_db, joiner.issuers()[0])? {
+            violations.insert(BlockRule::MembershipJoinsTwice);
+        }
+    }
+    for active in block.actives() {
+        if !is_member(bc_db, active.issuers()[0])? {
+            violations.insert(BlockRule::MembershipActiveIsMember);
+        }
+    }
+    for leaver in block.leavers() {
+        if !is_member(bc_db, leaver.issuers()[0])? {
+            violations.insert(BlockRule::MembershipLeaverIsMember);
+        }
+    }
+    for revoked in block.revoked() {
+        if !is_member(bc_db, revoked.to_compact_document().issuer)? {
+            violations.insert(BlockRule::MembershipRevokedIsMember);
+        }
+    }
+    for excluded in block.excluded() {
+        if !is_member(bc_db, *excluded)? {
+            violations.insert(BlockRule::MembershipExcludedIsMember);
+        }
+    }
+
+    Ok(newcomers)
+}
+
+// BR_G68, BR_G69
+fn check_certifications<BcDb: BcV2DbReadable>(
+    bc_db: &BcDb,
+    block: &DubpBlockV10,
+    newcomers: &HashSet<PublicKey>,
+    violations: &mut BTreeSet<BlockRule>,
+) -> KvResult<()> {
+    for cert in block.certifications() {
+        let cert = cert.to_compact_document();
+        // The genesis block certifications are issued by its newcomers
+        if block.number() != BlockNumber(0) && !is_member(bc_db, cert.issuer)? {
+            violations.insert(BlockRule::CertificationFromMember);
+        }
+        if !newcomers.contains(&cert.target) && !is_member(bc_db, cert.target)? {
+            violations.insert(BlockRule::CertificationToMemberOrNewcomer);
+        }
+    }
+    Ok(())
+}
+
+// BR_G87, BR_G88, BR_G89
+fn check_sources<BcDb: BcV2DbReadable>(
+    bc_db: &BcDb,
+    rules_index_db: &RulesIndexV1Db<FileBackend>,
+    block: &DubpBlockV10,
+    violations: &mut BTreeSet<BlockRule>,
+) -> KvResult<()> {
+    let median_time = block.common_time();
+    // Outputs of the block transactions can be consumed by the next transactions of the block
+    let mut created_utxos = HashMap::new();
+    let mut consumed_sources = HashSet::new();
+    for tx in block.transactions() {
+        let issuers = tx.issuers();
+        for (input_index, input) in tx.get_inputs().iter().enumerate() {
+            // Script and written time of the source, if it is available
+            let source_opt = match input.id {
+                SourceIdV10::Ud(UdSourceIdV10 {
+                    issuer,
+                    block_number,
+                }) => {
+                    if bc_db.uds().get(&UdIdV2(issuer, block_number))?.is_some() {
+                        let written_time = bc_db
+                            .blocks_meta()
+                            .get(&U32BE(block_number.0))?
+                            .map_or(0, |block_meta| block_meta.median_time);
+                        Some((
+                            WalletScriptV10::single(WalletConditionV10::Sig(issuer)),
+                            written_time,
+                        ))
+                    } else {
+                        None
+                    }
+                }
+                SourceIdV10::Utxo(UtxoIdV10 {
+                    tx_hash,
+                    output_index,
+                }) => {
+                    if let Some(script) = created_utxos.get(&(tx_hash, output_index)) {
+                        Some((script.clone(), median_time))
+                    } else if let Some(utxo) = bc_db
+                        .utxos()
+                        .get(&UtxoIdDbV2(tx_hash, output_index as u32))?
+                    {
+                        // Outputs written before the rules index have no known written time
+                        let written_time =
+                            crate::rules_index::tx_written_time(bc_db, rules_index_db, tx_hash)?
+                                .unwrap_or(0);
+                        Some((utxo.wallet_script, written_time))
+                    } else {
+                        None
+                    }
+                }
+            };
+            if !consumed_sources.insert(input.id) {
+                violations.insert(BlockRule::InputIsAvailable);
+            }
+            if let Some((script, written_time)) = source_opt {
+                match input_unlocks(tx, input_index).map(|unlocks| {
+                    SourceV10::unlockable_on(&issuers, unlocks, written_time, &script)
+                }) {
+                    Some(Ok(unlockable_on)) => {
+                        if unlockable_on > median_time {
+                            violations.insert(BlockRule::InputIsTimeUnlocked);
+                        }
+                    }
+                    Some(Err(_)) | None => {
+                        violations.insert(BlockRule::InputIsUnlocked);
+                    }
+                }
+            } else {
+                violations.insert(BlockRule::InputIsAvailable);
+            }
+        }
+        let tx_hash = tx.get_hash();
+        for (output_index, output) in tx.get_outputs().iter().enumerate() {
+            created_utxos.insert((tx_hash, output_index), output.conditions.script.clone());
+        }
+    }
+    Ok(())
+}
+
+// BR_G66, BR_G71, BR_G76
+fn check_wot(
+    mut wot: RustyWebOfTrust,
+    block: &DubpBlockV10,
+    head: HeadState,
+    newcomers: &HashSet<PublicKey>,
+    wot_ids: &HashMap<PublicKey, WotId>,
+    violations: &mut BTreeSet<BlockRule>,
+) {
+    // Newcomers are temporarily added to the WoT, as non-members
+    let mut wot_ids = wot_ids.clone();
+    for newcomer in newcomers {
+        let wot_id = wot.add_node();
+        wot.set_enabled(wot_id, false);
+        wot_ids.insert(*newcomer, wot_id);
+    }
+
+    // The block certifications are temporarily added to the WoT
+    let certs: Vec<_> = block
+        .certifications()
+        .iter()
+        .filter_map(|cert| {
+            let cert = cert.to_compact_document();
+            Some((*wot_ids.get(&cert.issuer)?, *wot_ids.get(&cert.target)?))
+        })
+        .collect();
+    for (source, target) in certs {
+        if matches!(wot.has_link(source, target), HasLinkResult::Link(true)) {
+            violations.insert(BlockRule::CertificationReplay);
+        } else if let NewLinkResult::AllCertificationsUsed(_) = wot.add_link(source, target) {
+            violations.insert(BlockRule::CertificationStock);
+        }
+    }
+
+    // Joiners and actives must not be outdistanced
+    let currency_params = head.currency_params;
+    let members_count = head.current.map_or(0, |current| current.members_count);
+    let step_max = u32::try_from(currency_params.step_max).unwrap_or(u32::MAX);
+    let sentry_requirement = (members_count as f64)
+        .powf(1.0 / f64::from(step_max))
+        .ceil() as u32;
+    for pubkey in block
+        .joiners()
+        .iter()
+        .chain(block.actives())
+        .map(|membership| membership.issuers()[0])
+    {
+        let outdistanced = if let Some(node) = wot_ids.get(&pubkey) {
+            RustyDistanceCalculator {}
+                .compute_distance(
+                    &wot,
+                    WotDistanceParameters {
+                        node: *node,
+                        sentry_requirement,
+                        step_max,
+                        x_percent: f64::from(currency_params.x_percent),
+                    },
+                )
+                .map_or(true, |distance| distance.outdistanced)
+        } else {
+            true
+        };
+        if outdistanced {
+            violations.insert(BlockRule::MembershipDistance);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, block_hash, tx, ISSUER};
+    use duniter_core::common::crypto::keys::KeyPair as _;
+
+    const MEMBER_B: &str = "4tNQ7d9pj2Da5wUVoW9mFn7JjuPoowF977au8DdhEjVR";
+    const MEMBER_C: &str = "FD9wujR7KABw88RyKEGBYRLz8PA6jzVCbcBAsrBXBqSa";
+
+    fn pubkey(base58: &str) -> PublicKey {
+        PublicKey::from_base58(base58).expect("invalid test pubkey")
+    }
+
+    fn parse(block: DubpBlockV10Stringified) -> DubpBlockV10 {
+        DubpBlockV10::from_string_object(&block).expect("invalid test block")
+    }
+
+    fn server_with_blocks(count: u64) -> anyhow::Result<DuniterServer> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        server.apply_chunk_of_blocks(test_utils::branch(0, 0..count, None))?;
+        Ok(server)
+    }
+
+    fn spend_utxo(tx_hash: Hash, unlock_index: usize) -> TransactionDocumentV10 {
+        tx(
+            &[test_utils::utxo_input(tx_hash, 0)],
+            &[test_utils::sig_unlock(unlock_index)],
+            vec![],
+        )
+    }
+
+    #[test]
+    fn test_check_head() -> anyhow::Result<()> {
+        let server = server_with_blocks(3)?;
+        let mut head = HeadState {
+            current: server.current,
+            currency_params: server.currency_params,
+            uds_count: 0,
+        };
+        head.currency_params.ud_time0 = 1_000;
+        head.currency_params.dt = 100;
+
+        let check = |block: DubpBlockV10Stringified, head: HeadState| {
+            let mut violations = BTreeSet::new();
+            check_head(&server.bc_db, &parse(block), head, &mut violations)?;
+            Ok::<_, KvError>(violations.into_iter().collect::<Vec<_>>())
+        };
+
+        // The first dividend is due: the median time of the block (1030) reached ud_time0
+        assert_eq!(
+            check(test_utils::block(0, 3, Some(block_hash(0, 2))), head)?,
+            vec![BlockRule::Dividend, BlockRule::IssuerIsMember]
+        );
+        // The next dividend is due at 1100
+        head.uds_count = 1;
+        assert_eq!(
+            check(test_utils::block(0, 3, Some(block_hash(0, 2))), head)?,
+            vec![BlockRule::IssuerIsMember]
+        );
+        assert_eq!(
+            check(test_utils::block(1, 5, Some(block_hash(1, 4))), head)?,
+            vec![
+                BlockRule::Number,
+                BlockRule::PreviousHash,
+                BlockRule::IssuerIsMember
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_sources() -> anyhow::Result<()> {
+        let server = server_with_blocks(3)?;
+        let check = |txs: Vec<TransactionDocumentV10>| {
+            let mut block = test_utils::block(0, 3, Some(block_hash(0, 2)));
+            block.transactions = txs.iter().map(|tx| tx.to_string_object()).collect();
+            let mut violations = BTreeSet::new();
+            check_sources(
+                &server.bc_db,
+                &server.dbs_pool.server_dbs().rules_index_db,
+                &parse(block),
+                &mut violations,
+            )?;
+            Ok::<_, KvError>(violations.into_iter().collect::<Vec<_>>())
+        };
+
+        let issuer_sig = WalletScriptV10::single(WalletConditionV10::Sig(pubkey(ISSUER)));
+        let parent = tx(&[], &[], vec![issuer_sig]);
+        assert_eq!(
+            check(vec![parent.clone(), spend_utxo(parent.get_hash(), 0)])?,
+            vec![]
+        );
+        // Unlocks are found by the index of their input, not by their position
+        assert_eq!(
+            check(vec![parent.clone(), spend_utxo(parent.get_hash(), 1)])?,
+            vec![BlockRule::InputIsUnlocked]
+        );
+        // Double spend
+        assert_eq!(
+            check(vec![
+                parent.clone(),
+                spend_utxo(parent.get_hash(), 0),
+                spend_utxo(parent.get_hash(), 0)
+            ])?,
+            vec![BlockRule::InputIsAvailable]
+        );
+        // The output is locked until after the block median time (1030)
+        let time_locked = tx(
+            &[],
+            &[],
+            vec![WalletScriptV10::single(WalletConditionV10::Cltv(2_000))],
+        );
+        assert_eq!(
+            check(vec![
+                time_locked.clone(),
+                spend_utxo(time_locked.get_hash(), 0)
+            ])?,
+            vec![BlockRule::InputIsTimeUnlocked]
+        );
+        // Unknown source
+        assert_eq!(
+            check(vec![spend_utxo(Hash::default(), 0)])?,
+            vec![BlockRule::InputIsAvailable]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_sources_relative_time_lock() -> anyhow::Result<()> {
+        let mut server = server_with_blocks(3)?;
+        // Outputs of a transaction written in block #3 (median time 1030)
+        let parent = tx(
+            &[],
+            &[],
+            vec![
+                WalletScriptV10::single(WalletConditionV10::Csv(100)),
+                WalletScriptV10::single(WalletConditionV10::Csv(5)),
+            ],
+        );
+        let mut b3 = test_utils::block(0, 3, Some(block_hash(0, 2)));
+        b3.transactions = vec![parent.to_string_object()];
+        server.apply_block(b3)?;
+
+        let check = |output_index: usize| {
+            let spending = tx(
+                &[test_utils::utxo_input(parent.get_hash(), output_index)],
+                &[test_utils::sig_unlock(0)],
+                vec![],
+            );
+            let mut block = test_utils::block(0, 4, Some(block_hash(0, 3)));
+            block.transactions = vec![spending.to_string_object()];
+            let mut violations = BTreeSet::new();
+            check_sources(
+                &server.bc_db,
+                &server.dbs_pool.server_dbs().rules_index_db,
+                &parse(block),
+                &mut violations,
+            )?;
+            Ok::<_, KvError>(violations.into_iter().collect::<Vec<_>>())
+        };
+
+        // Block #4 median time is 1040
+        assert_eq!(check(0)?, vec![BlockRule::InputIsTimeUnlocked]);
+        assert_eq!(check(1)?, vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_wot_ids() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let alice = test_utils::keypair();
+        server.apply_block(test_utils::genesis_with_member(&alice, "alice"))?;
+
+        let mut block = test_utils::block(0, 1, Some(test_utils::block_hash(0, 0)));
+        block.certifications = vec![format!(
+            "{}:{}:0:{}",
+            alice.public_key(),
+            MEMBER_B,
+            test_utils::SIGNATURE
+        )];
+        let wot_ids = block_wot_ids(&server.dbs_pool.server_dbs().rules_index_db, &parse(block))?;
+
+        // Alice is the first identity of the chain, B is not a member
+        assert_eq!(
+            wot_ids.into_iter().collect::<Vec<_>>(),
+            vec![(alice.public_key(), WotId(0))]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_wot() -> anyhow::Result<()> {
+        let server = server_with_blocks(3)?;
+        let head = HeadState {
+            current: server.current,
+            currency_params: server.currency_params,
+            uds_count: 0,
+        };
+
+        // Each member can issue one certification, A already certifies B
+        let mut wot = RustyWebOfTrust::new(1);
+        let (a, b, c) = (wot.add_node(), wot.add_node(), wot.add_node());
+        wot.add_link(a, b);
+        let wot_ids: HashMap<_, _> = vec![
+            (pubkey(ISSUER), a),
+            (pubkey(MEMBER_B), b),
+            (pubkey(MEMBER_C), c),
+        ]
+        .into_iter()
+        .collect();
+
+        let mut block = test_utils::block(0, 3, Some(block_hash(0, 2)));
+        block.certifications = vec![
+            format!("{}:{}:0:{}", MEMBER_B, MEMBER_C, test_utils::SIGNATURE),
+            format!("{}:{}:0:{}", ISSUER, MEMBER_B, test_utils::SIGNATURE),
+            format!("{}:{}:0:{}", ISSUER, MEMBER_C, test_utils::SIGNATURE),
+        ];
+        let mut violations = BTreeSet::new();
+        check_wot(
+            wot,
+            &parse(block),
+            head,
+            &HashSet::new(),
+            &wot_ids,
+            &mut violations,
+        );
+
+        assert_eq!(
+            violations.into_iter().collect::<Vec<_>>(),
+            vec![
+                BlockRule::CertificationStock,
+                BlockRule::CertificationReplay
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_main_wot_required() -> anyhow::Result<()> {
+        // The main WoT is only loaded by the JS node
+        let server = server_with_blocks(3)?;
+        assert!(server
+            .check_block_rules(test_utils::block(0, 3, Some(block_hash(0, 2))))
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Block data needed by the rules engine that the bc_v2 database does not keep: the node of
+//! each identity in the main WoT, and the block where each transaction was written.
+//!
+//! Identities get their WoT node in the order they are written, like in the main WoT loaded
+//! by the JS node. Data is indexed when blocks are written, from the first block written with
+//! this index: WoT nodes are only known when the index starts from the genesis block.
+
+use crate::*;
+use duniter_core::dbs::{HashKeyV2, U32BE};
+use duniter_core::wot::data::WotId;
+
+db_schema!(
+    RulesIndexV1,
+    [
+        ["wot_ids", WotIds, PubKeyKeyV2, u32],
+        ["wot_nodes_count", WotNodesCount, (), u32],
+        ["txs_blocks", TxsBlocks, HashKeyV2, u32],
+        ["indexed_from", IndexedFrom, (), u32],
+    ]
+);
+
+pub(crate) fn open_rules_index_db(
+    profile_path_opt: Option<&Path>,
+) -> KvResult<RulesIndexV1Db<FileBackend>> {
+    RulesIndexV1Db::<FileBackend>::open(FileBackend::gen_backend_conf(
+        "rules_index_v1",
+        profile_path_opt,
+    ))
+}
+
+/// Index the identities and transactions of a block, once it is written in the blockchain
+/// database
+pub(crate) fn apply_block(
+    rules_index_db: &RulesIndexV1Db<FileBackend>,
+    block: &DubpBlockV10,
+) -> KvResult<()> {
+    let block_number = block.number();
+    if rules_index_db.indexed_from().get(&())?.is_none() {
+        rules_index_db
+            .indexed_from_write()
+            .upsert((), block_number.0)?;
+    }
+    let mut wot_nodes_count = rules_index_db.wot_nodes_count().get(&())?.unwrap_or(0);
+    for idty in block.identities() {
+        rules_index_db
+            .wot_ids_write()
+            .upsert(PubKeyKeyV2(idty.issuers()[0]), wot_nodes_count)?;
+        wot_nodes_count += 1;
+    }
+    rules_index_db
+        .wot_nodes_count_write()
+        .upsert((), wot_nodes_count)?;
+    for tx in block.transactions() {
+        rules_index_db
+            .txs_blocks_write()
+            .upsert(HashKeyV2(tx.get_hash()), block_number.0)?;
+    }
+    Ok(())
+}
+
+/// Remove the identities and transactions of a reverted block
+pub(crate) fn revert_block(
+    rules_index_db: &RulesIndexV1Db<FileBackend>,
+    block: &DubpBlockV10,
+) -> KvResult<()> {
+    let block_number = block.number();
+    let mut wot_nodes_count = rules_index_db.wot_nodes_count().get(&())?.unwrap_or(0);
+    for idty in block.identities() {
+        rules_index_db
+            .wot_ids_write()
+            .remove(PubKeyKeyV2(idty.issuers()[0]))?;
+        wot_nodes_count = wot_nodes_count.saturating_sub(1);
+    }
+    rules_index_db
+        .wot_nodes_count_write()
+        .upsert((), wot_nodes_count)?;
+    for tx in block.transactions() {
+        rules_index_db
+            .txs_blocks_write()
+            .remove(HashKeyV2(tx.get_hash()))?;
+    }
+    if rules_index_db.indexed_from().get(&())? == Some(block_number.0) {
+        rules_index_db.indexed_from_write().remove(())?;
+    }
+    Ok(())
+}
+
+/// Node of `pubkey` in the main WoT. Fail if identities are not indexed from the genesis
+/// block.
+pub(crate) fn wot_id(
+    rules_index_db: &RulesIndexV1Db<FileBackend>,
+    pubkey: PublicKey,
+) -> anyhow::Result<Option<WotId>> {
+    match rules_index_db.indexed_from().get(&())? {
+        Some(0) | None => Ok(rules_index_db
+            .wot_ids()
+            .get(&PubKeyKeyV2(pubkey))?
+            .map(|wot_id| WotId(wot_id as usize))),
+        Some(indexed_from) => Err(anyhow::anyhow!(
+            "WoT nodes are indexed from block #{}, a new synchronization is needed",
+            indexed_from
+        )),
+    }
+}
+
+/// Median time of the block where the transaction `tx_hash` was written. Unknown for
+/// transactions written before the index.
+pub(crate) fn tx_written_time<BcDb: BcV2DbReadable>(
+    bc_db: &BcDb,
+    rules_index_db: &RulesIndexV1Db<FileBackend>,
+    tx_hash: Hash,
+) -> KvResult<Option<u64>> {
+    if let Some(block_number) = rules_index_db.txs_blocks().get(&HashKeyV2(tx_hash))? {
+        Ok(bc_db
+            .blocks_meta()
+            .get(&U32BE(block_number))?
+            .map(|block_meta| block_meta.median_time))
+    } else {
+        Ok(None)
+    }
+}
@@ -0,0 +1,255 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Fixtures shared by unit tests.
+
+use crate::*;
+use duniter_core::common::crypto::keys::{ed25519::Ed25519KeyPair, KeyPair as _, Signature as _};
+use duniter_core::documents::identity::IdentityDocumentV10Builder;
+use duniter_core::documents::membership::{MembershipDocumentV10Builder, MembershipType};
+use duniter_core::documents::smallvec::smallvec;
+use duniter_core::documents::transaction::{
+    SourceIdV10, TransactionDocumentV10Builder, TransactionInputUnlocksV10, TransactionInputV10,
+    TransactionOutputV10, UTXOConditions, UtxoIdV10,
+};
+use duniter_core::wallet::prelude::*;
+
+pub(crate) const ISSUER: &str = "D9D2zaJoWYWveii1JRYLVK3J4Z7ZH3QczoKrnQeiM6mx";
+pub(crate) const SIGNATURE: &str =
+    "7B0hvcfajE2G8nBLp0vLVaQcQdQIyli21Gu8F2l+nimKHRe+fUNi+MWd1e/u29BYZa+RZ1yxhbHIbFzytg7fAA==";
+
+/// Hash of the block `number` of the given test branch
+pub(crate) fn block_hash(branch: u8, number: u64) -> Hash {
+    let mut bytes = [0u8; 32];
+    bytes[0] = branch;
+    bytes[24..].copy_from_slice(&number.to_be_bytes());
+    Hash(bytes)
+}
+
+/// Block `number` of the given test branch, following the block whose hash is `previous_hash`.
+/// Hashs and signature are not valid, these blocks must be applied without full verification.
+pub(crate) fn block(
+    branch: u8,
+    number: u64,
+    previous_hash: Option<Hash>,
+) -> DubpBlockV10Stringified {
+    let hash = block_hash(branch, number).to_hex();
+    DubpBlockV10Stringified {
+        version: 10,
+        number,
+        time: 1_000 + number * 10,
+        median_time: 1_000 + number * 10,
+        currency: "test".to_owned(),
+        issuer: ISSUER.to_owned(),
+        signature: SIGNATURE.to_owned(),
+        hash: Some(hash.clone()),
+        inner_hash: Some(hash),
+        previous_hash: previous_hash.map(|hash| hash.to_hex()),
+        previous_issuer: previous_hash.map(|_| ISSUER.to_owned()),
+        ..Default::default()
+    }
+}
+
+/// Blocks `numbers` of the given test branch, the first one follows the block whose hash is
+/// `previous_hash` (the genesis block has no previous block).
+pub(crate) fn branch(
+    branch: u8,
+    numbers: std::ops::Range<u64>,
+    previous_hash: Option<Hash>,
+) -> Vec<DubpBlockV10Stringified> {
+    let mut previous_hash = previous_hash;
+    numbers
+        .map(|number| {
+            let block = block(branch, number, previous_hash);
+            previous_hash = Some(block_hash(branch, number));
+            block
+        })
+        .collect()
+}
+
+/// Genesis block of the test branch 0 where `keypair` becomes a member named `username`
+pub(crate) fn genesis_with_member(
+    keypair: &Ed25519KeyPair,
+    username: &str,
+) -> DubpBlockV10Stringified {
+    let idty = IdentityDocumentV10Builder {
+        currency: "test",
+        username,
+        blockstamp: &Blockstamp::default(),
+        issuer: keypair.public_key(),
+    }
+    .build_and_sign(vec![keypair.generate_signator()]);
+    let join = MembershipDocumentV10Builder {
+        currency: "test",
+        issuer: keypair.public_key(),
+        blockstamp: &Blockstamp::default(),
+        membership: MembershipType::In(),
+        identity_username: username,
+        identity_blockstamp: &Blockstamp::default(),
+    }
+    .build_and_sign(vec![keypair.generate_signator()]);
+    let mut genesis = block(0, 0, None);
+    genesis.identities = vec![format!(
+        "{}:{}:{}:{}",
+        keypair.public_key(),
+        idty.signatures()[0].to_base64(),
+        Blockstamp::default(),
+        username,
+    )];
+    genesis.joiners = vec![format!(
+        "{}:{}:{}:{}:{}",
+        keypair.public_key(),
+        join.signatures()[0].to_base64(),
+        Blockstamp::default(),
+        Blockstamp::default(),
+        username,
+    )];
+    genesis
+}
+
+/// Input consuming the output `output_index` (worth 100) of the transaction `tx_hash`
+pub(crate) fn utxo_input(tx_hash: Hash, output_index: usize) -> TransactionInputV10 {
+    TransactionInputV10 {
+        amount: SourceAmount::with_base0(100),
+        id: SourceIdV10::Utxo(UtxoIdV10 {
+            tx_hash,
+            output_index,
+        }),
+    }
+}
+
+/// Unlock of the input `index` by the signature of the first issuer
+pub(crate) fn sig_unlock(index: usize) -> TransactionInputUnlocksV10 {
+    TransactionInputUnlocksV10 {
+        index,
+        unlocks: smallvec![WalletUnlockProofV10::Sig(0)],
+    }
+}
+
+fn tx_builder<'a>(
+    issuer: PublicKey,
+    inputs: &'a [TransactionInputV10],
+    unlocks: &'a [TransactionInputUnlocksV10],
+    outputs: Vec<WalletScriptV10>,
+) -> TransactionDocumentV10Builder<'a> {
+    TransactionDocumentV10Builder {
+        currency: "test",
+        blockstamp: Blockstamp::default(),
+        locktime: 0,
+        issuers: smallvec![issuer],
+        inputs,
+        unlocks,
+        outputs: outputs
+            .into_iter()
+            .map(|script| TransactionOutputV10 {
+                amount: SourceAmount::with_base0(100),
+                conditions: UTXOConditions::from(script),
+            })
+            .collect(),
+        comment: "",
+        hash: None,
+    }
+}
+
+/// Transaction issued by `ISSUER`, without signature. Each output is worth 100.
+pub(crate) fn tx(
+    inputs: &[TransactionInputV10],
+    unlocks: &[TransactionInputUnlocksV10],
+    outputs: Vec<WalletScriptV10>,
+) -> TransactionDocumentV10 {
+    let issuer = PublicKey::from_base58(ISSUER).expect("invalid test issuer");
+    tx_builder(issuer, inputs, unlocks, outputs).build_with_signature(smallvec![])
+}
+
+/// Transaction issued by `ISSUER` at `blockstamp` with the given `locktime`, without inputs
+/// nor signature. Each output is worth 100.
+pub(crate) fn tx_at(
+    blockstamp: Blockstamp,
+    locktime: u64,
+    outputs: Vec<WalletScriptV10>,
+) -> TransactionDocumentV10 {
+    let issuer = PublicKey::from_base58(ISSUER).expect("invalid test issuer");
+    TransactionDocumentV10Builder {
+        blockstamp,
+        locktime,
+        ..tx_builder(issuer, &[], &[], outputs)
+    }
+    .build_with_signature(smallvec![])
+}
+
+/// Transaction issued by `ISSUER` at `blockstamp`, without signature. Each output is worth
+/// 100.
+pub(crate) fn spending_tx_at(
+    blockstamp: Blockstamp,
+    inputs: &[TransactionInputV10],
+    unlocks: &[TransactionInputUnlocksV10],
+    outputs: Vec<WalletScriptV10>,
+) -> TransactionDocumentV10 {
+    let issuer = PublicKey::from_base58(ISSUER).expect("invalid test issuer");
+    TransactionDocumentV10Builder {
+        blockstamp,
+        ..tx_builder(issuer, inputs, unlocks, outputs)
+    }
+    .build_with_signature(smallvec![])
+}
+
+/// Transaction issued and signed by `keypair`. Each output is worth 100.
+pub(crate) fn signed_tx(
+    keypair: &Ed25519KeyPair,
+    inputs: &[TransactionInputV10],
+    unlocks: &[TransactionInputUnlocksV10],
+    outputs: Vec<WalletScriptV10>,
+) -> TransactionDocumentV10 {
+    signed_tx_at(keypair, Blockstamp::default(), inputs, unlocks, outputs)
+}
+
+/// Transaction issued and signed by `keypair` at `blockstamp`. Each output is worth 100.
+pub(crate) fn signed_tx_at(
+    keypair: &Ed25519KeyPair,
+    blockstamp: Blockstamp,
+    inputs: &[TransactionInputV10],
+    unlocks: &[TransactionInputUnlocksV10],
+    outputs: Vec<WalletScriptV10>,
+) -> TransactionDocumentV10 {
+    TransactionDocumentV10Builder {
+        blockstamp,
+        ..tx_builder(keypair.public_key(), inputs, unlocks, outputs)
+    }
+    .build_and_sign(vec![keypair.generate_signator()])
+}
+
+/// Transaction issued and signed by `keypair`, without inputs nor outputs. A random comment
+/// makes it unique.
+pub(crate) fn empty_signed_tx(keypair: &Ed25519KeyPair) -> TransactionDocumentV10 {
+    let comment = self::keypair().public_key().to_string();
+    TransactionDocumentV10Builder {
+        comment: &comment,
+        ..tx_builder(keypair.public_key(), &[], &[], vec![])
+    }
+    .build_and_sign(vec![keypair.generate_signator()])
+}
+
+/// Blockstamp of the block `number` of the given test branch
+pub(crate) fn blockstamp(branch: u8, number: u32) -> Blockstamp {
+    Blockstamp {
+        number: BlockNumber(number),
+        hash: BlockHash(block_hash(branch, u64::from(number))),
+    }
+}
+
+/// New random keypair
+pub(crate) fn keypair() -> Ed25519KeyPair {
+    Ed25519KeyPair::generate_random().expect("fail to gen random keypair")
+}
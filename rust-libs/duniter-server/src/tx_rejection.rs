@@ -0,0 +1,390 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Checks applied to a new transaction before accepting it in the mempool.
+//!
+//! Each input must declare the amount of its source, and the outputs must spend exactly the
+//! sum of the inputs.
+//!
+//! Time locks are checked against the current median time. The bc_v2 database does not store
+//! the written time of UTXOs, so relative time locks of UTXOs are not checked.
+
+use crate::*;
+use duniter_core::dbs::{HashKeyV2, UdIdV2, UtxoIdDbV2, U32BE};
+use duniter_core::documents::transaction::{
+    SourceIdV10, TransactionDocumentTrait, UdSourceIdV10, UtxoIdV10,
+};
+use duniter_core::wallet::prelude::*;
+
+/// Maximum age of the blockstamp of a transaction (in blockchain time)
+pub(crate) const TX_WINDOW: u64 = 604_800;
+
+/// Reason why a transaction is refused by the mempool.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TxRejection {
+    MempoolFull,
+    /// The source does not exist or is already consumed
+    SourceNotAvailable(SourceIdV10),
+    /// The unlocks of the input do not match the conditions of its source
+    InvalidUnlock {
+        input_index: usize,
+    },
+    /// The amount of the input is not the amount of its source
+    InputAmountMismatch {
+        input_index: usize,
+    },
+    /// The source of the input is time locked until `unlockable_on`
+    Locked {
+        input_index: usize,
+        unlockable_on: u64,
+    },
+    BadSignature,
+    /// The blockstamp is not in the current chain or is older than the transaction window
+    ExpiredBlockstamp(Blockstamp),
+    /// The transaction is already in the mempool or in the blockchain
+    Duplicate,
+    /// The source is already spent by a pending transaction
+    DoubleSpend {
+        source: SourceIdV10,
+        pending_tx: Hash,
+    },
+    /// The sum of the inputs is not the sum of the outputs
+    UnbalancedAmounts,
+    WrongCurrency {
+        expected: String,
+        found: String,
+    },
+}
+
+impl TxRejection {
+    /// Stable error code, intended to be used by clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::MempoolFull => "MEMPOOL_FULL",
+            Self::SourceNotAvailable(_) => "SOURCE_NOT_AVAILABLE",
+            Self::InvalidUnlock { .. } => "INVALID_UNLOCK",
+            Self::InputAmountMismatch { .. } => "INPUT_AMOUNT_MISMATCH",
+            Self::Locked { .. } => "LOCKED",
+            Self::BadSignature => "BAD_SIGNATURE",
+            Self::ExpiredBlockstamp(_) => "EXPIRED_BLOCKSTAMP",
+            Self::Duplicate => "DUPLICATE",
+            Self::DoubleSpend { .. } => "DOUBLE_SPEND",
+            Self::UnbalancedAmounts => "UNBALANCED_AMOUNTS",
+            Self::WrongCurrency { .. } => "WRONG_CURRENCY",
+        }
+    }
+}
+
+impl std::fmt::Display for TxRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MempoolFull => write!(f, "transactions mempool is full"),
+            Self::SourceNotAvailable(SourceIdV10::Ud(UdSourceIdV10 {
+                issuer,
+                block_number,
+            })) => write!(
+                f,
+                "source D:{}:{} does not exist or is already consumed",
+                issuer, block_number
+            ),
+            Self::SourceNotAvailable(SourceIdV10::Utxo(UtxoIdV10 {
+                tx_hash,
+                output_index,
+            })) => write!(
+                f,
+                "source T:{}:{} does not exist or is already consumed",
+                tx_hash, output_index
+            ),
+            Self::InvalidUnlock { input_index } => {
+                write!(f, "invalid unlock for input {}", input_index)
+            }
+            Self::InputAmountMismatch { input_index } => write!(
+                f,
+                "amount of input {} does not match its source",
+                input_index
+            ),
+            Self::Locked {
+                input_index,
+                unlockable_on,
+            } => write!(
+                f,
+                "source of input {} is locked until {}",
+                input_index, unlockable_on
+            ),
+            Self::BadSignature => write!(f, "bad signature"),
+            Self::ExpiredBlockstamp(blockstamp) => write!(f, "expired blockstamp {}", blockstamp),
+            Self::Duplicate => write!(f, "transaction already known"),
+            Self::DoubleSpend { pending_tx, .. } => write!(
+                f,
+                "a source is already spent by pending transaction {}",
+                pending_tx
+            ),
+            Self::UnbalancedAmounts => write!(f, "sum of inputs must equal sum of outputs"),
+            Self::WrongCurrency { expected, found } => {
+                write!(f, "wrong currency: expected {}, found {}", expected, found)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TxRejection {}
+
+/// Check a new transaction against the blockchain and the mempool.
+/// An empty `currency` means that the currency is not known yet.
+pub(crate) fn check_new_tx<BcDb: BcV2DbReadable, TxsMpDb: TxsMpV2DbReadable>(
+    bc_db: &BcDb,
+    txs_mp_db: &TxsMpDb,
+    currency: &str,
+    current: Option<BlockMetaV2>,
+    tx: &TransactionDocumentV10,
+) -> KvResult<Result<(), TxRejection>> {
+    if !currency.is_empty() && tx.currency() != currency {
+        return Ok(Err(TxRejection::WrongCurrency {
+            expected: currency.to_owned(),
+            found: tx.currency().to_owned(),
+        }));
+    }
+    if tx.verify_signatures().is_err() {
+        return Ok(Err(TxRejection::BadSignature));
+    }
+    if txs_mp_db.txs().get(&HashKeyV2(tx.get_hash()))?.is_some() {
+        return Ok(Err(TxRejection::Duplicate));
+    }
+    if let Some(current) = current {
+        let blockstamp = tx.blockstamp();
+        let in_window = bc_db
+            .blocks_meta()
+            .get(&U32BE(blockstamp.number.0))?
+            .map(|block_meta| {
+                block_meta.hash == blockstamp.hash.0
+                    && current.median_time.saturating_sub(block_meta.median_time) <= TX_WINDOW
+            })
+            .unwrap_or(false);
+        if !in_window {
+            return Ok(Err(TxRejection::ExpiredBlockstamp(blockstamp)));
+        }
+    }
+
+    let median_time = current.map_or(0, |current| current.median_time);
+    let issuers = tx.issuers();
+    for (input_index, input) in tx.get_inputs().iter().enumerate() {
+        // Amount, script and written time of the source
+        let (amount, script, written_time) = match input.id {
+            SourceIdV10::Ud(UdSourceIdV10 {
+                issuer,
+                block_number,
+            }) => {
+                let ud_opt = if bc_db.uds().get(&UdIdV2(issuer, block_number))?.is_some() {
+                    bc_db
+                        .blocks_meta()
+                        .get(&U32BE(block_number.0))?
+                        .and_then(|block_meta| Some((block_meta.dividend?, block_meta.median_time)))
+                } else {
+                    None
+                };
+                if let Some((amount, written_time)) = ud_opt {
+                    (
+                        amount,
+                        WalletScriptV10::single(WalletConditionV10::Sig(issuer)),
+                        written_time,
+                    )
+                } else {
+                    return Ok(Err(TxRejection::SourceNotAvailable(input.id)));
+                }
+            }
+            SourceIdV10::Utxo(UtxoIdV10 {
+                tx_hash,
+                output_index,
+            }) => {
+                if let Some(utxo) = bc_db
+                    .utxos()
+                    .get(&UtxoIdDbV2(tx_hash, output_index as u32))?
+                {
+                    (utxo.source_amount, utxo.wallet_script, 0)
+                } else if let Some(output) = txs_mp_db
+                    .txs()
+                    .get(&HashKeyV2(tx_hash))?
+                    .and_then(|parent| parent.doc.get_outputs().get(output_index).cloned())
+                {
+                    // Output of a pending transaction, it can not be written before the
+                    // next block.
+                    (output.amount, output.conditions.script, median_time)
+                } else {
+                    return Ok(Err(TxRejection::SourceNotAvailable(input.id)));
+                }
+            }
+        };
+        if input.amount != amount {
+            return Ok(Err(TxRejection::InputAmountMismatch { input_index }));
+        }
+        match crate::rules::input_unlocks(tx, input_index)
+            .map(|unlocks| SourceV10::unlockable_on(&issuers, unlocks, written_time, &script))
+        {
+            Some(Ok(unlockable_on)) => {
+                if unlockable_on > median_time {
+                    return Ok(Err(TxRejection::Locked {
+                        input_index,
+                        unlockable_on,
+                    }));
+                }
+            }
+            Some(Err(_)) | None => return Ok(Err(TxRejection::InvalidUnlock { input_index })),
+        }
+    }
+
+    let inputs_sum = sum_amounts(tx.get_inputs().iter().map(|input| input.amount));
+    let outputs_sum = sum_amounts(tx.get_outputs().iter().map(|output| output.amount));
+    if inputs_sum.is_none() || inputs_sum != outputs_sum {
+        return Ok(Err(TxRejection::UnbalancedAmounts));
+    }
+
+    Ok(Ok(()))
+}
+
+/// Sum of `amounts` in units of base 0, `None` if it does not fit in an `i64`
+fn sum_amounts(mut amounts: impl Iterator<Item = SourceAmount>) -> Option<i64> {
+    amounts.try_fold(0i64, |sum, amount| {
+        sum.checked_add(crate::wallet_group_history::amount_value(amount)?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{sig_unlock, signed_tx, tx, utxo_input};
+    use duniter_core::common::crypto::keys::{ed25519::Ed25519KeyPair, KeyPair as _};
+    use duniter_core::documents::transaction::TransactionInputV10;
+
+    #[test]
+    fn test_check_new_tx() -> anyhow::Result<()> {
+        let server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let keypair = Ed25519KeyPair::generate_random().expect("fail to gen random keypair");
+        let check = |tx: &TransactionDocumentV10| {
+            check_new_tx(
+                &server.bc_db,
+                &server.shared_dbs.txs_mp_db,
+                "test",
+                server.current,
+                tx,
+            )
+        };
+
+        // Pending parent with an output unlockable by the keypair, and a time locked output
+        let parent = tx(
+            &[],
+            &[],
+            vec![
+                WalletScriptV10::single(WalletConditionV10::Sig(keypair.public_key())),
+                WalletScriptV10::single(WalletConditionV10::Cltv(2_000)),
+            ],
+        );
+        server.add_pending_tx_force(parent.clone())?;
+        let recipient = WalletScriptV10::single(WalletConditionV10::Sig(keypair.public_key()));
+
+        let child = signed_tx(
+            &keypair,
+            &[utxo_input(parent.get_hash(), 0)],
+            &[sig_unlock(0)],
+            vec![recipient.clone()],
+        );
+        assert_eq!(check(&child)?, Ok(()));
+
+        // Inputs must declare the amount of their source
+        let wrong_amount = signed_tx(
+            &keypair,
+            &[TransactionInputV10 {
+                amount: SourceAmount::new(10, 1),
+                ..utxo_input(parent.get_hash(), 0)
+            }],
+            &[sig_unlock(0)],
+            vec![recipient.clone()],
+        );
+        assert_eq!(
+            check(&wrong_amount)?,
+            Err(TxRejection::InputAmountMismatch { input_index: 0 })
+        );
+
+        // The sum of the outputs must be the sum of the inputs
+        for outputs in vec![vec![], vec![recipient.clone(), recipient.clone()]] {
+            let unbalanced = signed_tx(
+                &keypair,
+                &[utxo_input(parent.get_hash(), 0)],
+                &[sig_unlock(0)],
+                outputs,
+            );
+            assert_eq!(check(&unbalanced)?, Err(TxRejection::UnbalancedAmounts));
+        }
+
+        // Unlocks are found by the index of their input
+        let wrong_index = signed_tx(
+            &keypair,
+            &[utxo_input(parent.get_hash(), 0)],
+            &[sig_unlock(1)],
+            vec![],
+        );
+        assert_eq!(
+            check(&wrong_index)?,
+            Err(TxRejection::InvalidUnlock { input_index: 0 })
+        );
+
+        let locked = signed_tx(
+            &keypair,
+            &[TransactionInputV10 {
+                amount: SourceAmount::with_base0(100),
+                id: SourceIdV10::Utxo(UtxoIdV10 {
+                    tx_hash: parent.get_hash(),
+                    output_index: 1,
+                }),
+            }],
+            &[sig_unlock(0)],
+            vec![],
+        );
+        assert_eq!(
+            check(&locked)?,
+            Err(TxRejection::Locked {
+                input_index: 0,
+                unlockable_on: 2_000
+            })
+        );
+
+        let unknown_source = signed_tx(
+            &keypair,
+            &[utxo_input(Hash::default(), 0)],
+            &[sig_unlock(0)],
+            vec![],
+        );
+        assert_eq!(
+            check(&unknown_source)?,
+            Err(TxRejection::SourceNotAvailable(
+                utxo_input(Hash::default(), 0).id
+            ))
+        );
+
+        // Not signed
+        assert_eq!(
+            check(&tx(
+                &[utxo_input(parent.get_hash(), 0)],
+                &[sig_unlock(0)],
+                vec![]
+            ))?,
+            Err(TxRejection::BadSignature)
+        );
+
+        server.add_pending_tx_force(child.clone())?;
+        assert_eq!(check(&child)?, Err(TxRejection::Duplicate));
+
+        Ok(())
+    }
+}
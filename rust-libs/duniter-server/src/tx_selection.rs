@@ -0,0 +1,245 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Selection of the pending transactions to include in the next block.
+
+use crate::*;
+use duniter_core::dbs::{UdIdV2, UtxoIdDbV2};
+use duniter_core::documents::transaction::{
+    SourceIdV10, TransactionDocumentTrait, UdSourceIdV10, UtxoIdV10,
+};
+use std::collections::{HashMap, HashSet};
+
+/// Maximum size of a transaction in a block (in lines of its compact format)
+const MAX_TX_SIZE: usize = 100;
+/// Maximum depth of a transaction chain in a block
+const MAX_TX_CHAINING_DEPTH: usize = 5;
+
+/// Size of the transaction in a block, in lines of its compact format
+fn tx_size(tx: &TransactionDocumentV10) -> usize {
+    let tx = tx.to_string_object();
+    2 + tx.issuers.len() * 2
+        + tx.inputs.len()
+        + tx.unlocks.len()
+        + tx.outputs.len()
+        + if tx.comment.is_empty() { 0 } else { 1 }
+}
+
+/// Select the transactions whose sources are available, either in the blockchain or in the
+/// outputs of a previously selected transaction.
+///
+/// `pending_txs` must be sorted by dependencies. Only the first transaction spending a source
+/// is considered, even if it is not selected. The sum of the sizes of the selected
+/// transactions does not exceed `max_size`.
+fn select_txs<BcDb: BcV2DbReadable>(
+    bc_db: &BcDb,
+    pending_txs: Vec<PendingTxDbV2>,
+    max_size: usize,
+) -> KvResult<Vec<TransactionDocumentV10>> {
+    let pending_txs = crate::mempool_conflicts::keep_first_spenders(pending_txs);
+    let mut selected = Vec::new();
+    let mut block_size = 0;
+    let mut consumed = HashSet::new();
+    // Depth of the selected transactions in the chains of the block
+    let mut depths: HashMap<Hash, usize> = HashMap::new();
+
+    'txs: for PendingTxDbV2 { doc: tx, .. } in pending_txs {
+        let size = tx_size(&tx);
+        if size > MAX_TX_SIZE || block_size + size > max_size {
+            continue;
+        }
+        let mut depth = 0;
+        for input in tx.get_inputs() {
+            if consumed.contains(&input.id) {
+                continue 'txs;
+            }
+            let available = match input.id {
+                SourceIdV10::Ud(UdSourceIdV10 {
+                    issuer,
+                    block_number,
+                }) => bc_db.uds().get(&UdIdV2(issuer, block_number))?.is_some(),
+                SourceIdV10::Utxo(UtxoIdV10 {
+                    tx_hash,
+                    output_index,
+                }) => {
+                    if let Some(parent_depth) = depths.get(&tx_hash) {
+                        depth = depth.max(parent_depth + 1);
+                        true
+                    } else {
+                        bc_db
+                            .utxos()
+                            .get(&UtxoIdDbV2(tx_hash, output_index as u32))?
+                            .is_some()
+                    }
+                }
+            };
+            if !available {
+                continue 'txs;
+            }
+        }
+        if depth > MAX_TX_CHAINING_DEPTH {
+            continue;
+        }
+
+        consumed.extend(tx.get_inputs().iter().map(|input| input.id));
+        depths.insert(tx.get_hash(), depth);
+        block_size += size;
+        selected.push(tx);
+    }
+
+    Ok(selected)
+}
+
+/// Pending transactions of version `min_version` or more, usable at `median_time`, to include
+/// in the next block, in dependency order
+pub(crate) fn select_txs_for_next_block(
+    dbs: &SharedDbs<FileBackend>,
+    max_size: usize,
+    median_time: u64,
+    min_version: usize,
+) -> KvResult<Vec<TransactionDocumentV10>> {
+    let pending_txs =
+        crate::pending_txs::get_pending_txs_by_dependencies(dbs, median_time as i64, min_version)?;
+    select_txs(&dbs.bc_db_ro, pending_txs, max_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        blockstamp, branch, keypair, sig_unlock, spending_tx_at, tx, tx_at, utxo_input,
+    };
+    use duniter_core::common::crypto::keys::KeyPair as _;
+    use duniter_core::dbs::HashKeyV2;
+    use duniter_core::wallet::prelude::*;
+
+    // Script of a new random key, to get distinct transactions
+    fn script() -> WalletScriptV10 {
+        WalletScriptV10::single(WalletConditionV10::Sig(keypair().public_key()))
+    }
+
+    #[test]
+    fn test_tx_size() {
+        let inputs = [
+            utxo_input(Hash::default(), 0),
+            utxo_input(Hash::default(), 1),
+        ];
+        // Header and blockstamp, issuer and signature, inputs, unlocks and output
+        assert_eq!(
+            tx_size(&tx(&inputs, &[sig_unlock(0)], vec![script()])),
+            2 + 2 + 2 + 1 + 1
+        );
+        assert_eq!(
+            tx_size(&tx(
+                &inputs,
+                &[sig_unlock(0), sig_unlock(1)],
+                vec![script()]
+            )),
+            2 + 2 + 2 + 2 + 1
+        );
+    }
+
+    #[test]
+    fn test_select_txs_for_next_block() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        // Blocks #0 to #2, median times 1_000 to 1_020
+        server.apply_chunk_of_blocks(branch(0, 0..3, None))?;
+        let b2 = blockstamp(0, 2);
+
+        // A chain of 7 transactions, the root has no input
+        let root = tx_at(b2, 0, vec![script()]);
+        let mut chain = vec![root];
+        for _ in 0..6 {
+            let parent = chain[chain.len() - 1].get_hash();
+            chain.push(spending_tx_at(
+                b2,
+                &[utxo_input(parent, 0)],
+                &[sig_unlock(0)],
+                vec![script()],
+            ));
+        }
+        for tx in &chain {
+            server.add_pending_tx_force(tx.clone())?;
+        }
+        let hashs = |txs: Vec<TransactionDocumentV10>| -> Vec<Hash> {
+            txs.iter().map(|tx| tx.get_hash()).collect()
+        };
+        let chain_hashs = hashs(chain.clone());
+        let (root_size, child_size) = (tx_size(&chain[0]), tx_size(&chain[1]));
+
+        // The last transaction is beyond the maximum chaining depth
+        assert_eq!(
+            hashs(server.select_txs_for_next_block(1_000, 1_020, 10)?),
+            chain_hashs[..6].to_vec()
+        );
+        // Children of a transaction that does not fit in the block are skipped
+        assert_eq!(
+            hashs(server.select_txs_for_next_block(root_size + child_size, 1_020, 10)?),
+            chain_hashs[..2].to_vec()
+        );
+        assert_eq!(
+            hashs(server.select_txs_for_next_block(root_size + child_size - 1, 1_020, 10)?),
+            chain_hashs[..1].to_vec()
+        );
+        // Transactions of an older version are skipped
+        assert!(server
+            .select_txs_for_next_block(1_000, 1_020, 11)?
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_txs_keeps_first_spenders() -> anyhow::Result<()> {
+        let server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let root = tx_at(Blockstamp::default(), 0, vec![script()]);
+        let spend = |outputs| {
+            spending_tx_at(
+                Blockstamp::default(),
+                &[utxo_input(root.get_hash(), 0)],
+                &[sig_unlock(0)],
+                outputs,
+            )
+        };
+        let first = spend(vec![script(), script()]);
+        let second = spend(vec![script()]);
+        let hashs = vec![root.get_hash(), first.get_hash(), second.get_hash()];
+        for tx in vec![&root, &first, &second] {
+            server.add_pending_tx_force(tx.clone())?;
+        }
+        let pending_txs = server
+            .dbs_pool
+            .execute(move |dbs| {
+                let mut txs = Vec::new();
+                for hash in hashs {
+                    if let Some(pending_tx) = dbs.txs_mp_db.txs().get(&HashKeyV2(hash))? {
+                        txs.push(pending_tx);
+                    }
+                }
+                Ok::<_, KvError>(txs)
+            })
+            .expect("dbs pool disconnected")?;
+
+        // The first spender does not fit in the block, the second one is not selected instead
+        let max_size = tx_size(&root) + tx_size(&second);
+        let selected = select_txs(&server.bc_db, pending_txs, max_size)?;
+        assert_eq!(
+            selected.iter().map(|tx| tx.get_hash()).collect::<Vec<_>>(),
+            vec![root.get_hash()]
+        );
+
+        Ok(())
+    }
+}
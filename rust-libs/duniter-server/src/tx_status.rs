@@ -0,0 +1,317 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Status of a transaction, whether it is written, pending, or was dropped from the mempool.
+//!
+//! Transactions dropped from the mempool are recorded with the reason of their removal, so
+//! that clients polling for a payment can tell a dropped transaction from an unknown one.
+//! Records are forgotten after some time, like expired pending transactions.
+
+use crate::*;
+use duniter_core::dbs::databases::txs_mp_v2::TxsMpV2Db;
+use duniter_core::dbs::{BTreeSetV2, HashKeyV2, TimestampKeyV1, U32BE};
+
+db_schema!(
+    TxsDroppedV1,
+    [
+        ["dropped_txs", DroppedTxs, HashKeyV2, TxDropReason],
+        [
+            "dropped_txs_by_time",
+            DroppedTxsByTime,
+            TimestampKeyV1,
+            BTreeSetV2<HashKeyV2>
+        ],
+    ]
+);
+
+pub(crate) fn open_txs_dropped_db(
+    profile_path_opt: Option<&Path>,
+) -> KvResult<TxsDroppedV1Db<FileBackend>> {
+    TxsDroppedV1Db::<FileBackend>::open(FileBackend::gen_backend_conf(
+        "txs_dropped_v1",
+        profile_path_opt,
+    ))
+}
+
+/// Status of a transaction, from the point of view of this node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxStatus {
+    /// In the mempool
+    Pending { received_time: i64 },
+    /// Written in the current chain. `confirmations` is 1 when the transaction is written in
+    /// the current block.
+    Written {
+        block_number: BlockNumber,
+        median_time: u64,
+        confirmations: u32,
+    },
+    /// Dropped from the mempool because it was pending for too long
+    Expired,
+    /// Dropped from the mempool to make room for another transaction, or because its sources
+    /// are no longer available or its blockstamp was reverted
+    Evicted,
+    /// Never seen, or dropped long ago
+    Unknown,
+}
+
+/// Why a pending transaction was dropped from the mempool, stored on a single byte
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TxDropReason {
+    Expired,
+    Evicted,
+}
+
+impl AsBytes for TxDropReason {
+    fn as_bytes<T, F: FnMut(&[u8]) -> T>(&self, mut f: F) -> T {
+        f(&[match self {
+            TxDropReason::Expired => 0,
+            TxDropReason::Evicted => 1,
+        }])
+    }
+}
+
+impl FromBytes for TxDropReason {
+    type Err = StringErr;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Self::Err> {
+        match bytes {
+            [0] => Ok(TxDropReason::Expired),
+            [1] => Ok(TxDropReason::Evicted),
+            _ => Err(StringErr(format!("invalid tx drop reason: {:?}", bytes))),
+        }
+    }
+}
+
+impl From<TxDropReason> for TxStatus {
+    fn from(reason: TxDropReason) -> Self {
+        match reason {
+            TxDropReason::Expired => TxStatus::Expired,
+            TxDropReason::Evicted => TxStatus::Evicted,
+        }
+    }
+}
+
+/// Record the pending transactions `dropped`, just removed from the mempool, as dropped for
+/// `reason`.
+pub(crate) fn record_dropped_txs(
+    txs_dropped_db: &TxsDroppedV1Db<FileBackend>,
+    reason: TxDropReason,
+    dropped: &[Hash],
+) -> KvResult<()> {
+    if dropped.is_empty() {
+        return Ok(());
+    }
+    let now = TimestampKeyV1(crate::wot_mempools::now());
+    let mut hashs = txs_dropped_db
+        .dropped_txs_by_time()
+        .get(&now)?
+        .unwrap_or_default();
+    for hash in dropped {
+        txs_dropped_db
+            .dropped_txs_write()
+            .upsert(HashKeyV2(*hash), reason)?;
+        hashs.0.insert(HashKeyV2(*hash));
+    }
+    txs_dropped_db
+        .dropped_txs_by_time_write()
+        .upsert(now, hashs)
+}
+
+/// Forget the transactions dropped before `limit_time`
+pub(crate) fn trim_dropped_txs(
+    txs_dropped_db: &TxsDroppedV1Db<FileBackend>,
+    limit_time: i64,
+) -> KvResult<()> {
+    let expired = txs_dropped_db
+        .dropped_txs_by_time()
+        .iter(..TimestampKeyV1(limit_time), |it| {
+            it.collect::<KvResult<Vec<_>>>()
+        })?;
+    for (dropped_time, hashs) in expired {
+        for hash in hashs.0 {
+            txs_dropped_db.dropped_txs_write().remove(hash)?;
+        }
+        txs_dropped_db
+            .dropped_txs_by_time_write()
+            .remove(dropped_time)?;
+    }
+    Ok(())
+}
+
+/// Status of a transaction which is not written in the blockchain
+fn get_non_written_tx_status(
+    txs_mp_db: &TxsMpV2Db<FileBackend>,
+    txs_dropped_db: &TxsDroppedV1Db<FileBackend>,
+    hash: Hash,
+) -> KvResult<TxStatus> {
+    if let Some(pending_tx) = txs_mp_db.txs().get(&HashKeyV2(hash))? {
+        return Ok(TxStatus::Pending {
+            received_time: pending_tx.received_time,
+        });
+    }
+    Ok(txs_dropped_db
+        .dropped_txs()
+        .get(&HashKeyV2(hash))?
+        .map_or(TxStatus::Unknown, TxStatus::from))
+}
+
+impl DuniterServer {
+    /// Status of the transaction `hash`. Confirmations are counted from the current block of
+    /// the server.
+    pub fn get_tx_status(&self, hash: Hash) -> KvResult<TxStatus> {
+        let written_block_opt = self
+            .get_tx_by_hash(hash)?
+            .and_then(|(_, block_opt)| block_opt);
+        let current = self.current;
+        self.dbs_pool
+            .execute(move |dbs| get_tx_status(dbs, current, hash, written_block_opt))
+            .expect("dbs pool disconnected")
+    }
+    pub async fn get_tx_status_async(&self, hash: Hash) -> anyhow::Result<TxStatus> {
+        let written_block_opt = self
+            .get_tx_by_hash_async(hash)
+            .await?
+            .and_then(|(_, block_opt)| block_opt);
+        let current = self.current;
+        Ok(self
+            .dbs_pool_async
+            .execute(move |dbs| get_tx_status(dbs, current, hash, written_block_opt))
+            .await
+            .expect("dbs pool disconnected")?)
+    }
+}
+
+fn get_tx_status(
+    dbs: &dbs_pool::PoolDbs<'_>,
+    current: Option<BlockMetaV2>,
+    hash: Hash,
+    written_block_opt: Option<BlockNumber>,
+) -> KvResult<TxStatus> {
+    if let Some(block_number) = written_block_opt {
+        if let Some(block_meta) = dbs.bc_db_ro.blocks_meta().get(&U32BE(block_number.0))? {
+            let confirmations = current.map_or(0, |current| {
+                (current.number + 1).saturating_sub(block_number.0)
+            });
+            return Ok(TxStatus::Written {
+                block_number,
+                median_time: block_meta.median_time,
+                confirmations,
+            });
+        }
+    }
+    get_non_written_tx_status(&dbs.txs_mp_db, &dbs.server.txs_dropped_db, hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        block, block_hash, keypair, sig_unlock, signed_tx, spending_tx_at, tx, utxo_input,
+    };
+    use duniter_core::common::crypto::keys::KeyPair as _;
+    use duniter_core::wallet::prelude::*;
+
+    #[test]
+    fn test_get_tx_status() -> anyhow::Result<()> {
+        let mut server = DuniterServerBuilder::new("test".to_owned())
+            .duniter_mode(DuniterMode::Start)
+            .software_version(duniter_core::module::SOFTWARE_NAME)
+            .without_mempool_maintenance()
+            .start()?;
+        let script = WalletScriptV10::single(WalletConditionV10::Sig(keypair().public_key()));
+        let received_time = crate::wot_mempools::now() - 3_600;
+
+        // Funds the expired transaction
+        let issuer = keypair();
+        let funding = tx(
+            &[],
+            &[],
+            vec![WalletScriptV10::single(WalletConditionV10::Sig(
+                issuer.public_key(),
+            ))],
+        );
+        server.add_pending_tx_force(funding.clone())?;
+
+        let expired = signed_tx(
+            &issuer,
+            &[utxo_input(funding.get_hash(), 0)],
+            &[sig_unlock(0)],
+            vec![script.clone()],
+        );
+        assert_eq!(server.get_tx_status(expired.get_hash())?, TxStatus::Unknown);
+        assert_eq!(
+            server.add_pending_tx_received_at(
+                expired.clone(),
+                keypair().public_key(),
+                received_time
+            )?,
+            Ok(())
+        );
+        assert_eq!(
+            server.get_tx_status(expired.get_hash())?,
+            TxStatus::Pending { received_time }
+        );
+
+        // Spends the output of the expired transaction
+        let child = spending_tx_at(
+            Blockstamp::default(),
+            &[utxo_input(expired.get_hash(), 0)],
+            &[sig_unlock(0)],
+            vec![script.clone()],
+        );
+        server.add_pending_tx_force(child.clone())?;
+        server.trim_expired_non_written_txs(received_time + 60)?;
+        assert_eq!(server.get_tx_status(expired.get_hash())?, TxStatus::Expired);
+        assert_eq!(server.get_tx_status(child.get_hash())?, TxStatus::Evicted);
+
+        // Records are forgotten after some time
+        server
+            .dbs_pool
+            .execute(|dbs| {
+                trim_dropped_txs(&dbs.server.txs_dropped_db, crate::wot_mempools::now() + 1)
+            })
+            .expect("dbs pool disconnected")?;
+        assert_eq!(server.get_tx_status(expired.get_hash())?, TxStatus::Unknown);
+        assert_eq!(server.get_tx_status(child.get_hash())?, TxStatus::Unknown);
+
+        // Transactions removed on request are not evicted
+        let removed = tx(
+            &[],
+            &[],
+            vec![WalletScriptV10::single(WalletConditionV10::Sig(
+                keypair().public_key(),
+            ))],
+        );
+        server.add_pending_tx_force(removed.clone())?;
+        server.remove_pending_tx_by_hash(removed.get_hash())?;
+        assert_eq!(server.get_tx_status(removed.get_hash())?, TxStatus::Unknown);
+
+        let written = tx(&[], &[], vec![script]);
+        let mut genesis = block(0, 0, None);
+        genesis.transactions = vec![written.to_string_object()];
+        server.apply_block(genesis)?;
+        server.apply_block(block(0, 1, Some(block_hash(0, 0))))?;
+        assert_eq!(
+            server.get_tx_status(written.get_hash())?,
+            TxStatus::Written {
+                block_number: BlockNumber(0),
+                median_time: 1_000,
+                confirmations: 2,
+            }
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,565 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Filtered and paginated transactions history of a public key.
+//!
+//! The written history comes from the modules, which only give it in full. It is cached for
+//! the last requested public keys, until the next block, so that a client walking through
+//! the pages does not load it for each page. The pending history is read from the mempool.
+
+use crate::*;
+use duniter_core::dbs::databases::txs_mp_v2::TxsMpV2DbReadable;
+use duniter_core::documents::transaction::TransactionDocumentTrait;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::PoisonError;
+
+/// Number of public keys whose written history is cached
+const WRITTEN_HISTORY_CACHE_SIZE: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TxDirection {
+    /// Written transactions issued by the public key
+    Sent,
+    /// Written transactions received by the public key
+    Received,
+    /// Pending transactions issued by the public key
+    Sending,
+    /// Pending transactions received by the public key
+    Pending,
+}
+
+impl TxDirection {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TxDirection::Sent => "sent",
+            TxDirection::Received => "received",
+            TxDirection::Sending => "sending",
+            TxDirection::Pending => "pending",
+        }
+    }
+}
+
+impl FromStr for TxDirection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sent" => Ok(TxDirection::Sent),
+            "received" => Ok(TxDirection::Received),
+            "sending" => Ok(TxDirection::Sending),
+            "pending" => Ok(TxDirection::Pending),
+            _ => Err(anyhow::anyhow!("Invalid transaction direction: {}", s)),
+        }
+    }
+}
+
+/// Criteria of the transactions to keep, all of them must match. Ranges are inclusive.
+///
+/// Pending transactions are not written in a block, so they never match a block range.
+/// Their time is their received time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TxsHistoryFilter {
+    pub from_block: Option<u32>,
+    pub to_block: Option<u32>,
+    pub from_time: Option<i64>,
+    pub to_time: Option<i64>,
+    /// All directions by default
+    pub directions: Option<Vec<TxDirection>>,
+    /// Keep transactions whose comment contains this string
+    pub comment: Option<String>,
+}
+
+impl TxsHistoryFilter {
+    fn accepts_direction(&self, direction: TxDirection) -> bool {
+        self.directions
+            .as_ref()
+            .map_or(true, |directions| directions.contains(&direction))
+    }
+    fn accepts_written(&self) -> bool {
+        self.accepts_direction(TxDirection::Sent) || self.accepts_direction(TxDirection::Received)
+    }
+    fn matches(&self, entry: &TxHistoryEntry) -> bool {
+        if !self.accepts_direction(entry.direction) {
+            return false;
+        }
+        if self.from_block.is_some() || self.to_block.is_some() {
+            match entry.written_block {
+                Some(blockstamp) => {
+                    let number = blockstamp.number.0;
+                    if self.from_block.map_or(false, |from| number < from)
+                        || self.to_block.map_or(false, |to| number > to)
+                    {
+                        return false;
+                    }
+                }
+                None => return false,
+            }
+        }
+        if self.from_time.map_or(false, |from| entry.time < from)
+            || self.to_time.map_or(false, |to| entry.time > to)
+        {
+            return false;
+        }
+        if let Some(ref comment) = self.comment {
+            if !entry
+                .tx
+                .to_string_object()
+                .comment
+                .contains(comment.as_str())
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Position in the transactions history, sorted by time, then by hash, then by direction (a
+/// transaction to oneself is both sent and received).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TxsHistoryCursor {
+    pub time: i64,
+    pub hash: Hash,
+    pub direction: TxDirection,
+}
+
+impl std::fmt::Display for TxsHistoryCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.time, self.hash, self.direction.as_str())
+    }
+}
+
+impl FromStr for TxsHistoryCursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(time), Some(hash), Some(direction)) => Ok(TxsHistoryCursor {
+                time: time.parse().context("invalid cursor time")?,
+                hash: Hash::from_hex(hash).context("invalid cursor hash")?,
+                direction: TxDirection::from_str(direction)?,
+            }),
+            _ => Err(anyhow::anyhow!("invalid cursor: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TxHistoryEntry {
+    pub direction: TxDirection,
+    pub tx: TransactionDocumentV10,
+    /// `None` for pending transactions
+    pub written_block: Option<Blockstamp>,
+    /// Written time, or received time for pending transactions
+    pub time: i64,
+}
+
+impl TxHistoryEntry {
+    fn cursor(&self) -> TxsHistoryCursor {
+        TxsHistoryCursor {
+            time: self.time,
+            hash: self.tx.get_hash(),
+            direction: self.direction,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TxsHistoryPage {
+    pub txs: Vec<TxHistoryEntry>,
+    /// Cursor of the last transaction of the page, `None` if there is no next page
+    pub next_cursor: Option<TxsHistoryCursor>,
+}
+
+/// Number and hash of the current block, `None` before the genesis block
+type CurrentBlock = Option<(u32, Hash)>;
+
+/// Written transactions histories of the last requested public keys, sorted by cursor. Each
+/// history is valid until the current block changes.
+#[derive(Default)]
+pub(crate) struct WrittenHistoryCache(
+    VecDeque<(PublicKey, CurrentBlock, Arc<Vec<TxHistoryEntry>>)>,
+);
+
+impl WrittenHistoryCache {
+    fn get(&self, pubkey: PublicKey, current: CurrentBlock) -> Option<Arc<Vec<TxHistoryEntry>>> {
+        self.0
+            .iter()
+            .find(|(cached_pubkey, cached_current, _)| {
+                *cached_pubkey == pubkey && *cached_current == current
+            })
+            .map(|(_, _, history)| Arc::clone(history))
+    }
+    fn insert(
+        &mut self,
+        pubkey: PublicKey,
+        current: CurrentBlock,
+        history: Arc<Vec<TxHistoryEntry>>,
+    ) {
+        self.0
+            .retain(|(cached_pubkey, _, _)| *cached_pubkey != pubkey);
+        self.0.push_front((pubkey, current, history));
+        self.0.truncate(WRITTEN_HISTORY_CACHE_SIZE);
+    }
+}
+
+/// Written entries of `history`, sorted by cursor
+fn written_entries(history: TxsHistoryForBma) -> Vec<TxHistoryEntry> {
+    let written = |direction| {
+        move |(tx, written_block, written_time): (TransactionDocumentV10, Blockstamp, i64)| {
+            TxHistoryEntry {
+                direction,
+                tx,
+                written_block: Some(written_block),
+                time: written_time,
+            }
+        }
+    };
+    let mut entries: Vec<TxHistoryEntry> = history
+        .sent
+        .into_iter()
+        .map(written(TxDirection::Sent))
+        .chain(
+            history
+                .received
+                .into_iter()
+                .map(written(TxDirection::Received)),
+        )
+        .collect();
+    entries.sort_by_key(TxHistoryEntry::cursor);
+    entries
+}
+
+/// Pending transactions issued or received by `pubkey`, sorted by cursor
+pub(crate) fn pending_entries<TxsMpDb: TxsMpV2DbReadable>(
+    txs_mp_db: &TxsMpDb,
+    pubkey: PublicKey,
+    filter: &TxsHistoryFilter,
+) -> KvResult<Vec<TxHistoryEntry>> {
+    let mut entries = Vec::new();
+    for &direction in &[TxDirection::Sending, TxDirection::Pending] {
+        if !filter.accepts_direction(direction) {
+            continue;
+        }
+        let hashs_opt = if direction == TxDirection::Sending {
+            txs_mp_db.txs_by_issuer().get(&PubKeyKeyV2(pubkey))?
+        } else {
+            txs_mp_db.txs_by_recipient().get(&PubKeyKeyV2(pubkey))?
+        };
+        for hash in hashs_opt.map(|hashs| hashs.0).unwrap_or_default() {
+            if let Some(pending_tx) = txs_mp_db.txs().get(&hash)? {
+                entries.push(TxHistoryEntry {
+                    direction,
+                    time: pending_tx.received_time,
+                    tx: pending_tx.doc,
+                    written_block: None,
+                });
+            }
+        }
+    }
+    entries.sort_by_key(TxHistoryEntry::cursor);
+    Ok(entries)
+}
+
+/// Keep the entries of `written` and `pending` (both sorted by cursor) matching `filter` and
+/// following `cursor_opt`, then take at most `page_size` of them.
+fn filter_history(
+    written: &[TxHistoryEntry],
+    pending: Vec<TxHistoryEntry>,
+    filter: &TxsHistoryFilter,
+    cursor_opt: Option<TxsHistoryCursor>,
+    page_size: Option<usize>,
+) -> TxsHistoryPage {
+    let written_start = cursor_opt.map_or(0, |start| {
+        match written.binary_search_by(|entry| entry.cursor().cmp(&start)) {
+            Ok(index) => index + 1,
+            Err(index) => index,
+        }
+    });
+    let mut written = written[written_start..]
+        .iter()
+        .filter(|entry| filter.matches(entry))
+        .peekable();
+    let mut pending = pending
+        .into_iter()
+        .filter(|entry| {
+            cursor_opt.map_or(true, |start| entry.cursor() > start) && filter.matches(entry)
+        })
+        .peekable();
+
+    let mut txs = Vec::new();
+    let mut next_cursor = None;
+    loop {
+        let take_written = match (written.peek(), pending.peek()) {
+            (Some(written_entry), Some(pending_entry)) => {
+                written_entry.cursor() < pending_entry.cursor()
+            }
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => break,
+        };
+        if page_size.map_or(false, |page_size| txs.len() == page_size) {
+            next_cursor = txs.last().map(TxHistoryEntry::cursor);
+            break;
+        }
+        if take_written {
+            txs.extend(written.next().cloned());
+        } else {
+            txs.extend(pending.next());
+        }
+    }
+    TxsHistoryPage { txs, next_cursor }
+}
+
+impl DuniterServer {
+    /// Get at most `page_size` transactions of the history of `pubkey` matching `filter`,
+    /// after `cursor_opt`, sorted by time.
+    pub fn get_transactions_history_page(
+        &self,
+        pubkey: PublicKey,
+        filter: TxsHistoryFilter,
+        cursor_opt: Option<TxsHistoryCursor>,
+        page_size: usize,
+    ) -> KvResult<TxsHistoryPage> {
+        self.get_filtered_history(pubkey, filter, cursor_opt, Some(page_size))
+    }
+    /// Get all the transactions of the history of `pubkey` matching `filter`, sorted by
+    /// time.
+    pub fn get_filtered_transactions_history(
+        &self,
+        pubkey: PublicKey,
+        filter: TxsHistoryFilter,
+    ) -> KvResult<Vec<TxHistoryEntry>> {
+        Ok(self.get_filtered_history(pubkey, filter, None, None)?.txs)
+    }
+    fn get_filtered_history(
+        &self,
+        pubkey: PublicKey,
+        filter: TxsHistoryFilter,
+        cursor_opt: Option<TxsHistoryCursor>,
+        page_size: Option<usize>,
+    ) -> KvResult<TxsHistoryPage> {
+        let written = if filter.accepts_written() {
+            self.get_written_history(pubkey)?
+        } else {
+            Arc::new(Vec::new())
+        };
+        let pending = self
+            .dbs_pool
+            .execute({
+                let filter = filter.clone();
+                move |dbs| pending_entries(&dbs.txs_mp_db, pubkey, &filter)
+            })
+            .expect("dbs pool disconnected")?;
+        Ok(filter_history(
+            &written, pending, &filter, cursor_opt, page_size,
+        ))
+    }
+    pub async fn get_transactions_history_page_async(
+        &self,
+        pubkey: PublicKey,
+        filter: TxsHistoryFilter,
+        cursor_opt: Option<TxsHistoryCursor>,
+        page_size: usize,
+    ) -> anyhow::Result<TxsHistoryPage> {
+        let written = if filter.accepts_written() {
+            self.get_written_history_async(pubkey).await?
+        } else {
+            Arc::new(Vec::new())
+        };
+        let pending = self
+            .dbs_pool_async
+            .execute({
+                let filter = filter.clone();
+                move |dbs| pending_entries(&dbs.txs_mp_db, pubkey, &filter)
+            })
+            .await
+            .expect("dbs pool disconnected")?;
+        Ok(filter_history(
+            &written,
+            pending,
+            &filter,
+            cursor_opt,
+            Some(page_size),
+        ))
+    }
+    fn current_block(&self) -> CurrentBlock {
+        self.current.map(|current| (current.number, current.hash))
+    }
+    fn cached_written_history(&self, pubkey: PublicKey) -> Option<Arc<Vec<TxHistoryEntry>>> {
+        self.txs_history_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(pubkey, self.current_block())
+    }
+    fn cache_written_history(
+        &self,
+        pubkey: PublicKey,
+        history: TxsHistoryForBma,
+    ) -> Arc<Vec<TxHistoryEntry>> {
+        let history = Arc::new(written_entries(history));
+        self.txs_history_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(pubkey, self.current_block(), Arc::clone(&history));
+        history
+    }
+    pub(crate) fn get_written_history(
+        &self,
+        pubkey: PublicKey,
+    ) -> KvResult<Arc<Vec<TxHistoryEntry>>> {
+        if let Some(history) = self.cached_written_history(pubkey) {
+            return Ok(history);
+        }
+        Ok(self.cache_written_history(pubkey, self.get_transactions_history(pubkey)?))
+    }
+    pub(crate) async fn get_written_history_async(
+        &self,
+        pubkey: PublicKey,
+    ) -> anyhow::Result<Arc<Vec<TxHistoryEntry>>> {
+        if let Some(history) = self.cached_written_history(pubkey) {
+            return Ok(history);
+        }
+        let history = self.get_transactions_history_async(pubkey).await?;
+        Ok(self.cache_written_history(pubkey, history))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{blockstamp, keypair, tx_at};
+    use duniter_core::common::crypto::keys::KeyPair as _;
+    use duniter_core::wallet::prelude::*;
+
+    fn script() -> WalletScriptV10 {
+        WalletScriptV10::single(WalletConditionV10::Sig(keypair().public_key()))
+    }
+
+    fn written(direction: TxDirection, number: u32, time: i64) -> TxHistoryEntry {
+        TxHistoryEntry {
+            direction,
+            tx: tx_at(blockstamp(0, 0), 0, vec![script()]),
+            written_block: Some(blockstamp(0, number)),
+            time,
+        }
+    }
+
+    fn pending(time: i64) -> TxHistoryEntry {
+        TxHistoryEntry {
+            direction: TxDirection::Pending,
+            tx: tx_at(blockstamp(0, 0), 0, vec![script()]),
+            written_block: None,
+            time,
+        }
+    }
+
+    fn cursors(txs: &[TxHistoryEntry]) -> Vec<TxsHistoryCursor> {
+        txs.iter().map(TxHistoryEntry::cursor).collect()
+    }
+
+    #[test]
+    fn test_walk_through_pages() {
+        let sent = written(TxDirection::Sent, 1, 1_010);
+        // A transaction to oneself is both sent and received, at the same time
+        let to_oneself = written(TxDirection::Sent, 2, 1_020);
+        let to_oneself_received = TxHistoryEntry {
+            direction: TxDirection::Received,
+            ..to_oneself.clone()
+        };
+        let received = written(TxDirection::Received, 3, 1_030);
+        let mut all_written = vec![
+            sent.clone(),
+            to_oneself.clone(),
+            to_oneself_received.clone(),
+            received.clone(),
+        ];
+        all_written.sort_by_key(TxHistoryEntry::cursor);
+        let pending_tx = pending(1_025);
+        let expected = cursors(&[
+            sent,
+            to_oneself,
+            to_oneself_received,
+            pending_tx.clone(),
+            received,
+        ]);
+
+        let mut cursor_opt = None;
+        let mut walked = Vec::new();
+        loop {
+            let page = filter_history(
+                &all_written,
+                vec![pending_tx.clone()],
+                &TxsHistoryFilter::default(),
+                cursor_opt,
+                Some(2),
+            );
+            assert!(page.txs.len() <= 2);
+            walked.extend(cursors(&page.txs));
+            match page.next_cursor {
+                Some(next_cursor) => cursor_opt = Some(next_cursor),
+                None => break,
+            }
+        }
+        assert_eq!(walked, expected);
+
+        let all = filter_history(
+            &all_written,
+            vec![pending_tx],
+            &TxsHistoryFilter::default(),
+            None,
+            None,
+        );
+        assert_eq!(cursors(&all.txs), expected);
+        assert_eq!(all.next_cursor, None);
+    }
+
+    #[test]
+    fn test_filter_history() {
+        let sent = written(TxDirection::Sent, 1, 1_010);
+        let received = written(TxDirection::Received, 3, 1_030);
+        let all_written = vec![sent.clone(), received.clone()];
+        let pending_tx = pending(1_020);
+
+        let by_block = TxsHistoryFilter {
+            from_block: Some(2),
+            ..Default::default()
+        };
+        let page = filter_history(
+            &all_written,
+            vec![pending_tx.clone()],
+            &by_block,
+            None,
+            None,
+        );
+        assert_eq!(cursors(&page.txs), cursors(&[received.clone()]));
+
+        let by_time_and_direction = TxsHistoryFilter {
+            to_time: Some(1_020),
+            directions: Some(vec![TxDirection::Sent, TxDirection::Pending]),
+            ..Default::default()
+        };
+        let page = filter_history(
+            &all_written,
+            vec![pending_tx.clone()],
+            &by_time_and_direction,
+            None,
+            None,
+        );
+        assert_eq!(cursors(&page.txs), cursors(&[sent, pending_tx]));
+    }
+}
@@ -0,0 +1,174 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! In memory indexes of the pending transactions by the sources they consume, and by the
+//! transactions whose outputs they consume.
+//!
+//! The indexes follow the events of the mempool database, and apply the received events
+//! before each query, and at regular intervals by a background thread. Databases send the
+//! events of a write to their subscribers before the write returns, so the indexes are
+//! consistent with the database whoever writes in the mempool (duniter-core block writes,
+//! duniter modules or this crate).
+
+use crate::events::DbEventsIndex;
+use crate::*;
+use duniter_core::dbs::databases::txs_mp_v2::{TxsEvent, TxsMpV2Db};
+use duniter_core::dbs::HashKeyV2;
+use duniter_core::documents::transaction::{SourceIdV10, TransactionDocumentTrait, UtxoIdV10};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::PoisonError;
+
+#[derive(Clone)]
+pub(crate) struct TxsMpIndex(Arc<Mutex<TxsMpIndexInner>>);
+
+struct TxsMpIndexInner {
+    events_recv: flume::Receiver<Arc<Events<TxsEvent>>>,
+    /// Sources consumed by each pending transaction
+    txs_sources: HashMap<Hash, Vec<SourceIdV10>>,
+    /// Pending transactions consuming an output of each transaction
+    children: HashMap<Hash, BTreeSet<Hash>>,
+    /// Pending transactions consuming each source, in the order the mempool received them
+    spenders: HashMap<SourceIdV10, Vec<Hash>>,
+    /// Sources consumed by several pending transactions
+    conflicts: HashSet<SourceIdV10>,
+}
+
+impl TxsMpIndex {
+    /// Index the pending transactions, then follow the changes of the mempool
+    pub(crate) fn new(txs_mp_db: &TxsMpV2Db<FileBackend>) -> anyhow::Result<Self> {
+        // Subscribe before reading the mempool so that no change is missed. Events of
+        // transactions already read are ignored.
+        let (events_sender, events_recv) = flume::unbounded();
+        txs_mp_db.txs().subscribe(events_sender)?;
+        let mut inner = TxsMpIndexInner {
+            events_recv,
+            txs_sources: HashMap::new(),
+            children: HashMap::new(),
+            spenders: HashMap::new(),
+            conflicts: HashSet::new(),
+        };
+        txs_mp_db.txs_by_received_time().iter(.., |it| {
+            for hashs_res in it.values() {
+                for HashKeyV2(hash) in hashs_res?.0 {
+                    if let Some(pending_tx) = txs_mp_db.txs().get(&HashKeyV2(hash))? {
+                        inner.insert(hash, &pending_tx.doc);
+                    }
+                }
+            }
+            Ok::<_, KvError>(())
+        })?;
+        let inner = Arc::new(Mutex::new(inner));
+        crate::events::follow_db_events("duniter-txs-mp-index", &inner)?;
+        Ok(TxsMpIndex(inner))
+    }
+    /// Pending transactions consuming an output of `parent`
+    pub(crate) fn children(&self, parent: Hash) -> Vec<Hash> {
+        let inner = self.sync();
+        inner
+            .children
+            .get(&parent)
+            .map(|children| children.iter().copied().collect())
+            .unwrap_or_default()
+    }
+    /// Pending transactions consuming `source`, in the order the mempool received them
+    pub(crate) fn spenders(&self, source: SourceIdV10) -> Vec<Hash> {
+        let inner = self.sync();
+        inner.spenders.get(&source).cloned().unwrap_or_default()
+    }
+    /// Sources consumed by several pending transactions, with these transactions in the
+    /// order the mempool received them
+    pub(crate) fn conflicts(&self) -> Vec<(SourceIdV10, Vec<Hash>)> {
+        let inner = self.sync();
+        inner
+            .conflicts
+            .iter()
+            .filter_map(|source| {
+                inner
+                    .spenders
+                    .get(source)
+                    .map(|spenders| (*source, spenders.clone()))
+            })
+            .collect()
+    }
+    fn sync(&self) -> std::sync::MutexGuard<'_, TxsMpIndexInner> {
+        let mut inner = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.apply_events();
+        inner
+    }
+}
+
+impl DbEventsIndex for TxsMpIndexInner {
+    fn apply_events(&mut self) {
+        let events: Vec<_> = self.events_recv.try_iter().collect();
+        for event in events.iter().flat_map(|events| events.iter()) {
+            match event {
+                // A transaction is identified by its hash, an upsert of an indexed transaction
+                // changes nothing and must keep its reception order
+                TxsEvent::Upsert { key, value } => {
+                    if !self.txs_sources.contains_key(&key.0) {
+                        self.insert(key.0, &value.doc);
+                    }
+                }
+                TxsEvent::Remove { key } => self.remove(key.0),
+                TxsEvent::RemoveAll => {
+                    self.txs_sources.clear();
+                    self.children.clear();
+                    self.spenders.clear();
+                    self.conflicts.clear();
+                }
+            }
+        }
+    }
+}
+
+impl TxsMpIndexInner {
+    fn insert(&mut self, hash: Hash, tx: &TransactionDocumentV10) {
+        let sources: Vec<SourceIdV10> = tx.get_inputs().iter().map(|input| input.id).collect();
+        for source in &sources {
+            if let SourceIdV10::Utxo(UtxoIdV10 { tx_hash, .. }) = source {
+                self.children.entry(*tx_hash).or_default().insert(hash);
+            }
+            let spenders = self.spenders.entry(*source).or_default();
+            if !spenders.contains(&hash) {
+                spenders.push(hash);
+            }
+            if spenders.len() > 1 {
+                self.conflicts.insert(*source);
+            }
+        }
+        self.txs_sources.insert(hash, sources);
+    }
+    fn remove(&mut self, hash: Hash) {
+        for source in self.txs_sources.remove(&hash).unwrap_or_default() {
+            if let SourceIdV10::Utxo(UtxoIdV10 { tx_hash, .. }) = source {
+                if let Some(children) = self.children.get_mut(&tx_hash) {
+                    children.remove(&hash);
+                    if children.is_empty() {
+                        self.children.remove(&tx_hash);
+                    }
+                }
+            }
+            if let Some(spenders) = self.spenders.get_mut(&source) {
+                spenders.retain(|spender| *spender != hash);
+                if spenders.len() < 2 {
+                    self.conflicts.remove(&source);
+                }
+                if spenders.is_empty() {
+                    self.spenders.remove(&source);
+                }
+            }
+        }
+    }
+}
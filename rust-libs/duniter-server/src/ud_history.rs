@@ -0,0 +1,356 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Universal dividends created by a member.
+//!
+//! The blockchain database only keeps unconsumed dividends, so the dividends of a member
+//! are found from its membership periods: a member gets the dividend of each block written
+//! while it is a member. Joins and exclusions are indexed when blocks are written, from the
+//! first block written with this index: earlier dividends can only be found after a new
+//! synchronization.
+
+use crate::*;
+use duniter_core::dbs::{UdIdV2, U32BE};
+use duniter_core::wallet::prelude::SourceAmount;
+use std::ops::RangeInclusive;
+
+// Membership events are keyed by member then block number, which is the layout of `UdIdV2`
+db_schema!(
+    MembershipsV1,
+    [
+        ["membership_events", MembershipEvents, UdIdV2, String],
+        ["indexed_from", IndexedFrom, (), u32],
+    ]
+);
+
+const JOIN: &str = "join";
+const EXCLUSION: &str = "exclusion";
+
+pub(crate) fn open_memberships_db(
+    profile_path_opt: Option<&Path>,
+) -> KvResult<MembershipsV1Db<FileBackend>> {
+    MembershipsV1Db::<FileBackend>::open(FileBackend::gen_backend_conf(
+        "memberships_v1",
+        profile_path_opt,
+    ))
+}
+
+/// Index the joins and exclusions of a block, once it is written in the blockchain database
+pub(crate) fn apply_block(
+    memberships_db: &MembershipsV1Db<FileBackend>,
+    block: &DubpBlockV10,
+) -> KvResult<()> {
+    let block_number = block.number();
+    if memberships_db.indexed_from().get(&())?.is_none() {
+        memberships_db
+            .indexed_from_write()
+            .upsert((), block_number.0)?;
+    }
+    for joiner in block.joiners() {
+        memberships_db
+            .membership_events_write()
+            .upsert(UdIdV2(joiner.issuers()[0], block_number), JOIN.to_owned())?;
+    }
+    for excluded in block.excluded() {
+        memberships_db
+            .membership_events_write()
+            .upsert(UdIdV2(*excluded, block_number), EXCLUSION.to_owned())?;
+    }
+    Ok(())
+}
+
+/// Remove the joins and exclusions of a reverted block
+pub(crate) fn revert_block(
+    memberships_db: &MembershipsV1Db<FileBackend>,
+    block: &DubpBlockV10,
+) -> KvResult<()> {
+    let block_number = block.number();
+    for joiner in block.joiners() {
+        memberships_db
+            .membership_events_write()
+            .remove(UdIdV2(joiner.issuers()[0], block_number))?;
+    }
+    for excluded in block.excluded() {
+        memberships_db
+            .membership_events_write()
+            .remove(UdIdV2(*excluded, block_number))?;
+    }
+    if memberships_db.indexed_from().get(&())? == Some(block_number.0) {
+        memberships_db.indexed_from_write().remove(())?;
+    }
+    Ok(())
+}
+
+/// Universal dividends to keep, ranges are inclusive
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UdHistoryRange {
+    All,
+    Blocks(RangeInclusive<u32>),
+    /// Median times of the blocks
+    Times(RangeInclusive<u64>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UdHistoryEntry {
+    pub block_number: BlockNumber,
+    /// Median time of the block
+    pub time: u64,
+    pub amount: SourceAmount,
+    pub consumed: bool,
+}
+
+/// Membership periods of `pubkey`, as ranges of block numbers (the end is excluded)
+fn membership_periods(
+    memberships_db: &MembershipsV1Db<FileBackend>,
+    pubkey: PublicKey,
+) -> KvResult<Vec<(u32, u32)>> {
+    let events = memberships_db.membership_events().iter(
+        UdIdV2(pubkey, BlockNumber(0))..=UdIdV2(pubkey, BlockNumber(u32::MAX)),
+        |it| it.collect::<KvResult<Vec<_>>>(),
+    )?;
+    let mut periods = Vec::new();
+    let mut join_opt = None;
+    for (UdIdV2(_, block_number), event) in events {
+        match (event.as_str(), join_opt) {
+            (JOIN, None) => join_opt = Some(block_number.0),
+            (EXCLUSION, Some(join)) => {
+                periods.push((join, block_number.0));
+                join_opt = None;
+            }
+            _ => (),
+        }
+    }
+    if let Some(join) = join_opt {
+        periods.push((join, u32::MAX));
+    }
+    Ok(periods)
+}
+
+/// Fail if `range` starts before the first block whose memberships are indexed
+fn check_indexed_range<BcDb: BcV2DbReadable>(
+    bc_db: &BcDb,
+    memberships_db: &MembershipsV1Db<FileBackend>,
+    range: &UdHistoryRange,
+) -> anyhow::Result<()> {
+    let indexed_from = match memberships_db.indexed_from().get(&())? {
+        Some(indexed_from) => indexed_from,
+        None if bc_db.blocks_meta().count()? == 0 => return Ok(()),
+        None => {
+            return Err(anyhow::anyhow!(
+                "memberships are not indexed, the blockchain must be synchronized again"
+            ))
+        }
+    };
+    let indexed = match range {
+        _ if indexed_from == 0 => true,
+        UdHistoryRange::All => false,
+        UdHistoryRange::Blocks(blocks) => *blocks.start() >= indexed_from,
+        UdHistoryRange::Times(times) => bc_db
+            .blocks_meta()
+            .get(&U32BE(indexed_from))?
+            .map_or(false, |block_meta| *times.start() >= block_meta.median_time),
+    };
+    if indexed {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "memberships are indexed from block #{}, earlier dividends need a new synchronization",
+            indexed_from
+        ))
+    }
+}
+
+pub(crate) fn get_ud_history<BcDb: BcV2DbReadable>(
+    bc_db: &BcDb,
+    memberships_db: &MembershipsV1Db<FileBackend>,
+    pubkey: PublicKey,
+    range: &UdHistoryRange,
+) -> anyhow::Result<Vec<UdHistoryEntry>> {
+    check_indexed_range(bc_db, memberships_db, range)?;
+
+    let mut history = Vec::new();
+    for (join, exclusion) in membership_periods(memberships_db, pubkey)? {
+        let (mut from, mut to) = (join, exclusion.saturating_sub(1));
+        if let UdHistoryRange::Blocks(blocks) = range {
+            from = from.max(*blocks.start());
+            to = to.min(*blocks.end());
+        }
+        if from > to {
+            continue;
+        }
+        bc_db.blocks_meta().iter(U32BE(from)..=U32BE(to), |it| {
+            for block_meta_res in it.values() {
+                let block_meta = block_meta_res?;
+                if let Some(amount) = block_meta.dividend {
+                    if let UdHistoryRange::Times(times) = range {
+                        if !times.contains(&block_meta.median_time) {
+                            continue;
+                        }
+                    }
+                    let block_number = BlockNumber(block_meta.number);
+                    history.push(UdHistoryEntry {
+                        block_number,
+                        time: block_meta.median_time,
+                        amount,
+                        consumed: bc_db.uds().get(&UdIdV2(pubkey, block_number))?.is_none(),
+                    });
+                }
+            }
+            Ok::<_, KvError>(())
+        })?;
+    }
+    Ok(history)
+}
+
+impl DuniterServer {
+    /// Universal dividends created by `pubkey` in `range`, sorted by block number.
+    pub fn get_ud_history(
+        &self,
+        pubkey: PublicKey,
+        range: UdHistoryRange,
+    ) -> anyhow::Result<Vec<UdHistoryEntry>> {
+        self.dbs_pool
+            .execute(move |dbs| {
+                get_ud_history(&dbs.bc_db_ro, &dbs.server.memberships_db, pubkey, &range)
+            })
+            .expect("dbs pool disconnected")
+    }
+    pub async fn get_ud_history_async(
+        &self,
+        pubkey: PublicKey,
+        range: UdHistoryRange,
+    ) -> anyhow::Result<Vec<UdHistoryEntry>> {
+        self.dbs_pool_async
+            .execute(move |dbs| {
+                get_ud_history(&dbs.bc_db_ro, &dbs.server.memberships_db, pubkey, &range)
+            })
+            .await
+            .expect("dbs pool disconnected")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{block, block_hash, genesis_with_member, keypair, sig_unlock, tx};
+    use duniter_core::common::crypto::keys::{ed25519::Ed25519KeyPair, KeyPair as _};
+    use duniter_core::documents::transaction::{TransactionInputV10, UdSourceIdV10};
+    use duniter_core::wallet::prelude::*;
+
+    fn entry(number: u32, consumed: bool) -> UdHistoryEntry {
+        UdHistoryEntry {
+            block_number: BlockNumber(number),
+            time: 1_000 + u64::from(number) * 10,
+            amount: SourceAmount::with_base0(1_000),
+            consumed,
+        }
+    }
+
+    /// Alice joins in the genesis block, gets the dividends of blocks 1 and 2, spends the
+    /// first one in block 2, and is excluded in block 3, before the dividend of block 4.
+    fn blocks(alice: &Ed25519KeyPair) -> Vec<DubpBlockV10Stringified> {
+        let mut blocks = vec![genesis_with_member(alice, "alice")];
+        let spending = tx(
+            &[TransactionInputV10 {
+                amount: SourceAmount::with_base0(1_000),
+                id: SourceIdV10::Ud(UdSourceIdV10 {
+                    issuer: alice.public_key(),
+                    block_number: BlockNumber(1),
+                }),
+            }],
+            &[sig_unlock(0)],
+            vec![WalletScriptV10::single(WalletConditionV10::Sig(
+                keypair().public_key(),
+            ))],
+        );
+        for number in 1..5 {
+            let mut block = block(0, number, Some(block_hash(0, number - 1)));
+            match number {
+                2 => block.transactions = vec![spending.to_string_object()],
+                3 => block.excluded = vec![alice.public_key().to_string()],
+                _ => (),
+            }
+            if number != 3 {
+                block.dividend = Some(1_000);
+            }
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_get_ud_history() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let alice = keypair();
+        for block in blocks(&alice) {
+            server.apply_block(block)?;
+        }
+
+        assert_eq!(
+            server.get_ud_history(alice.public_key(), UdHistoryRange::All)?,
+            vec![entry(1, true), entry(2, false)]
+        );
+        assert_eq!(
+            server.get_ud_history(alice.public_key(), UdHistoryRange::Blocks(2..=4))?,
+            vec![entry(2, false)]
+        );
+        assert_eq!(
+            server.get_ud_history(alice.public_key(), UdHistoryRange::Times(1_000..=1_015))?,
+            vec![entry(1, true)]
+        );
+
+        // The exclusion is forgotten with its block
+        server.revert_block(blocks(&alice).remove(4))?;
+        server.revert_block(blocks(&alice).remove(3))?;
+        let mut block = block(0, 3, Some(block_hash(0, 2)));
+        block.dividend = Some(1_000);
+        server.apply_block(block)?;
+        assert_eq!(
+            server.get_ud_history(alice.public_key(), UdHistoryRange::All)?,
+            vec![entry(1, true), entry(2, false), entry(3, false)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ranges_before_indexing_are_refused() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let alice = keypair();
+        let blocks = blocks(&alice);
+        for block in blocks.iter().cloned() {
+            server.apply_block(block)?;
+        }
+        // Memberships indexed from block 3, like on a node upgraded after block 2
+        let memberships_db = open_memberships_db(None)?;
+        let range_res =
+            |range| get_ud_history(&server.bc_db, &memberships_db, alice.public_key(), &range);
+        assert!(range_res(UdHistoryRange::Blocks(0..=4)).is_err());
+        for block in &blocks[3..] {
+            apply_block(
+                &memberships_db,
+                &DubpBlockV10::from_string_object(block).expect("invalid test block"),
+            )?;
+        }
+
+        assert!(range_res(UdHistoryRange::All).is_err());
+        assert!(range_res(UdHistoryRange::Blocks(2..=4)).is_err());
+        assert!(range_res(UdHistoryRange::Times(1_020..=1_040)).is_err());
+        assert_eq!(range_res(UdHistoryRange::Blocks(3..=4))?, vec![]);
+        assert_eq!(range_res(UdHistoryRange::Times(1_030..=1_040))?, vec![]);
+
+        Ok(())
+    }
+}
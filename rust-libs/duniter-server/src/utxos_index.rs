@@ -0,0 +1,116 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! In memory index of the unspent transaction outputs of the blockchain by output script.
+//!
+//! It follows the events of the blockchain database: received events are applied before each
+//! query, and at regular intervals by a background thread. Scripts are indexed by the hash of
+//! their text, so that long scripts are not stored for each of their outputs.
+
+use crate::events::DbEventsIndex;
+use crate::*;
+use duniter_core::dbs::databases::bc_v2::{BcV2Db, UtxosEvent};
+use duniter_core::dbs::UtxoIdDbV2;
+use duniter_core::wallet::prelude::*;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::PoisonError;
+
+#[derive(Clone)]
+pub(crate) struct UtxosIndex(Arc<Mutex<UtxosIndexInner>>);
+
+struct UtxosIndexInner {
+    events_recv: flume::Receiver<Arc<Events<UtxosEvent>>>,
+    /// Unspent outputs (transaction hash and output index) of each script hash
+    by_script: HashMap<Hash, BTreeSet<(Hash, u32)>>,
+    /// Script hash of each unspent output
+    scripts: HashMap<(Hash, u32), Hash>,
+}
+
+impl UtxosIndex {
+    /// Index the unspent outputs, then follow the changes of the blockchain database
+    pub(crate) fn new(bc_db: &BcV2Db<FileBackend>) -> anyhow::Result<Self> {
+        // Subscribe before reading the outputs so that no change is missed
+        let (events_sender, events_recv) = flume::unbounded();
+        bc_db.utxos().subscribe(events_sender)?;
+        let mut inner = UtxosIndexInner {
+            events_recv,
+            by_script: HashMap::new(),
+            scripts: HashMap::new(),
+        };
+        bc_db.utxos().iter(.., |it| {
+            for entry_res in it {
+                let (UtxoIdDbV2(tx_hash, output_index), utxo) = entry_res?;
+                inner.insert((tx_hash, output_index), &utxo.wallet_script);
+            }
+            Ok::<_, KvError>(())
+        })?;
+        let inner = Arc::new(Mutex::new(inner));
+        crate::events::follow_db_events("duniter-utxos-index", &inner)?;
+        Ok(UtxosIndex(inner))
+    }
+    /// Unspent outputs that can be unlocked by `script`, by transaction hash and output index
+    pub(crate) fn utxos(&self, script: &WalletScriptV10) -> Vec<(Hash, u32)> {
+        let mut inner = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.apply_events();
+        inner
+            .by_script
+            .get(&script_hash(script))
+            .map(|utxos| utxos.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl DbEventsIndex for UtxosIndexInner {
+    fn apply_events(&mut self) {
+        let events: Vec<_> = self.events_recv.try_iter().collect();
+        for event in events.iter().flat_map(|events| events.iter()) {
+            match event {
+                UtxosEvent::Upsert {
+                    key: UtxoIdDbV2(tx_hash, output_index),
+                    value,
+                } => self.insert((*tx_hash, *output_index), &value.wallet_script),
+                UtxosEvent::Remove {
+                    key: UtxoIdDbV2(tx_hash, output_index),
+                } => self.remove((*tx_hash, *output_index)),
+                UtxosEvent::RemoveAll => {
+                    self.by_script.clear();
+                    self.scripts.clear();
+                }
+            }
+        }
+    }
+}
+
+impl UtxosIndexInner {
+    fn insert(&mut self, utxo: (Hash, u32), script: &WalletScriptV10) {
+        let script_hash = script_hash(script);
+        self.by_script.entry(script_hash).or_default().insert(utxo);
+        self.scripts.insert(utxo, script_hash);
+    }
+    fn remove(&mut self, utxo: (Hash, u32)) {
+        if let Some(script_hash) = self.scripts.remove(&utxo) {
+            if let Some(utxos) = self.by_script.get_mut(&script_hash) {
+                utxos.remove(&utxo);
+                if utxos.is_empty() {
+                    self.by_script.remove(&script_hash);
+                }
+            }
+        }
+    }
+}
+
+fn script_hash(script: &WalletScriptV10) -> Hash {
+    Hash::compute(script.to_string().as_bytes())
+}
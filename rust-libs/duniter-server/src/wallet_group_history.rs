@@ -0,0 +1,343 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Merged transactions history of a group of public keys held by the same user.
+//!
+//! A transaction is listed once even when it concerns several keys of the group, and its
+//! net amount only counts what enters or leaves the group. The written history of each key
+//! comes from the written histories cache, the pending transactions of all keys are read in
+//! a single job.
+
+use crate::txs_history::{TxHistoryEntry, TxsHistoryFilter};
+use crate::*;
+use duniter_core::documents::transaction::{SourceIdV10, TransactionDocumentTrait, UdSourceIdV10};
+use duniter_core::wallet::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+
+#[derive(Clone, Debug)]
+pub struct WalletGroupTx {
+    pub tx: TransactionDocumentV10,
+    /// `None` for pending transactions
+    pub written_block: Option<Blockstamp>,
+    /// Written time, or received time for pending transactions
+    pub time: i64,
+    /// All inputs are spent by keys of the group and all outputs go to keys of the group
+    pub internal: bool,
+    /// Amount received by the group minus amount spent by the group, in units of base 0.
+    /// `None` if it does not fit in an `i64`.
+    pub net_amount: Option<i64>,
+}
+
+/// Value of `amount` in units of base 0, `None` if it does not fit in an `i64`
+pub(crate) fn amount_value(amount: SourceAmount) -> Option<i64> {
+    u32::try_from(amount.base())
+        .ok()
+        .and_then(|base| 10i64.checked_pow(base))
+        .and_then(|unit| amount.amount().checked_mul(unit))
+}
+
+/// Only keys of `group` can unlock `script`: it requires at least one signature, all its
+/// signatures are of keys of the group and it has no hash lock. Time locks do not matter.
+fn held_by_group(group: &HashSet<PublicKey>, script: &WalletScriptV10) -> bool {
+    let mut has_sig = false;
+    for sub_script in std::iter::once(&script.root).chain(script.nodes.iter()) {
+        match sub_script {
+            WalletSubScriptV10::Single(WalletConditionV10::Sig(pubkey)) => {
+                if !group.contains(pubkey) {
+                    return false;
+                }
+                has_sig = true;
+            }
+            WalletSubScriptV10::Single(WalletConditionV10::Xhx(_)) => return false,
+            _ => (),
+        }
+    }
+    has_sig
+}
+
+/// The input `input_index` of `tx` is spent by `group`: it is a dividend of a key of the
+/// group, or it is unlocked by signatures of keys of the group only.
+fn spent_by_group(
+    group: &HashSet<PublicKey>,
+    tx: &TransactionDocumentV10,
+    input_index: usize,
+) -> bool {
+    if let SourceIdV10::Ud(UdSourceIdV10 { issuer, .. }) = tx.get_inputs()[input_index].id {
+        return group.contains(&issuer);
+    }
+    let issuers = tx.issuers();
+    let mut has_sig = false;
+    for unlock in crate::rules::input_unlocks(tx, input_index).unwrap_or(&[]) {
+        if let WalletUnlockProofV10::Sig(issuer_index) = unlock {
+            if !issuers
+                .get(*issuer_index)
+                .map_or(false, |issuer| group.contains(issuer))
+            {
+                return false;
+            }
+            has_sig = true;
+        }
+    }
+    has_sig
+}
+
+impl WalletGroupTx {
+    fn new(
+        group: &HashSet<PublicKey>,
+        tx: TransactionDocumentV10,
+        written_block: Option<Blockstamp>,
+        time: i64,
+    ) -> Self {
+        let mut spent = Some(0i64);
+        let mut all_inputs_in_group = true;
+        for (input_index, input) in tx.get_inputs().iter().enumerate() {
+            if spent_by_group(group, &tx, input_index) {
+                spent = spent.and_then(|spent| spent.checked_add(amount_value(input.amount)?));
+            } else {
+                all_inputs_in_group = false;
+            }
+        }
+        let mut received = Some(0i64);
+        let mut all_outputs_in_group = true;
+        for output in tx.get_outputs() {
+            if held_by_group(group, &output.conditions.script) {
+                received = received
+                    .and_then(|received| received.checked_add(amount_value(output.amount)?));
+            } else {
+                all_outputs_in_group = false;
+            }
+        }
+        WalletGroupTx {
+            tx,
+            written_block,
+            time,
+            internal: all_inputs_in_group && all_outputs_in_group,
+            net_amount: received.and_then(|received| received.checked_sub(spent?)),
+        }
+    }
+}
+
+/// Merge the history entries of the keys of `group`, each transaction once, sorted by time
+/// then by hash.
+pub(crate) fn merge_histories<'a>(
+    group: &HashSet<PublicKey>,
+    entries: impl IntoIterator<Item = &'a TxHistoryEntry>,
+) -> Vec<WalletGroupTx> {
+    let mut txs = HashMap::new();
+    for entry in entries {
+        txs.entry(entry.tx.get_hash()).or_insert_with(|| {
+            WalletGroupTx::new(group, entry.tx.clone(), entry.written_block, entry.time)
+        });
+    }
+    let mut txs: Vec<(Hash, WalletGroupTx)> = txs.into_iter().collect();
+    txs.sort_by(|(hash1, tx1), (hash2, tx2)| (tx1.time, hash1).cmp(&(tx2.time, hash2)));
+    txs.into_iter().map(|(_, tx)| tx).collect()
+}
+
+/// Pending transactions issued or received by keys of `group`
+fn group_pending_entries(
+    dbs: &dbs_pool::PoolDbs<'_>,
+    group: &HashSet<PublicKey>,
+) -> KvResult<Vec<TxHistoryEntry>> {
+    let mut entries = Vec::new();
+    for pubkey in group {
+        entries.extend(crate::txs_history::pending_entries(
+            &dbs.txs_mp_db,
+            *pubkey,
+            &TxsHistoryFilter::default(),
+        )?);
+    }
+    Ok(entries)
+}
+
+impl DuniterServer {
+    /// Transactions history of a group of public keys, sorted by time.
+    pub fn get_wallet_group_history(&self, pubkeys: &[PublicKey]) -> KvResult<Vec<WalletGroupTx>> {
+        let group: HashSet<PublicKey> = pubkeys.iter().copied().collect();
+        let written = group
+            .iter()
+            .map(|pubkey| self.get_written_history(*pubkey))
+            .collect::<KvResult<Vec<_>>>()?;
+        let pending = self
+            .dbs_pool
+            .execute({
+                let group = group.clone();
+                move |dbs| group_pending_entries(dbs, &group)
+            })
+            .expect("dbs pool disconnected")?;
+        Ok(merge_histories(
+            &group,
+            written
+                .iter()
+                .flat_map(|entries| entries.iter())
+                .chain(&pending),
+        ))
+    }
+    pub async fn get_wallet_group_history_async(
+        &self,
+        pubkeys: &[PublicKey],
+    ) -> anyhow::Result<Vec<WalletGroupTx>> {
+        let group: HashSet<PublicKey> = pubkeys.iter().copied().collect();
+        let mut written = Vec::with_capacity(group.len());
+        for pubkey in &group {
+            written.push(self.get_written_history_async(*pubkey).await?);
+        }
+        let pending = self
+            .dbs_pool_async
+            .execute({
+                let group = group.clone();
+                move |dbs| group_pending_entries(dbs, &group)
+            })
+            .await
+            .expect("dbs pool disconnected")?;
+        Ok(merge_histories(
+            &group,
+            written
+                .iter()
+                .flat_map(|entries| entries.iter())
+                .chain(&pending),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{block, keypair, sig_unlock, signed_tx, tx, utxo_input};
+    use duniter_core::common::crypto::keys::{ed25519::Ed25519KeyPair, KeyPair as _};
+    use duniter_core::documents::smallvec::smallvec;
+    use duniter_core::documents::transaction::TransactionInputV10;
+
+    fn sig(keypair: &Ed25519KeyPair) -> WalletScriptV10 {
+        WalletScriptV10::single(WalletConditionV10::Sig(keypair.public_key()))
+    }
+
+    fn ud_input(keypair: &Ed25519KeyPair) -> TransactionInputV10 {
+        TransactionInputV10 {
+            amount: SourceAmount::with_base0(100),
+            id: SourceIdV10::Ud(UdSourceIdV10 {
+                issuer: keypair.public_key(),
+                block_number: BlockNumber(1),
+            }),
+        }
+    }
+
+    fn group_tx(group: &HashSet<PublicKey>, tx: TransactionDocumentV10) -> WalletGroupTx {
+        WalletGroupTx::new(group, tx, None, 0)
+    }
+
+    #[test]
+    fn test_wallet_group_tx() {
+        let (alice, bob, carol) = (keypair(), keypair(), keypair());
+        let group: HashSet<PublicKey> = vec![alice.public_key(), bob.public_key()]
+            .into_iter()
+            .collect();
+        let inputs = [utxo_input(Hash::default(), 0), ud_input(&alice)];
+        // Unlocks are found by their index, not by their position
+        let unlocks = [sig_unlock(1), sig_unlock(0)];
+        let multisig = WalletScriptV10 {
+            root: WalletSubScriptV10::And(0, 1),
+            nodes: smallvec![
+                WalletSubScriptV10::Single(WalletConditionV10::Sig(alice.public_key())),
+                WalletSubScriptV10::Single(WalletConditionV10::Sig(bob.public_key())),
+            ],
+        };
+        let hash_lock = WalletScriptV10 {
+            root: WalletSubScriptV10::Or(0, 1),
+            nodes: smallvec![
+                WalletSubScriptV10::Single(WalletConditionV10::Sig(alice.public_key())),
+                WalletSubScriptV10::Single(WalletConditionV10::Xhx(Hash::default())),
+            ],
+        };
+
+        let internal = group_tx(
+            &group,
+            signed_tx(&alice, &inputs, &unlocks, vec![multisig, sig(&bob)]),
+        );
+        assert!(internal.internal);
+        assert_eq!(internal.net_amount, Some(0));
+
+        let spending = group_tx(
+            &group,
+            signed_tx(&alice, &inputs, &unlocks, vec![sig(&carol), hash_lock]),
+        );
+        assert!(!spending.internal);
+        assert_eq!(spending.net_amount, Some(-200));
+
+        // The input is unlocked by a key out of the group
+        let receiving = group_tx(
+            &group,
+            signed_tx(
+                &carol,
+                &[utxo_input(Hash::default(), 0)],
+                &[sig_unlock(0)],
+                vec![sig(&alice)],
+            ),
+        );
+        assert!(!receiving.internal);
+        assert_eq!(receiving.net_amount, Some(100));
+    }
+
+    #[test]
+    fn test_amount_value() {
+        assert_eq!(amount_value(SourceAmount::new(123, 2)), Some(12_300));
+        assert_eq!(amount_value(SourceAmount::new(i64::MAX, 1)), None);
+        assert_eq!(amount_value(SourceAmount::new(1, 19)), None);
+        assert_eq!(amount_value(SourceAmount::new(1, -1)), None);
+    }
+
+    #[test]
+    fn test_get_wallet_group_history() -> anyhow::Result<()> {
+        let mut server = DuniterServerBuilder::new("test".to_owned())
+            .duniter_mode(DuniterMode::Start)
+            .software_version(duniter_core::module::SOFTWARE_NAME)
+            .without_mempool_maintenance()
+            .start()?;
+        let (alice, bob, carol) = (keypair(), keypair(), keypair());
+
+        let written = tx(&[], &[], vec![sig(&alice), sig(&carol)]);
+        let mut genesis = block(0, 0, None);
+        genesis.transactions = vec![written.to_string_object()];
+        server.apply_block(genesis)?;
+        // Both keys of the group are concerned
+        let pending = signed_tx(
+            &alice,
+            &[utxo_input(written.get_hash(), 0)],
+            &[sig_unlock(0)],
+            vec![sig(&bob)],
+        );
+        server.add_pending_tx_force(pending.clone())?;
+
+        let history = server.get_wallet_group_history(&[alice.public_key(), bob.public_key()])?;
+        assert_eq!(
+            history
+                .iter()
+                .map(|group_tx| (
+                    group_tx.tx.get_hash(),
+                    group_tx.written_block.map(|wb| wb.number),
+                    group_tx.internal,
+                    group_tx.net_amount
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (written.get_hash(), Some(BlockNumber(0)), false, Some(100)),
+                (pending.get_hash(), None, true, Some(0)),
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,252 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Sources (unspent transaction outputs and unconsumed universal dividends) of an output
+//! script.
+
+use crate::*;
+use duniter_core::dbs::{UdIdV2, UtxoIdDbV2, U32BE};
+use duniter_core::documents::transaction::{
+    SourceIdV10, TransactionDocumentTrait, UdSourceIdV10, UtxoIdV10,
+};
+use duniter_core::wallet::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalletSource {
+    pub id: SourceIdV10,
+    pub amount: SourceAmount,
+    /// Script of the source, as written in the blockchain or in the pending transaction
+    pub conditions: WalletScriptV10,
+    /// Output of a pending transaction
+    pub pending: bool,
+}
+
+/// Sources that can be unlocked by `script`.
+///
+/// Universal dividends are only returned for single signature scripts. With `with_pending`,
+/// the outputs of pending transactions are added and the sources consumed by pending
+/// transactions are removed.
+pub(crate) fn get_sources(
+    dbs: &dbs_pool::PoolDbs<'_>,
+    script: &WalletScriptV10,
+    with_pending: bool,
+) -> KvResult<Vec<WalletSource>> {
+    let mut sources = Vec::new();
+
+    if let Some(pubkey) = single_sig_pubkey(script) {
+        let uds = dbs.bc_db_ro.uds().iter(
+            UdIdV2(pubkey, BlockNumber(0))..=UdIdV2(pubkey, BlockNumber(u32::MAX)),
+            |it| it.keys().collect::<KvResult<Vec<_>>>(),
+        )?;
+        for UdIdV2(issuer, block_number) in uds {
+            // The amount of a dividend is the one of the last revaluation before its creation
+            let amount = dbs
+                .bc_db_ro
+                .uds_reval()
+                .iter_rev(..=U32BE(block_number.0), |it| {
+                    it.values().map_ok(|v| v.0).next_res()
+                })?
+                .unwrap_or(SourceAmount::ZERO);
+            sources.push(WalletSource {
+                id: SourceIdV10::Ud(UdSourceIdV10 {
+                    issuer,
+                    block_number,
+                }),
+                amount,
+                conditions: WalletScriptV10::single(WalletConditionV10::Sig(issuer)),
+                pending: false,
+            });
+        }
+    }
+
+    for (tx_hash, output_index) in dbs.server.utxos_index.utxos(script) {
+        if let Some(utxo) = dbs
+            .bc_db_ro
+            .utxos()
+            .get(&UtxoIdDbV2(tx_hash, output_index))?
+        {
+            sources.push(WalletSource {
+                id: SourceIdV10::Utxo(UtxoIdV10 {
+                    tx_hash,
+                    output_index: output_index as usize,
+                }),
+                amount: utxo.source_amount,
+                conditions: utxo.wallet_script,
+                pending: false,
+            });
+        }
+    }
+
+    if with_pending {
+        // The mempool is bounded, its outputs are scanned
+        dbs.txs_mp_db.txs().iter(.., |it| {
+            for pending_tx_res in it.values() {
+                let tx = pending_tx_res?.doc;
+                let tx_hash = tx.get_hash();
+                for (output_index, output) in tx.get_outputs().iter().enumerate() {
+                    if &output.conditions.script == script {
+                        sources.push(WalletSource {
+                            id: SourceIdV10::Utxo(UtxoIdV10 {
+                                tx_hash,
+                                output_index,
+                            }),
+                            amount: output.amount,
+                            conditions: output.conditions.script.clone(),
+                            pending: true,
+                        });
+                    }
+                }
+            }
+            Ok::<_, KvError>(())
+        })?;
+        sources.retain(|source| dbs.server.txs_mp_index.spenders(source.id).is_empty());
+    }
+
+    Ok(sources)
+}
+
+pub(crate) fn balance(sources: &[WalletSource]) -> SourceAmount {
+    sources.iter().fold(SourceAmount::ZERO, |balance, source| {
+        balance + source.amount
+    })
+}
+
+pub(crate) fn single_sig_pubkey(script: &WalletScriptV10) -> Option<PublicKey> {
+    match script.root {
+        WalletSubScriptV10::Single(WalletConditionV10::Sig(pubkey)) if script.nodes.is_empty() => {
+            Some(pubkey)
+        }
+        _ => None,
+    }
+}
+
+impl DuniterServer {
+    /// Get the sources that can be unlocked by `script`, optionally with the outputs of
+    /// pending transactions.
+    pub fn get_sources(
+        &self,
+        script: WalletScriptV10,
+        with_pending: bool,
+    ) -> KvResult<Vec<WalletSource>> {
+        self.dbs_pool
+            .execute(move |dbs| get_sources(dbs, &script, with_pending))
+            .expect("dbs pool disconnected")
+    }
+    pub async fn get_sources_async(
+        &self,
+        script: WalletScriptV10,
+        with_pending: bool,
+    ) -> KvResult<Vec<WalletSource>> {
+        self.dbs_pool_async
+            .execute(move |dbs| get_sources(dbs, &script, with_pending))
+            .await
+            .expect("dbs pool disconnected")
+    }
+    /// Sum of the sources that can be unlocked by `script`.
+    pub fn get_balance(
+        &self,
+        script: WalletScriptV10,
+        with_pending: bool,
+    ) -> KvResult<SourceAmount> {
+        Ok(balance(&self.get_sources(script, with_pending)?))
+    }
+    pub async fn get_balance_async(
+        &self,
+        script: WalletScriptV10,
+        with_pending: bool,
+    ) -> KvResult<SourceAmount> {
+        Ok(balance(
+            &self.get_sources_async(script, with_pending).await?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        block, block_hash, genesis_with_member, keypair, sig_unlock, tx, utxo_input,
+    };
+    use duniter_core::common::crypto::keys::KeyPair as _;
+
+    #[test]
+    fn test_get_sources() -> anyhow::Result<()> {
+        let mut server = DuniterServer::test(DuniterCoreConf::default(), DuniterMode::Start)?;
+        let alice = keypair();
+        let alice_script = WalletScriptV10::single(WalletConditionV10::Sig(alice.public_key()));
+        let bob_script = WalletScriptV10::single(WalletConditionV10::Sig(keypair().public_key()));
+
+        // Alice becomes a member in the genesis block, to receive the dividend of block 1
+        server.apply_block(genesis_with_member(&alice, "alice"))?;
+
+        let funding = tx(&[], &[], vec![alice_script.clone(), bob_script]);
+        let mut b1 = block(0, 1, Some(block_hash(0, 0)));
+        b1.dividend = Some(1_000);
+        b1.transactions = vec![funding.to_string_object()];
+        server.apply_block(b1)?;
+
+        let ud = WalletSource {
+            id: SourceIdV10::Ud(UdSourceIdV10 {
+                issuer: alice.public_key(),
+                block_number: BlockNumber(1),
+            }),
+            amount: SourceAmount::with_base0(1_000),
+            conditions: alice_script.clone(),
+            pending: false,
+        };
+        let utxo = WalletSource {
+            id: SourceIdV10::Utxo(UtxoIdV10 {
+                tx_hash: funding.get_hash(),
+                output_index: 0,
+            }),
+            amount: SourceAmount::with_base0(100),
+            conditions: alice_script.clone(),
+            pending: false,
+        };
+        assert_eq!(
+            server.get_sources(alice_script.clone(), false)?,
+            vec![ud.clone(), utxo.clone()]
+        );
+        assert_eq!(
+            server.get_balance(alice_script.clone(), false)?,
+            SourceAmount::with_base0(1_100)
+        );
+
+        // Spends the output of alice and gives back the change
+        let spending = tx(
+            &[utxo_input(funding.get_hash(), 0)],
+            &[sig_unlock(0)],
+            vec![alice_script.clone()],
+        );
+        server.add_pending_tx_force(spending.clone())?;
+        let change = WalletSource {
+            id: SourceIdV10::Utxo(UtxoIdV10 {
+                tx_hash: spending.get_hash(),
+                output_index: 0,
+            }),
+            amount: SourceAmount::with_base0(100),
+            conditions: alice_script.clone(),
+            pending: true,
+        };
+
+        assert_eq!(
+            server.get_sources(alice_script.clone(), false)?,
+            vec![ud.clone(), utxo]
+        );
+        assert_eq!(server.get_sources(alice_script, true)?, vec![ud, change]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,615 @@
+//  Copyright (C) 2020 Éloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Mempools of identities, certifications, memberships and revocations.
+//!
+//! Documents are stored in their raw text format, each kind in its own collection,
+//! and indexed by received time so that expired documents can be trimmed. Documents
+//! written in a block are removed when the block is applied.
+
+use crate::*;
+use duniter_core::dbs::{BTreeSetV2, HashKeyV2, TimestampKeyV1, U32BE};
+use duniter_core::documents::certification::CertificationDocumentV10;
+use duniter_core::documents::identity::IdentityDocumentV10;
+use duniter_core::documents::membership::MembershipDocumentV10;
+use duniter_core::documents::revocation::RevocationDocumentV10;
+use duniter_core::documents_parser::prelude::*;
+use std::collections::HashSet;
+
+/// Default maximum number of pending documents of each kind
+pub(crate) const DEFAULT_WOT_MEMPOOL_SIZE: usize = 5_000;
+
+db_schema!(
+    WotMpV1,
+    [
+        ["idties", Idties, HashKeyV2, String],
+        ["certs", Certs, HashKeyV2, String],
+        ["memberships", Memberships, HashKeyV2, String],
+        ["revocations", Revocations, HashKeyV2, String],
+        [
+            "docs_by_received_time",
+            DocsByReceivedTime,
+            TimestampKeyV1,
+            BTreeSetV2<HashKeyV2>
+        ],
+        ["received_times", ReceivedTimes, HashKeyV2, i64],
+    ]
+);
+
+/// Current unix timestamp, used as received time
+pub(crate) fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as i64)
+}
+
+pub(crate) fn open_wot_mp_db(profile_path_opt: Option<&Path>) -> KvResult<WotMpV1Db<FileBackend>> {
+    WotMpV1Db::<FileBackend>::open(FileBackend::gen_backend_conf("wot_mp_v1", profile_path_opt))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WotDocKind {
+    Identity,
+    Certification,
+    Membership,
+    Revocation,
+}
+
+impl std::str::FromStr for WotDocKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "identity" => Ok(WotDocKind::Identity),
+            "certification" => Ok(WotDocKind::Certification),
+            "membership" => Ok(WotDocKind::Membership),
+            "revocation" => Ok(WotDocKind::Revocation),
+            _ => Err(anyhow::anyhow!("Invalid wot document kind: {}", s)),
+        }
+    }
+}
+
+/// A document handled by the wot mempools.
+#[derive(Clone, Debug)]
+pub enum WotDocument {
+    Identity(IdentityDocumentV10),
+    Certification(CertificationDocumentV10),
+    Membership(MembershipDocumentV10),
+    Revocation(RevocationDocumentV10),
+}
+
+impl WotDocument {
+    /// Parse a signed document in raw text format, its kind is read from its `Type` field.
+    pub fn parse_from_raw_text(raw: &str) -> anyhow::Result<Self> {
+        let doc_type = raw
+            .lines()
+            .find_map(|line| line.strip_prefix("Type: "))
+            .ok_or_else(|| anyhow::anyhow!("missing document type"))?;
+        Ok(match doc_type {
+            "Identity" => WotDocument::Identity(IdentityDocumentV10::parse_from_raw_text(raw)?),
+            "Certification" => {
+                WotDocument::Certification(CertificationDocumentV10::parse_from_raw_text(raw)?)
+            }
+            "Membership" => {
+                WotDocument::Membership(MembershipDocumentV10::parse_from_raw_text(raw)?)
+            }
+            "Revocation" => {
+                WotDocument::Revocation(RevocationDocumentV10::parse_from_raw_text(raw)?)
+            }
+            _ => return Err(anyhow::anyhow!("unexpected document type: {}", doc_type)),
+        })
+    }
+    pub fn kind(&self) -> WotDocKind {
+        match self {
+            WotDocument::Identity(_) => WotDocKind::Identity,
+            WotDocument::Certification(_) => WotDocKind::Certification,
+            WotDocument::Membership(_) => WotDocKind::Membership,
+            WotDocument::Revocation(_) => WotDocKind::Revocation,
+        }
+    }
+    /// Signed document in raw text format
+    pub fn as_signed_text(&self) -> String {
+        match self {
+            WotDocument::Identity(doc) => doc.as_signed_text(),
+            WotDocument::Certification(doc) => doc.as_signed_text(),
+            WotDocument::Membership(doc) => doc.as_signed_text(),
+            WotDocument::Revocation(doc) => doc.as_signed_text(),
+        }
+    }
+    pub fn hash(&self) -> Hash {
+        Hash::compute(self.as_signed_text().as_bytes())
+    }
+    fn blockstamp(&self) -> Blockstamp {
+        match self {
+            WotDocument::Identity(doc) => doc.blockstamp(),
+            WotDocument::Certification(doc) => doc.blockstamp(),
+            WotDocument::Membership(doc) => doc.blockstamp(),
+            WotDocument::Revocation(doc) => doc.identity_blockstamp(),
+        }
+    }
+    fn currency(&self) -> &str {
+        match self {
+            WotDocument::Identity(doc) => doc.currency(),
+            WotDocument::Certification(doc) => doc.currency(),
+            WotDocument::Membership(doc) => doc.currency(),
+            WotDocument::Revocation(doc) => doc.currency(),
+        }
+    }
+    fn issuer(&self) -> PublicKey {
+        match self {
+            WotDocument::Identity(doc) => doc.issuers()[0],
+            WotDocument::Certification(doc) => doc.issuers()[0],
+            WotDocument::Membership(doc) => doc.issuers()[0],
+            WotDocument::Revocation(doc) => doc.issuers()[0],
+        }
+    }
+    fn signatures_are_valid(&self) -> bool {
+        match self {
+            WotDocument::Identity(doc) => doc.verify_signatures().is_ok(),
+            WotDocument::Certification(doc) => doc.verify_signatures().is_ok(),
+            WotDocument::Membership(doc) => doc.verify_signatures().is_ok(),
+            WotDocument::Revocation(doc) => doc.verify_signatures().is_ok(),
+        }
+    }
+}
+
+/// Reason why a document is refused by the wot mempools.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WotDocRejection {
+    MempoolFull,
+    BadSignature,
+    /// The blockstamp is not in the current chain
+    UnknownBlockstamp(Blockstamp),
+    /// The document is already in the mempool
+    Duplicate,
+    /// The username or the public key of the identity is already used in the blockchain
+    IdentityAlreadyUsed,
+    /// The identity targeted by the document is neither in the blockchain nor pending
+    UnknownIdentity,
+    /// The issuer of the certification is not a member
+    IssuerNotMember,
+    WrongCurrency {
+        expected: String,
+        found: String,
+    },
+}
+
+impl WotDocRejection {
+    /// Stable error code, intended to be used by clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::MempoolFull => "MEMPOOL_FULL",
+            Self::BadSignature => "BAD_SIGNATURE",
+            Self::UnknownBlockstamp(_) => "UNKNOWN_BLOCKSTAMP",
+            Self::Duplicate => "DUPLICATE",
+            Self::IdentityAlreadyUsed => "IDENTITY_ALREADY_USED",
+            Self::UnknownIdentity => "UNKNOWN_IDENTITY",
+            Self::IssuerNotMember => "ISSUER_NOT_MEMBER",
+            Self::WrongCurrency { .. } => "WRONG_CURRENCY",
+        }
+    }
+}
+
+impl std::fmt::Display for WotDocRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MempoolFull => write!(f, "wot mempool is full"),
+            Self::BadSignature => write!(f, "bad signature"),
+            Self::UnknownBlockstamp(blockstamp) => write!(f, "unknown blockstamp {}", blockstamp),
+            Self::Duplicate => write!(f, "document already known"),
+            Self::IdentityAlreadyUsed => write!(f, "username or public key already used"),
+            Self::UnknownIdentity => write!(f, "unknown identity"),
+            Self::IssuerNotMember => write!(f, "issuer is not a member"),
+            Self::WrongCurrency { expected, found } => {
+                write!(f, "wrong currency: expected {}, found {}", expected, found)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WotDocRejection {}
+
+/// Check a new document against the blockchain and the mempools, then add it.
+/// An empty `currency` means that the currency is not known yet.
+pub(crate) fn accept_new_doc<BcDb: BcV2DbReadable>(
+    bc_db: &BcDb,
+    wot_mp_db: &WotMpV1Db<FileBackend>,
+    currency: &str,
+    max_size: usize,
+    doc: WotDocument,
+    received_time: i64,
+) -> KvResult<Result<(), WotDocRejection>> {
+    if !currency.is_empty() && doc.currency() != currency {
+        return Ok(Err(WotDocRejection::WrongCurrency {
+            expected: currency.to_owned(),
+            found: doc.currency().to_owned(),
+        }));
+    }
+    if !doc.signatures_are_valid() {
+        return Ok(Err(WotDocRejection::BadSignature));
+    }
+    let hash = doc.hash();
+    if get_raw_doc(wot_mp_db, doc.kind(), hash)?.is_some() {
+        return Ok(Err(WotDocRejection::Duplicate));
+    }
+    if count_docs(wot_mp_db, doc.kind())? >= max_size {
+        return Ok(Err(WotDocRejection::MempoolFull));
+    }
+    let blockstamp = doc.blockstamp();
+    // The blockstamp of the genesis block is accepted before the genesis block is written
+    if blockstamp.number.0 != 0 || bc_db.blocks_meta().count()? > 0 {
+        let in_chain = bc_db
+            .blocks_meta()
+            .get(&U32BE(blockstamp.number.0))?
+            .map_or(false, |block_meta| block_meta.hash == blockstamp.hash.0);
+        if !in_chain {
+            return Ok(Err(WotDocRejection::UnknownBlockstamp(blockstamp)));
+        }
+    }
+
+    let rejection_opt = match doc {
+        WotDocument::Identity(ref idty) => {
+            if bc_db
+                .uids_index()
+                .get(&idty.username().to_owned())?
+                .is_some()
+                || bc_db
+                    .identities()
+                    .get(&PubKeyKeyV2(doc.issuer()))?
+                    .is_some()
+            {
+                Some(WotDocRejection::IdentityAlreadyUsed)
+            } else {
+                None
+            }
+        }
+        WotDocument::Certification(ref cert) => {
+            let target = cert.to_compact_document().target;
+            if !crate::rules::is_member(bc_db, doc.issuer())? {
+                Some(WotDocRejection::IssuerNotMember)
+            } else if !identity_exists(bc_db, wot_mp_db, target)? {
+                Some(WotDocRejection::UnknownIdentity)
+            } else {
+                None
+            }
+        }
+        WotDocument::Membership(_) => {
+            if identity_exists(bc_db, wot_mp_db, doc.issuer())? {
+                None
+            } else {
+                Some(WotDocRejection::UnknownIdentity)
+            }
+        }
+        WotDocument::Revocation(_) => {
+            if bc_db
+                .identities()
+                .get(&PubKeyKeyV2(doc.issuer()))?
+                .is_some()
+            {
+                None
+            } else {
+                Some(WotDocRejection::UnknownIdentity)
+            }
+        }
+    };
+    if let Some(rejection) = rejection_opt {
+        return Ok(Err(rejection));
+    }
+
+    add_doc(wot_mp_db, &doc, received_time)?;
+    Ok(Ok(()))
+}
+
+/// Whether the identity of `pubkey` is in the blockchain or pending
+fn identity_exists<BcDb: BcV2DbReadable>(
+    bc_db: &BcDb,
+    wot_mp_db: &WotMpV1Db<FileBackend>,
+    pubkey: PublicKey,
+) -> KvResult<bool> {
+    if bc_db.identities().get(&PubKeyKeyV2(pubkey))?.is_some() {
+        return Ok(true);
+    }
+    Ok(get_docs(wot_mp_db, WotDocKind::Identity)?
+        .iter()
+        .any(|idty| idty.issuer() == pubkey))
+}
+
+fn add_doc(
+    wot_mp_db: &WotMpV1Db<FileBackend>,
+    doc: &WotDocument,
+    received_time: i64,
+) -> KvResult<()> {
+    let hash = HashKeyV2(doc.hash());
+    let raw = doc.as_signed_text();
+    match doc.kind() {
+        WotDocKind::Identity => wot_mp_db.idties_write().upsert(hash, raw)?,
+        WotDocKind::Certification => wot_mp_db.certs_write().upsert(hash, raw)?,
+        WotDocKind::Membership => wot_mp_db.memberships_write().upsert(hash, raw)?,
+        WotDocKind::Revocation => wot_mp_db.revocations_write().upsert(hash, raw)?,
+    }
+    let mut hashs = wot_mp_db
+        .docs_by_received_time()
+        .get(&TimestampKeyV1(received_time))?
+        .unwrap_or_default();
+    hashs.0.insert(hash);
+    wot_mp_db
+        .docs_by_received_time_write()
+        .upsert(TimestampKeyV1(received_time), hashs)?;
+    wot_mp_db.received_times_write().upsert(hash, received_time)
+}
+
+fn get_raw_doc(
+    wot_mp_db: &WotMpV1Db<FileBackend>,
+    kind: WotDocKind,
+    hash: Hash,
+) -> KvResult<Option<String>> {
+    let hash = HashKeyV2(hash);
+    match kind {
+        WotDocKind::Identity => wot_mp_db.idties().get(&hash),
+        WotDocKind::Certification => wot_mp_db.certs().get(&hash),
+        WotDocKind::Membership => wot_mp_db.memberships().get(&hash),
+        WotDocKind::Revocation => wot_mp_db.revocations().get(&hash),
+    }
+}
+
+pub(crate) fn count_docs(wot_mp_db: &WotMpV1Db<FileBackend>, kind: WotDocKind) -> KvResult<usize> {
+    match kind {
+        WotDocKind::Identity => wot_mp_db.idties().count(),
+        WotDocKind::Certification => wot_mp_db.certs().count(),
+        WotDocKind::Membership => wot_mp_db.memberships().count(),
+        WotDocKind::Revocation => wot_mp_db.revocations().count(),
+    }
+}
+
+/// Pending documents of the given kind, sorted by received time
+pub(crate) fn get_docs(
+    wot_mp_db: &WotMpV1Db<FileBackend>,
+    kind: WotDocKind,
+) -> KvResult<Vec<WotDocument>> {
+    wot_mp_db.docs_by_received_time().iter(.., |it| {
+        let mut docs = Vec::new();
+        for hashs_res in it.values() {
+            for HashKeyV2(hash) in hashs_res?.0 {
+                if let Some(raw) = get_raw_doc(wot_mp_db, kind, hash)? {
+                    // Stored documents were parsed before being written
+                    if let Ok(doc) = WotDocument::parse_from_raw_text(&raw) {
+                        docs.push(doc);
+                    }
+                }
+            }
+        }
+        Ok::<_, KvError>(docs)
+    })
+}
+
+pub(crate) fn remove_doc_by_hash(wot_mp_db: &WotMpV1Db<FileBackend>, hash: Hash) -> KvResult<()> {
+    let hash = HashKeyV2(hash);
+    wot_mp_db.idties_write().remove(hash)?;
+    wot_mp_db.certs_write().remove(hash)?;
+    wot_mp_db.memberships_write().remove(hash)?;
+    wot_mp_db.revocations_write().remove(hash)?;
+    if let Some(received_time) = wot_mp_db.received_times().get(&hash)? {
+        let received_time = TimestampKeyV1(received_time);
+        if let Some(mut hashs) = wot_mp_db.docs_by_received_time().get(&received_time)? {
+            hashs.0.remove(&hash);
+            if hashs.0.is_empty() {
+                wot_mp_db
+                    .docs_by_received_time_write()
+                    .remove(received_time)?;
+            } else {
+                wot_mp_db
+                    .docs_by_received_time_write()
+                    .upsert(received_time, hashs)?;
+            }
+        }
+        wot_mp_db.received_times_write().remove(hash)?;
+    }
+    Ok(())
+}
+
+/// Remove the pending documents written in `block`. Pending identities using the public key
+/// or the username of a written identity cannot be written anymore, they are removed too.
+pub(crate) fn remove_written_docs(
+    wot_mp_db: &WotMpV1Db<FileBackend>,
+    block: &DubpBlockV10,
+) -> KvResult<()> {
+    let idties_pubkeys: HashSet<PublicKey> = block
+        .identities()
+        .iter()
+        .map(|idty| idty.issuers()[0])
+        .collect();
+    let usernames: HashSet<&str> = block
+        .identities()
+        .iter()
+        .map(|idty| idty.username())
+        .collect();
+    let certs: HashSet<(PublicKey, PublicKey)> = block
+        .certifications()
+        .iter()
+        .map(|cert| {
+            let cert = cert.to_compact_document();
+            (cert.issuer, cert.target)
+        })
+        .collect();
+    let memberships: HashSet<(PublicKey, Blockstamp)> = block
+        .joiners()
+        .iter()
+        .chain(block.actives())
+        .chain(block.leavers())
+        .map(|membership| (membership.issuers()[0], membership.blockstamp()))
+        .collect();
+    let revoked: HashSet<PublicKey> = block
+        .revoked()
+        .iter()
+        .map(|revocation| revocation.to_compact_document().issuer)
+        .collect();
+
+    let mut written = Vec::new();
+    if !idties_pubkeys.is_empty() {
+        written.extend(find_docs(wot_mp_db, WotDocKind::Identity, |doc| {
+            matches!(doc, WotDocument::Identity(idty)
+                if idties_pubkeys.contains(&doc.issuer()) || usernames.contains(idty.username()))
+        })?);
+    }
+    if !certs.is_empty() {
+        written.extend(find_docs(wot_mp_db, WotDocKind::Certification, |doc| {
+            matches!(doc, WotDocument::Certification(cert)
+                if certs.contains(&(doc.issuer(), cert.to_compact_document().target)))
+        })?);
+    }
+    if !memberships.is_empty() {
+        written.extend(find_docs(wot_mp_db, WotDocKind::Membership, |doc| {
+            memberships.contains(&(doc.issuer(), doc.blockstamp()))
+        })?);
+    }
+    if !revoked.is_empty() {
+        written.extend(find_docs(wot_mp_db, WotDocKind::Revocation, |doc| {
+            revoked.contains(&doc.issuer())
+        })?);
+    }
+    for hash in written {
+        remove_doc_by_hash(wot_mp_db, hash)?;
+    }
+    Ok(())
+}
+
+/// Remove the documents whose blockstamp is one of the `reverted` blocks, they can no longer
+/// be written in the current chain.
+pub(crate) fn remove_docs_by_blockstamps(
+    wot_mp_db: &WotMpV1Db<FileBackend>,
+    reverted: &HashSet<Blockstamp>,
+) -> KvResult<()> {
+    let mut invalid = Vec::new();
+    for kind in [
+        WotDocKind::Identity,
+        WotDocKind::Certification,
+        WotDocKind::Membership,
+        WotDocKind::Revocation,
+    ]
+    .iter()
+    {
+        invalid.extend(find_docs(wot_mp_db, *kind, |doc| {
+            reverted.contains(&doc.blockstamp())
+        })?);
+    }
+    for hash in invalid {
+        remove_doc_by_hash(wot_mp_db, hash)?;
+    }
+    Ok(())
+}
+
+/// Hashs of the pending documents of the given kind matching `matches`
+fn find_docs<F>(
+    wot_mp_db: &WotMpV1Db<FileBackend>,
+    kind: WotDocKind,
+    matches: F,
+) -> KvResult<Vec<Hash>>
+where
+    F: Fn(&WotDocument) -> bool,
+{
+    match kind {
+        WotDocKind::Identity => wot_mp_db.idties().iter(.., |it| filter_docs(it, &matches)),
+        WotDocKind::Certification => wot_mp_db.certs().iter(.., |it| filter_docs(it, &matches)),
+        WotDocKind::Membership => wot_mp_db
+            .memberships()
+            .iter(.., |it| filter_docs(it, &matches)),
+        WotDocKind::Revocation => wot_mp_db
+            .revocations()
+            .iter(.., |it| filter_docs(it, &matches)),
+    }
+}
+
+fn filter_docs<I, F>(it: I, matches: &F) -> KvResult<Vec<Hash>>
+where
+    I: Iterator<Item = KvResult<(HashKeyV2, String)>>,
+    F: Fn(&WotDocument) -> bool,
+{
+    let mut hashs = Vec::new();
+    for entry_res in it {
+        let (HashKeyV2(hash), raw) = entry_res?;
+        // Stored documents were parsed before being written
+        if let Ok(doc) = WotDocument::parse_from_raw_text(&raw) {
+            if matches(&doc) {
+                hashs.push(hash);
+            }
+        }
+    }
+    Ok(hashs)
+}
+
+/// Remove the documents received before `limit_time`
+pub(crate) fn trim_expired_docs(
+    wot_mp_db: &WotMpV1Db<FileBackend>,
+    limit_time: i64,
+) -> KvResult<()> {
+    let expired = wot_mp_db
+        .docs_by_received_time()
+        .iter(..TimestampKeyV1(limit_time), |it| {
+            it.collect::<KvResult<Vec<_>>>()
+        })?;
+    for (received_time, hashs) in expired {
+        for HashKeyV2(hash) in hashs.0 {
+            remove_doc_by_hash(wot_mp_db, hash)?;
+        }
+        wot_mp_db
+            .docs_by_received_time_write()
+            .remove(received_time)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn remove_all_docs(wot_mp_db: &WotMpV1Db<FileBackend>) -> KvResult<()> {
+    wot_mp_db.idties_write().clear()?;
+    wot_mp_db.certs_write().clear()?;
+    wot_mp_db.memberships_write().clear()?;
+    wot_mp_db.revocations_write().clear()?;
+    wot_mp_db.docs_by_received_time_write().clear()?;
+    wot_mp_db.received_times_write().clear()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::keypair;
+    use duniter_core::common::crypto::keys::KeyPair as _;
+    use duniter_core::documents::identity::IdentityDocumentV10Builder;
+
+    #[test]
+    fn test_parse_signed_doc() -> anyhow::Result<()> {
+        let alice = keypair();
+        let idty = IdentityDocumentV10Builder {
+            currency: "test",
+            username: "alice",
+            blockstamp: &Blockstamp::default(),
+            issuer: alice.public_key(),
+        }
+        .build_and_sign(vec![alice.generate_signator()]);
+        let doc = WotDocument::Identity(idty);
+
+        let parsed = WotDocument::parse_from_raw_text(&doc.as_signed_text())?;
+        assert_eq!(parsed.kind(), WotDocKind::Identity);
+        assert_eq!(parsed.hash(), doc.hash());
+        assert_eq!(parsed.issuer(), alice.public_key());
+        assert!(parsed.signatures_are_valid());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_unknown_doc_type() {
+        assert!(WotDocument::parse_from_raw_text("Version: 10\nType: Transaction\n").is_err());
+        assert!(WotDocument::parse_from_raw_text("").is_err());
+    }
+}
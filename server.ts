@@ -45,7 +45,7 @@ import {LevelUp} from "levelup";
 import {BMAConstants} from "./app/modules/bma/lib/constants"
 import {HttpMilestonePage} from "./app/modules/bma/lib/dtos"
 import * as toJson from "./app/modules/bma/lib/tojson"
-import { rawTxParseAndVerify, txVerify } from "./neon/lib"
+import { rawTxParseAndVerify, subscribeServerEvents, txVerify } from "./neon/lib"
 import { TransactionDTOV10 } from "./neon/native"
 import { format } from "util";
 
@@ -72,6 +72,8 @@ export class Server extends stream.Duplex implements HookableServer {
 
   private paramsP:Promise<FileDALParams>
   private endpointsDefinitions:(()=>Promise<string>)[] = []
+  private rustEndpoints:string[] = []
+  private unsubscribeServerEvents:(() => void)|null = null
   private wrongEndpointsFilters:((endpoints:string[])=>Promise<string[]>)[] = []
   startService:()=>Promise<void>
   stopService:()=>Promise<void>
@@ -355,11 +357,13 @@ export class Server extends stream.Duplex implements HookableServer {
   async initDAL(conf:ConfDTO|null = null, commandName: string|null = null) {
     // Init DAL
     await this.dal.init(this.conf, commandName);
-    // Get rust endpoints
-    for (let endpoint of this.dal.getRustEndpoints()) {
-      //logger.info("TMP: rustEndpoint: %s", endpoint);
-      this.addEndpointsDefinitions(async () => endpoint);
-    }
+    // Get rust endpoints, then follow their changes
+    this.rustEndpoints = this.dal.getRustEndpoints()
+    this.unsubscribeServerEvents = subscribeServerEvents(this.dal.rustServer, (event) => {
+      if (event.type === 'selfEndpointsChanged') {
+        this.onRustEndpointsChanged()
+      }
+    }, (err) => logger.warn('Server events stream closed: %s', err))
     // Maintenance
     let head_1 = await this.dal.bindexDAL.head(1);
     if (head_1) {
@@ -520,7 +524,14 @@ export class Server extends stream.Duplex implements HookableServer {
 
   async disconnect() {
     await this.documentFIFO.closeFIFO()
+    if (this.unsubscribeServerEvents) {
+      this.unsubscribeServerEvents()
+      this.unsubscribeServerEvents = null
+    }
     if (this.dal) {
+      if (this.dal.rustServer) {
+        this.dal.rustServer.shutdown()
+      }
       await this.dal.close()
     }
   }
@@ -651,7 +662,22 @@ export class Server extends stream.Duplex implements HookableServer {
 
   async getEndpoints() {
     const endpoints = await Promise.all(this.endpointsDefinitions.map(d => d()))
-    return endpoints.filter(ep => !!ep)
+    return endpoints.concat(this.rustEndpoints).filter(ep => !!ep)
+  }
+
+  /**
+   * Rebuild the peer document with the new rust endpoints, then broadcast it.
+   */
+  private async onRustEndpointsChanged() {
+    try {
+      this.rustEndpoints = this.dal.getRustEndpoints()
+      const selfPeer = await this.PeeringService.generateSelfPeer(this.conf)
+      if (selfPeer) {
+        this.dal.rustServer.updateSelfPeer(PeerDTO.fromDBPeer(selfPeer))
+      }
+    } catch (e) {
+      logger.error('Fail to update peer document with new rust endpoints: %s', e)
+    }
   }
 
   async getWrongEndpoints(endpoints:string[]) {